@@ -0,0 +1,157 @@
+use std::fmt::{Display, Formatter};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CidrBlock {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl CidrBlock {
+    pub fn new(address: IpAddr, prefix_len: u8) -> io::Result<Self> {
+        let max_prefix_len = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "prefix length {} exceeds {} bits for {}",
+                    prefix_len, max_prefix_len, address
+                ),
+            ));
+        }
+
+        Ok(Self {
+            network: mask_address(address, prefix_len),
+            prefix_len,
+        })
+    }
+
+    // A bare address is accepted as a single-host block (/32 or /128).
+    pub fn parse(value: &str) -> io::Result<Self> {
+        let value = value.trim();
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => {
+                let prefix_len = prefix_len.parse::<u8>().map_err(|_| {
+                    io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid CIDR prefix length: {}", value),
+                    )
+                })?;
+                (address, Some(prefix_len))
+            }
+            None => (value, None),
+        };
+        let address = address.parse::<IpAddr>().map_err(|_| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid CIDR address: {}", value),
+            )
+        })?;
+        let prefix_len = prefix_len.unwrap_or(match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        });
+
+        Self::new(address, prefix_len)
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let address = address.to_canonical();
+        match (self.network, address) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => {
+                mask_address(address, self.prefix_len) == self.network
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Display for CidrBlock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientAcl {
+    blocks: Vec<CidrBlock>,
+}
+
+impl ClientAcl {
+    pub fn new(blocks: Vec<CidrBlock>) -> io::Result<Self> {
+        if blocks.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "client ACL must contain at least one CIDR block",
+            ));
+        }
+
+        Ok(Self { blocks })
+    }
+
+    pub fn parse<I, S>(entries: I) -> io::Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let blocks = entries
+            .into_iter()
+            .map(|entry| CidrBlock::parse(entry.as_ref()))
+            .collect::<io::Result<Vec<_>>>()?;
+        Self::new(blocks)
+    }
+
+    pub fn loopback() -> Self {
+        Self {
+            blocks: vec![
+                CidrBlock {
+                    network: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)),
+                    prefix_len: 8,
+                },
+                CidrBlock {
+                    network: IpAddr::V6(Ipv6Addr::LOCALHOST),
+                    prefix_len: 128,
+                },
+            ],
+        }
+    }
+
+    pub fn blocks(&self) -> &[CidrBlock] {
+        &self.blocks
+    }
+
+    pub fn permits(&self, address: IpAddr) -> bool {
+        self.blocks.iter().any(|block| block.contains(address))
+    }
+}
+
+impl Default for ClientAcl {
+    fn default() -> Self {
+        Self::loopback()
+    }
+}
+
+fn mask_address(address: IpAddr, prefix_len: u8) -> IpAddr {
+    match address {
+        IpAddr::V4(address) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix_len)).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(address) & mask))
+        }
+        IpAddr::V6(address) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix_len)).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(address) & mask))
+        }
+    }
+}
@@ -0,0 +1,580 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::base64;
+use crate::digest;
+
+pub(crate) const LOGIN_USERNAME_CHALLENGE: &str = "VXNlcm5hbWU6";
+pub(crate) const LOGIN_PASSWORD_CHALLENGE: &str = "UGFzc3dvcmQ6";
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct AuthRequest {
+    pub mechanism: &'static str,
+    pub authzid: Option<String>,
+    pub username: String,
+    pub password: String,
+    pub client_addr: Option<SocketAddr>,
+    pub secured: bool,
+}
+
+impl std::fmt::Debug for AuthRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthRequest")
+            .field("mechanism", &self.mechanism)
+            .field("authzid", &self.authzid)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .field("client_addr", &self.client_addr)
+            .field("secured", &self.secured)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthenticationError {
+    InvalidCredentials,
+    Temporary(String),
+}
+
+impl Display for AuthenticationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthenticationError::InvalidCredentials => f.write_str("invalid credentials"),
+            AuthenticationError::Temporary(message) => f.write_str(message),
+        }
+    }
+}
+
+// Returns the identity the session is authenticated as, which backends may canonicalize.
+pub trait Authenticator: Send + Sync + 'static {
+    fn authenticate(&self, request: &AuthRequest) -> Result<String, AuthenticationError>;
+}
+
+// Only salted hashes are accepted: an unsalted {SHA256} digest falls to a precomputed table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PasswordHash {
+    digest: Vec<u8>,
+    salt: Vec<u8>,
+}
+
+impl PasswordHash {
+    // Dovecot passwd-file scheme {SSHA256}, base64 or `.HEX` encoded. It is a legacy,
+    // single-round hash: fast enough that a leaked file can be brute-forced.
+    fn parse(value: &str) -> Result<Self, &'static str> {
+        const MALFORMED: &str = "unsupported or malformed password hash";
+        let (scheme, encoded) = value
+            .strip_prefix('{')
+            .and_then(|rest| rest.split_once('}'))
+            .ok_or(MALFORMED)?;
+        let scheme = scheme.to_ascii_uppercase();
+        let (scheme, hex_encoded) = match scheme.strip_suffix(".HEX") {
+            Some(scheme) => (scheme.to_string(), true),
+            None => (scheme.strip_suffix(".B64").unwrap_or(&scheme).to_string(), false),
+        };
+        if scheme == "SHA256" {
+            return Err("unsalted {SHA256} hashes are not accepted; use {SSHA256}");
+        }
+        let bytes = if hex_encoded {
+            decode_hex(encoded)
+        } else {
+            base64::decode(encoded)
+        };
+
+        match bytes {
+            Some(bytes) if scheme == "SSHA256" && bytes.len() > 32 => {
+                let (digest, salt) = bytes.split_at(32);
+                Ok(Self {
+                    digest: digest.to_vec(),
+                    salt: salt.to_vec(),
+                })
+            }
+            _ => Err(MALFORMED),
+        }
+    }
+
+    // Checked against on a user miss, so an unknown user costs as much as a wrong password.
+    fn dummy() -> Self {
+        Self {
+            digest: vec![0; 32],
+            salt: vec![0; 8],
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        let mut input = password.as_bytes().to_vec();
+        input.extend_from_slice(&self.salt);
+        constant_time_eq(&digest::sha256(&input), &self.digest)
+    }
+}
+
+#[derive(Debug)]
+pub struct PasswordFileAuthenticator {
+    path: PathBuf,
+    entries: RwLock<HashMap<String, PasswordHash>>,
+}
+
+impl PasswordFileAuthenticator {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let entries = load_password_file(&path)?;
+        Ok(Self {
+            path,
+            entries: RwLock::new(entries),
+        })
+    }
+
+    // On error the previously loaded entries stay in place.
+    pub fn reload(&self) -> io::Result<usize> {
+        let entries = load_password_file(&self.path)?;
+        let count = entries.len();
+        *self
+            .entries
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = entries;
+        Ok(count)
+    }
+
+    pub fn user_count(&self) -> usize {
+        self.entries
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .len()
+    }
+}
+
+impl Authenticator for PasswordFileAuthenticator {
+    fn authenticate(&self, request: &AuthRequest) -> Result<String, AuthenticationError> {
+        if request
+            .authzid
+            .as_deref()
+            .is_some_and(|authzid| authzid != request.username)
+        {
+            return Err(AuthenticationError::InvalidCredentials);
+        }
+
+        let entries = self
+            .entries
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let verified = match entries.get(&request.username) {
+            Some(hash) => hash.verify(&request.password),
+            None => {
+                PasswordHash::dummy().verify(&request.password);
+                false
+            }
+        };
+        if verified {
+            Ok(request.username.clone())
+        } else {
+            Err(AuthenticationError::InvalidCredentials)
+        }
+    }
+}
+
+fn load_password_file(path: &Path) -> io::Result<HashMap<String, PasswordHash>> {
+    let contents = fs::read_to_string(path)?;
+    let mut entries = HashMap::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = |message: &str| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), index + 1, message),
+            )
+        };
+        let mut fields = line.split(':');
+        let username = fields.next().unwrap_or("");
+        let password = fields
+            .next()
+            .ok_or_else(|| invalid("expected user:{SCHEME}hash"))?;
+        if username.is_empty() {
+            return Err(invalid("empty user name"));
+        }
+        let hash = PasswordHash::parse(password).map_err(invalid)?;
+        if entries.insert(username.to_string(), hash).is_some() {
+            return Err(invalid(&format!("duplicate user {}", username)));
+        }
+    }
+
+    Ok(entries)
+}
+
+fn decode_hex(encoded: &str) -> Option<Vec<u8>> {
+    if !encoded.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..encoded.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(encoded.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+pub const DEFAULT_DOVECOT_AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Client side of the Dovecot auth protocol (doc.dovecot.org "Authentication Protocol"),
+// the same socket Postfix uses with smtpd_sasl_type = dovecot. One connection per attempt.
+#[cfg(unix)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DovecotAuthenticator {
+    socket_path: PathBuf,
+    service: String,
+    timeout: Duration,
+}
+
+#[cfg(unix)]
+impl DovecotAuthenticator {
+    pub fn new(socket_path: impl Into<PathBuf>) -> Self {
+        Self {
+            socket_path: socket_path.into(),
+            service: "smtp".to_string(),
+            timeout: DEFAULT_DOVECOT_AUTH_TIMEOUT,
+        }
+    }
+
+    pub fn with_service(mut self, service: impl Into<String>) -> Self {
+        self.service = service.into();
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn exchange(&self, request: &AuthRequest) -> io::Result<Result<String, AuthenticationError>> {
+        let mut writer = UnixStream::connect(&self.socket_path)?;
+        writer.set_read_timeout(Some(self.timeout))?;
+        writer.set_write_timeout(Some(self.timeout))?;
+        let mut reader = BufReader::new(writer.try_clone()?);
+
+        write!(writer, "VERSION\t1\t2\nCPID\t{}\n", std::process::id())?;
+        writer.flush()?;
+
+        let mut version_seen = false;
+        let mut plain_offered = false;
+        loop {
+            let fields = read_dovecot_line(&mut reader)?;
+            match fields[0].as_str() {
+                "VERSION" => {
+                    if fields.get(1).map(String::as_str) != Some("1") {
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("unsupported auth protocol version: {}", fields[1..].join(".")),
+                        ));
+                    }
+                    version_seen = true;
+                }
+                "MECH" => {
+                    plain_offered |= fields
+                        .get(1)
+                        .is_some_and(|mechanism| mechanism.eq_ignore_ascii_case("PLAIN"));
+                }
+                "DONE" => break,
+                _ => {}
+            }
+        }
+        if !version_seen {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "auth server handshake did not include VERSION",
+            ));
+        }
+        if !plain_offered {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "auth server does not offer the PLAIN mechanism",
+            ));
+        }
+
+        // Both SMTP mechanisms are forwarded as PLAIN since the password is already in hand.
+        let mut response = Vec::new();
+        response.extend_from_slice(request.authzid.as_deref().unwrap_or("").as_bytes());
+        response.push(0);
+        response.extend_from_slice(request.username.as_bytes());
+        response.push(0);
+        response.extend_from_slice(request.password.as_bytes());
+
+        let mut command = format!(
+            "AUTH\t1\tPLAIN\tservice={}\tnologin",
+            escape_dovecot_value(&self.service)
+        );
+        if request.secured {
+            command.push_str("\tsecured");
+        }
+        if let Some(client_addr) = request.client_addr {
+            command.push_str(&format!("\trip={}", client_addr.ip()));
+        }
+        command.push_str(&format!("\tresp={}\n", base64::encode(&response)));
+        writer.write_all(command.as_bytes())?;
+        writer.flush()?;
+
+        let fields = read_dovecot_line(&mut reader)?;
+        if fields.get(1).map(String::as_str) != Some("1") {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("auth server replied for unknown request: {}", fields.join(" ")),
+            ));
+        }
+        let parameter = |name: &str| {
+            fields[2..].iter().find_map(|field| {
+                field
+                    .strip_prefix(name)
+                    .and_then(|rest| rest.strip_prefix('='))
+                    .map(str::to_string)
+            })
+        };
+
+        match fields[0].as_str() {
+            "OK" => Ok(Ok(parameter("user").unwrap_or_else(|| request.username.clone()))),
+            "FAIL" if fields[2..].iter().any(|field| field == "temp") => {
+                Ok(Err(AuthenticationError::Temporary(
+                    parameter("reason").unwrap_or_else(|| "temporary auth failure".to_string()),
+                )))
+            }
+            "FAIL" => Ok(Err(AuthenticationError::InvalidCredentials)),
+            other => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unexpected auth server reply: {}", other),
+            )),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Authenticator for DovecotAuthenticator {
+    fn authenticate(&self, request: &AuthRequest) -> Result<String, AuthenticationError> {
+        self.exchange(request).unwrap_or_else(|error| {
+            Err(AuthenticationError::Temporary(format!(
+                "dovecot auth socket {}: {}",
+                self.socket_path.display(),
+                error
+            )))
+        })
+    }
+}
+
+#[cfg(unix)]
+fn read_dovecot_line<R>(reader: &mut R) -> io::Result<Vec<String>>
+where
+    R: BufRead,
+{
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "auth server closed the connection",
+        ));
+    }
+
+    Ok(line
+        .trim_end_matches('\n')
+        .split('\t')
+        .map(unescape_dovecot_value)
+        .collect())
+}
+
+// Values escape \x01, TAB, LF and CR as \x01 followed by '1', 't', 'n' or 'r'.
+#[cfg(unix)]
+fn escape_dovecot_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for character in value.chars() {
+        match character {
+            '\u{1}' => escaped.push_str("\u{1}1"),
+            '\t' => escaped.push_str("\u{1}t"),
+            '\n' => escaped.push_str("\u{1}n"),
+            '\r' => escaped.push_str("\u{1}r"),
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+#[cfg(unix)]
+fn unescape_dovecot_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut characters = value.chars();
+    while let Some(character) = characters.next() {
+        if character != '\u{1}' {
+            unescaped.push(character);
+            continue;
+        }
+        match characters.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('1') => unescaped.push('\u{1}'),
+            Some(other) => unescaped.push(other),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SaslMechanism {
+    Plain,
+    Login,
+}
+
+impl SaslMechanism {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        if name.eq_ignore_ascii_case("PLAIN") {
+            Some(Self::Plain)
+        } else if name.eq_ignore_ascii_case("LOGIN") {
+            Some(Self::Login)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            SaslMechanism::Plain => "PLAIN",
+            SaslMechanism::Login => "LOGIN",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct SaslCredentials {
+    pub(crate) authzid: Option<String>,
+    pub(crate) username: String,
+    pub(crate) password: String,
+}
+
+// Passwords must never reach logs through a stray `{:?}`.
+impl std::fmt::Debug for SaslCredentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SaslCredentials")
+            .field("authzid", &self.authzid)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SaslExchange {
+    Credentials(SaslCredentials),
+    Cancelled,
+    Malformed,
+    Disconnected,
+}
+
+// `challenge` sends a 334 continuation and returns the client's reply line, or `None`
+// when the client went away.
+pub(crate) fn run_server_exchange<F>(
+    mechanism: SaslMechanism,
+    initial_response: Option<&str>,
+    mut challenge: F,
+) -> io::Result<SaslExchange>
+where
+    F: FnMut(&str) -> io::Result<Option<String>>,
+{
+    match mechanism {
+        SaslMechanism::Plain => {
+            let response = match initial_response {
+                Some("=") => String::new(),
+                Some(response) => response.to_string(),
+                None => match challenge("")? {
+                    Some(response) => response,
+                    None => return Ok(SaslExchange::Disconnected),
+                },
+            };
+            if response == "*" {
+                return Ok(SaslExchange::Cancelled);
+            }
+            Ok(decode_plain_response(&response)
+                .map(SaslExchange::Credentials)
+                .unwrap_or(SaslExchange::Malformed))
+        }
+        SaslMechanism::Login => {
+            let username = match initial_response {
+                Some(response) => response.to_string(),
+                None => match challenge(LOGIN_USERNAME_CHALLENGE)? {
+                    Some(response) => response,
+                    None => return Ok(SaslExchange::Disconnected),
+                },
+            };
+            if username == "*" {
+                return Ok(SaslExchange::Cancelled);
+            }
+            let password = match challenge(LOGIN_PASSWORD_CHALLENGE)? {
+                Some(response) => response,
+                None => return Ok(SaslExchange::Disconnected),
+            };
+            if password == "*" {
+                return Ok(SaslExchange::Cancelled);
+            }
+
+            match (decode_text(&username), decode_text(&password)) {
+                (Some(username), Some(password)) if !username.is_empty() => {
+                    Ok(SaslExchange::Credentials(SaslCredentials {
+                        authzid: None,
+                        username,
+                        password,
+                    }))
+                }
+                _ => Ok(SaslExchange::Malformed),
+            }
+        }
+    }
+}
+
+// RFC 4616: authzid NUL authcid NUL passwd.
+pub(crate) fn decode_plain_response(response: &str) -> Option<SaslCredentials> {
+    let decoded = decode_text(response)?;
+    let mut fields = decoded.split('\0');
+    let authzid = fields.next()?;
+    let username = fields.next()?;
+    let password = fields.next()?;
+    if fields.next().is_some() || username.is_empty() {
+        return None;
+    }
+
+    Some(SaslCredentials {
+        authzid: (!authzid.is_empty()).then(|| authzid.to_string()),
+        username: username.to_string(),
+        password: password.to_string(),
+    })
+}
+
+pub(crate) fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    if left.len() != right.len() {
+        return false;
+    }
+
+    left.iter()
+        .zip(right)
+        .fold(0u8, |difference, (left, right)| difference | (left ^ right))
+        == 0
+}
+
+fn decode_text(encoded: &str) -> Option<String> {
+    String::from_utf8(base64::decode(encoded.trim())?).ok()
+}
+
+pub(crate) fn encode_xtext(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if (33..=126).contains(&byte) && byte != b'+' && byte != b'=' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("+{:02X}", byte));
+        }
+    }
+    encoded
+}
@@ -0,0 +1,71 @@
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(crate) fn encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let b0 = chunk[0];
+        let b1 = chunk.get(1).copied().unwrap_or(0);
+        let b2 = chunk.get(2).copied().unwrap_or(0);
+        let triple = (u32::from(b0) << 16) | (u32::from(b1) << 8) | u32::from(b2);
+
+        encoded.push(ALPHABET[(triple >> 18) as usize & 0x3f] as char);
+        encoded.push(ALPHABET[(triple >> 12) as usize & 0x3f] as char);
+        if chunk.len() > 1 {
+            encoded.push(ALPHABET[(triple >> 6) as usize & 0x3f] as char);
+        } else {
+            encoded.push('=');
+        }
+        if chunk.len() > 2 {
+            encoded.push(ALPHABET[triple as usize & 0x3f] as char);
+        } else {
+            encoded.push('=');
+        }
+    }
+
+    encoded
+}
+
+// Strict RFC 4648 decoding: padding is required and no whitespace is skipped.
+pub(crate) fn decode(encoded: &str) -> Option<Vec<u8>> {
+    let bytes = encoded.as_bytes();
+    if !bytes.len().is_multiple_of(4) {
+        return None;
+    }
+
+    let mut decoded = Vec::with_capacity(bytes.len() / 4 * 3);
+    for (index, chunk) in bytes.chunks(4).enumerate() {
+        let last_chunk = index + 1 == bytes.len() / 4;
+        let padding = chunk.iter().rev().take_while(|byte| **byte == b'=').count();
+        if padding > 2 || (padding > 0 && !last_chunk) {
+            return None;
+        }
+
+        let mut triple = 0u32;
+        for byte in &chunk[..4 - padding] {
+            triple = (triple << 6) | u32::from(decode_symbol(*byte)?);
+        }
+        triple <<= 6 * padding as u32;
+
+        decoded.push((triple >> 16) as u8);
+        if padding < 2 {
+            decoded.push((triple >> 8) as u8);
+        }
+        if padding < 1 {
+            decoded.push(triple as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+fn decode_symbol(byte: u8) -> Option<u8> {
+    match byte {
+        b'A'..=b'Z' => Some(byte - b'A'),
+        b'a'..=b'z' => Some(byte - b'a' + 26),
+        b'0'..=b'9' => Some(byte - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
@@ -0,0 +1,250 @@
+const BASE: u32 = 36;
+const T_MIN: u32 = 1;
+const T_MAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 0x80;
+const ACE_PREFIX: &str = "xn--";
+const MAX_LABEL_LEN: usize = 63;
+const MAX_DOMAIN_LEN: usize = 253;
+
+// UTS #46 ToASCII with non-transitional processing, CheckHyphens, UseSTD3ASCIIRules and
+// VerifyDnsLength. U-labels and A-labels both come out as lowercase A-labels, so the result
+// can be compared and handed to DNS as is. Input is expected in NFC, which is what clients and
+// config files carry in practice; the mapping step covers case, full-width forms and the
+// characters UTS #46 ignores.
+pub(crate) fn to_ascii(domain: &str) -> Option<String> {
+    let mapped = map(domain)?;
+    let mapped = mapped.strip_suffix('.').unwrap_or(&mapped);
+    if mapped.is_empty() {
+        return None;
+    }
+
+    let mut labels = Vec::new();
+    for label in mapped.split('.') {
+        labels.push(label_to_ascii(label)?);
+    }
+
+    let ascii = labels.join(".");
+    if ascii.len() > MAX_DOMAIN_LEN {
+        return None;
+    }
+    Some(ascii)
+}
+
+fn map(domain: &str) -> Option<String> {
+    let mut mapped = String::with_capacity(domain.len());
+    for character in domain.chars() {
+        match character {
+            // Ideographic and full-width full stops separate labels like '.'.
+            '\u{3002}' | '\u{ff0e}' | '\u{ff61}' => mapped.push('.'),
+            // Full-width ASCII forms map to ASCII.
+            '\u{ff01}'..='\u{ff5e}' => {
+                let ascii = char::from_u32(character as u32 - 0xfee0)?;
+                mapped.push(ascii.to_ascii_lowercase());
+            }
+            // Soft hyphen, zero-width space, word joiner, BOM and variation selectors.
+            '\u{00ad}' | '\u{200b}' | '\u{2060}' | '\u{feff}' | '\u{fe00}'..='\u{fe0f}' => {}
+            _ if character.is_control() || character.is_whitespace() => return None,
+            _ => mapped.extend(character.to_lowercase()),
+        }
+    }
+    Some(mapped)
+}
+
+fn label_to_ascii(label: &str) -> Option<String> {
+    if label.is_ascii() {
+        if let Some(encoded) = strip_ace_prefix(label) {
+            // An A-label must decode to a valid U-label that encodes back to itself.
+            let decoded = decode(encoded)?;
+            if decoded.is_ascii() || map(&decoded)? != decoded {
+                return None;
+            }
+            check_label(&decoded)?;
+            if encode(&decoded)? != encoded {
+                return None;
+            }
+        } else {
+            check_label(label)?;
+        }
+        return (label.len() <= MAX_LABEL_LEN).then(|| label.to_string());
+    }
+
+    check_label(label)?;
+    let ascii = format!("{}{}", ACE_PREFIX, encode(label)?);
+    (ascii.len() <= MAX_LABEL_LEN).then_some(ascii)
+}
+
+fn strip_ace_prefix(label: &str) -> Option<&str> {
+    label
+        .get(..ACE_PREFIX.len())
+        .filter(|prefix| prefix.eq_ignore_ascii_case(ACE_PREFIX))
+        .map(|_| &label[ACE_PREFIX.len()..])
+}
+
+fn check_label(label: &str) -> Option<()> {
+    if label.is_empty() {
+        return None;
+    }
+    if label.starts_with('-') || label.ends_with('-') || label.get(2..4) == Some("--") {
+        return None;
+    }
+    if label.starts_with(is_combining_mark) {
+        return None;
+    }
+
+    let valid = label.chars().all(|character| {
+        character.is_ascii_alphanumeric()
+            || character == '-'
+            || (!character.is_ascii() && character.is_alphanumeric())
+            || is_combining_mark(character)
+    });
+    valid.then_some(())
+}
+
+fn is_combining_mark(character: char) -> bool {
+    matches!(
+        character,
+        '\u{0300}'..='\u{036f}'
+            | '\u{0483}'..='\u{0489}'
+            | '\u{0591}'..='\u{05bd}'
+            | '\u{0610}'..='\u{061a}'
+            | '\u{064b}'..='\u{065f}'
+            | '\u{0900}'..='\u{0903}'
+            | '\u{093a}'..='\u{094f}'
+            | '\u{0e31}'
+            | '\u{0e34}'..='\u{0e3a}'
+            | '\u{0e47}'..='\u{0e4e}'
+            | '\u{1ab0}'..='\u{1aff}'
+            | '\u{1dc0}'..='\u{1dff}'
+            | '\u{200c}'..='\u{200d}'
+            | '\u{20d0}'..='\u{20ff}'
+            | '\u{3099}'..='\u{309a}'
+            | '\u{fe20}'..='\u{fe2f}'
+    )
+}
+
+// RFC 3492 Punycode, without the ACE prefix.
+fn encode(input: &str) -> Option<String> {
+    let code_points: Vec<u32> = input.chars().map(u32::from).collect();
+    let mut output: String = input.chars().filter(char::is_ascii).collect();
+    let basic_len = output.len() as u32;
+    let mut handled = basic_len;
+    if basic_len > 0 {
+        output.push('-');
+    }
+
+    let mut n = INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    while (handled as usize) < code_points.len() {
+        let next = code_points.iter().copied().filter(|point| *point >= n).min()?;
+        delta = delta.checked_add((next - n).checked_mul(handled + 1)?)?;
+        n = next;
+
+        for point in &code_points {
+            if *point < n {
+                delta = delta.checked_add(1)?;
+            }
+            if *point == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = threshold(k, bias);
+                    if q < t {
+                        break;
+                    }
+                    output.push(encode_digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(encode_digit(q));
+                bias = adapt(delta, handled + 1, handled == basic_len);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta = delta.checked_add(1)?;
+        n = n.checked_add(1)?;
+    }
+
+    Some(output)
+}
+
+fn decode(input: &str) -> Option<String> {
+    let (basic, extended) = match input.rfind('-') {
+        Some(position) => (&input[..position], &input[position + 1..]),
+        None => ("", input),
+    };
+    if !basic.is_ascii() {
+        return None;
+    }
+
+    let mut output: Vec<char> = basic.chars().collect();
+    let mut n = INITIAL_N;
+    let mut i: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    let mut digits = extended.bytes().peekable();
+    while digits.peek().is_some() {
+        let old_i = i;
+        let mut weight: u32 = 1;
+        let mut k = BASE;
+        loop {
+            let digit = decode_digit(digits.next()?)?;
+            i = i.checked_add(digit.checked_mul(weight)?)?;
+            let t = threshold(k, bias);
+            if digit < t {
+                break;
+            }
+            weight = weight.checked_mul(BASE - t)?;
+            k += BASE;
+        }
+
+        let length = output.len() as u32 + 1;
+        bias = adapt(i - old_i, length, old_i == 0);
+        n = n.checked_add(i / length)?;
+        i %= length;
+        output.insert(i as usize, char::from_u32(n)?);
+        i += 1;
+    }
+
+    Some(output.into_iter().collect())
+}
+
+fn threshold(k: u32, bias: u32) -> u32 {
+    if k <= bias {
+        T_MIN
+    } else if k >= bias + T_MAX {
+        T_MAX
+    } else {
+        k - bias
+    }
+}
+
+fn adapt(delta: u32, num_points: u32, first_time: bool) -> u32 {
+    let mut delta = if first_time { delta / DAMP } else { delta / 2 };
+    delta += delta / num_points;
+    let mut k = 0;
+    while delta > ((BASE - T_MIN) * T_MAX) / 2 {
+        delta /= BASE - T_MIN;
+        k += BASE;
+    }
+    k + (BASE - T_MIN + 1) * delta / (delta + SKEW)
+}
+
+fn encode_digit(digit: u32) -> char {
+    match digit {
+        0..=25 => (b'a' + digit as u8) as char,
+        _ => (b'0' + (digit - 26) as u8) as char,
+    }
+}
+
+fn decode_digit(byte: u8) -> Option<u32> {
+    match byte {
+        b'a'..=b'z' => Some(u32::from(byte - b'a')),
+        b'A'..=b'Z' => Some(u32::from(byte - b'A')),
+        b'0'..=b'9' => Some(u32::from(byte - b'0') + 26),
+        _ => None,
+    }
+}
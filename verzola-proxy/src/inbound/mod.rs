@@ -3,11 +3,41 @@ use std::io::{self, BufRead, BufReader, ErrorKind, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::auth::{
+    self, AuthRequest, AuthenticationError, Authenticator, SaslExchange, SaslMechanism,
+};
+use crate::logging::{
+    self, DeliveryAttempt, DeliveryOutcome, JsonLogger, SessionDirection,
+};
+use crate::metrics::MetricsRegistry;
+use crate::shutdown::{
+    self, LineRead, SessionShutdown, ShutdownReport, ShutdownSignal,
+    DEFAULT_SHUTDOWN_GRACE_PERIOD, SHUTDOWN_POLL_INTERVAL,
+};
+use crate::smtp::{
+    is_data_terminator, split_command, write_command_line, write_multiline_reply, write_reply,
+    EhloCapabilities, MailFrom, RcptTo, Reply,
+};
+use crate::telemetry::{
+    JsonLogSink, MetricsSink, SessionContext, SessionReport, StartTlsResult, TelemetryEvent,
+    TelemetrySink, TelemetrySinks,
+};
+use crate::transaction::{TransactionLog, TransactionOutcome, TransactionRecord, TransactionStage};
+use crate::upstream::{
+    self, BackendHealth, EhloExtensionConfig, PostfixBackend, UpstreamCapabilities, UpstreamPool,
+    UpstreamPoolConfig, UpstreamProtocol, UpstreamStream, UpstreamTarget,
+};
 
 pub const DEFAULT_MAX_LINE_LEN: usize = 4096;
+pub const MAX_AUTH_FAILURES: usize = 3;
+const TRANSACTION_LOST_MESSAGE: &str =
+    "4.4.0 Upstream transaction lost, send RSET and try again";
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum InboundTlsPolicy {
+    #[default]
     Opportunistic,
     RequireTls,
 }
@@ -18,20 +48,36 @@ impl InboundTlsPolicy {
     }
 }
 
-impl Default for InboundTlsPolicy {
-    fn default() -> Self {
-        Self::Opportunistic
-    }
+// Implicit runs the TLS handshake right after accept (RFC 8314, port 465).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InboundTlsMode {
+    #[default]
+    StartTls,
+    Implicit,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ListenerMode {
+    #[default]
+    Relay,
+    Submission,
 }
 
 #[derive(Debug, Clone)]
 pub struct ListenerConfig {
     pub bind_addr: SocketAddr,
     pub banner_host: String,
+    pub mode: ListenerMode,
+    pub tls_mode: InboundTlsMode,
     pub advertise_starttls: bool,
     pub inbound_tls_policy: InboundTlsPolicy,
     pub max_line_len: usize,
     pub postfix_upstream_addr: Option<SocketAddr>,
+    pub postfix_upstreams: Vec<PostfixBackend>,
+    pub upstream_pool: UpstreamPoolConfig,
+    pub upstream_protocol: UpstreamProtocol,
+    pub ehlo_extensions: EhloExtensionConfig,
+    pub shutdown_grace_period: Duration,
 }
 
 impl ListenerConfig {
@@ -50,13 +96,24 @@ impl ListenerConfig {
             ));
         }
 
-        if self.inbound_tls_policy.requires_tls() && !self.advertise_starttls {
+        // Implicit TLS covers every session, so STARTTLS is never needed.
+        let tls_available =
+            self.advertise_starttls || self.tls_mode == InboundTlsMode::Implicit;
+
+        if self.inbound_tls_policy.requires_tls() && !tls_available {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "inbound_tls_policy=require-tls requires advertise_starttls=true",
             ));
         }
 
+        if self.mode == ListenerMode::Submission && !tls_available {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "mode=submission requires advertise_starttls=true (AUTH is only offered over TLS)",
+            ));
+        }
+
         if self.postfix_upstream_addr == Some(self.bind_addr) {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -64,8 +121,37 @@ impl ListenerConfig {
             ));
         }
 
+        if self.postfix_upstream_addr.is_some() && !self.postfix_upstreams.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "set either postfix_upstream_addr or postfix_upstreams, not both",
+            ));
+        }
+
+        upstream::validate_backends(&self.postfix_upstreams, self.bind_addr)?;
+        self.upstream_pool.validate()?;
+
+        // Submission hands the AUTH identity over with XCLIENT, which LMTP servers
+        // such as Dovecot do not accept for final delivery.
+        if self.upstream_protocol == UpstreamProtocol::Lmtp && self.mode != ListenerMode::Relay {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "upstream_protocol=lmtp requires mode=relay",
+            ));
+        }
+
+        self.ehlo_extensions.validate()?;
+
         Ok(())
     }
+
+    // `postfix_upstream_addr` is shorthand for a single backend.
+    pub fn postfix_backends(&self) -> Vec<PostfixBackend> {
+        match self.postfix_upstream_addr {
+            Some(upstream_addr) => vec![PostfixBackend::new(upstream_addr)],
+            None => self.postfix_upstreams.clone(),
+        }
+    }
 }
 
 impl Default for ListenerConfig {
@@ -75,10 +161,17 @@ impl Default for ListenerConfig {
                 .parse()
                 .expect("default inbound socket address must parse"),
             banner_host: "localhost".to_string(),
+            mode: ListenerMode::default(),
+            tls_mode: InboundTlsMode::default(),
             advertise_starttls: true,
             inbound_tls_policy: InboundTlsPolicy::default(),
             max_line_len: DEFAULT_MAX_LINE_LEN,
             postfix_upstream_addr: None,
+            postfix_upstreams: Vec::new(),
+            upstream_pool: UpstreamPoolConfig::default(),
+            upstream_protocol: UpstreamProtocol::default(),
+            ehlo_extensions: EhloExtensionConfig::default(),
+            shutdown_grace_period: DEFAULT_SHUTDOWN_GRACE_PERIOD,
         }
     }
 }
@@ -98,6 +191,31 @@ impl Display for TlsUpgradeError {
 
 pub trait TlsUpgrader: Send + Sync + 'static {
     fn upgrade(&self, stream: &mut TcpStream) -> Result<(), TlsUpgradeError>;
+
+    fn session_parameters(&self, _stream: &TcpStream) -> Option<TlsSessionParameters> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsSessionParameters {
+    pub protocol_version: String,
+    pub cipher_suite: String,
+    pub key_exchange_group: Option<String>,
+}
+
+// Lets one certificate-loading upgrader back several listeners.
+impl<U> TlsUpgrader for Arc<U>
+where
+    U: TlsUpgrader,
+{
+    fn upgrade(&self, stream: &mut TcpStream) -> Result<(), TlsUpgradeError> {
+        self.as_ref().upgrade(stream)
+    }
+
+    fn session_parameters(&self, stream: &TcpStream) -> Option<TlsSessionParameters> {
+        self.as_ref().session_parameters(stream)
+    }
 }
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -109,13 +227,23 @@ impl TlsUpgrader for NoopTlsUpgrader {
     }
 }
 
-#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct SessionSummary {
+    pub session_id: String,
+    pub listener: Option<String>,
+    pub client_addr: Option<SocketAddr>,
+    pub helo_name: Option<String>,
     pub command_count: usize,
     pub protocol_errors: usize,
     pub tls_negotiated: bool,
+    pub tls_parameters: Option<TlsSessionParameters>,
+    pub tls_mode: InboundTlsMode,
     pub inbound_tls_policy: InboundTlsPolicy,
+    pub authenticated_identity: Option<String>,
     pub telemetry: SessionTelemetry,
+    pub closed_by_shutdown: bool,
+    pub session_duration: Duration,
+    pub transactions: Vec<TransactionRecord>,
 }
 
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
@@ -125,6 +253,32 @@ pub struct SessionTelemetry {
     pub tls_upgrade_failures: usize,
     pub require_tls_rejections: usize,
     pub relay_temporary_failures: usize,
+    pub auth_failures: usize,
+}
+
+impl SessionTelemetry {
+    pub fn observe(&mut self, event: &TelemetryEvent) {
+        match event {
+            TelemetryEvent::Ehlo {
+                starttls_offered, ..
+            } => self.starttls_offered |= *starttls_offered,
+            TelemetryEvent::StartTls(result) => {
+                self.starttls_attempts += 1;
+                if matches!(result, StartTlsResult::Failed { .. }) {
+                    self.tls_upgrade_failures += 1;
+                }
+            }
+            TelemetryEvent::ImplicitTls(StartTlsResult::Failed { .. }) => {
+                self.tls_upgrade_failures += 1;
+            }
+            TelemetryEvent::TlsRequired { .. } => self.require_tls_rejections += 1,
+            TelemetryEvent::RelayFailure { .. } => self.relay_temporary_failures += 1,
+            TelemetryEvent::Auth {
+                succeeded: false, ..
+            } => self.auth_failures += 1,
+            _ => {}
+        }
+    }
 }
 
 pub struct InboundListener<U>
@@ -134,6 +288,9 @@ where
     listener: TcpListener,
     config: ListenerConfig,
     tls_upgrader: Arc<U>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    upstreams: Arc<UpstreamPool>,
+    telemetry: TelemetrySinks,
 }
 
 impl<U> InboundListener<U>
@@ -143,31 +300,112 @@ where
     pub fn bind(config: ListenerConfig, tls_upgrader: U) -> io::Result<Self> {
         config.validate()?;
         let listener = TcpListener::bind(config.bind_addr)?;
+        let upstreams = UpstreamPool::new(
+            config.postfix_backends(),
+            config.upstream_pool,
+            config.upstream_protocol,
+        );
         Ok(Self {
             listener,
             config,
             tls_upgrader: Arc::new(tls_upgrader),
+            authenticator: None,
+            upstreams: Arc::new(upstreams),
+            telemetry: TelemetrySinks::default(),
         })
     }
 
+    pub fn with_metrics(
+        self,
+        registry: Arc<MetricsRegistry>,
+        listener_name: impl Into<String>,
+    ) -> Self {
+        let listener_name = listener_name.into();
+        self.upstreams
+            .attach_metrics(Arc::clone(&registry), listener_name.clone());
+        self.with_telemetry_sink(Arc::new(MetricsSink::new(registry, listener_name)))
+    }
+
+    pub fn with_json_logger(self, logger: Arc<JsonLogger>) -> Self {
+        self.with_telemetry_sink(Arc::new(JsonLogSink::new(logger)))
+    }
+
+    pub fn with_telemetry_sink(mut self, sink: Arc<dyn TelemetrySink>) -> Self {
+        self.telemetry.push(sink);
+        self
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.telemetry.set_listener(name.into());
+        self
+    }
+
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         self.listener.local_addr()
     }
 
+    pub fn upstream_health(&self) -> Vec<BackendHealth> {
+        self.upstreams.health()
+    }
+
+    // One synchronous round of active health checks; `serve_until_shutdown` runs
+    // these in the background every `upstream_pool.health_check_interval`.
+    pub fn check_upstreams(&self) {
+        self.upstreams.check_all(&self.config.banner_host);
+    }
+
+    // AUTH is the only way into a submission listener, so one without an authenticator is
+    // refused before it accepts a connection rather than answering every AUTH with 454.
+    pub(crate) fn ensure_authenticator(&self) -> io::Result<()> {
+        if self.config.mode == ListenerMode::Submission && self.authenticator.is_none() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "mode=submission requires an authenticator (see with_authenticator)",
+            ));
+        }
+        Ok(())
+    }
+
     pub fn serve_one(&self) -> io::Result<SessionSummary> {
+        self.ensure_authenticator()?;
         let (mut stream, _) = self.listener.accept()?;
-        handle_session(&mut stream, &self.config, self.tls_upgrader.as_ref())
+        handle_session(
+            &mut stream,
+            &self.config,
+            self.tls_upgrader.as_ref(),
+            self.authenticator.as_deref(),
+            &self.upstreams,
+            None,
+            &self.telemetry,
+        )
     }
 
     pub fn serve_n(&self, session_count: usize) -> io::Result<Vec<SessionSummary>> {
+        self.ensure_authenticator()?;
         let mut handles = Vec::with_capacity(session_count);
 
         for _ in 0..session_count {
             let (mut stream, _) = self.listener.accept()?;
             let config = self.config.clone();
             let tls_upgrader = Arc::clone(&self.tls_upgrader);
+            let authenticator = self.authenticator.clone();
+            let upstreams = Arc::clone(&self.upstreams);
+            let telemetry = self.telemetry.clone();
             handles.push(thread::spawn(move || {
-                handle_session(&mut stream, &config, tls_upgrader.as_ref())
+                handle_session(
+                    &mut stream,
+                    &config,
+                    tls_upgrader.as_ref(),
+                    authenticator.as_deref(),
+                    &upstreams,
+                    None,
+                    &telemetry,
+                )
             }));
         }
 
@@ -175,91 +413,329 @@ where
         for handle in handles {
             let summary = handle
                 .join()
-                .map_err(|_| io::Error::new(ErrorKind::Other, "session worker thread panicked"))??;
+                .map_err(|_| io::Error::other("session worker thread panicked"))??;
             summaries.push(summary);
         }
 
         Ok(summaries)
     }
+
+    pub fn serve_until_shutdown(&self, shutdown: &ShutdownSignal) -> io::Result<ShutdownReport> {
+        self.ensure_authenticator()?;
+        let health_checker = self.spawn_health_checker(shutdown);
+        let report = shutdown::serve_until_shutdown(&self.listener, shutdown, |mut stream| {
+            let config = self.config.clone();
+            let tls_upgrader = Arc::clone(&self.tls_upgrader);
+            let authenticator = self.authenticator.clone();
+            let upstreams = Arc::clone(&self.upstreams);
+            let telemetry = self.telemetry.clone();
+            let shutdown = shutdown.clone();
+            thread::spawn(move || {
+                let session_shutdown =
+                    SessionShutdown::new(&shutdown, config.shutdown_grace_period);
+                handle_session(
+                    &mut stream,
+                    &config,
+                    tls_upgrader.as_ref(),
+                    authenticator.as_deref(),
+                    &upstreams,
+                    Some(&session_shutdown),
+                    &telemetry,
+                )
+                .map(|summary| summary.closed_by_shutdown)
+            })
+        });
+        if let Some(health_checker) = health_checker {
+            // Make sure the checker stops even if the accept loop failed.
+            shutdown.request();
+            let _ = health_checker.join();
+        }
+        report
+    }
+
+    fn spawn_health_checker(&self, shutdown: &ShutdownSignal) -> Option<thread::JoinHandle<()>> {
+        if !self.upstreams.is_configured() {
+            return None;
+        }
+
+        let upstreams = Arc::clone(&self.upstreams);
+        let ehlo_host = self.config.banner_host.clone();
+        let shutdown = shutdown.clone();
+        Some(thread::spawn(move || {
+            let interval = upstreams.config().health_check_interval;
+            let mut last_check: Option<Instant> = None;
+            while !shutdown.is_requested() {
+                if last_check.is_none_or(|checked_at| checked_at.elapsed() >= interval) {
+                    upstreams.check_all(&ehlo_host);
+                    last_check = Some(Instant::now());
+                }
+                thread::sleep(SHUTDOWN_POLL_INTERVAL);
+            }
+        }))
+    }
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Default)]
 struct SessionState {
+    context: SessionContext,
+    helo_name: Option<String>,
     tls_active: bool,
+    tls_parameters: Option<TlsSessionParameters>,
+    authenticated_identity: Option<String>,
     ehlo_seen: bool,
+    transaction_active: bool,
+    closed_by_shutdown: bool,
     command_count: usize,
     protocol_errors: usize,
     telemetry: SessionTelemetry,
+    envelope_sender: Option<String>,
+    envelope_recipients: Vec<String>,
+    // MAIL carried SMTPUTF8, so RCPT may name non-ASCII mailboxes.
+    smtputf8: bool,
+    // MAIL and accepted RCPT command lines as relayed upstream, replayed on a new
+    // connection if the old one drops mid-envelope.
+    relay_envelope: Vec<String>,
+    // Set when that replay failed; RCPT and DATA get 4xx until the client starts over.
+    upstream_transaction_lost: bool,
+    transactions: TransactionLog,
+}
+
+impl SessionState {
+    fn emit(&mut self, telemetry: &TelemetrySinks, event: TelemetryEvent) {
+        self.telemetry.observe(&event);
+        telemetry.emit(&self.context, &event);
+    }
+
+    fn finish(
+        self,
+        config: &ListenerConfig,
+        telemetry: &TelemetrySinks,
+        started_at: Instant,
+    ) -> SessionSummary {
+        let summary = SessionSummary {
+            session_id: self.context.session_id.clone(),
+            listener: self.context.listener.clone(),
+            client_addr: self.context.client_addr,
+            helo_name: self.helo_name,
+            command_count: self.command_count,
+            protocol_errors: self.protocol_errors,
+            tls_negotiated: self.tls_active,
+            tls_parameters: self.tls_parameters,
+            tls_mode: config.tls_mode,
+            inbound_tls_policy: config.inbound_tls_policy,
+            authenticated_identity: self.authenticated_identity,
+            telemetry: self.telemetry,
+            closed_by_shutdown: self.closed_by_shutdown,
+            session_duration: started_at.elapsed(),
+            transactions: self.transactions.into_records(),
+        };
+        telemetry.emit(
+            &self.context,
+            &TelemetryEvent::Disconnected(Box::new(SessionReport::Inbound(summary.clone()))),
+        );
+
+        summary
+    }
+
+    fn begin_transaction(&mut self, sender: String, started_after: Duration) {
+        self.transactions.begin(&sender, started_after);
+        if let Some(record) = self.transactions.current_mut() {
+            record.tls_negotiated = self.tls_active;
+            record.tls_parameters = self.tls_parameters.clone();
+        }
+        self.transaction_active = true;
+        self.envelope_sender = Some(sender);
+    }
+
+    fn reset_transaction(&mut self) {
+        self.transactions.abandon();
+        self.transaction_active = false;
+        self.envelope_sender = None;
+        self.envelope_recipients.clear();
+        self.smtputf8 = false;
+        self.relay_envelope.clear();
+    }
+
+    // The client still believes its transaction is open, so it is failed as a whole
+    // rather than continued on an upstream session that never saw MAIL.
+    fn lose_upstream_transaction(&mut self) {
+        if self.transaction_active {
+            self.transactions.finish(TransactionOutcome::Deferred, 0);
+            self.reset_transaction();
+            self.upstream_transaction_lost = true;
+        }
+    }
+
+    fn delivery_attempt(
+        &self,
+        config: &ListenerConfig,
+        reply_code: Option<u16>,
+        failure_reason: Option<String>,
+    ) -> DeliveryAttempt {
+        let outcome = match reply_code {
+            Some(code) if code / 100 == 2 => DeliveryOutcome::Accepted,
+            Some(code) if code / 100 == 5 => DeliveryOutcome::Rejected,
+            _ => DeliveryOutcome::Deferred,
+        };
+
+        DeliveryAttempt {
+            session_id: self.context.session_id.clone(),
+            direction: SessionDirection::Inbound,
+            listener: self.context.listener.clone(),
+            client_addr: self.context.client_addr,
+            helo_name: self.helo_name.clone(),
+            sender: self.envelope_sender.clone(),
+            recipients: self.envelope_recipients.clone(),
+            next_hop: None,
+            postfix_queue_id: None,
+            tls_negotiated: self.tls_active,
+            tls_parameters: self.tls_parameters.clone(),
+            tls_policy: Some(logging::inbound_policy_label(config.inbound_tls_policy).to_string()),
+            tls_policy_rule: None,
+            policy_decision: Some(
+                if self.tls_active {
+                    "tls"
+                } else {
+                    "plaintext-allowed"
+                }
+                .to_string(),
+            ),
+            reply_code,
+            outcome,
+            failure_reason,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 enum MailCommandRejection {
     EhloRequired(&'static str),
     TlsRequired,
+    NotAuthenticated,
 }
 
+// Postfix refused the XCLIENT LOGIN handover. The backend answered, so it stays healthy,
+// and its reply goes back to the client instead of the next backend being tried.
 #[derive(Debug)]
-struct SmtpReply {
-    code: u16,
-    lines: Vec<String>,
+struct XclientRejected(Reply);
+
+impl Display for XclientRejected {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "upstream Postfix XCLIENT was rejected ({}): {}",
+            self.0.code,
+            self.0.summary()
+        )
+    }
+}
+
+impl std::error::Error for XclientRejected {}
+
+fn xclient_rejection(error: &io::Error) -> Option<&Reply> {
+    error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<XclientRejected>())
+        .map(|rejected| &rejected.0)
 }
 
 struct PostfixRelay {
-    writer: TcpStream,
-    reader: BufReader<TcpStream>,
+    backend: UpstreamTarget,
+    protocol: UpstreamProtocol,
+    capabilities: UpstreamCapabilities,
+    writer: UpstreamStream,
+    reader: BufReader<UpstreamStream>,
 }
 
 impl PostfixRelay {
-    fn connect(upstream_addr: SocketAddr, ehlo_host: &str) -> io::Result<Self> {
-        let mut writer = TcpStream::connect(upstream_addr)?;
+    fn connect(
+        target: &UpstreamTarget,
+        protocol: UpstreamProtocol,
+        connect_timeout: Duration,
+        ehlo_host: &str,
+        login: Option<&str>,
+    ) -> io::Result<Self> {
+        let mut writer = UpstreamStream::connect(target, connect_timeout)?;
+        // A backend that accepts but never greets counts as a connect-stage failure.
+        writer.set_read_timeout(Some(connect_timeout))?;
         let mut reader = BufReader::new(writer.try_clone()?);
 
-        let banner_reply = read_smtp_reply(&mut reader)?;
+        let banner_reply = Reply::read_from(&mut reader)?;
         if banner_reply.code / 100 != 2 {
             return Err(io::Error::new(
                 ErrorKind::ConnectionAborted,
                 format!(
                     "upstream Postfix banner was non-2xx ({}): {}",
                     banner_reply.code,
-                    banner_reply.lines.join(" | ")
+                    banner_reply.summary()
                 ),
             ));
         }
 
-        write_command_line(&mut writer, &format!("EHLO {}", ehlo_host))?;
-        let ehlo_reply = read_smtp_reply(&mut reader)?;
-        if ehlo_reply.code / 100 != 2 {
-            return Err(io::Error::new(
-                ErrorKind::ConnectionAborted,
-                format!(
-                    "upstream Postfix EHLO was non-2xx ({}): {}",
-                    ehlo_reply.code,
-                    ehlo_reply.lines.join(" | ")
-                ),
-            ));
+        let mut ehlo_reply = send_upstream_ehlo(&mut writer, &mut reader, protocol, ehlo_host)?;
+
+        // XCLIENT LOGIN hands the submission identity to Postfix, which needs this host in
+        // smtpd_authorized_xclient_hosts. A successful XCLIENT restarts the session.
+        if let Some(login) = login {
+            if !reply_offers_xclient_login(&ehlo_reply) {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "upstream Postfix does not offer XCLIENT LOGIN \
+                     (check smtpd_authorized_xclient_hosts)",
+                ));
+            }
+
+            write_command_line(
+                &mut writer,
+                &format!("XCLIENT LOGIN={}", auth::encode_xtext(login)),
+            )?;
+            let xclient_reply = Reply::read_from(&mut reader)?;
+            if xclient_reply.code != 220 {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    XclientRejected(xclient_reply),
+                ));
+            }
+            ehlo_reply = send_upstream_ehlo(&mut writer, &mut reader, protocol, ehlo_host)?;
         }
 
-        Ok(Self { writer, reader })
+        writer.set_read_timeout(None)?;
+        Ok(Self {
+            backend: target.clone(),
+            protocol,
+            capabilities: UpstreamCapabilities::from_ehlo_reply(&ehlo_reply),
+            writer,
+            reader,
+        })
     }
 
-    fn relay_command(&mut self, command_line: &str) -> io::Result<SmtpReply> {
+    fn relay_command(&mut self, command_line: &str) -> io::Result<Reply> {
         write_command_line(&mut self.writer, command_line)?;
-        read_smtp_reply(&mut self.reader)
+        Reply::read_from(&mut self.reader)
     }
 
+    // Returns the reply for the client, the message size, and for LMTP a summary of
+    // the recipients that did not take the message.
     fn relay_data_block(
         &mut self,
         client_reader: &mut BufReader<TcpStream>,
+        recipients: &[String],
         max_line_len: usize,
-    ) -> io::Result<SmtpReply> {
+        shutdown: Option<&SessionShutdown<'_>>,
+    ) -> io::Result<(Reply, u64, Option<String>)> {
+        let mut message_bytes = 0u64;
+        let mut upstream_error: Option<io::Error> = None;
         loop {
-            let mut line = String::new();
-            let bytes_read = client_reader.read_line(&mut line)?;
-            if bytes_read == 0 {
-                return Err(io::Error::new(
-                    ErrorKind::UnexpectedEof,
-                    "connection closed during DATA relay",
-                ));
+            let mut line = Vec::new();
+            match shutdown::read_session_bytes(client_reader, &mut line, shutdown, true)? {
+                LineRead::Line => {}
+                LineRead::Closed => {
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "connection closed during DATA relay",
+                    ));
+                }
+                LineRead::Shutdown => return Err(shutdown::shutdown_interrupted_error()),
             }
 
             if line.len() > max_line_len {
@@ -269,38 +745,192 @@ impl PostfixRelay {
                 ));
             }
 
-            self.writer.write_all(line.as_bytes())?;
-            self.writer.flush()?;
+            // If the upstream goes away mid-message, the rest of the message is still
+            // read so it is not mistaken for commands.
+            if upstream_error.is_none() {
+                if let Err(error) = self
+                    .writer
+                    .write_all(&line)
+                    .and_then(|()| self.writer.flush())
+                {
+                    upstream_error = Some(error);
+                }
+            }
 
             if is_data_terminator(&line) {
                 break;
             }
+            message_bytes += line.len() as u64;
         }
 
-        read_smtp_reply(&mut self.reader)
+        if let Some(error) = upstream_error {
+            return Err(error);
+        }
+
+        if self.protocol == UpstreamProtocol::Smtp {
+            return Ok((Reply::read_from(&mut self.reader)?, message_bytes, None));
+        }
+
+        let mut replies = Vec::with_capacity(recipients.len());
+        for _ in 0..recipients.len().max(1) {
+            replies.push(Reply::read_from(&mut self.reader)?);
+        }
+        let (reply, failures) = merge_lmtp_replies(replies);
+        Ok((reply, message_bytes, failures))
     }
 }
 
+// LMTP answers the end of DATA once per accepted recipient, but the SMTP client gets
+// a single reply. It is only a success when every recipient took the message. Any
+// failure, permanent ones included, defers the whole message: VERZOLA sends no
+// bounces, so a 250 would lose the rejected recipients without a trace. The client
+// retries, which delivers again to the recipients that already took the message, and
+// bounces the rest once its queue lifetime runs out. A duplicate beats a lost message.
+// The failure reason names recipients by position, since it is logged unredacted.
+fn merge_lmtp_replies(replies: Vec<Reply>) -> (Reply, Option<String>) {
+    let delivered = replies.iter().filter(|reply| reply.code / 100 == 2).count();
+    if delivered == replies.len() {
+        let reply = replies
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| Reply::new(250, "2.0.0 Ok"));
+        return (reply, None);
+    }
+
+    let failures = replies
+        .iter()
+        .enumerate()
+        .filter(|(_, reply)| reply.code / 100 != 2)
+        .map(|(index, reply)| {
+            let mut failure =
+                format!("recipient {} of {}: {}", index + 1, replies.len(), reply.code);
+            if let Some(status) = reply.enhanced_status() {
+                failure.push_str(&format!(" {}", status));
+            }
+            failure
+        })
+        .collect::<Vec<_>>()
+        .join("; ");
+    let failed = replies.len() - delivered;
+
+    let reply = if delivered == 0 {
+        let first_failure = replies
+            .iter()
+            .position(|reply| reply.code / 100 == 4)
+            .unwrap_or(0);
+        replies
+            .into_iter()
+            .nth(first_failure)
+            .unwrap_or_else(|| Reply::new(451, "4.3.0 LMTP delivery failed"))
+    } else {
+        Reply::new(
+            451,
+            &format!(
+                "4.3.0 LMTP delivery failed for {} of {} recipients, try again later",
+                failed,
+                replies.len()
+            ),
+        )
+    };
+
+    (reply, Some(failures))
+}
+
 fn handle_session<U>(
     stream: &mut TcpStream,
     config: &ListenerConfig,
     tls_upgrader: &U,
+    authenticator: Option<&dyn Authenticator>,
+    upstreams: &UpstreamPool,
+    shutdown: Option<&SessionShutdown<'_>>,
+    telemetry: &TelemetrySinks,
 ) -> io::Result<SessionSummary>
 where
     U: TlsUpgrader,
 {
-    write_reply(stream, 220, &format!("{} ESMTP VERZOLA", config.banner_host))?;
+    let started_at = Instant::now();
+    let mut state = SessionState {
+        context: telemetry.session_context(SessionDirection::Inbound, stream.peer_addr().ok()),
+        ..SessionState::default()
+    };
+    state.emit(telemetry, TelemetryEvent::Connected);
+
+    // Sessions cut short by an I/O error are reported too; a reset client or a write timeout
+    // is what operators most need to see.
+    let result = serve_session(
+        stream,
+        &mut state,
+        started_at,
+        config,
+        tls_upgrader,
+        authenticator,
+        upstreams,
+        shutdown,
+        telemetry,
+    );
+    let summary = state.finish(config, telemetry, started_at);
+    result.map(|()| summary)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn serve_session<U>(
+    stream: &mut TcpStream,
+    state: &mut SessionState,
+    started_at: Instant,
+    config: &ListenerConfig,
+    tls_upgrader: &U,
+    authenticator: Option<&dyn Authenticator>,
+    upstreams: &UpstreamPool,
+    shutdown: Option<&SessionShutdown<'_>>,
+    telemetry: &TelemetrySinks,
+) -> io::Result<()>
+where
+    U: TlsUpgrader,
+{
 
+    if config.tls_mode == InboundTlsMode::Implicit {
+        // A failed handshake leaves no channel to send an SMTP reply on; just close.
+        match tls_upgrader.upgrade(stream) {
+            Ok(()) => {
+                state.tls_active = true;
+                state.tls_parameters = tls_upgrader.session_parameters(stream);
+                let parameters = state.tls_parameters.clone();
+                state.emit(
+                    telemetry,
+                    TelemetryEvent::ImplicitTls(StartTlsResult::Negotiated(parameters)),
+                );
+            }
+            Err(error) => {
+                state.protocol_errors += 1;
+                state.emit(
+                    telemetry,
+                    TelemetryEvent::ImplicitTls(StartTlsResult::Failed {
+                        reason: error.to_string(),
+                    }),
+                );
+                return Ok(());
+            }
+        }
+    }
+
+    write_reply(stream, 220, &format!("{} ESMTP VERZOLA", config.banner_host))?;
     let mut reader = BufReader::new(stream.try_clone()?);
-    let mut state = SessionState::default();
-    state.telemetry.starttls_offered = config.advertise_starttls;
     let mut relay: Option<PostfixRelay> = None;
 
     loop {
         let mut line = String::new();
-        let bytes_read = reader.read_line(&mut line)?;
-        if bytes_read == 0 {
-            break;
+        match shutdown::read_session_line(
+            &mut reader,
+            &mut line,
+            shutdown,
+            state.transaction_active,
+        )? {
+            LineRead::Line => {}
+            LineRead::Closed => break,
+            LineRead::Shutdown => {
+                close_for_shutdown(stream, &mut relay, state)?;
+                break;
+            }
         }
 
         if line.len() > config.max_line_len {
@@ -322,44 +952,85 @@ where
         match verb.as_str() {
             "EHLO" | "HELO" => {
                 state.ehlo_seen = true;
+                if state.transaction_active {
+                    abort_upstream_transaction(&mut relay);
+                }
+                state.reset_transaction();
+                state.upstream_transaction_lost = false;
+                if !argument.is_empty() {
+                    state.helo_name = Some(argument.to_string());
+                }
                 let greeting_target = if argument.is_empty() { "client" } else { argument };
                 let mut lines = vec![format!("{} greets {}", config.banner_host, greeting_target)];
-                if config.advertise_starttls && !state.tls_active {
+                let starttls_offered = config.advertise_starttls && !state.tls_active;
+                if starttls_offered {
                     lines.push("STARTTLS".to_string());
                 }
-                lines.push("SIZE 10485760".to_string());
+                if config.mode == ListenerMode::Submission
+                    && state.tls_active
+                    && state.authenticated_identity.is_none()
+                {
+                    lines.push("AUTH PLAIN LOGIN".to_string());
+                }
+                let upstream_capabilities = upstreams.known_capabilities();
+                let upstream_view =
+                    upstreams.is_configured().then_some(upstream_capabilities.as_ref());
+                lines.extend(config.ehlo_extensions.advertised(upstream_view));
                 write_multiline_reply(stream, 250, &lines)?;
+                state.emit(
+                    telemetry,
+                    TelemetryEvent::Ehlo {
+                        helo_name: argument.to_string(),
+                        starttls_offered,
+                    },
+                );
             }
             "STARTTLS" => {
-                state.telemetry.starttls_attempts += 1;
-                if !config.advertise_starttls {
-                    state.protocol_errors += 1;
-                    write_reply(stream, 502, "5.5.1 STARTTLS not supported")?;
-                    continue;
-                }
-
-                if state.tls_active {
-                    state.protocol_errors += 1;
-                    write_reply(stream, 503, "5.5.1 TLS already active")?;
-                    continue;
-                }
-
-                if !state.ehlo_seen {
+                let refusal = if !config.advertise_starttls {
+                    Some((502, "5.5.1 STARTTLS not supported"))
+                } else if state.tls_active {
+                    Some((503, "5.5.1 TLS already active"))
+                } else if !state.ehlo_seen {
+                    Some((503, "5.5.1 Send EHLO before STARTTLS"))
+                } else {
+                    None
+                };
+                if let Some((reply_code, message)) = refusal {
                     state.protocol_errors += 1;
-                    write_reply(stream, 503, "5.5.1 Send EHLO before STARTTLS")?;
+                    state.emit(
+                        telemetry,
+                        TelemetryEvent::StartTls(StartTlsResult::Refused { reply_code }),
+                    );
+                    write_reply(stream, reply_code, message)?;
                     continue;
                 }
 
+                // Anything pipelined behind STARTTLS arrived in plaintext and must not
+                // be read as if it came over TLS (CVE-2011-0411).
+                let pipelined = reader.buffer().len();
+                reader.consume(pipelined);
                 write_reply(stream, 220, "Ready to start TLS")?;
                 match tls_upgrader.upgrade(stream) {
                     Ok(()) => {
                         state.tls_active = true;
+                        state.tls_parameters = tls_upgrader.session_parameters(stream);
                         state.ehlo_seen = false;
+                        state.reset_transaction();
                         relay = None;
+                        let parameters = state.tls_parameters.clone();
+                        state.emit(
+                            telemetry,
+                            TelemetryEvent::StartTls(StartTlsResult::Negotiated(parameters)),
+                        );
                     }
                     Err(error) => {
                         state.protocol_errors += 1;
-                        state.telemetry.tls_upgrade_failures += 1;
+                        state.emit(
+                            telemetry,
+                            TelemetryEvent::StartTls(StartTlsResult::Failed {
+                                reason: error.to_string(),
+                            }),
+                        );
                         write_reply(
                             stream,
                             454,
@@ -369,28 +1040,60 @@ where
                 }
             }
             "MAIL" => {
-                match can_process_mail_command(&state, config.inbound_tls_policy) {
+                match can_process_mail_command(state, config) {
                     Ok(()) => {}
                     Err(MailCommandRejection::EhloRequired(message)) => {
                         state.protocol_errors += 1;
                         write_reply(stream, 503, message)?;
                         continue;
                     }
+                    Err(MailCommandRejection::NotAuthenticated) => {
+                        write_reply(stream, 530, "5.7.0 Authentication required")?;
+                        continue;
+                    }
                     Err(MailCommandRejection::TlsRequired) => {
-                        state.telemetry.require_tls_rejections += 1;
+                        state.emit(
+                            telemetry,
+                            TelemetryEvent::TlsRequired {
+                                command: "MAIL".to_string(),
+                            },
+                        );
                         write_reply(stream, 530, "5.7.0 Must issue STARTTLS first")?;
                         continue;
                     }
                 }
 
-                if config.postfix_upstream_addr.is_some() {
+                let mail = match MailFrom::parse(argument) {
+                    Ok(mail) => mail,
+                    Err(error) => {
+                        state.protocol_errors += 1;
+                        write_reply(stream, error.code, error.message)?;
+                        continue;
+                    }
+                };
+
+                if upstreams.is_configured() {
+                    state.upstream_transaction_lost = false;
                     let mail_reply =
-                        match relay_command_to_postfix(&mut relay, config, command_line) {
+                        match relay_transaction_command(
+                            &mut relay,
+                            config,
+                            upstreams,
+                            state.authenticated_identity.as_deref(),
+                            &[],
+                            command_line,
+                        ) {
                             Ok(reply) => reply,
                             Err(error) => {
                                 relay = None;
                                 state.protocol_errors += 1;
-                                state.telemetry.relay_temporary_failures += 1;
+                                state.emit(
+                                    telemetry,
+                                    TelemetryEvent::RelayFailure {
+                                        stage: "mail",
+                                        reason: error.to_string(),
+                                    },
+                                );
                                 write_reply(
                                     stream,
                                     451,
@@ -399,34 +1102,110 @@ where
                                 continue;
                             }
                         };
-                    write_smtp_reply(stream, &mail_reply)?;
+                    state.reset_transaction();
+                    let sender = mail.address();
+                    state.begin_transaction(sender, started_at.elapsed());
+                    state.smtputf8 = mail.smtputf8();
+                    state.transactions.record_reply(
+                        TransactionStage::Mail,
+                        mail_reply.code,
+                        &mail_reply.last_line(),
+                    );
+                    if mail_reply.code / 100 == 2 {
+                        state.relay_envelope.push(command_line.to_string());
+                    } else {
+                        state
+                            .transactions
+                            .finish(TransactionOutcome::from_reply_code(mail_reply.code), 0);
+                        state.reset_transaction();
+                    }
+                    mail_reply.write_to(stream)?;
+                    state.emit(
+                        telemetry,
+                        TelemetryEvent::Mail {
+                            sender: mail.address(),
+                            reply_code: mail_reply.code,
+                        },
+                    );
                 } else {
+                    state.reset_transaction();
+                    let sender = mail.address();
+                    state.begin_transaction(sender, started_at.elapsed());
+                    state.smtputf8 = mail.smtputf8();
+                    state.transactions.record_reply(
+                        TransactionStage::Mail,
+                        250,
+                        "250 2.1.0 Sender OK",
+                    );
                     write_reply(stream, 250, "2.1.0 Sender OK")?;
+                    state.emit(
+                        telemetry,
+                        TelemetryEvent::Mail {
+                            sender: mail.address(),
+                            reply_code: 250,
+                        },
+                    );
                 }
             }
             "RCPT" => {
-                match can_process_mail_command(&state, config.inbound_tls_policy) {
+                match can_process_mail_command(state, config) {
                     Ok(()) => {}
                     Err(MailCommandRejection::EhloRequired(message)) => {
                         state.protocol_errors += 1;
                         write_reply(stream, 503, message)?;
                         continue;
                     }
+                    Err(MailCommandRejection::NotAuthenticated) => {
+                        write_reply(stream, 530, "5.7.0 Authentication required")?;
+                        continue;
+                    }
                     Err(MailCommandRejection::TlsRequired) => {
-                        state.telemetry.require_tls_rejections += 1;
+                        state.emit(
+                            telemetry,
+                            TelemetryEvent::TlsRequired {
+                                command: "RCPT".to_string(),
+                            },
+                        );
                         write_reply(stream, 530, "5.7.0 Must issue STARTTLS first")?;
                         continue;
                     }
                 }
 
-                if config.postfix_upstream_addr.is_some() {
+                let rcpt = match RcptTo::parse(argument, state.smtputf8) {
+                    Ok(rcpt) => rcpt,
+                    Err(error) => {
+                        state.protocol_errors += 1;
+                        write_reply(stream, error.code, error.message)?;
+                        continue;
+                    }
+                };
+
+                if upstreams.is_configured() {
+                    if state.upstream_transaction_lost {
+                        write_reply(stream, 451, TRANSACTION_LOST_MESSAGE)?;
+                        continue;
+                    }
                     let rcpt_reply =
-                        match relay_command_to_postfix(&mut relay, config, command_line) {
+                        match relay_transaction_command(
+                            &mut relay,
+                            config,
+                            upstreams,
+                            state.authenticated_identity.as_deref(),
+                            &state.relay_envelope,
+                            command_line,
+                        ) {
                             Ok(reply) => reply,
                             Err(error) => {
                                 relay = None;
+                                state.lose_upstream_transaction();
                                 state.protocol_errors += 1;
-                                state.telemetry.relay_temporary_failures += 1;
+                                state.emit(
+                                    telemetry,
+                                    TelemetryEvent::RelayFailure {
+                                        stage: "rcpt",
+                                        reason: error.to_string(),
+                                    },
+                                );
                                 write_reply(
                                     stream,
                                     451,
@@ -435,34 +1214,95 @@ where
                                 continue;
                             }
                         };
-                    write_smtp_reply(stream, &rcpt_reply)?;
+                    state.transactions.record_reply(
+                        TransactionStage::Rcpt,
+                        rcpt_reply.code,
+                        &rcpt_reply.last_line(),
+                    );
+                    if rcpt_reply.code / 100 == 2 {
+                        state.relay_envelope.push(command_line.to_string());
+                        state.transactions.add_recipient();
+                        state
+                            .envelope_recipients
+                            .push(rcpt.address());
+                    }
+                    rcpt_reply.write_to(stream)?;
+                    state.emit(
+                        telemetry,
+                        TelemetryEvent::Recipient {
+                            recipient: rcpt.address(),
+                            reply_code: rcpt_reply.code,
+                        },
+                    );
                 } else {
+                    state.transactions.record_reply(
+                        TransactionStage::Rcpt,
+                        250,
+                        "250 2.1.5 Recipient OK",
+                    );
+                    state.transactions.add_recipient();
+                    state
+                        .envelope_recipients
+                        .push(rcpt.address());
                     write_reply(stream, 250, "2.1.5 Recipient OK")?;
+                    state.emit(
+                        telemetry,
+                        TelemetryEvent::Recipient {
+                            recipient: rcpt.address(),
+                            reply_code: 250,
+                        },
+                    );
                 }
             }
             "DATA" => {
-                match can_process_mail_command(&state, config.inbound_tls_policy) {
+                match can_process_mail_command(state, config) {
                     Ok(()) => {}
                     Err(MailCommandRejection::EhloRequired(message)) => {
                         state.protocol_errors += 1;
                         write_reply(stream, 503, message)?;
                         continue;
                     }
+                    Err(MailCommandRejection::NotAuthenticated) => {
+                        write_reply(stream, 530, "5.7.0 Authentication required")?;
+                        continue;
+                    }
                     Err(MailCommandRejection::TlsRequired) => {
-                        state.telemetry.require_tls_rejections += 1;
+                        state.emit(
+                            telemetry,
+                            TelemetryEvent::TlsRequired {
+                                command: "DATA".to_string(),
+                            },
+                        );
                         write_reply(stream, 530, "5.7.0 Must issue STARTTLS first")?;
                         continue;
                     }
                 }
 
-                if config.postfix_upstream_addr.is_some() {
-                    let data_reply = match relay_command_to_postfix(&mut relay, config, command_line)
-                    {
+                if upstreams.is_configured() {
+                    if state.upstream_transaction_lost {
+                        write_reply(stream, 451, TRANSACTION_LOST_MESSAGE)?;
+                        continue;
+                    }
+                    let data_reply = match relay_transaction_command(
+                        &mut relay,
+                        config,
+                        upstreams,
+                        state.authenticated_identity.as_deref(),
+                        &state.relay_envelope,
+                        command_line,
+                    ) {
                         Ok(reply) => reply,
                         Err(error) => {
                             relay = None;
+                            state.lose_upstream_transaction();
                             state.protocol_errors += 1;
-                            state.telemetry.relay_temporary_failures += 1;
+                            state.emit(
+                                telemetry,
+                                TelemetryEvent::RelayFailure {
+                                    stage: "data",
+                                    reason: error.to_string(),
+                                },
+                            );
                             write_reply(
                                 stream,
                                 451,
@@ -471,28 +1311,82 @@ where
                             continue;
                         }
                     };
-                    write_smtp_reply(stream, &data_reply)?;
+                    data_reply.write_to(stream)?;
+                    state.transactions.record_reply(
+                        TransactionStage::Data,
+                        data_reply.code,
+                        &data_reply.last_line(),
+                    );
 
                     if data_reply.code / 100 != 3 {
                         continue;
                     }
 
                     let final_data_reply = match relay.as_mut() {
-                        Some(postfix_relay) => {
-                            postfix_relay.relay_data_block(&mut reader, config.max_line_len)
-                        }
+                        Some(postfix_relay) => postfix_relay.relay_data_block(
+                            &mut reader,
+                            &state.envelope_recipients,
+                            config.max_line_len,
+                            shutdown,
+                        ),
                         None => Err(io::Error::new(
                             ErrorKind::NotConnected,
                             "relay state missing after DATA command",
                         )),
                     };
 
+                    let mut attempt = match &final_data_reply {
+                        Ok((reply, message_bytes, lmtp_failures)) => {
+                            let queue_id = parse_postfix_queue_id(reply);
+                            state.transactions.record_reply(
+                                TransactionStage::DataFinal,
+                                reply.code,
+                                &reply.last_line(),
+                            );
+                            if let Some(record) = state.transactions.current_mut() {
+                                record.postfix_queue_id = queue_id.clone();
+                            }
+                            state.transactions.finish(
+                                TransactionOutcome::from_reply_code(reply.code),
+                                *message_bytes,
+                            );
+                            let mut attempt = state.delivery_attempt(
+                                config,
+                                Some(reply.code),
+                                lmtp_failures.clone(),
+                            );
+                            attempt.postfix_queue_id = queue_id;
+                            attempt
+                        }
+                        Err(error) => {
+                            state.transactions.finish(TransactionOutcome::Deferred, 0);
+                            state.delivery_attempt(config, None, Some(error.to_string()))
+                        }
+                    };
+                    attempt.next_hop = relay
+                        .as_ref()
+                        .map(|postfix_relay| postfix_relay.backend.to_string());
+                    state.emit(telemetry, TelemetryEvent::Data(Box::new(attempt)));
+                    state.reset_transaction();
                     match final_data_reply {
-                        Ok(reply) => write_smtp_reply(stream, &reply)?,
+                        Ok((reply, _, _)) => reply.write_to(stream)?,
+                        Err(error) if error.kind() == ErrorKind::Interrupted => {
+                            // Dropping the upstream connection mid-DATA makes Postfix
+                            // discard the partial message instead of queueing it.
+                            relay = None;
+                            close_for_shutdown(stream, &mut relay, state)?;
+                            break;
+                        }
                         Err(error) => {
                             relay = None;
                             state.protocol_errors += 1;
-                            state.telemetry.relay_temporary_failures += 1;
+                            state.emit(
+                                telemetry,
+                                TelemetryEvent::RelayFailure {
+                                    stage: "data",
+                                    reason: error.to_string(),
+                                },
+                            );
                             write_reply(
                                 stream,
                                 451,
@@ -502,7 +1396,36 @@ where
                     }
                 } else {
                     write_reply(stream, 354, "End data with <CR><LF>.<CR><LF>")?;
-                    if let Err(error) = consume_data_block(&mut reader, config.max_line_len) {
+                    state.transactions.record_reply(
+                        TransactionStage::Data,
+                        354,
+                        "354 End data with <CR><LF>.<CR><LF>",
+                    );
+                    let consumed = consume_data_block(&mut reader, config.max_line_len, shutdown);
+                    let attempt = match &consumed {
+                        Ok(message_bytes) => {
+                            state.transactions.record_reply(
+                                TransactionStage::DataFinal,
+                                250,
+                                "250 2.0.0 Queued",
+                            );
+                            state
+                                .transactions
+                                .finish(TransactionOutcome::Accepted, *message_bytes);
+                            state.delivery_attempt(config, Some(250), None)
+                        }
+                        Err(error) => {
+                            state.transactions.finish(TransactionOutcome::Deferred, 0);
+                            state.delivery_attempt(config, None, Some(error.to_string()))
+                        }
+                    };
+                    state.emit(telemetry, TelemetryEvent::Data(Box::new(attempt)));
+                    state.reset_transaction();
+                    if let Err(error) = consumed {
+                        if error.kind() == ErrorKind::Interrupted {
+                            close_for_shutdown(stream, &mut relay, state)?;
+                            break;
+                        }
                         state.protocol_errors += 1;
                         write_reply(stream, 451, &format!("4.3.0 DATA read failure: {}", error))?;
                         continue;
@@ -510,33 +1433,162 @@ where
                     write_reply(stream, 250, "2.0.0 Queued")?;
                 }
             }
-            "RSET" => {
-                if relay.is_some() {
-                    match relay_command_to_postfix(&mut relay, config, command_line) {
-                        Ok(reply) => write_smtp_reply(stream, &reply)?,
-                        Err(error) => {
-                            relay = None;
-                            state.protocol_errors += 1;
-                            state.telemetry.relay_temporary_failures += 1;
-                            write_reply(
-                                stream,
-                                451,
-                                &format!("4.4.0 Postfix relay unavailable: {}", error),
-                            )?;
+            "AUTH" => {
+                if config.mode != ListenerMode::Submission {
+                    state.protocol_errors += 1;
+                    write_reply(stream, 502, "5.5.1 Command not implemented")?;
+                    continue;
+                }
+
+                let refusal = if !state.tls_active {
+                    Some((
+                        538,
+                        "5.7.11 Encryption required for requested authentication mechanism",
+                    ))
+                } else if !state.ehlo_seen {
+                    Some((503, "5.5.1 Send EHLO before AUTH"))
+                } else if state.authenticated_identity.is_some() {
+                    Some((503, "5.5.1 Already authenticated"))
+                } else if state.transaction_active {
+                    Some((503, "5.5.1 AUTH not allowed in a mail transaction"))
+                } else {
+                    None
+                };
+                if let Some((reply_code, message)) = refusal {
+                    state.protocol_errors += 1;
+                    write_reply(stream, reply_code, message)?;
+                    continue;
+                }
+
+                let (mechanism_name, initial_response) = match argument.split_once(' ') {
+                    Some((mechanism_name, response)) => (mechanism_name, Some(response.trim())),
+                    None => (argument, None),
+                };
+                let Some(mechanism) = SaslMechanism::parse(mechanism_name) else {
+                    state.protocol_errors += 1;
+                    write_reply(stream, 504, "5.5.4 Unrecognized authentication mechanism")?;
+                    continue;
+                };
+
+                let Some(authenticator) = authenticator else {
+                    write_reply(stream, 454, "4.7.0 Temporary authentication failure")?;
+                    continue;
+                };
+
+                let exchange = auth::run_server_exchange(mechanism, initial_response, |challenge| {
+                    write_reply(stream, 334, challenge)?;
+                    let mut response = String::new();
+                    let read =
+                        shutdown::read_session_line(&mut reader, &mut response, shutdown, true)?;
+                    match read {
+                        LineRead::Line => {
+                            Ok(Some(response.trim_end_matches(['\r', '\n']).to_string()))
                         }
+                        LineRead::Closed | LineRead::Shutdown => Ok(None),
+                    }
+                })?;
+
+                let credentials = match exchange {
+                    SaslExchange::Credentials(credentials) => credentials,
+                    SaslExchange::Cancelled => {
+                        write_reply(stream, 501, "5.0.0 Authentication cancelled")?;
+                        continue;
+                    }
+                    SaslExchange::Malformed => {
+                        state.protocol_errors += 1;
+                        write_reply(stream, 501, "5.5.2 Cannot decode authentication response")?;
+                        continue;
+                    }
+                    SaslExchange::Disconnected => break,
+                };
+
+                let request = AuthRequest {
+                    mechanism: mechanism.label(),
+                    authzid: credentials.authzid,
+                    username: credentials.username,
+                    password: credentials.password,
+                    client_addr: state.context.client_addr,
+                    secured: state.tls_active,
+                };
+                match authenticator.authenticate(&request) {
+                    Ok(identity) => {
+                        state.authenticated_identity = Some(identity);
+                        relay = None;
+                        state.emit(
+                            telemetry,
+                            TelemetryEvent::Auth {
+                                mechanism: mechanism.label(),
+                                succeeded: true,
+                            },
+                        );
+                        write_reply(stream, 235, "2.7.0 Authentication successful")?;
+                    }
+                    Err(AuthenticationError::InvalidCredentials) => {
+                        state.emit(
+                            telemetry,
+                            TelemetryEvent::Auth {
+                                mechanism: mechanism.label(),
+                                succeeded: false,
+                            },
+                        );
+                        if state.telemetry.auth_failures >= MAX_AUTH_FAILURES {
+                            write_reply(stream, 421, "4.7.0 Too many authentication failures")?;
+                            break;
+                        }
+                        write_reply(stream, 535, "5.7.8 Authentication credentials invalid")?;
+                    }
+                    Err(AuthenticationError::Temporary(reason)) => {
+                        state.emit(
+                            telemetry,
+                            TelemetryEvent::RelayFailure {
+                                stage: "auth",
+                                reason,
+                            },
+                        );
+                        write_reply(stream, 454, "4.7.0 Temporary authentication failure")?;
+                    }
+                }
+            }
+            "RSET" => {
+                state.reset_transaction();
+                state.upstream_transaction_lost = false;
+                // A dropped upstream has no transaction left to reset, so RSET still
+                // succeeds; the next MAIL opens a new connection.
+                let reply = match relay.as_mut() {
+                    Some(postfix_relay) => match postfix_relay.relay_command(command_line) {
+                        Ok(reply) if reply.code / 100 == 2 => Some(reply),
+                        Ok(_) | Err(_) => None,
+                    },
+                    None => None,
+                };
+                match reply {
+                    Some(reply) => reply.write_to(stream)?,
+                    None => {
+                        relay = None;
+                        write_reply(stream, 250, "2.0.0 Reset state")?;
                     }
-                } else {
-                    write_reply(stream, 250, "2.0.0 Reset state")?;
                 }
             }
             "NOOP" => {
                 if relay.is_some() {
-                    match relay_command_to_postfix(&mut relay, config, command_line) {
-                        Ok(reply) => write_smtp_reply(stream, &reply)?,
+                    match relay_command_to_postfix(
+                        &mut relay,
+                        config,
+                        upstreams,
+                        state.authenticated_identity.as_deref(),
+                        command_line,
+                    ) {
+                        Ok(reply) => reply.write_to(stream)?,
                         Err(error) => {
                             relay = None;
                             state.protocol_errors += 1;
-                            state.telemetry.relay_temporary_failures += 1;
+                            state.emit(
+                                telemetry,
+                                TelemetryEvent::RelayFailure {
+                                    stage: "noop",
+                                    reason: error.to_string(),
+                                },
+                            );
                             write_reply(
                                 stream,
                                 451,
@@ -550,8 +1602,14 @@ where
             }
             "QUIT" => {
                 if relay.is_some() {
-                    match relay_command_to_postfix(&mut relay, config, command_line) {
-                        Ok(reply) => write_smtp_reply(stream, &reply)?,
+                    match relay_command_to_postfix(
+                        &mut relay,
+                        config,
+                        upstreams,
+                        state.authenticated_identity.as_deref(),
+                        command_line,
+                    ) {
+                        Ok(reply) => reply.write_to(stream)?,
                         Err(_) => write_reply(stream, 221, "2.0.0 Bye")?,
                     }
                 } else {
@@ -566,27 +1624,39 @@ where
         }
     }
 
-    Ok(SessionSummary {
-        command_count: state.command_count,
-        protocol_errors: state.protocol_errors,
-        tls_negotiated: state.tls_active,
-        inbound_tls_policy: config.inbound_tls_policy,
-        telemetry: state.telemetry,
-    })
+    Ok(())
+}
+
+fn close_for_shutdown(
+    stream: &mut TcpStream,
+    relay: &mut Option<PostfixRelay>,
+    state: &mut SessionState,
+) -> io::Result<()> {
+    if let Some(postfix_relay) = relay.as_mut() {
+        let _ = postfix_relay.relay_command("QUIT");
+    }
+    *relay = None;
+    state.reset_transaction();
+    state.closed_by_shutdown = true;
+    write_reply(stream, 421, "4.3.2 Service shutting down")
 }
 
 fn can_process_mail_command(
     state: &SessionState,
-    policy: InboundTlsPolicy,
+    config: &ListenerConfig,
 ) -> Result<(), MailCommandRejection> {
     if !state.ehlo_seen {
         return Err(MailCommandRejection::EhloRequired(required_ehlo_message(state)));
     }
 
-    if policy.requires_tls() && !state.tls_active {
+    if config.inbound_tls_policy.requires_tls() && !state.tls_active {
         return Err(MailCommandRejection::TlsRequired);
     }
 
+    if config.mode == ListenerMode::Submission && state.authenticated_identity.is_none() {
+        return Err(MailCommandRejection::NotAuthenticated);
+    }
+
     Ok(())
 }
 
@@ -598,15 +1668,23 @@ fn required_ehlo_message(state: &SessionState) -> &'static str {
     }
 }
 
-fn consume_data_block(reader: &mut BufReader<TcpStream>, max_line_len: usize) -> io::Result<()> {
+fn consume_data_block(
+    reader: &mut BufReader<TcpStream>,
+    max_line_len: usize,
+    shutdown: Option<&SessionShutdown<'_>>,
+) -> io::Result<u64> {
+    let mut message_bytes = 0u64;
     loop {
-        let mut line = String::new();
-        let bytes_read = reader.read_line(&mut line)?;
-        if bytes_read == 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "connection closed during DATA",
-            ));
+        let mut line = Vec::new();
+        match shutdown::read_session_bytes(reader, &mut line, shutdown, true)? {
+            LineRead::Line => {}
+            LineRead::Closed => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed during DATA",
+                ));
+            }
+            LineRead::Shutdown => return Err(shutdown::shutdown_interrupted_error()),
         }
         if line.len() > max_line_len {
             return Err(io::Error::new(
@@ -614,149 +1692,206 @@ fn consume_data_block(reader: &mut BufReader<TcpStream>, max_line_len: usize) ->
                 "DATA line too long",
             ));
         }
-        if line == ".\r\n" || line == ".\n" {
-            return Ok(());
+        if is_data_terminator(&line) {
+            return Ok(message_bytes);
         }
+        message_bytes += line.len() as u64;
     }
 }
 
-fn split_command(line: &str) -> (String, &str) {
-    let mut parts = line.splitn(2, |character: char| character.is_whitespace());
-    let verb = parts.next().unwrap_or("").trim().to_ascii_uppercase();
-    let argument = parts.next().unwrap_or("").trim();
-    (verb, argument)
+// Postfix ends DATA with "250 2.0.0 Ok: queued as 4XyZ1k2LmNz"; the ID joins our events
+// with the maillog.
+fn parse_postfix_queue_id(reply: &Reply) -> Option<String> {
+    if reply.code / 100 != 2 {
+        return None;
+    }
+
+    const MARKER: &str = "queued as ";
+    reply.lines.iter().find_map(|line| {
+        let start = line.to_ascii_lowercase().find(MARKER)? + MARKER.len();
+        let queue_id: String = line[start..]
+            .chars()
+            .take_while(|character| character.is_ascii_alphanumeric())
+            .collect();
+        if queue_id.is_empty() {
+            None
+        } else {
+            Some(queue_id)
+        }
+    })
 }
 
 fn relay_command_to_postfix(
     relay: &mut Option<PostfixRelay>,
     config: &ListenerConfig,
+    upstreams: &UpstreamPool,
+    login: Option<&str>,
     command_line: &str,
-) -> io::Result<SmtpReply> {
-    let postfix_relay = ensure_postfix_relay(relay, config)?;
-    postfix_relay.relay_command(command_line)
-}
-
-fn ensure_postfix_relay<'a>(
-    relay: &'a mut Option<PostfixRelay>,
-    config: &ListenerConfig,
-) -> io::Result<&'a mut PostfixRelay> {
-    if relay.is_none() {
-        let upstream_addr = config.postfix_upstream_addr.ok_or_else(|| {
-            io::Error::new(
-                ErrorKind::InvalidInput,
-                "postfix_upstream_addr is required for relay mode",
-            )
-        })?;
-        *relay = Some(PostfixRelay::connect(upstream_addr, &config.banner_host)?);
-    }
-
-    match relay {
-        Some(postfix_relay) => Ok(postfix_relay),
-        None => Err(io::Error::new(
-            ErrorKind::NotConnected,
-            "relay initialization failed",
-        )),
+) -> io::Result<Reply> {
+    let postfix_relay = ensure_postfix_relay(relay, config, upstreams, login)?;
+    let reply = postfix_relay.relay_command(command_line);
+    if let Err(error) = &reply {
+        upstreams.record_failure(&postfix_relay.backend, error);
     }
+    reply
 }
 
-fn read_smtp_reply(reader: &mut BufReader<TcpStream>) -> io::Result<SmtpReply> {
-    let mut lines = Vec::new();
-    let mut code: Option<u16> = None;
-
+// Sends MAIL, RCPT or DATA. If the upstream connection turns out to be gone (an I/O
+// error, or a 421 reply before it closes), the command is retried once on a new
+// connection after replaying `envelope`. An error means the transaction is lost.
+fn relay_transaction_command(
+    relay: &mut Option<PostfixRelay>,
+    config: &ListenerConfig,
+    upstreams: &UpstreamPool,
+    login: Option<&str>,
+    envelope: &[String],
+    command_line: &str,
+) -> io::Result<Reply> {
+    let mut retries_left = 1;
     loop {
-        let mut raw = String::new();
-        let bytes_read = reader.read_line(&mut raw)?;
-        if bytes_read == 0 {
-            return Err(io::Error::new(
-                ErrorKind::UnexpectedEof,
-                "upstream closed connection while reading SMTP reply",
-            ));
-        }
-
-        let line = raw.trim_end_matches(['\r', '\n']).to_string();
-        let line_bytes = line.as_bytes();
-        if line_bytes.len() < 3 || !line_bytes[..3].iter().all(|byte| byte.is_ascii_digit()) {
-            return Err(io::Error::new(
-                ErrorKind::InvalidData,
-                format!("invalid SMTP reply line from upstream: {}", line),
-            ));
+        if relay.is_none() {
+            if let Err(error) = ensure_postfix_relay(relay, config, upstreams, login) {
+                // Postfix's answer to XCLIENT is the answer to the command that opened it.
+                return match xclient_rejection(&error) {
+                    Some(reply) => Ok(reply.clone()),
+                    None => Err(error),
+                };
+            }
+            replay_envelope(relay, upstreams, envelope)?;
         }
 
-        let parsed_code = line[..3].parse::<u16>().map_err(|error| {
-            io::Error::new(
-                ErrorKind::InvalidData,
-                format!("invalid SMTP reply code from upstream: {}", error),
-            )
-        })?;
-
-        if let Some(expected_code) = code {
-            if expected_code != parsed_code {
-                return Err(io::Error::new(
-                    ErrorKind::InvalidData,
-                    format!(
-                        "inconsistent SMTP reply code from upstream: expected {}, got {}",
-                        expected_code, parsed_code
-                    ),
-                ));
+        match relay_command_to_postfix(relay, config, upstreams, login, command_line) {
+            Ok(reply) if reply.code != 421 => return Ok(reply),
+            Ok(reply) if retries_left == 0 => {
+                *relay = None;
+                return Ok(reply);
             }
-        } else {
-            code = Some(parsed_code);
+            Err(error) if retries_left == 0 => return Err(error),
+            Ok(_) | Err(_) => *relay = None,
         }
+        retries_left -= 1;
+    }
+}
 
-        let separator = if line_bytes.len() > 3 {
-            line_bytes[3]
-        } else {
-            b' '
+fn replay_envelope(
+    relay: &mut Option<PostfixRelay>,
+    upstreams: &UpstreamPool,
+    envelope: &[String],
+) -> io::Result<()> {
+    let Some(postfix_relay) = relay.as_mut() else {
+        return Ok(());
+    };
+    for replayed in envelope {
+        let reply = match postfix_relay.relay_command(replayed) {
+            Ok(reply) => reply,
+            Err(error) => {
+                upstreams.record_failure(&postfix_relay.backend, &error);
+                *relay = None;
+                return Err(error);
+            }
         };
-        lines.push(line);
-        if separator == b' ' {
-            break;
-        }
-        if separator != b'-' {
-            return Err(io::Error::new(
-                ErrorKind::InvalidData,
-                "invalid SMTP multiline reply separator from upstream",
-            ));
+        if reply.code / 100 != 2 {
+            let verb = replayed.split_whitespace().next().unwrap_or("command");
+            let error = io::Error::new(
+                ErrorKind::ConnectionAborted,
+                format!("upstream rejected replayed {}: {}", verb, reply.last_line()),
+            );
+            *relay = None;
+            return Err(error);
         }
     }
 
-    Ok(SmtpReply {
-        code: code.unwrap_or(500),
-        lines,
-    })
-}
-
-fn write_command_line(stream: &mut TcpStream, line: &str) -> io::Result<()> {
-    write!(stream, "{}\r\n", line)?;
-    stream.flush()
+    Ok(())
 }
 
-fn write_smtp_reply(stream: &mut TcpStream, reply: &SmtpReply) -> io::Result<()> {
-    for line in &reply.lines {
-        write!(stream, "{}\r\n", line)?;
+// The client reset its envelope without RSET (a new EHLO), so reset the upstream one
+// too. A connection that cannot take RSET is dropped instead.
+fn abort_upstream_transaction(relay: &mut Option<PostfixRelay>) {
+    if let Some(postfix_relay) = relay.as_mut() {
+        if !matches!(postfix_relay.relay_command("RSET"), Ok(reply) if reply.code / 100 == 2) {
+            *relay = None;
+        }
     }
-
-    stream.flush()
 }
 
-fn is_data_terminator(line: &str) -> bool {
-    line == ".\r\n" || line == ".\n"
-}
+// Connect-stage failures (connect, banner, EHLO, missing XCLIENT) are retried on the
+// next backend; once a command has been sent the session is pinned to its backend. A
+// rejected XCLIENT is returned as is without counting against the backend.
+fn ensure_postfix_relay<'a>(
+    relay: &'a mut Option<PostfixRelay>,
+    config: &ListenerConfig,
+    upstreams: &UpstreamPool,
+    login: Option<&str>,
+) -> io::Result<&'a mut PostfixRelay> {
+    if relay.is_none() {
+        let mut last_error = io::Error::new(
+            ErrorKind::InvalidInput,
+            "postfix_upstream_addr or postfix_upstreams is required for relay mode",
+        );
+        let candidates = upstreams.connect_order();
+        for backend in candidates
+            .into_iter()
+            .take(upstreams.config().max_connect_attempts)
+        {
+            match PostfixRelay::connect(
+                &backend.target,
+                upstreams.protocol(),
+                upstreams.config().connect_timeout,
+                &config.banner_host,
+                login,
+            ) {
+                Ok(postfix_relay) => {
+                    upstreams.record_success(&backend.target);
+                    upstreams
+                        .record_capabilities(&backend.target, postfix_relay.capabilities.clone());
+                    *relay = Some(postfix_relay);
+                    break;
+                }
+                Err(error) if xclient_rejection(&error).is_some() => return Err(error),
+                Err(error) => {
+                    upstreams.record_failure(&backend.target, &error);
+                    last_error = error;
+                }
+            }
+        }
+        if relay.is_none() {
+            return Err(last_error);
+        }
+    }
 
-fn write_reply(stream: &mut TcpStream, code: u16, message: &str) -> io::Result<()> {
-    write!(stream, "{} {}\r\n", code, message)?;
-    stream.flush()
+    match relay {
+        Some(postfix_relay) => Ok(postfix_relay),
+        None => Err(io::Error::new(
+            ErrorKind::NotConnected,
+            "relay initialization failed",
+        )),
+    }
 }
 
-fn write_multiline_reply(stream: &mut TcpStream, code: u16, lines: &[String]) -> io::Result<()> {
-    if lines.is_empty() {
-        return write_reply(stream, code, "OK");
+fn send_upstream_ehlo(
+    writer: &mut UpstreamStream,
+    reader: &mut BufReader<UpstreamStream>,
+    protocol: UpstreamProtocol,
+    ehlo_host: &str,
+) -> io::Result<Reply> {
+    let verb = protocol.greeting_verb();
+    write_command_line(writer, &format!("{} {}", verb, ehlo_host))?;
+    let ehlo_reply = Reply::read_from(reader)?;
+    if ehlo_reply.code / 100 != 2 {
+        return Err(io::Error::new(
+            ErrorKind::ConnectionAborted,
+            format!(
+                "upstream Postfix {} was non-2xx ({}): {}",
+                verb,
+                ehlo_reply.code,
+                ehlo_reply.summary()
+            ),
+        ));
     }
 
-    for (index, line) in lines.iter().enumerate() {
-        let separator = if index + 1 == lines.len() { ' ' } else { '-' };
-        write!(stream, "{}{}{}\r\n", code, separator, line)?;
-    }
+    Ok(ehlo_reply)
+}
 
-    stream.flush()
+fn reply_offers_xclient_login(reply: &Reply) -> bool {
+    EhloCapabilities::from_reply(reply).has_parameter("XCLIENT", "LOGIN")
 }
@@ -1,2 +1,17 @@
+mod base64;
+mod digest;
+mod idna;
+
+pub mod access;
+pub mod auth;
 pub mod inbound;
+pub mod logging;
+pub mod metrics;
 pub mod outbound;
+pub mod shutdown;
+pub mod smtp;
+pub mod supervisor;
+pub mod telemetry;
+pub mod tls_policy_map;
+pub mod transaction;
+pub mod upstream;
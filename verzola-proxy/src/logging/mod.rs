@@ -0,0 +1,639 @@
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::digest;
+use crate::inbound::{InboundTlsMode, InboundTlsPolicy, SessionSummary, TlsSessionParameters};
+use crate::outbound::{OutboundSessionSummary, OutboundTlsPolicy};
+
+pub const DEFAULT_LOG_FILE_MAX_BYTES: u64 = 64 * 1024 * 1024;
+pub const DEFAULT_LOG_FILE_MAX_FILES: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogTarget {
+    Stdout,
+    File {
+        path: PathBuf,
+        max_bytes: u64,
+        max_files: usize,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum EnvelopeRedaction {
+    Plain,
+    Hash {
+        salt: String,
+    },
+    #[default]
+    Redact,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonLogConfig {
+    pub target: LogTarget,
+    pub envelope_redaction: EnvelopeRedaction,
+}
+
+impl Default for JsonLogConfig {
+    fn default() -> Self {
+        Self {
+            target: LogTarget::Stdout,
+            envelope_redaction: EnvelopeRedaction::default(),
+        }
+    }
+}
+
+impl JsonLogConfig {
+    pub fn validate(&self) -> io::Result<()> {
+        if let LogTarget::File {
+            path,
+            max_bytes,
+            max_files,
+        } = &self.target
+        {
+            if path.as_os_str().is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "log file path must not be empty",
+                ));
+            }
+
+            if *max_bytes < 4096 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "log file max_bytes must be at least 4096 bytes",
+                ));
+            }
+
+            if *max_files == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "log file max_files must be at least 1",
+                ));
+            }
+        }
+
+        if let EnvelopeRedaction::Hash { salt } = &self.envelope_redaction {
+            if salt.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "envelope hash redaction requires a non-empty salt",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SessionDirection {
+    #[default]
+    Inbound,
+    Outbound,
+}
+
+impl SessionDirection {
+    fn label(self) -> &'static str {
+        match self {
+            SessionDirection::Inbound => "inbound",
+            SessionDirection::Outbound => "outbound",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeliveryOutcome {
+    Accepted,
+    #[default]
+    Deferred,
+    Rejected,
+}
+
+impl DeliveryOutcome {
+    fn label(self) -> &'static str {
+        match self {
+            DeliveryOutcome::Accepted => "accepted",
+            DeliveryOutcome::Deferred => "deferred",
+            DeliveryOutcome::Rejected => "rejected",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeliveryAttempt {
+    pub session_id: String,
+    pub direction: SessionDirection,
+    pub listener: Option<String>,
+    pub client_addr: Option<SocketAddr>,
+    pub helo_name: Option<String>,
+    pub sender: Option<String>,
+    pub recipients: Vec<String>,
+    pub next_hop: Option<String>,
+    pub postfix_queue_id: Option<String>,
+    pub tls_negotiated: bool,
+    pub tls_parameters: Option<TlsSessionParameters>,
+    pub tls_policy: Option<String>,
+    pub tls_policy_rule: Option<String>,
+    pub policy_decision: Option<String>,
+    pub reply_code: Option<u16>,
+    pub outcome: DeliveryOutcome,
+    pub failure_reason: Option<String>,
+}
+
+pub struct JsonLogger {
+    envelope_redaction: EnvelopeRedaction,
+    writer: Mutex<LogWriter>,
+}
+
+impl JsonLogger {
+    pub fn open(config: JsonLogConfig) -> io::Result<Self> {
+        config.validate()?;
+        let writer = match config.target {
+            LogTarget::Stdout => LogWriter::Stdout,
+            LogTarget::File {
+                path,
+                max_bytes,
+                max_files,
+            } => LogWriter::File(RotatingFile::open(path, max_bytes, max_files)?),
+        };
+
+        Ok(Self {
+            envelope_redaction: config.envelope_redaction,
+            writer: Mutex::new(writer),
+        })
+    }
+
+    pub fn log_inbound_session(&self, summary: &SessionSummary) -> io::Result<()> {
+        let mut event = JsonObject::new();
+        event.string("ts", &format_timestamp(SystemTime::now()));
+        event.string("event", "smtp_session");
+        event.string("direction", SessionDirection::Inbound.label());
+        event.optional_string("listener", summary.listener.as_deref());
+        event.string("session_id", &summary.session_id);
+        event.optional_string(
+            "client_addr",
+            summary.client_addr.map(|address| address.to_string()).as_deref(),
+        );
+        event.optional_string("helo", summary.helo_name.as_deref());
+        event.boolean("tls_negotiated", summary.tls_negotiated);
+        push_tls_parameters(&mut event, summary.tls_parameters.as_ref());
+        event.string("tls_mode", inbound_tls_mode_label(summary.tls_mode));
+        event.string("tls_policy", inbound_policy_label(summary.inbound_tls_policy));
+        event.string("policy_decision", inbound_policy_decision(summary));
+        event.number("commands", summary.command_count as u64);
+        event.number("protocol_errors", summary.protocol_errors as u64);
+        event.number("starttls_attempts", summary.telemetry.starttls_attempts as u64);
+        event.number(
+            "tls_upgrade_failures",
+            summary.telemetry.tls_upgrade_failures as u64,
+        );
+        event.number(
+            "require_tls_rejections",
+            summary.telemetry.require_tls_rejections as u64,
+        );
+        event.number(
+            "relay_temporary_failures",
+            summary.telemetry.relay_temporary_failures as u64,
+        );
+        let auth_identity = summary
+            .authenticated_identity
+            .as_deref()
+            .map(|identity| self.redact_address(identity));
+        event.optional_string("auth_identity", auth_identity.as_deref());
+        event.number("auth_failures", summary.telemetry.auth_failures as u64);
+        event.number("transactions", summary.transactions.len() as u64);
+        event.boolean("closed_by_shutdown", summary.closed_by_shutdown);
+        event.number("duration_ms", summary.session_duration.as_millis() as u64);
+        self.write_event(event)
+    }
+
+    pub fn log_outbound_session(&self, summary: &OutboundSessionSummary) -> io::Result<()> {
+        let mut event = JsonObject::new();
+        event.string("ts", &format_timestamp(SystemTime::now()));
+        event.string("event", "smtp_session");
+        event.string("direction", SessionDirection::Outbound.label());
+        event.optional_string("listener", summary.listener.as_deref());
+        event.string("session_id", &summary.session_id);
+        event.optional_string(
+            "client_addr",
+            summary.client_addr.map(|address| address.to_string()).as_deref(),
+        );
+        event.optional_string("helo", summary.helo_name.as_deref());
+        event.boolean("tls_negotiated", summary.tls_negotiated);
+        push_tls_parameters(&mut event, summary.tls_parameters.as_ref());
+        event.optional_string(
+            "tls_policy",
+            summary.effective_tls_policy.map(outbound_policy_label),
+        );
+        event.optional_string(
+            "tls_policy_rule",
+            summary.tls_policy_rule.as_ref().map(ToString::to_string).as_deref(),
+        );
+        event.string(
+            "policy_decision",
+            outbound_policy_decision(
+                summary.effective_tls_policy,
+                summary.tls_negotiated,
+                summary.remote_session_established,
+            ),
+        );
+        event.optional_string(
+            "delivery_route",
+            summary.delivery_route.as_ref().map(ToString::to_string).as_deref(),
+        );
+        event.optional_string(
+            "relay_auth_mechanism",
+            summary.relay_auth_mechanism.map(|mechanism| mechanism.label()),
+        );
+        event.optional_string("selected_mx", summary.selected_mx.as_deref());
+        event.number("commands", summary.command_count as u64);
+        event.number("protocol_errors", summary.protocol_errors as u64);
+        event.number("temporary_failures", summary.temporary_failures as u64);
+        event.number(
+            "mx_candidates_attempted",
+            summary.mx_candidates_attempted as u64,
+        );
+        event.number(
+            "mx_addresses_attempted",
+            summary.mx_address_attempts.len() as u64,
+        );
+        event.number("starttls_attempts", summary.starttls_attempts as u64);
+        event.number("tls_upgrade_failures", summary.tls_upgrade_failures as u64);
+        event.number(
+            "opportunistic_tls_fallbacks",
+            summary.opportunistic_tls_fallbacks as u64,
+        );
+        event.number(
+            "policy_deferred_failures",
+            summary.policy_deferred_failures as u64,
+        );
+        event.number("relay_auth_failures", summary.relay_auth_failures as u64);
+        event.number(
+            "delivery_budget_exhaustions",
+            summary.delivery_budget_exhaustions as u64,
+        );
+        event.boolean("client_rejected", summary.client_rejected);
+        event.boolean("client_authenticated", summary.client_authenticated);
+        event.number("auth_failures", summary.auth_failures as u64);
+        event.number("transactions", summary.transactions.len() as u64);
+        event.boolean("closed_by_shutdown", summary.closed_by_shutdown);
+        event.number("duration_ms", summary.session_duration.as_millis() as u64);
+        self.write_event(event)
+    }
+
+    pub fn log_delivery_attempt(&self, attempt: &DeliveryAttempt) -> io::Result<()> {
+        let mut event = JsonObject::new();
+        event.string("ts", &format_timestamp(SystemTime::now()));
+        event.string("event", "delivery_attempt");
+        event.string("direction", attempt.direction.label());
+        event.optional_string("listener", attempt.listener.as_deref());
+        event.string("session_id", &attempt.session_id);
+        event.optional_string(
+            "client_addr",
+            attempt.client_addr.map(|address| address.to_string()).as_deref(),
+        );
+        event.optional_string("helo", attempt.helo_name.as_deref());
+        event.optional_string(
+            "sender",
+            attempt
+                .sender
+                .as_deref()
+                .map(|sender| self.redact_address(sender))
+                .as_deref(),
+        );
+        let recipients: Vec<String> = attempt
+            .recipients
+            .iter()
+            .map(|recipient| self.redact_address(recipient))
+            .collect();
+        event.string_array("recipients", &recipients);
+        event.number("recipient_count", attempt.recipients.len() as u64);
+        event.optional_string("next_hop", attempt.next_hop.as_deref());
+        event.optional_string("postfix_queue_id", attempt.postfix_queue_id.as_deref());
+        event.boolean("tls_negotiated", attempt.tls_negotiated);
+        push_tls_parameters(&mut event, attempt.tls_parameters.as_ref());
+        event.optional_string("tls_policy", attempt.tls_policy.as_deref());
+        event.optional_string("tls_policy_rule", attempt.tls_policy_rule.as_deref());
+        event.optional_string("policy_decision", attempt.policy_decision.as_deref());
+        event.optional_number("reply_code", attempt.reply_code.map(u64::from));
+        event.string("outcome", attempt.outcome.label());
+        event.optional_string("failure_reason", attempt.failure_reason.as_deref());
+        self.write_event(event)
+    }
+
+    pub fn redact_address(&self, address: &str) -> String {
+        match &self.envelope_redaction {
+            EnvelopeRedaction::Plain => address.to_string(),
+            EnvelopeRedaction::Hash { salt } => {
+                let mut input = Vec::with_capacity(salt.len() + address.len());
+                input.extend_from_slice(salt.as_bytes());
+                input.extend_from_slice(address.to_ascii_lowercase().as_bytes());
+                format!("sha256:{}", digest::to_hex(&digest::sha256(&input)[..16]))
+            }
+            EnvelopeRedaction::Redact => "[redacted]".to_string(),
+        }
+    }
+
+    fn write_event(&self, event: JsonObject) -> io::Result<()> {
+        let mut line = event.finish();
+        line.push('\n');
+
+        let mut writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        writer.write_line(line.as_bytes())
+    }
+}
+
+enum LogWriter {
+    Stdout,
+    File(RotatingFile),
+}
+
+impl LogWriter {
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        match self {
+            LogWriter::Stdout => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(line)?;
+                stdout.flush()
+            }
+            LogWriter::File(file) => file.write_line(line),
+        }
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    written_bytes: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written_bytes = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            max_files,
+            file,
+            written_bytes,
+        })
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.written_bytes > 0 && self.written_bytes + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line)?;
+        self.file.flush()?;
+        self.written_bytes += line.len() as u64;
+        Ok(())
+    }
+
+    // Keeps `path.1` .. `path.<max_files>` as rotated generations, newest first.
+    fn rotate(&mut self) -> io::Result<()> {
+        let oldest = rotated_path(&self.path, self.max_files);
+        if oldest.exists() {
+            fs::remove_file(&oldest)?;
+        }
+
+        for generation in (1..self.max_files).rev() {
+            let source = rotated_path(&self.path, generation);
+            if source.exists() {
+                fs::rename(&source, rotated_path(&self.path, generation + 1))?;
+            }
+        }
+
+        fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written_bytes = 0;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, generation: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_os_string();
+    rotated.push(format!(".{}", generation));
+    PathBuf::from(rotated)
+}
+
+static SESSION_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn new_session_id() -> String {
+    let sequence = SESSION_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_micros() as u64)
+        .unwrap_or_default();
+    format!(
+        "{:012x}{:04x}{:04x}",
+        started_at & 0xffff_ffff_ffff,
+        std::process::id() & 0xffff,
+        sequence & 0xffff
+    )
+}
+
+fn push_tls_parameters(event: &mut JsonObject, parameters: Option<&TlsSessionParameters>) {
+    event.optional_string(
+        "tls_version",
+        parameters.map(|parameters| parameters.protocol_version.as_str()),
+    );
+    event.optional_string(
+        "tls_cipher",
+        parameters.map(|parameters| parameters.cipher_suite.as_str()),
+    );
+    event.optional_string(
+        "tls_group",
+        parameters.and_then(|parameters| parameters.key_exchange_group.as_deref()),
+    );
+}
+
+pub(crate) fn inbound_policy_label(policy: InboundTlsPolicy) -> &'static str {
+    match policy {
+        InboundTlsPolicy::Opportunistic => "opportunistic",
+        InboundTlsPolicy::RequireTls => "require-tls",
+    }
+}
+
+fn inbound_tls_mode_label(mode: InboundTlsMode) -> &'static str {
+    match mode {
+        InboundTlsMode::StartTls => "starttls",
+        InboundTlsMode::Implicit => "implicit",
+    }
+}
+
+pub(crate) fn outbound_policy_label(policy: OutboundTlsPolicy) -> &'static str {
+    match policy {
+        OutboundTlsPolicy::Opportunistic => "opportunistic",
+        OutboundTlsPolicy::RequireTls => "require-tls",
+    }
+}
+
+pub(crate) fn outbound_policy_decision(
+    policy: Option<OutboundTlsPolicy>,
+    tls_negotiated: bool,
+    remote_session_established: bool,
+) -> &'static str {
+    match policy {
+        _ if tls_negotiated => "tls",
+        Some(OutboundTlsPolicy::RequireTls) => "deferred",
+        Some(OutboundTlsPolicy::Opportunistic) if remote_session_established => {
+            "opportunistic-fallback"
+        }
+        _ => "unresolved",
+    }
+}
+
+fn inbound_policy_decision(summary: &SessionSummary) -> &'static str {
+    if summary.tls_negotiated {
+        "tls"
+    } else if summary.telemetry.require_tls_rejections > 0 {
+        "rejected-plaintext"
+    } else {
+        "plaintext-allowed"
+    }
+}
+
+struct JsonObject {
+    buffer: String,
+}
+
+impl JsonObject {
+    fn new() -> Self {
+        Self {
+            buffer: String::from("{"),
+        }
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.buffer.len() > 1 {
+            self.buffer.push(',');
+        }
+        push_json_string(&mut self.buffer, key);
+        self.buffer.push(':');
+    }
+
+    fn string(&mut self, key: &str, value: &str) {
+        self.key(key);
+        push_json_string(&mut self.buffer, value);
+    }
+
+    fn optional_string(&mut self, key: &str, value: Option<&str>) {
+        match value {
+            Some(value) => self.string(key, value),
+            None => {
+                self.key(key);
+                self.buffer.push_str("null");
+            }
+        }
+    }
+
+    fn number(&mut self, key: &str, value: u64) {
+        self.key(key);
+        let _ = write!(self.buffer, "{}", value);
+    }
+
+    fn optional_number(&mut self, key: &str, value: Option<u64>) {
+        match value {
+            Some(value) => self.number(key, value),
+            None => {
+                self.key(key);
+                self.buffer.push_str("null");
+            }
+        }
+    }
+
+    fn boolean(&mut self, key: &str, value: bool) {
+        self.key(key);
+        self.buffer.push_str(if value { "true" } else { "false" });
+    }
+
+    fn string_array(&mut self, key: &str, values: &[String]) {
+        self.key(key);
+        self.buffer.push('[');
+        for (index, value) in values.iter().enumerate() {
+            if index > 0 {
+                self.buffer.push(',');
+            }
+            push_json_string(&mut self.buffer, value);
+        }
+        self.buffer.push(']');
+    }
+
+    fn finish(mut self) -> String {
+        self.buffer.push('}');
+        self.buffer
+    }
+}
+
+fn push_json_string(buffer: &mut String, value: &str) {
+    buffer.push('"');
+    for character in value.chars() {
+        match character {
+            '"' => buffer.push_str("\\\""),
+            '\\' => buffer.push_str("\\\\"),
+            '\n' => buffer.push_str("\\n"),
+            '\r' => buffer.push_str("\\r"),
+            '\t' => buffer.push_str("\\t"),
+            control if (control as u32) < 0x20 => {
+                let _ = write!(buffer, "\\u{:04x}", control as u32);
+            }
+            _ => buffer.push(character),
+        }
+    }
+    buffer.push('"');
+}
+
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let seconds_of_day = seconds % 86_400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        (seconds_of_day % 3600) / 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// Howard Hinnant's days-to-civil conversion for the proleptic Gregorian calendar.
+fn civil_from_days(days_since_epoch: i64) -> (i64, u32, u32) {
+    let shifted = days_since_epoch + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let day_of_era = shifted.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
@@ -1,13 +1,21 @@
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
 
 use verzola_proxy::inbound::{
     InboundListener, InboundTlsPolicy, ListenerConfig, NoopTlsUpgrader,
 };
+use verzola_proxy::logging::{JsonLogConfig, JsonLogger};
+use verzola_proxy::metrics::{MetricsExporter, MetricsRegistry};
+use verzola_proxy::shutdown::{self, ShutdownSignal};
 
 fn main() -> std::io::Result<()> {
     let bind_addr: SocketAddr = "127.0.0.1:2525"
         .parse()
         .expect("hard-coded socket address must be valid");
+    let metrics_addr: SocketAddr = "127.0.0.1:9425"
+        .parse()
+        .expect("hard-coded socket address must be valid");
 
     let config = ListenerConfig {
         bind_addr,
@@ -16,10 +24,26 @@ fn main() -> std::io::Result<()> {
         inbound_tls_policy: InboundTlsPolicy::Opportunistic,
         max_line_len: 4096,
         postfix_upstream_addr: None,
+        ..ListenerConfig::default()
     };
 
-    let listener = InboundListener::bind(config, NoopTlsUpgrader)?;
-    listener.serve_one()?;
+    let shutdown_signal = ShutdownSignal::new();
+    shutdown::install_termination_handler(&shutdown_signal)?;
+
+    let metrics = Arc::new(MetricsRegistry::default());
+    let exporter = MetricsExporter::bind(metrics_addr, Arc::clone(&metrics))?;
+    let exporter_shutdown = shutdown_signal.clone();
+    let exporter_handle = thread::spawn(move || exporter.serve_until_shutdown(&exporter_shutdown));
+
+    let logger = Arc::new(JsonLogger::open(JsonLogConfig::default())?);
+    let listener = InboundListener::bind(config, NoopTlsUpgrader)?
+        .with_metrics(metrics, "inbound")
+        .with_json_logger(logger);
+    listener.serve_until_shutdown(&shutdown_signal)?;
+
+    exporter_handle
+        .join()
+        .map_err(|_| std::io::Error::other("metrics exporter thread panicked"))??;
 
     Ok(())
 }
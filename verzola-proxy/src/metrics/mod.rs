@@ -0,0 +1,662 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::inbound::SessionSummary;
+use crate::outbound::OutboundSessionSummary;
+use crate::shutdown::{ShutdownSignal, SHUTDOWN_POLL_INTERVAL};
+
+pub const DEFAULT_LABEL_SET_BUDGET: usize = 64;
+pub const OVERFLOW_LABEL_VALUE: &str = "other";
+pub const LATENCY_BUCKETS_SECONDS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+const MAX_REQUEST_HEADER_BYTES: usize = 8192;
+const SCRAPE_READ_TIMEOUT: Duration = Duration::from_secs(5);
+const LABEL_OVERFLOW_METRIC: &str = "verzola_metrics_label_overflow_total";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl MetricKind {
+    fn label(self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MetricFamily {
+    name: &'static str,
+    help: &'static str,
+    kind: MetricKind,
+}
+
+const METRIC_FAMILIES: &[MetricFamily] = &[
+    MetricFamily {
+        name: "verzola_inbound_sessions_total",
+        help: "Inbound SMTP sessions completed.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_inbound_starttls_offered_total",
+        help: "Inbound sessions that advertised STARTTLS.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_inbound_starttls_attempts_total",
+        help: "Inbound STARTTLS commands received.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_inbound_starttls_failures_total",
+        help: "Inbound TLS upgrades that failed.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_inbound_require_tls_rejections_total",
+        help: "Inbound envelope commands rejected by require-tls policy.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_inbound_relay_failures_total",
+        help: "Inbound Postfix relay temporary failures.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_inbound_auth_failures_total",
+        help: "Inbound submission AUTH attempts rejected for bad credentials.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_inbound_session_duration_seconds",
+        help: "Inbound SMTP session duration.",
+        kind: MetricKind::Histogram,
+    },
+    MetricFamily {
+        name: "verzola_outbound_sessions_total",
+        help: "Outbound relay sessions completed.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_outbound_relay_failures_total",
+        help: "Outbound relay temporary failures returned to Postfix.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_outbound_mx_candidates_attempted_total",
+        help: "Outbound MX candidates attempted.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_outbound_mx_addresses_attempted_total",
+        help: "Outbound connection attempts to MX addresses.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_outbound_starttls_attempts_total",
+        help: "Outbound STARTTLS negotiations attempted with remote MX hosts.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_outbound_starttls_failures_total",
+        help: "Outbound STARTTLS negotiations that failed.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_outbound_opportunistic_tls_fallbacks_total",
+        help: "Outbound deliveries that fell back to plaintext under opportunistic policy.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_outbound_policy_deferrals_total",
+        help: "Outbound deliveries deferred by TLS policy.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_outbound_client_rejections_total",
+        help: "Outbound relay connections refused by the client ACL.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_outbound_auth_failures_total",
+        help: "Outbound relay client authentication failures.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_outbound_session_duration_seconds",
+        help: "Outbound relay session duration.",
+        kind: MetricKind::Histogram,
+    },
+    MetricFamily {
+        name: "verzola_outbound_mx_connect_seconds",
+        help: "Time to establish a ready remote MX session (resolve, connect, greet, MAIL).",
+        kind: MetricKind::Histogram,
+    },
+    MetricFamily {
+        name: "verzola_upstream_backend_healthy",
+        help: "Whether a Postfix upstream backend is in rotation (1) or ejected (0).",
+        kind: MetricKind::Gauge,
+    },
+    MetricFamily {
+        name: "verzola_upstream_backend_failures_total",
+        help: "Failed connections, commands and health checks per Postfix upstream backend.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: "verzola_upstream_backend_ejections_total",
+        help: "Times a Postfix upstream backend was ejected after consecutive failures.",
+        kind: MetricKind::Counter,
+    },
+    MetricFamily {
+        name: LABEL_OVERFLOW_METRIC,
+        help: "Samples folded into the overflow series by the label cardinality budget.",
+        kind: MetricKind::Counter,
+    },
+];
+
+type LabelSet = Vec<(&'static str, String)>;
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    bucket_counts: [u64; LATENCY_BUCKETS_SECONDS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            bucket_counts: [0; LATENCY_BUCKETS_SECONDS.len()],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (index, upper_bound) in LATENCY_BUCKETS_SECONDS.iter().enumerate() {
+            if seconds <= *upper_bound {
+                self.bucket_counts[index] += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Series {
+    Counter(u64),
+    Gauge(i64),
+    Histogram(Histogram),
+}
+
+#[derive(Debug, Default)]
+struct MetricsState {
+    families: BTreeMap<&'static str, BTreeMap<LabelSet, Series>>,
+}
+
+#[derive(Debug)]
+pub struct MetricsRegistry {
+    label_set_budget: usize,
+    state: Mutex<MetricsState>,
+}
+
+impl Default for MetricsRegistry {
+    fn default() -> Self {
+        Self::new(DEFAULT_LABEL_SET_BUDGET)
+    }
+}
+
+impl MetricsRegistry {
+    pub fn new(label_set_budget: usize) -> Self {
+        Self {
+            label_set_budget: label_set_budget.max(1),
+            state: Mutex::new(MetricsState::default()),
+        }
+    }
+
+    pub fn record_inbound_session(&self, listener: &str, summary: &SessionSummary) {
+        let labels = listener_labels(listener);
+        let telemetry = &summary.telemetry;
+
+        self.increment("verzola_inbound_sessions_total", &labels, 1);
+        self.increment(
+            "verzola_inbound_starttls_offered_total",
+            &labels,
+            u64::from(telemetry.starttls_offered),
+        );
+        self.increment(
+            "verzola_inbound_starttls_attempts_total",
+            &labels,
+            telemetry.starttls_attempts as u64,
+        );
+        self.increment(
+            "verzola_inbound_starttls_failures_total",
+            &labels,
+            telemetry.tls_upgrade_failures as u64,
+        );
+        self.increment(
+            "verzola_inbound_require_tls_rejections_total",
+            &labels,
+            telemetry.require_tls_rejections as u64,
+        );
+        self.increment(
+            "verzola_inbound_relay_failures_total",
+            &labels,
+            telemetry.relay_temporary_failures as u64,
+        );
+        self.increment(
+            "verzola_inbound_auth_failures_total",
+            &labels,
+            telemetry.auth_failures as u64,
+        );
+        self.observe(
+            "verzola_inbound_session_duration_seconds",
+            &labels,
+            summary.session_duration,
+        );
+    }
+
+    pub fn record_outbound_session(&self, listener: &str, summary: &OutboundSessionSummary) {
+        let labels = listener_labels(listener);
+
+        self.increment("verzola_outbound_sessions_total", &labels, 1);
+        self.increment(
+            "verzola_outbound_relay_failures_total",
+            &labels,
+            summary.temporary_failures as u64,
+        );
+        self.increment(
+            "verzola_outbound_mx_candidates_attempted_total",
+            &labels,
+            summary.mx_candidates_attempted as u64,
+        );
+        self.increment(
+            "verzola_outbound_mx_addresses_attempted_total",
+            &labels,
+            summary.mx_address_attempts.len() as u64,
+        );
+        self.increment(
+            "verzola_outbound_starttls_attempts_total",
+            &labels,
+            summary.starttls_attempts as u64,
+        );
+        self.increment(
+            "verzola_outbound_starttls_failures_total",
+            &labels,
+            summary.tls_upgrade_failures as u64,
+        );
+        self.increment(
+            "verzola_outbound_opportunistic_tls_fallbacks_total",
+            &labels,
+            summary.opportunistic_tls_fallbacks as u64,
+        );
+        self.increment(
+            "verzola_outbound_policy_deferrals_total",
+            &labels,
+            summary.policy_deferred_failures as u64,
+        );
+        self.increment(
+            "verzola_outbound_client_rejections_total",
+            &labels,
+            u64::from(summary.client_rejected),
+        );
+        self.increment(
+            "verzola_outbound_auth_failures_total",
+            &labels,
+            summary.auth_failures as u64,
+        );
+        self.observe(
+            "verzola_outbound_session_duration_seconds",
+            &labels,
+            summary.session_duration,
+        );
+        for connect_duration in &summary.mx_connect_durations {
+            self.observe(
+                "verzola_outbound_mx_connect_seconds",
+                &labels,
+                *connect_duration,
+            );
+        }
+    }
+
+    pub fn set_upstream_backend_health(&self, listener: &str, backend: &str, healthy: bool) {
+        let labels = backend_labels(listener, backend);
+        self.set_gauge("verzola_upstream_backend_healthy", &labels, i64::from(healthy));
+    }
+
+    pub fn record_upstream_backend_failure(
+        &self,
+        listener: &str,
+        backend: &str,
+        ejected: bool,
+    ) {
+        let labels = backend_labels(listener, backend);
+        self.increment("verzola_upstream_backend_failures_total", &labels, 1);
+        self.increment(
+            "verzola_upstream_backend_ejections_total",
+            &labels,
+            u64::from(ejected),
+        );
+    }
+
+    pub fn render(&self) -> String {
+        let state = self.lock_state();
+        let mut output = String::new();
+
+        for family in METRIC_FAMILIES {
+            let _ = writeln!(output, "# HELP {} {}", family.name, family.help);
+            let _ = writeln!(output, "# TYPE {} {}", family.name, family.kind.label());
+
+            let series_by_labels = match state.families.get(family.name) {
+                Some(series_by_labels) => series_by_labels,
+                None => continue,
+            };
+
+            for (labels, series) in series_by_labels {
+                match series {
+                    Series::Counter(value) => {
+                        let _ = writeln!(
+                            output,
+                            "{}{} {}",
+                            family.name,
+                            format_labels(labels, None),
+                            value
+                        );
+                    }
+                    Series::Gauge(value) => {
+                        let _ = writeln!(
+                            output,
+                            "{}{} {}",
+                            family.name,
+                            format_labels(labels, None),
+                            value
+                        );
+                    }
+                    Series::Histogram(histogram) => {
+                        render_histogram(&mut output, family.name, labels, histogram);
+                    }
+                }
+            }
+        }
+
+        output
+    }
+
+    fn increment(&self, name: &'static str, labels: &LabelSet, amount: u64) {
+        let mut state = self.lock_state();
+        let labels = self.budgeted_labels(&mut state, name, labels);
+        let series = state
+            .families
+            .entry(name)
+            .or_default()
+            .entry(labels)
+            .or_insert(Series::Counter(0));
+        if let Series::Counter(value) = series {
+            *value += amount;
+        }
+    }
+
+    fn set_gauge(&self, name: &'static str, labels: &LabelSet, value: i64) {
+        let mut state = self.lock_state();
+        let labels = self.budgeted_labels(&mut state, name, labels);
+        state
+            .families
+            .entry(name)
+            .or_default()
+            .insert(labels, Series::Gauge(value));
+    }
+
+    fn observe(&self, name: &'static str, labels: &LabelSet, duration: Duration) {
+        let mut state = self.lock_state();
+        let labels = self.budgeted_labels(&mut state, name, labels);
+        let series = state
+            .families
+            .entry(name)
+            .or_default()
+            .entry(labels)
+            .or_insert_with(|| Series::Histogram(Histogram::default()));
+        if let Series::Histogram(histogram) = series {
+            histogram.observe(duration.as_secs_f64());
+        }
+    }
+
+    // Each family keeps at most `label_set_budget` label sets, counting the overflow series
+    // whose values read "other": the last slot is held back for it, so anything past
+    // `label_set_budget - 1` distinct label sets is folded into that one series.
+    fn budgeted_labels(
+        &self,
+        state: &mut MetricsState,
+        name: &'static str,
+        labels: &LabelSet,
+    ) -> LabelSet {
+        let series_by_labels = state.families.entry(name).or_default();
+        if series_by_labels.contains_key(labels)
+            || series_by_labels.len() + 1 < self.label_set_budget
+        {
+            return labels.clone();
+        }
+
+        let overflow_series = state
+            .families
+            .entry(LABEL_OVERFLOW_METRIC)
+            .or_default()
+            .entry(Vec::new())
+            .or_insert(Series::Counter(0));
+        if let Series::Counter(value) = overflow_series {
+            *value += 1;
+        }
+
+        labels
+            .iter()
+            .map(|(name, _)| (*name, OVERFLOW_LABEL_VALUE.to_string()))
+            .collect()
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, MetricsState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+pub struct MetricsExporter {
+    listener: TcpListener,
+    registry: Arc<MetricsRegistry>,
+}
+
+impl MetricsExporter {
+    pub fn bind(bind_addr: SocketAddr, registry: Arc<MetricsRegistry>) -> io::Result<Self> {
+        let listener = TcpListener::bind(bind_addr)?;
+        Ok(Self { listener, registry })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn serve_one(&self) -> io::Result<()> {
+        let (mut stream, _) = self.listener.accept()?;
+        handle_scrape(&mut stream, &self.registry)
+    }
+
+    pub fn serve_until_shutdown(&self, shutdown: &ShutdownSignal) -> io::Result<()> {
+        self.listener.set_nonblocking(true)?;
+
+        let accept_result = loop {
+            if shutdown.is_requested() {
+                break Ok(());
+            }
+
+            match self.listener.accept() {
+                Ok((mut stream, _)) => {
+                    let registry = Arc::clone(&self.registry);
+                    thread::spawn(move || {
+                        if stream.set_nonblocking(false).is_ok() {
+                            let _ = handle_scrape(&mut stream, &registry);
+                        }
+                    });
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(SHUTDOWN_POLL_INTERVAL);
+                }
+                Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                Err(error) => break Err(error),
+            }
+        };
+
+        self.listener.set_nonblocking(false)?;
+        accept_result
+    }
+}
+
+fn handle_scrape(stream: &mut TcpStream, registry: &MetricsRegistry) -> io::Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_READ_TIMEOUT))?;
+    // The cap applies while reading, so a line that never ends cannot grow without bound.
+    let mut reader =
+        BufReader::new(stream.try_clone()?).take(MAX_REQUEST_HEADER_BYTES as u64 + 1);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    let mut header = String::new();
+    while reader.limit() > 0 {
+        header.clear();
+        let bytes_read = reader.read_line(&mut header)?;
+        if bytes_read == 0 || header == "\r\n" || header == "\n" {
+            break;
+        }
+    }
+    if reader.limit() == 0 {
+        return write_http_response(
+            stream,
+            "431 Request Header Fields Too Large",
+            "text/plain",
+            "",
+        );
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let target = parts.next().unwrap_or("");
+    let path = target.split('?').next().unwrap_or("");
+
+    if method != "GET" {
+        return write_http_response(stream, "405 Method Not Allowed", "text/plain", "");
+    }
+
+    if path != "/metrics" {
+        return write_http_response(stream, "404 Not Found", "text/plain", "");
+    }
+
+    write_http_response(
+        stream,
+        "200 OK",
+        "text/plain; version=0.0.4; charset=utf-8",
+        &registry.render(),
+    )
+}
+
+fn write_http_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+fn render_histogram(output: &mut String, name: &str, labels: &LabelSet, histogram: &Histogram) {
+    for (index, upper_bound) in LATENCY_BUCKETS_SECONDS.iter().enumerate() {
+        let _ = writeln!(
+            output,
+            "{}_bucket{} {}",
+            name,
+            format_labels(labels, Some(&upper_bound.to_string())),
+            histogram.bucket_counts[index]
+        );
+    }
+    let _ = writeln!(
+        output,
+        "{}_bucket{} {}",
+        name,
+        format_labels(labels, Some("+Inf")),
+        histogram.count
+    );
+    let _ = writeln!(
+        output,
+        "{}_sum{} {}",
+        name,
+        format_labels(labels, None),
+        histogram.sum
+    );
+    let _ = writeln!(
+        output,
+        "{}_count{} {}",
+        name,
+        format_labels(labels, None),
+        histogram.count
+    );
+}
+
+fn format_labels(labels: &LabelSet, upper_bound: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+        .collect();
+    if let Some(upper_bound) = upper_bound {
+        pairs.push(format!("le=\"{}\"", upper_bound));
+    }
+
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for character in value.chars() {
+        match character {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+fn listener_labels(listener: &str) -> LabelSet {
+    vec![("listener", listener.to_string())]
+}
+
+fn backend_labels(listener: &str, backend: &str) -> LabelSet {
+    vec![
+        ("listener", listener.to_string()),
+        ("backend", backend.to_string()),
+    ]
+}
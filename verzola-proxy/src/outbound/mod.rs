@@ -1,50 +1,929 @@
-use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::env;
 use std::fmt::{Display, Formatter};
-use std::io::{self, BufRead, BufReader, ErrorKind, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::sync::Arc;
+use std::fs;
+use std::hash::BuildHasher;
+use std::io::{self, BufReader, ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::access::ClientAcl;
+use crate::auth::{self, SaslExchange, SaslMechanism};
+use crate::base64;
+use crate::idna;
+use crate::inbound::{TlsSessionParameters, TlsUpgradeError};
+use crate::logging::{
+    self, DeliveryAttempt, DeliveryOutcome, JsonLogger, SessionDirection,
+};
+use crate::metrics::MetricsRegistry;
+use crate::smtp::{
+    is_data_terminator, split_command, write_command_line, write_multiline_reply, write_reply,
+    CommandError, EhloCapabilities, MailFrom, RcptTo, Reply,
+};
+use crate::telemetry::{
+    JsonLogSink, MetricsSink, SessionContext, SessionReport, StartTlsResult, TelemetryEvent,
+    TelemetrySink, TelemetrySinks,
+};
+use crate::transaction::{TransactionLog, TransactionOutcome, TransactionRecord, TransactionStage};
+use crate::tls_policy_map::PostfixTlsPolicyMap;
+use crate::shutdown::{
+    self, LineRead, SessionShutdown, ShutdownReport, ShutdownSignal,
+    DEFAULT_SHUTDOWN_GRACE_PERIOD,
+};
+
+pub const XFORWARD_ATTRIBUTES: [&str; 6] = ["NAME", "ADDR", "PROTO", "HELO", "SOURCE", "IDENT"];
+pub const DEFAULT_MAX_LINE_LEN: usize = 4096;
+pub const MAX_CLIENT_AUTH_FAILURES: usize = 3;
+pub const DEFAULT_CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+pub const DEFAULT_MX_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_MAX_MX_HOSTS: usize = 5;
+pub const DEFAULT_MAX_MX_ADDRESSES: usize = 10;
+pub const DEFAULT_DELIVERY_TIME_BUDGET: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutboundTlsPolicy {
+    #[default]
+    Opportunistic,
+    RequireTls,
+}
+
+impl OutboundTlsPolicy {
+    fn requires_tls(self) -> bool {
+        matches!(self, Self::RequireTls)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboundDomainTlsPolicy {
+    pub recipient_domain: String,
+    pub policy: OutboundTlsPolicy,
+}
+
+impl OutboundDomainTlsPolicy {
+    // `example.com` matches the domain itself, `*.example.com` any name one label below it and
+    // `.example.com` any subdomain at any depth.
+    pub fn new(recipient_domain: impl Into<String>, policy: OutboundTlsPolicy) -> io::Result<Self> {
+        let (kind, domain) = parse_domain_pattern(&recipient_domain.into()).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "outbound domain policy requires a valid recipient domain or pattern",
+            )
+        })?;
+
+        Ok(Self {
+            recipient_domain: kind.format(&domain),
+            policy,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DomainPatternKind {
+    Exact,
+    Wildcard,
+    Subdomains,
+}
+
+impl DomainPatternKind {
+    fn format(self, domain: &str) -> String {
+        match self {
+            Self::Exact => domain.to_string(),
+            Self::Wildcard => format!("*.{}", domain),
+            Self::Subdomains => format!(".{}", domain),
+        }
+    }
+}
+
+fn parse_domain_pattern(raw_pattern: &str) -> Option<(DomainPatternKind, String)> {
+    let pattern = raw_pattern.trim();
+    let (kind, domain) = if let Some(domain) = pattern.strip_prefix("*.") {
+        (DomainPatternKind::Wildcard, domain)
+    } else if let Some(domain) = pattern.strip_prefix('.') {
+        (DomainPatternKind::Subdomains, domain)
+    } else {
+        (DomainPatternKind::Exact, pattern)
+    };
+    if kind != DomainPatternKind::Exact && domain.starts_with(['.', '[']) {
+        return None;
+    }
+
+    normalize_domain(domain.to_string()).map(|domain| (kind, domain))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboundMxTlsPolicy {
+    pub mx_hostname: String,
+    pub policy: OutboundTlsPolicy,
+}
+
+impl OutboundMxTlsPolicy {
+    // Same pattern syntax as recipient domains, matched against `MxCandidate::exchange`.
+    pub fn new(mx_hostname: impl Into<String>, policy: OutboundTlsPolicy) -> io::Result<Self> {
+        let (kind, hostname) = parse_domain_pattern(&mx_hostname.into()).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "outbound MX policy requires a valid MX hostname or pattern",
+            )
+        })?;
+
+        Ok(Self {
+            mx_hostname: kind.format(&hostname),
+            policy,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TlsPolicyRule {
+    RecipientDomain(String),
+    MxHostname(String),
+}
+
+impl Display for TlsPolicyRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsPolicyRule::RecipientDomain(pattern) => write!(f, "domain:{}", pattern),
+            TlsPolicyRule::MxHostname(pattern) => write!(f, "mx:{}", pattern),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum DomainRuleTarget {
+    RecipientDomain,
+    MxHostname,
+    TransportRoute,
+}
+
+impl DomainRuleTarget {
+    fn field(self) -> &'static str {
+        match self {
+            Self::RecipientDomain => "per_domain_tls_policies",
+            Self::MxHostname => "per_mx_tls_policies",
+            Self::TransportRoute => "transport_routes",
+        }
+    }
+
+    fn subject(self) -> &'static str {
+        match self {
+            Self::RecipientDomain | Self::TransportRoute => "recipient domain",
+            Self::MxHostname => "MX hostname",
+        }
+    }
+
+    fn rule_noun(self) -> &'static str {
+        match self {
+            Self::RecipientDomain => "domain",
+            Self::MxHostname => "MX",
+            Self::TransportRoute => "route",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TlsPolicyRules {
+    domains: DomainPatternIndex<OutboundTlsPolicy>,
+    mx_hostnames: DomainPatternIndex<OutboundTlsPolicy>,
+}
+
+impl TlsPolicyRules {
+    pub(crate) fn build(
+        domain_policies: &[OutboundDomainTlsPolicy],
+        mx_policies: &[OutboundMxTlsPolicy],
+    ) -> io::Result<Self> {
+        let domains = DomainPatternIndex::build(
+            DomainRuleTarget::RecipientDomain,
+            domain_policies
+                .iter()
+                .map(|rule| (rule.recipient_domain.as_str(), rule.policy)),
+        )?;
+        let mx_hostnames = DomainPatternIndex::build(
+            DomainRuleTarget::MxHostname,
+            mx_policies.iter().map(|rule| (rule.mx_hostname.as_str(), rule.policy)),
+        )?;
+        Ok(Self {
+            domains,
+            mx_hostnames,
+        })
+    }
+}
+
+// Configured rules first, then an imported Postfix table. Each is attached once per listener
+// and shared by its sessions.
+#[derive(Debug, Clone)]
+struct TlsPolicySources {
+    configured: Arc<TlsPolicyRules>,
+    policy_map: Option<Arc<PostfixTlsPolicyMap>>,
+}
+
+impl TlsPolicySources {
+    // Domain rules win over MX rules, which win over the global policy. Within each kind the
+    // most specific rule from either source wins; on a tie the configured rule is kept.
+    fn resolve(
+        &self,
+        default_policy: OutboundTlsPolicy,
+        recipient_domain: &str,
+        mx_hostname: Option<&str>,
+    ) -> (OutboundTlsPolicy, Option<TlsPolicyRule>) {
+        let imported = self.policy_map.as_ref().map(|policy_map| policy_map.rules());
+        let rule_sets: Vec<&TlsPolicyRules> = std::iter::once(self.configured.as_ref())
+            .chain(imported.as_deref())
+            .collect();
+
+        let domain_rule = normalize_domain(recipient_domain.to_string()).and_then(|domain| {
+            most_specific_rule(
+                rule_sets
+                    .iter()
+                    .map(|rules| rules.domains.lookup_with_depth(&domain)),
+            )
+        });
+        if let Some((pattern, policy)) = domain_rule {
+            return (policy, Some(TlsPolicyRule::RecipientDomain(pattern.to_string())));
+        }
+
+        let mx_rule = mx_hostname
+            .and_then(|hostname| normalize_domain(hostname.to_string()))
+            .and_then(|hostname| {
+                most_specific_rule(
+                    rule_sets
+                        .iter()
+                        .map(|rules| rules.mx_hostnames.lookup_with_depth(&hostname)),
+                )
+            });
+        match mx_rule {
+            Some((pattern, policy)) => {
+                (policy, Some(TlsPolicyRule::MxHostname(pattern.to_string())))
+            }
+            None => (default_policy, None),
+        }
+    }
+}
+
+// Keeps the first of the deepest matches, so earlier sources win ties.
+fn most_specific_rule<'a>(
+    matches: impl Iterator<Item = Option<(usize, &'a str, &'a OutboundTlsPolicy)>>,
+) -> Option<(&'a str, OutboundTlsPolicy)> {
+    let mut best: Option<(usize, &str, &OutboundTlsPolicy)> = None;
+    for (depth, pattern, policy) in matches.flatten() {
+        if best.is_none_or(|(best_depth, _, _)| depth > best_depth) {
+            best = Some((depth, pattern, policy));
+        }
+    }
+    best.map(|(_, pattern, policy)| (pattern, *policy))
+}
+
+// Suffix trie over reversed domain labels, so a lookup costs one step per label no matter how
+// many rules are configured.
+#[derive(Debug)]
+struct DomainPatternIndex<T> {
+    rules: Vec<(String, T)>,
+    root: DomainTrieNode,
+}
+
+impl<T> Default for DomainPatternIndex<T> {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            root: DomainTrieNode::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct DomainTrieNode {
+    children: HashMap<String, DomainTrieNode>,
+    exact: Option<usize>,
+    wildcard: Option<usize>,
+    subdomains: Option<usize>,
+}
+
+impl<T> DomainPatternIndex<T> {
+    fn build<'a>(
+        target: DomainRuleTarget,
+        rules: impl Iterator<Item = (&'a str, T)>,
+    ) -> io::Result<Self> {
+        let mut index = Self::default();
+        for (raw_pattern, value) in rules {
+            let (kind, domain) = parse_domain_pattern(raw_pattern).ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{} contains an invalid {}: {}",
+                        target.field(),
+                        target.subject(),
+                        raw_pattern
+                    ),
+                )
+            })?;
+            let pattern = kind.format(&domain);
+
+            let node = domain
+                .rsplit('.')
+                .fold(&mut index.root, |node, label| {
+                    node.children.entry(label.to_string()).or_default()
+                });
+            let (slot, other) = match kind {
+                DomainPatternKind::Exact => (&mut node.exact, None),
+                DomainPatternKind::Wildcard => (&mut node.wildcard, node.subdomains),
+                DomainPatternKind::Subdomains => (&mut node.subdomains, node.wildcard),
+            };
+            if slot.is_some() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{} contains duplicate {} rule: {}",
+                        target.field(),
+                        target.rule_noun(),
+                        pattern
+                    ),
+                ));
+            }
+            // `*.example.com` and `.example.com` both claim every direct subdomain, and neither
+            // is more specific than the other.
+            if let Some(other) = other {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{} contains overlapping rules: {} and {}",
+                        target.field(),
+                        index.rules[other].0,
+                        pattern
+                    ),
+                ));
+            }
+            *slot = Some(index.rules.len());
+            index.rules.push((pattern, value));
+        }
+        Ok(index)
+    }
+
+    // The deepest matching rule wins; an exact rule is deeper than any pattern.
+    fn lookup(&self, domain: &str) -> Option<(&str, &T)> {
+        self.lookup_with_depth(domain).map(|(_, pattern, value)| (pattern, value))
+    }
+
+    // Also returns how many labels the matched rule names, so matches from separate indexes
+    // can be compared.
+    fn lookup_with_depth(&self, domain: &str) -> Option<(usize, &str, &T)> {
+        let label_count = domain.split('.').count();
+        let mut node = &self.root;
+        let mut matched = None;
+        for (depth, label) in domain.rsplit('.').enumerate() {
+            let Some(child) = node.children.get(label) else {
+                break;
+            };
+            node = child;
+            let candidate = match label_count - depth - 1 {
+                0 => node.exact,
+                1 => node.wildcard.or(node.subdomains),
+                _ => node.subdomains,
+            };
+            if let Some(index) = candidate {
+                matched = Some((depth + 1, index));
+            }
+        }
+        matched.map(|(depth, index)| {
+            let (pattern, value) = &self.rules[index];
+            (depth, pattern.as_str(), value)
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutboundNextHop {
+    // Connect to the host directly; no MX lookup is made for the recipient domain.
+    Static { host: String, port: u16 },
+    // One of `OutboundListenerConfig::smarthosts`, by name.
+    Smarthost(String),
+}
+
+impl OutboundNextHop {
+    // `[host]` or `[host]:port` as in a Postfix transport map. IPv6 addresses may carry the
+    // Postfix `ipv6:` prefix.
+    pub fn parse(value: &str) -> io::Result<Self> {
+        let (host, port) = parse_bracketed_host(value)?;
+        Ok(Self::Static { host, port })
+    }
+}
+
+fn parse_bracketed_host(value: &str) -> io::Result<(String, u16)> {
+    let invalid = || {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("address must be [host] or [host]:port: {}", value),
+        )
+    };
+    let (host, port) = value
+        .trim()
+        .strip_prefix('[')
+        .and_then(|rest| rest.rsplit_once(']'))
+        .ok_or_else(invalid)?;
+    let port = match port {
+        "" => 25,
+        port => port
+            .strip_prefix(':')
+            .and_then(|port| port.parse::<u16>().ok())
+            .filter(|port| *port != 0)
+            .ok_or_else(invalid)?,
+    };
+    let host = normalize_next_hop_host(host).ok_or_else(invalid)?;
+    Ok((host, port))
+}
+
+impl Display for OutboundNextHop {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutboundNextHop::Static { host, port } => write!(f, "[{}]:{}", host, port),
+            OutboundNextHop::Smarthost(name) => write!(f, "smarthost:{}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboundTransportRoute {
+    pub recipient_domain: String,
+    pub next_hop: OutboundNextHop,
+}
+
+impl OutboundTransportRoute {
+    // Same pattern syntax as `OutboundDomainTlsPolicy`.
+    pub fn new(recipient_domain: impl Into<String>, next_hop: OutboundNextHop) -> io::Result<Self> {
+        let (kind, domain) = parse_domain_pattern(&recipient_domain.into()).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "transport route requires a valid recipient domain or pattern",
+            )
+        })?;
+
+        Ok(Self {
+            recipient_domain: kind.format(&domain),
+            next_hop,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboundSmarthost {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub credentials: Option<SmarthostCredentials>,
+}
+
+impl OutboundSmarthost {
+    pub fn new(name: impl Into<String>, address: &str) -> io::Result<Self> {
+        let (host, port) = parse_bracketed_host(address)?;
+        Ok(Self {
+            name: name.into(),
+            host,
+            port,
+            credentials: None,
+        })
+    }
+
+    pub fn with_credentials(mut self, credentials: SmarthostCredentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+}
+
+// Credentials are only ever sent after STARTTLS; secrets are read when a connection
+// authenticates, so a rotated password file or token is picked up without a restart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SmarthostCredentials {
+    // AUTH PLAIN, or AUTH LOGIN when the smarthost does not offer PLAIN.
+    Password {
+        username: String,
+        password: SecretSource,
+    },
+    // AUTH XOAUTH2 with an OAuth 2.0 bearer token.
+    OAuth2 {
+        username: String,
+        token: SecretSource,
+    },
+}
+
+impl SmarthostCredentials {
+    fn username(&self) -> &str {
+        match self {
+            Self::Password { username, .. } | Self::OAuth2 { username, .. } => username,
+        }
+    }
+
+    fn secret(&self) -> &SecretSource {
+        match self {
+            Self::Password { password, .. } => password,
+            Self::OAuth2 { token, .. } => token,
+        }
+    }
+
+    fn mechanisms(&self) -> &'static [RelayAuthMechanism] {
+        match self {
+            Self::Password { .. } => &[RelayAuthMechanism::Plain, RelayAuthMechanism::Login],
+            Self::OAuth2 { .. } => &[RelayAuthMechanism::XOAuth2],
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum SecretSource {
+    Value(String),
+    // The whole file, without trailing whitespace.
+    File(PathBuf),
+    Env(String),
+}
+
+impl SecretSource {
+    fn is_empty(&self) -> bool {
+        match self {
+            Self::Value(value) => value.is_empty(),
+            Self::File(path) => path.as_os_str().is_empty(),
+            Self::Env(name) => name.is_empty(),
+        }
+    }
+
+    fn load(&self) -> io::Result<String> {
+        let secret = match self {
+            Self::Value(value) => value.clone(),
+            Self::File(path) => fs::read_to_string(path)
+                .map_err(|error| {
+                    io::Error::new(
+                        error.kind(),
+                        format!("cannot read secret file {}: {}", path.display(), error),
+                    )
+                })?
+                .trim_end()
+                .to_string(),
+            Self::Env(name) => env::var(name).map_err(|_| {
+                io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("environment variable {} is not set", name),
+                )
+            })?,
+        };
+        if secret.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("secret from {:?} is empty", self),
+            ));
+        }
+        Ok(secret)
+    }
+}
+
+// File paths and variable names are shown; inline values are not.
+impl std::fmt::Debug for SecretSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Value(_) => f.write_str("Value(<redacted>)"),
+            Self::File(path) => f.debug_tuple("File").field(path).finish(),
+            Self::Env(name) => f.debug_tuple("Env").field(name).finish(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayAuthMechanism {
+    Plain,
+    Login,
+    XOAuth2,
+}
+
+impl RelayAuthMechanism {
+    pub fn label(self) -> &'static str {
+        match self {
+            RelayAuthMechanism::Plain => "PLAIN",
+            RelayAuthMechanism::Login => "LOGIN",
+            RelayAuthMechanism::XOAuth2 => "XOAUTH2",
+        }
+    }
+}
+
+// Carries the enhanced status an AUTH failure toward a smarthost defers with. Such failures
+// are always 4.7.x: a rejected password must not bounce mail that a fixed config would deliver.
+#[derive(Debug)]
+struct RelayAuthError {
+    status: &'static str,
+    message: String,
+}
+
+impl Display for RelayAuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for RelayAuthError {}
+
+fn relay_auth_error(status: &'static str, message: String) -> io::Error {
+    io::Error::new(ErrorKind::PermissionDenied, RelayAuthError { status, message })
+}
+
+fn relay_auth_status(error: &io::Error) -> Option<&'static str> {
+    error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<RelayAuthError>())
+        .map(|auth_error| auth_error.status)
+}
+
+// Ends the walk down the MX list before every candidate was tried.
+#[derive(Debug)]
+enum MxFallbackEnd {
+    // A 5xx reply to MAIL is the answer for the domain, not for one host.
+    PermanentReply(String),
+    BudgetExhausted(String),
+}
+
+impl Display for MxFallbackEnd {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PermanentReply(message) | Self::BudgetExhausted(message) => {
+                f.write_str(message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MxFallbackEnd {}
+
+fn mx_fallback_end(error: &io::Error) -> Option<&MxFallbackEnd> {
+    error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<MxFallbackEnd>())
+}
+
+// A STARTTLS exchange that was attempted and failed, as opposed to a peer that never offered it.
+#[derive(Debug)]
+struct StartTlsFailure(String);
+
+impl Display for StartTlsFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for StartTlsFailure {}
+
+fn starttls_failure(error: &io::Error) -> Option<&StartTlsFailure> {
+    error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<StartTlsFailure>())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryRoute {
+    Mx,
+    Transport {
+        rule: String,
+        next_hop: OutboundNextHop,
+    },
+}
+
+impl Display for DeliveryRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryRoute::Mx => f.write_str("mx"),
+            DeliveryRoute::Transport { rule, next_hop } => {
+                write!(f, "transport:{}={}", rule, next_hop)
+            }
+        }
+    }
+}
+
+// Transport routes are matched before the resolver is asked, with the same pattern rules as
+// TLS policies. Next hops and smarthosts are stored normalized.
+#[derive(Debug, Default)]
+struct TransportMap {
+    routes: DomainPatternIndex<OutboundNextHop>,
+    smarthosts: HashMap<String, OutboundSmarthost>,
+}
+
+impl TransportMap {
+    fn build(
+        routes: &[OutboundTransportRoute],
+        smarthosts: &[OutboundSmarthost],
+    ) -> io::Result<Self> {
+        let mut normalized_smarthosts = HashMap::new();
+        for smarthost in smarthosts {
+            let valid_name = !smarthost.name.is_empty()
+                && smarthost
+                    .name
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || b"._-".contains(&byte));
+            if !valid_name {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("smarthosts contains an invalid name: {:?}", smarthost.name),
+                ));
+            }
+            let Some(host) = normalize_next_hop_host(&smarthost.host) else {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "smarthost {} has an invalid host: {}",
+                        smarthost.name, smarthost.host
+                    ),
+                ));
+            };
+            if smarthost.port == 0 {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("smarthost {} requires a non-zero port", smarthost.name),
+                ));
+            }
+            if let Some(credentials) = &smarthost.credentials {
+                if credentials.username().is_empty() || credentials.secret().is_empty() {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("smarthost {} credentials must not be empty", smarthost.name),
+                    ));
+                }
+            }
+
+            let normalized = OutboundSmarthost {
+                host,
+                ..smarthost.clone()
+            };
+            if normalized_smarthosts
+                .insert(smarthost.name.clone(), normalized)
+                .is_some()
+            {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("smarthosts contains duplicate name: {}", smarthost.name),
+                ));
+            }
+        }
 
-pub const DEFAULT_MAX_LINE_LEN: usize = 4096;
+        let mut next_hops = Vec::with_capacity(routes.len());
+        for route in routes {
+            let next_hop = match &route.next_hop {
+                OutboundNextHop::Static { host, port } => {
+                    let host = normalize_next_hop_host(host).filter(|_| *port != 0);
+                    let Some(host) = host else {
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidInput,
+                            format!(
+                                "transport_routes contains an invalid next hop for {}: {}",
+                                route.recipient_domain, route.next_hop
+                            ),
+                        ));
+                    };
+                    OutboundNextHop::Static { host, port: *port }
+                }
+                OutboundNextHop::Smarthost(name) => {
+                    if !normalized_smarthosts.contains_key(name) {
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidInput,
+                            format!(
+                                "transport_routes entry {} names unknown smarthost: {}",
+                                route.recipient_domain, name
+                            ),
+                        ));
+                    }
+                    route.next_hop.clone()
+                }
+            };
+            next_hops.push((route.recipient_domain.as_str(), next_hop));
+        }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum OutboundTlsPolicy {
-    Opportunistic,
-    RequireTls,
+        Ok(Self {
+            routes: DomainPatternIndex::build(
+                DomainRuleTarget::TransportRoute,
+                next_hops.into_iter(),
+            )?,
+            smarthosts: normalized_smarthosts,
+        })
+    }
+
+    fn route(&self, recipient_domain: &str) -> Option<DeliveryRoute> {
+        let domain = normalize_domain(recipient_domain.to_string())?;
+        self.routes
+            .lookup(&domain)
+            .map(|(rule, next_hop)| DeliveryRoute::Transport {
+                rule: rule.to_string(),
+                next_hop: next_hop.clone(),
+            })
+    }
+
+    fn credentials(&self, next_hop: &OutboundNextHop) -> Option<&SmarthostCredentials> {
+        match next_hop {
+            OutboundNextHop::Smarthost(name) => self.smarthosts.get(name)?.credentials.as_ref(),
+            OutboundNextHop::Static { .. } => None,
+        }
+    }
+
+    // The next hop is a single candidate holding every address it resolved to.
+    fn candidates(&self, next_hop: &OutboundNextHop) -> io::Result<Vec<MxCandidate>> {
+        let (host, port) = match next_hop {
+            OutboundNextHop::Static { host, port } => (host.as_str(), *port),
+            OutboundNextHop::Smarthost(name) => {
+                let smarthost = self.smarthosts.get(name).ok_or_else(|| {
+                    io::Error::new(ErrorKind::NotFound, format!("unknown smarthost {}", name))
+                })?;
+                (smarthost.host.as_str(), smarthost.port)
+            }
+        };
+
+        let addresses: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) => (host, port)
+                .to_socket_addrs()
+                .map_err(|error| {
+                    io::Error::new(
+                        ErrorKind::WouldBlock,
+                        format!("next hop {} did not resolve: {}", next_hop, error),
+                    )
+                })?
+                .collect(),
+        };
+        if addresses.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("next hop {} has no addresses", next_hop),
+            ));
+        }
+
+        Ok(vec![MxCandidate::with_addresses(0, host, addresses)?])
+    }
 }
 
-impl OutboundTlsPolicy {
-    fn requires_tls(self) -> bool {
-        matches!(self, Self::RequireTls)
+// Client side of outbound STARTTLS, run on the stream once the remote MX answered 220.
+pub trait OutboundTlsUpgrader: Send + Sync + 'static {
+    fn upgrade(&self, stream: &mut TcpStream, exchange: &str) -> Result<(), TlsUpgradeError>;
+
+    // `None` means the session was left in plaintext, and smarthost credentials are refused.
+    fn session_parameters(&self, _stream: &TcpStream) -> Option<TlsSessionParameters> {
+        None
     }
 }
 
-impl Default for OutboundTlsPolicy {
-    fn default() -> Self {
-        Self::Opportunistic
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopOutboundTlsUpgrader;
+
+impl OutboundTlsUpgrader for NoopOutboundTlsUpgrader {
+    fn upgrade(&self, _stream: &mut TcpStream, _exchange: &str) -> Result<(), TlsUpgradeError> {
+        Ok(())
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct OutboundDomainTlsPolicy {
-    pub recipient_domain: String,
-    pub policy: OutboundTlsPolicy,
+// Per listener, shared by its sessions: TLS policy rules, the transport map and the STARTTLS
+// upgrader.
+#[derive(Clone)]
+struct OutboundRouting {
+    tls_policies: TlsPolicySources,
+    transport_map: Arc<TransportMap>,
+    tls_upgrader: Arc<dyn OutboundTlsUpgrader>,
 }
 
-impl OutboundDomainTlsPolicy {
-    pub fn new(recipient_domain: impl Into<String>, policy: OutboundTlsPolicy) -> io::Result<Self> {
-        let recipient_domain = normalize_domain(recipient_domain.into()).ok_or_else(|| {
-            io::Error::new(
-                ErrorKind::InvalidInput,
-                "outbound domain policy requires a non-empty recipient domain",
-            )
-        })?;
+#[derive(Clone, Default, PartialEq, Eq)]
+pub enum OutboundClientAuth {
+    #[default]
+    None,
+    Sasl {
+        username: String,
+        password: String,
+    },
+    SharedSecret(String),
+}
 
-        Ok(Self {
-            recipient_domain,
-            policy,
-        })
+impl OutboundClientAuth {
+    fn is_required(&self) -> bool {
+        !matches!(self, Self::None)
+    }
+
+    fn accepts_sasl(&self, username: &str, password: &str) -> bool {
+        match self {
+            Self::Sasl {
+                username: expected_username,
+                password: expected_password,
+            } => {
+                let username_matches =
+                    auth::constant_time_eq(username.as_bytes(), expected_username.as_bytes());
+                let password_matches =
+                    auth::constant_time_eq(password.as_bytes(), expected_password.as_bytes());
+                username_matches && password_matches
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Debug for OutboundClientAuth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => f.write_str("None"),
+            Self::Sasl { username, .. } => f
+                .debug_struct("Sasl")
+                .field("username", username)
+                .field("password", &"<redacted>")
+                .finish(),
+            Self::SharedSecret(_) => f.write_str("SharedSecret(<redacted>)"),
+        }
     }
 }
 
@@ -52,9 +931,21 @@ impl OutboundDomainTlsPolicy {
 pub struct OutboundListenerConfig {
     pub bind_addr: SocketAddr,
     pub banner_host: String,
+    pub client_acl: Option<ClientAcl>,
+    pub client_auth: OutboundClientAuth,
     pub outbound_tls_policy: OutboundTlsPolicy,
     pub per_domain_tls_policies: Vec<OutboundDomainTlsPolicy>,
+    pub per_mx_tls_policies: Vec<OutboundMxTlsPolicy>,
+    pub transport_routes: Vec<OutboundTransportRoute>,
+    pub smarthosts: Vec<OutboundSmarthost>,
+    pub address_family: AddressFamilyPreference,
+    pub connection_attempt_delay: Duration,
+    pub mx_connect_timeout: Duration,
+    pub max_mx_hosts: usize,
+    pub max_mx_addresses: usize,
+    pub delivery_time_budget: Duration,
     pub max_line_len: usize,
+    pub shutdown_grace_period: Duration,
 }
 
 impl OutboundListenerConfig {
@@ -73,28 +964,82 @@ impl OutboundListenerConfig {
             ));
         }
 
-        let mut seen_domains = HashSet::new();
-        for rule in &self.per_domain_tls_policies {
-            let normalized_domain = normalize_domain(rule.recipient_domain.clone()).ok_or_else(|| {
-                io::Error::new(
-                    ErrorKind::InvalidInput,
-                    "per_domain_tls_policies contains an empty recipient domain",
-                )
-            })?;
+        // Without an explicit ACL only loopback clients are served, which is useless
+        // (and usually a deployment mistake) on any other bind address.
+        if self.client_acl.is_none() && !self.bind_addr.ip().is_loopback() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "outbound listener bound to non-loopback address {} requires client_acl",
+                    self.bind_addr
+                ),
+            ));
+        }
 
-            if !seen_domains.insert(normalized_domain.clone()) {
+        match &self.client_auth {
+            OutboundClientAuth::Sasl { username, password }
+                if username.is_empty() || password.is_empty() =>
+            {
                 return Err(io::Error::new(
                     ErrorKind::InvalidInput,
-                    format!(
-                        "per_domain_tls_policies contains duplicate domain rule: {}",
-                        normalized_domain
-                    ),
+                    "client_auth SASL credentials must not be empty",
+                ));
+            }
+            OutboundClientAuth::SharedSecret(secret) if secret.len() < 16 => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "client_auth shared secret must be at least 16 bytes",
                 ));
             }
+            _ => {}
+        }
+
+        // RFC 8305 section 5: at least 10 ms (100 ms recommended) and at most 2 seconds.
+        if !(Duration::from_millis(10)..=Duration::from_secs(2))
+            .contains(&self.connection_attempt_delay)
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "connection_attempt_delay must be between 10 ms and 2 s",
+            ));
+        }
+
+        if self.mx_connect_timeout.is_zero() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "mx_connect_timeout must be greater than zero",
+            ));
+        }
+
+        if self.max_mx_hosts == 0 || self.max_mx_addresses == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "max_mx_hosts and max_mx_addresses must be greater than zero",
+            ));
         }
 
+        if self.delivery_time_budget.is_zero() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "delivery_time_budget must be greater than zero",
+            ));
+        }
+
+        TlsPolicyRules::build(&self.per_domain_tls_policies, &self.per_mx_tls_policies)?;
+        TransportMap::build(&self.transport_routes, &self.smarthosts)?;
+
         Ok(())
     }
+
+    fn permits_client(&self, client_addr: Option<SocketAddr>) -> bool {
+        let Some(client_addr) = client_addr else {
+            return false;
+        };
+        match &self.client_acl {
+            Some(acl) => acl.permits(client_addr.ip()),
+            None => ClientAcl::loopback().permits(client_addr.ip()),
+        }
+    }
 }
 
 impl Default for OutboundListenerConfig {
@@ -104,18 +1049,31 @@ impl Default for OutboundListenerConfig {
                 .parse()
                 .expect("default outbound socket address must parse"),
             banner_host: "localhost".to_string(),
+            client_acl: None,
+            client_auth: OutboundClientAuth::default(),
             outbound_tls_policy: OutboundTlsPolicy::default(),
             per_domain_tls_policies: Vec::new(),
+            per_mx_tls_policies: Vec::new(),
+            transport_routes: Vec::new(),
+            smarthosts: Vec::new(),
+            address_family: AddressFamilyPreference::default(),
+            connection_attempt_delay: DEFAULT_CONNECTION_ATTEMPT_DELAY,
+            mx_connect_timeout: DEFAULT_MX_CONNECT_TIMEOUT,
+            max_mx_hosts: DEFAULT_MAX_MX_HOSTS,
+            max_mx_addresses: DEFAULT_MAX_MX_ADDRESSES,
+            delivery_time_budget: DEFAULT_DELIVERY_TIME_BUDGET,
             max_line_len: DEFAULT_MAX_LINE_LEN,
+            shutdown_grace_period: DEFAULT_SHUTDOWN_GRACE_PERIOD,
         }
     }
 }
 
+// One MX host with every address it resolved to, in resolver order.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MxCandidate {
     pub preference: u16,
     pub exchange: String,
-    pub address: SocketAddr,
+    pub addresses: Vec<SocketAddr>,
 }
 
 impl MxCandidate {
@@ -123,6 +1081,14 @@ impl MxCandidate {
         preference: u16,
         exchange: impl Into<String>,
         address: SocketAddr,
+    ) -> io::Result<Self> {
+        Self::with_addresses(preference, exchange, vec![address])
+    }
+
+    pub fn with_addresses(
+        preference: u16,
+        exchange: impl Into<String>,
+        addresses: Vec<SocketAddr>,
     ) -> io::Result<Self> {
         let exchange = exchange.into();
         if exchange.trim().is_empty() {
@@ -131,15 +1097,45 @@ impl MxCandidate {
                 "mx exchange must not be empty",
             ));
         }
+        if addresses.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("mx exchange {} has no addresses", exchange),
+            ));
+        }
 
         Ok(Self {
             preference,
             exchange,
-            address,
+            addresses,
         })
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamilyPreference {
+    Ipv4,
+    Ipv6,
+    // Both families, alternating, starting with the family of the first address (RFC 8305).
+    #[default]
+    Both,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MxAddressOutcome {
+    Connected,
+    Failed(String),
+    // Still connecting when another address of the same host won the race.
+    Abandoned,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MxAddressAttempt {
+    pub exchange: String,
+    pub address: SocketAddr,
+    pub outcome: MxAddressOutcome,
+}
+
 #[derive(Debug, Clone)]
 pub enum MxResolutionError {
     Temporary(String),
@@ -157,6 +1153,15 @@ pub trait MxResolver: Send + Sync + 'static {
     fn resolve(&self, recipient_domain: &str) -> Result<Vec<MxCandidate>, MxResolutionError>;
 }
 
+impl<R> MxResolver for Arc<R>
+where
+    R: MxResolver,
+{
+    fn resolve(&self, recipient_domain: &str) -> Result<Vec<MxCandidate>, MxResolutionError> {
+        self.as_ref().resolve(recipient_domain)
+    }
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 pub struct NoopMxResolver;
 
@@ -170,6 +1175,10 @@ impl MxResolver for NoopMxResolver {
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct OutboundSessionSummary {
+    pub session_id: String,
+    pub listener: Option<String>,
+    pub client_addr: Option<SocketAddr>,
+    pub helo_name: Option<String>,
     pub command_count: usize,
     pub protocol_errors: usize,
     pub temporary_failures: usize,
@@ -179,9 +1188,95 @@ pub struct OutboundSessionSummary {
     pub selected_mx: Option<String>,
     pub selected_recipient_domain: Option<String>,
     pub effective_tls_policy: Option<OutboundTlsPolicy>,
+    pub tls_policy_rule: Option<TlsPolicyRule>,
+    pub delivery_route: Option<DeliveryRoute>,
+    pub relay_auth_mechanism: Option<RelayAuthMechanism>,
+    pub relay_auth_failures: usize,
+    pub delivery_budget_exhaustions: usize,
     pub tls_negotiated: bool,
+    pub tls_parameters: Option<TlsSessionParameters>,
+    pub starttls_attempts: usize,
+    pub tls_upgrade_failures: usize,
     pub opportunistic_tls_fallbacks: usize,
     pub policy_deferred_failures: usize,
+    pub client_rejected: bool,
+    pub client_authenticated: bool,
+    pub auth_failures: usize,
+    pub closed_by_shutdown: bool,
+    pub session_duration: Duration,
+    pub mx_connect_durations: Vec<Duration>,
+    pub mx_address_attempts: Vec<MxAddressAttempt>,
+    pub transactions: Vec<TransactionRecord>,
+}
+
+impl OutboundSessionSummary {
+    pub fn telemetry(&self) -> OutboundSessionTelemetry {
+        OutboundSessionTelemetry {
+            protocol_errors: self.protocol_errors,
+            temporary_failures: self.temporary_failures,
+            relay_auth_failures: self.relay_auth_failures,
+            delivery_budget_exhaustions: self.delivery_budget_exhaustions,
+            policy_deferred_failures: self.policy_deferred_failures,
+            starttls_attempts: self.starttls_attempts,
+            tls_upgrade_failures: self.tls_upgrade_failures,
+            opportunistic_tls_fallbacks: self.opportunistic_tls_fallbacks,
+            auth_failures: self.auth_failures,
+        }
+    }
+}
+
+// The summary counters, folded from the session's events like inbound `SessionTelemetry`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutboundSessionTelemetry {
+    pub protocol_errors: usize,
+    pub temporary_failures: usize,
+    pub relay_auth_failures: usize,
+    pub delivery_budget_exhaustions: usize,
+    pub policy_deferred_failures: usize,
+    pub starttls_attempts: usize,
+    pub tls_upgrade_failures: usize,
+    pub opportunistic_tls_fallbacks: usize,
+    pub auth_failures: usize,
+}
+
+impl OutboundSessionTelemetry {
+    pub fn observe(&mut self, event: &TelemetryEvent) {
+        match event {
+            TelemetryEvent::ProtocolError { .. } => self.protocol_errors += 1,
+            // A fallback always follows the `Failed` event of the attempt it recovers from.
+            TelemetryEvent::StartTls(StartTlsResult::PlaintextFallback) => {
+                self.opportunistic_tls_fallbacks += 1;
+            }
+            TelemetryEvent::StartTls(result) => {
+                self.starttls_attempts += 1;
+                if !matches!(result, StartTlsResult::Negotiated(_)) {
+                    self.tls_upgrade_failures += 1;
+                }
+            }
+            TelemetryEvent::Recipient { reply_code, .. } if reply_code / 100 == 4 => {
+                self.temporary_failures += 1;
+            }
+            // A delivery attempt without a reply code follows a `RelayFailure`.
+            TelemetryEvent::Data(attempt)
+                if attempt.reply_code.is_some_and(|code| code / 100 != 2) =>
+            {
+                self.temporary_failures += 1;
+            }
+            TelemetryEvent::RelayFailure { stage, .. } => {
+                self.temporary_failures += 1;
+                match *stage {
+                    "relay-auth" => self.relay_auth_failures += 1,
+                    "delivery-budget" => self.delivery_budget_exhaustions += 1,
+                    "tls-policy" => self.policy_deferred_failures += 1,
+                    _ => {}
+                }
+            }
+            TelemetryEvent::Auth {
+                succeeded: false, ..
+            } => self.auth_failures += 1,
+            _ => {}
+        }
+    }
 }
 
 pub struct OutboundListener<R>
@@ -190,7 +1285,9 @@ where
 {
     listener: TcpListener,
     config: OutboundListenerConfig,
+    routing: OutboundRouting,
     resolver: Arc<R>,
+    telemetry: TelemetrySinks,
 }
 
 impl<R> OutboundListener<R>
@@ -199,21 +1296,77 @@ where
 {
     pub fn bind(config: OutboundListenerConfig, resolver: R) -> io::Result<Self> {
         config.validate()?;
+        let routing = OutboundRouting {
+            tls_policies: TlsPolicySources {
+                configured: Arc::new(TlsPolicyRules::build(
+                    &config.per_domain_tls_policies,
+                    &config.per_mx_tls_policies,
+                )?),
+                policy_map: None,
+            },
+            transport_map: Arc::new(TransportMap::build(
+                &config.transport_routes,
+                &config.smarthosts,
+            )?),
+            tls_upgrader: Arc::new(NoopOutboundTlsUpgrader),
+        };
         let listener = TcpListener::bind(config.bind_addr)?;
         Ok(Self {
             listener,
             config,
+            routing,
             resolver: Arc::new(resolver),
+            telemetry: TelemetrySinks::default(),
         })
     }
 
+    pub fn with_metrics(
+        self,
+        registry: Arc<MetricsRegistry>,
+        listener_name: impl Into<String>,
+    ) -> Self {
+        self.with_telemetry_sink(Arc::new(MetricsSink::new(registry, listener_name)))
+    }
+
+    pub fn with_json_logger(self, logger: Arc<JsonLogger>) -> Self {
+        self.with_telemetry_sink(Arc::new(JsonLogSink::new(logger)))
+    }
+
+    pub fn with_telemetry_sink(mut self, sink: Arc<dyn TelemetrySink>) -> Self {
+        self.telemetry.push(sink);
+        self
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.telemetry.set_listener(name.into());
+        self
+    }
+
+    pub fn with_tls_upgrader(mut self, tls_upgrader: impl OutboundTlsUpgrader) -> Self {
+        self.routing.tls_upgrader = Arc::new(tls_upgrader);
+        self
+    }
+
+    // Rules from the map apply after the configured ones, and follow its `reload()`.
+    pub fn with_tls_policy_map(mut self, policy_map: Arc<PostfixTlsPolicyMap>) -> Self {
+        self.routing.tls_policies.policy_map = Some(policy_map);
+        self
+    }
+
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         self.listener.local_addr()
     }
 
     pub fn serve_one(&self) -> io::Result<OutboundSessionSummary> {
         let (mut stream, _) = self.listener.accept()?;
-        handle_session(&mut stream, &self.config, self.resolver.as_ref())
+        handle_session(
+            &mut stream,
+            &self.config,
+            &self.routing,
+            self.resolver.as_ref(),
+            None,
+            &self.telemetry,
+        )
     }
 
     pub fn serve_n(&self, session_count: usize) -> io::Result<Vec<OutboundSessionSummary>> {
@@ -222,9 +1375,18 @@ where
         for _ in 0..session_count {
             let (mut stream, _) = self.listener.accept()?;
             let config = self.config.clone();
+            let routing = self.routing.clone();
             let resolver = Arc::clone(&self.resolver);
+            let telemetry = self.telemetry.clone();
             handles.push(thread::spawn(move || {
-                handle_session(&mut stream, &config, resolver.as_ref())
+                handle_session(
+                    &mut stream,
+                    &config,
+                    &routing,
+                    resolver.as_ref(),
+                    None,
+                    &telemetry,
+                )
             }));
         }
 
@@ -232,38 +1394,176 @@ where
         for handle in handles {
             let summary = handle
                 .join()
-                .map_err(|_| io::Error::new(ErrorKind::Other, "session worker thread panicked"))??;
+                .map_err(|_| io::Error::other("session worker thread panicked"))??;
             summaries.push(summary);
         }
 
         Ok(summaries)
     }
+
+    pub fn serve_until_shutdown(&self, shutdown: &ShutdownSignal) -> io::Result<ShutdownReport> {
+        shutdown::serve_until_shutdown(&self.listener, shutdown, |mut stream| {
+            let config = self.config.clone();
+            let routing = self.routing.clone();
+            let resolver = Arc::clone(&self.resolver);
+            let telemetry = self.telemetry.clone();
+            let shutdown = shutdown.clone();
+            thread::spawn(move || {
+                let session_shutdown =
+                    SessionShutdown::new(&shutdown, config.shutdown_grace_period);
+                let summary = handle_session(
+                    &mut stream,
+                    &config,
+                    &routing,
+                    resolver.as_ref(),
+                    Some(&session_shutdown),
+                    &telemetry,
+                )?;
+                Ok(summary.closed_by_shutdown)
+            })
+        })
+    }
 }
 
 #[derive(Debug, Default)]
 struct SessionState {
+    context: SessionContext,
+    helo_name: Option<String>,
     ehlo_seen: bool,
     command_count: usize,
-    protocol_errors: usize,
-    temporary_failures: usize,
+    telemetry: OutboundSessionTelemetry,
     resolver_lookups: usize,
     mx_candidates_attempted: usize,
     remote_session_established: bool,
     selected_mx: Option<String>,
     selected_recipient_domain: Option<String>,
     effective_tls_policy: Option<OutboundTlsPolicy>,
+    tls_policy_rule: Option<TlsPolicyRule>,
+    delivery_route: Option<DeliveryRoute>,
+    relay_auth_mechanism: Option<RelayAuthMechanism>,
     tls_negotiated: bool,
-    opportunistic_tls_fallbacks: usize,
-    policy_deferred_failures: usize,
+    tls_parameters: Option<TlsSessionParameters>,
+    client_rejected: bool,
+    client_authenticated: bool,
+    closed_by_shutdown: bool,
+    mx_connect_durations: Vec<Duration>,
+    mx_address_attempts: Vec<MxAddressAttempt>,
     staged_mail_from: Option<String>,
     recipient_domain: Option<String>,
     recipient_count: usize,
+    envelope_recipients: Vec<String>,
+    transactions: TransactionLog,
+    xforward_ident: Option<String>,
+    postfix_queue_id: Option<String>,
 }
 
-#[derive(Debug)]
-struct SmtpReply {
-    code: u16,
-    lines: Vec<String>,
+impl SessionState {
+    fn emit(&mut self, telemetry: &TelemetrySinks, event: TelemetryEvent) {
+        self.telemetry.observe(&event);
+        telemetry.emit(&self.context, &event);
+    }
+
+    fn protocol_error(
+        &mut self,
+        stream: &mut TcpStream,
+        telemetry: &TelemetrySinks,
+        reply_code: u16,
+        message: &str,
+    ) -> io::Result<()> {
+        self.emit(telemetry, TelemetryEvent::ProtocolError { reply_code });
+        write_reply(stream, reply_code, message)
+    }
+
+    fn finish(self, telemetry: &TelemetrySinks, started_at: Instant) -> OutboundSessionSummary {
+        let summary = OutboundSessionSummary {
+            session_id: self.context.session_id.clone(),
+            listener: self.context.listener.clone(),
+            client_addr: self.context.client_addr,
+            helo_name: self.helo_name,
+            command_count: self.command_count,
+            protocol_errors: self.telemetry.protocol_errors,
+            temporary_failures: self.telemetry.temporary_failures,
+            resolver_lookups: self.resolver_lookups,
+            mx_candidates_attempted: self.mx_candidates_attempted,
+            remote_session_established: self.remote_session_established,
+            selected_mx: self.selected_mx,
+            selected_recipient_domain: self.selected_recipient_domain,
+            effective_tls_policy: self.effective_tls_policy,
+            tls_policy_rule: self.tls_policy_rule,
+            delivery_route: self.delivery_route,
+            relay_auth_mechanism: self.relay_auth_mechanism,
+            relay_auth_failures: self.telemetry.relay_auth_failures,
+            delivery_budget_exhaustions: self.telemetry.delivery_budget_exhaustions,
+            tls_negotiated: self.tls_negotiated,
+            tls_parameters: self.tls_parameters,
+            starttls_attempts: self.telemetry.starttls_attempts,
+            tls_upgrade_failures: self.telemetry.tls_upgrade_failures,
+            opportunistic_tls_fallbacks: self.telemetry.opportunistic_tls_fallbacks,
+            policy_deferred_failures: self.telemetry.policy_deferred_failures,
+            client_rejected: self.client_rejected,
+            client_authenticated: self.client_authenticated,
+            auth_failures: self.telemetry.auth_failures,
+            closed_by_shutdown: self.closed_by_shutdown,
+            session_duration: started_at.elapsed(),
+            mx_connect_durations: self.mx_connect_durations,
+            mx_address_attempts: self.mx_address_attempts,
+            transactions: self.transactions.into_records(),
+        };
+        telemetry.emit(
+            &self.context,
+            &TelemetryEvent::Disconnected(Box::new(SessionReport::Outbound(summary.clone()))),
+        );
+
+        summary
+    }
+
+    fn relay_failed(&mut self, telemetry: &TelemetrySinks, stage: &'static str, reason: String) {
+        self.emit(telemetry, TelemetryEvent::RelayFailure { stage, reason });
+    }
+
+    fn delivery_attempt(
+        &self,
+        reply_code: Option<u16>,
+        failure_reason: Option<String>,
+    ) -> DeliveryAttempt {
+        let outcome = match reply_code {
+            Some(code) if code / 100 == 2 => DeliveryOutcome::Accepted,
+            Some(code) if code / 100 == 5 => DeliveryOutcome::Rejected,
+            _ => DeliveryOutcome::Deferred,
+        };
+        DeliveryAttempt {
+            session_id: self.context.session_id.clone(),
+            direction: SessionDirection::Outbound,
+            listener: self.context.listener.clone(),
+            client_addr: self.context.client_addr,
+            helo_name: self.helo_name.clone(),
+            sender: self
+                .staged_mail_from
+                .as_deref()
+                .and_then(|mail_command| MailFrom::parse(split_command(mail_command).1).ok())
+                .map(|mail| mail.address()),
+            recipients: self.envelope_recipients.clone(),
+            next_hop: self.selected_mx.clone(),
+            postfix_queue_id: self.postfix_queue_id.clone(),
+            tls_negotiated: self.tls_negotiated,
+            tls_parameters: self.tls_parameters.clone(),
+            tls_policy: self
+                .effective_tls_policy
+                .map(|policy| logging::outbound_policy_label(policy).to_string()),
+            tls_policy_rule: self.tls_policy_rule.as_ref().map(ToString::to_string),
+            policy_decision: Some(
+                logging::outbound_policy_decision(
+                    self.effective_tls_policy,
+                    self.tls_negotiated,
+                    self.remote_session_established,
+                )
+                .to_string(),
+            ),
+            reply_code,
+            outcome,
+            failure_reason,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -285,7 +1585,7 @@ impl DeliveryStage {
 
 #[derive(Debug)]
 struct MappedDeliveryReply {
-    reply: SmtpReply,
+    reply: Reply,
     accepted: bool,
     temporary_failure: bool,
 }
@@ -295,47 +1595,101 @@ struct RemoteMxRelay {
     reader: BufReader<TcpStream>,
     exchange: String,
     tls_negotiated: bool,
-    opportunistic_fallback_used: bool,
+    tls_parameters: Option<TlsSessionParameters>,
+    // The failed STARTTLS attempt an opportunistic plaintext retry recovered from.
+    starttls_failure: Option<String>,
+    auth_mechanism: Option<RelayAuthMechanism>,
 }
 
 impl RemoteMxRelay {
+    #[allow(clippy::too_many_arguments)]
     fn connect(
-        candidate: &MxCandidate,
+        stream: TcpStream,
+        exchange: &str,
         ehlo_host: &str,
         mail_command: &str,
         tls_policy: OutboundTlsPolicy,
+        tls_upgrader: &dyn OutboundTlsUpgrader,
+        credentials: Option<&SmarthostCredentials>,
+        connect_timeout: Duration,
     ) -> io::Result<Self> {
-        let (mut writer, mut reader, starttls_advertised) = Self::open_and_greet(candidate, ehlo_host)?;
+        let address = stream.peer_addr()?;
+        let (mut writer, mut reader, starttls_advertised) =
+            Self::open_and_greet(stream, ehlo_host)?;
 
         if starttls_advertised {
-            match Self::negotiate_starttls(&mut writer, &mut reader, ehlo_host) {
-                Ok(()) => {
+            let negotiated = Self::negotiate_starttls(
+                &mut writer,
+                &mut reader,
+                exchange,
+                ehlo_host,
+                tls_upgrader,
+            );
+            let starttls_failure = match negotiated {
+                Ok((capabilities, tls_parameters)) => {
+                    if credentials.is_some() && tls_parameters.is_none() {
+                        return Err(relay_auth_error(
+                            "4.7.11",
+                            format!(
+                                "STARTTLS with {} left the session unencrypted and credentials \
+                                 are never sent in plaintext",
+                                exchange
+                            ),
+                        ));
+                    }
+                    let auth_mechanism = credentials
+                        .map(|credentials| {
+                            Self::authenticate(&mut writer, &mut reader, &capabilities, credentials)
+                        })
+                        .transpose()?;
                     Self::send_mail_command(&mut writer, &mut reader, mail_command)?;
                     return Ok(Self {
                         writer,
                         reader,
-                        exchange: candidate.exchange.clone(),
+                        exchange: exchange.to_string(),
                         tls_negotiated: true,
-                        opportunistic_fallback_used: false,
+                        tls_parameters,
+                        starttls_failure: None,
+                        auth_mechanism,
                     });
                 }
                 Err(starttls_error) => {
                     if tls_policy.requires_tls() {
-                        return Err(starttls_error);
+                        return Err(io::Error::new(
+                            starttls_error.kind(),
+                            StartTlsFailure(starttls_error.to_string()),
+                        ));
+                    }
+                    if credentials.is_some() {
+                        return Err(relay_auth_error(
+                            "4.7.11",
+                            format!(
+                                "STARTTLS with {} failed and credentials are never sent in \
+                                 plaintext: {}",
+                                exchange, starttls_error
+                            ),
+                        ));
                     }
+                    starttls_error.to_string()
                 }
-            }
+            };
 
+            // The plaintext retry goes to the address that won the race.
+            let read_timeout = writer.read_timeout()?;
+            let stream = TcpStream::connect_timeout(&address, connect_timeout)?;
+            stream.set_read_timeout(read_timeout)?;
             let (mut fallback_writer, mut fallback_reader, _) =
-                Self::open_and_greet(candidate, ehlo_host)?;
+                Self::open_and_greet(stream, ehlo_host)?;
             Self::send_mail_command(&mut fallback_writer, &mut fallback_reader, mail_command)?;
 
             return Ok(Self {
                 writer: fallback_writer,
                 reader: fallback_reader,
-                exchange: candidate.exchange.clone(),
+                exchange: exchange.to_string(),
                 tls_negotiated: false,
-                opportunistic_fallback_used: true,
+                tls_parameters: None,
+                starttls_failure: Some(starttls_failure),
+                auth_mechanism: None,
             });
         }
 
@@ -344,7 +1698,17 @@ impl RemoteMxRelay {
                 ErrorKind::PermissionDenied,
                 format!(
                     "candidate {} does not advertise STARTTLS and policy requires TLS",
-                    candidate.exchange
+                    exchange
+                ),
+            ));
+        }
+        if credentials.is_some() {
+            return Err(relay_auth_error(
+                "4.7.11",
+                format!(
+                    "candidate {} does not advertise STARTTLS and credentials are never sent \
+                     in plaintext",
+                    exchange
                 ),
             ));
         }
@@ -354,81 +1718,173 @@ impl RemoteMxRelay {
         Ok(Self {
             writer,
             reader,
-            exchange: candidate.exchange.clone(),
+            exchange: exchange.to_string(),
             tls_negotiated: false,
-            opportunistic_fallback_used: false,
+            tls_parameters: None,
+            starttls_failure: None,
+            auth_mechanism: None,
         })
     }
 
     fn open_and_greet(
-        candidate: &MxCandidate,
+        stream: TcpStream,
         ehlo_host: &str,
     ) -> io::Result<(TcpStream, BufReader<TcpStream>, bool)> {
-        let mut writer = TcpStream::connect(candidate.address)?;
+        let mut writer = stream;
         let mut reader = BufReader::new(writer.try_clone()?);
 
-        let banner_reply = read_smtp_reply(&mut reader)?;
+        let banner_reply = Reply::read_from(&mut reader)?;
         if banner_reply.code / 100 != 2 {
             return Err(io::Error::new(
                 ErrorKind::ConnectionAborted,
                 format!(
                     "remote MX banner was non-2xx ({}): {}",
                     banner_reply.code,
-                    banner_reply.lines.join(" | ")
+                    banner_reply.summary()
                 ),
             ));
         }
 
         write_command_line(&mut writer, &format!("EHLO {}", ehlo_host))?;
-        let ehlo_reply = read_smtp_reply(&mut reader)?;
+        let ehlo_reply = Reply::read_from(&mut reader)?;
         if ehlo_reply.code / 100 != 2 {
             return Err(io::Error::new(
                 ErrorKind::ConnectionAborted,
                 format!(
                     "remote MX EHLO was non-2xx ({}): {}",
                     ehlo_reply.code,
-                    ehlo_reply.lines.join(" | ")
+                    ehlo_reply.summary()
                 ),
             ));
         }
 
-        Ok((writer, reader, reply_advertises_starttls(&ehlo_reply)))
+        Ok((writer, reader, EhloCapabilities::from_reply(&ehlo_reply).has("STARTTLS")))
     }
 
     fn negotiate_starttls(
         writer: &mut TcpStream,
         reader: &mut BufReader<TcpStream>,
+        exchange: &str,
         ehlo_host: &str,
-    ) -> io::Result<()> {
+        tls_upgrader: &dyn OutboundTlsUpgrader,
+    ) -> io::Result<(EhloCapabilities, Option<TlsSessionParameters>)> {
         write_command_line(writer, "STARTTLS")?;
-        let starttls_reply = read_smtp_reply(reader)?;
+        let starttls_reply = Reply::read_from(reader)?;
         if starttls_reply.code / 100 != 2 {
             return Err(io::Error::new(
                 ErrorKind::PermissionDenied,
                 format!(
                     "remote MX STARTTLS was non-2xx ({}): {}",
                     starttls_reply.code,
-                    starttls_reply.lines.join(" | ")
+                    starttls_reply.summary()
                 ),
             ));
         }
 
-        // TLS adapter wiring is tracked in a later bolt; this STARTTLS handshake
-        // boundary is the policy decision point for outbound routing behavior.
+        tls_upgrader.upgrade(writer, exchange).map_err(|error| {
+            io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("TLS handshake with {} failed: {}", exchange, error),
+            )
+        })?;
+        let tls_parameters = tls_upgrader.session_parameters(writer);
+
         write_command_line(writer, &format!("EHLO {}", ehlo_host))?;
-        let ehlo_after_tls = read_smtp_reply(reader)?;
+        let ehlo_after_tls = Reply::read_from(reader)?;
         if ehlo_after_tls.code / 100 != 2 {
             return Err(io::Error::new(
                 ErrorKind::PermissionDenied,
                 format!(
                     "remote MX EHLO after STARTTLS was non-2xx ({}): {}",
                     ehlo_after_tls.code,
-                    ehlo_after_tls.lines.join(" | ")
+                    ehlo_after_tls.summary()
                 ),
             ));
         }
 
-        Ok(())
+        Ok((EhloCapabilities::from_reply(&ehlo_after_tls), tls_parameters))
+    }
+
+    // RFC 4954 client side. The first mechanism the credentials allow that the smarthost offers
+    // after STARTTLS is used.
+    fn authenticate(
+        writer: &mut TcpStream,
+        reader: &mut BufReader<TcpStream>,
+        capabilities: &EhloCapabilities,
+        credentials: &SmarthostCredentials,
+    ) -> io::Result<RelayAuthMechanism> {
+        let mechanisms = credentials.mechanisms();
+        let Some(mechanism) = mechanisms
+            .iter()
+            .copied()
+            .find(|mechanism| capabilities.has_parameter("AUTH", mechanism.label()))
+        else {
+            let wanted: Vec<&str> = mechanisms.iter().map(|mechanism| mechanism.label()).collect();
+            return Err(relay_auth_error(
+                "4.7.0",
+                format!("remote MX does not offer AUTH {}", wanted.join(" or ")),
+            ));
+        };
+        let secret = credentials.secret().load().map_err(|error| {
+            relay_auth_error("4.7.0", format!("smarthost credentials unavailable: {}", error))
+        })?;
+        let username = credentials.username();
+
+        let reply = match mechanism {
+            RelayAuthMechanism::Plain => {
+                let response = base64::encode(format!("\0{}\0{}", username, secret).as_bytes());
+                write_command_line(writer, &format!("AUTH PLAIN {}", response))?;
+                Reply::read_from(reader)?
+            }
+            RelayAuthMechanism::Login => {
+                write_command_line(writer, "AUTH LOGIN")?;
+                let mut reply = Reply::read_from(reader)?;
+                for response in [username, secret.as_str()] {
+                    if reply.code != 334 {
+                        break;
+                    }
+                    write_command_line(writer, &base64::encode(response.as_bytes()))?;
+                    reply = Reply::read_from(reader)?;
+                }
+                reply
+            }
+            RelayAuthMechanism::XOAuth2 => {
+                let response = base64::encode(
+                    format!("user={}\x01auth=Bearer {}\x01\x01", username, secret).as_bytes(),
+                );
+                write_command_line(writer, &format!("AUTH XOAUTH2 {}", response))?;
+                let reply = Reply::read_from(reader)?;
+                if reply.code == 334 {
+                    // The 334 carries an error description; an empty line ends the exchange.
+                    write_command_line(writer, "")?;
+                    Reply::read_from(reader)?
+                } else {
+                    reply
+                }
+            }
+        };
+
+        match reply.code {
+            235 => Ok(mechanism),
+            code if code / 100 == 4 => Err(relay_auth_error(
+                "4.7.0",
+                format!(
+                    "remote MX AUTH {} was temporarily refused ({}): {}",
+                    mechanism.label(),
+                    code,
+                    reply.summary()
+                ),
+            )),
+            code => Err(relay_auth_error(
+                "4.7.8",
+                format!(
+                    "remote MX rejected AUTH {} ({}): {}",
+                    mechanism.label(),
+                    code,
+                    reply.summary()
+                ),
+            )),
+        }
     }
 
     fn send_mail_command(
@@ -437,39 +1893,48 @@ impl RemoteMxRelay {
         mail_command: &str,
     ) -> io::Result<()> {
         write_command_line(writer, mail_command)?;
-        let mail_reply = read_smtp_reply(reader)?;
+        let mail_reply = Reply::read_from(reader)?;
         if mail_reply.code / 100 != 2 {
-            return Err(io::Error::new(
-                ErrorKind::ConnectionAborted,
-                format!(
-                    "remote MX MAIL was non-2xx ({}): {}",
-                    mail_reply.code,
-                    mail_reply.lines.join(" | ")
-                ),
-            ));
+            let message = format!(
+                "remote MX MAIL was non-2xx ({}): {}",
+                mail_reply.code,
+                mail_reply.summary()
+            );
+            if mail_reply.code / 100 == 5 {
+                return Err(io::Error::new(
+                    ErrorKind::ConnectionAborted,
+                    MxFallbackEnd::PermanentReply(message),
+                ));
+            }
+            return Err(io::Error::new(ErrorKind::ConnectionAborted, message));
         }
 
         Ok(())
     }
 
-    fn relay_command(&mut self, command_line: &str) -> io::Result<SmtpReply> {
+    fn relay_command(&mut self, command_line: &str) -> io::Result<Reply> {
         write_command_line(&mut self.writer, command_line)?;
-        read_smtp_reply(&mut self.reader)
+        Reply::read_from(&mut self.reader)
     }
 
     fn relay_data_block(
         &mut self,
         client_reader: &mut BufReader<TcpStream>,
         max_line_len: usize,
-    ) -> io::Result<SmtpReply> {
+        shutdown: Option<&SessionShutdown<'_>>,
+    ) -> io::Result<(Reply, u64)> {
+        let mut message_bytes = 0u64;
         loop {
-            let mut line = String::new();
-            let bytes_read = client_reader.read_line(&mut line)?;
-            if bytes_read == 0 {
-                return Err(io::Error::new(
-                    ErrorKind::UnexpectedEof,
-                    "connection closed during DATA relay",
-                ));
+            let mut line = Vec::new();
+            match shutdown::read_session_bytes(client_reader, &mut line, shutdown, true)? {
+                LineRead::Line => {}
+                LineRead::Closed => {
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "connection closed during DATA relay",
+                    ));
+                }
+                LineRead::Shutdown => return Err(shutdown::shutdown_interrupted_error()),
             }
 
             if line.len() > max_line_len {
@@ -479,49 +1944,101 @@ impl RemoteMxRelay {
                 ));
             }
 
-            self.writer.write_all(line.as_bytes())?;
+            self.writer.write_all(&line)?;
             self.writer.flush()?;
 
             if is_data_terminator(&line) {
                 break;
             }
+            message_bytes += line.len() as u64;
         }
 
-        read_smtp_reply(&mut self.reader)
+        Ok((Reply::read_from(&mut self.reader)?, message_bytes))
     }
 }
 
 fn handle_session<R>(
     stream: &mut TcpStream,
     config: &OutboundListenerConfig,
+    routing: &OutboundRouting,
     resolver: &R,
+    shutdown: Option<&SessionShutdown<'_>>,
+    telemetry: &TelemetrySinks,
 ) -> io::Result<OutboundSessionSummary>
 where
     R: MxResolver,
 {
-    write_reply(stream, 220, &format!("{} ESMTP VERZOLA", config.banner_host))?;
+    let started_at = Instant::now();
+    let mut state = SessionState {
+        context: telemetry.session_context(SessionDirection::Outbound, stream.peer_addr().ok()),
+        ..SessionState::default()
+    };
+    state.emit(telemetry, TelemetryEvent::Connected);
+
+    // The partial summary is reported before an I/O error is handed back.
+    let result = serve_session(
+        stream,
+        &mut state,
+        started_at,
+        config,
+        routing,
+        resolver,
+        shutdown,
+        telemetry,
+    );
+    let summary = state.finish(telemetry, started_at);
+    result.map(|()| summary)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn serve_session<R>(
+    stream: &mut TcpStream,
+    state: &mut SessionState,
+    started_at: Instant,
+    config: &OutboundListenerConfig,
+    routing: &OutboundRouting,
+    resolver: &R,
+    shutdown: Option<&SessionShutdown<'_>>,
+    telemetry: &TelemetrySinks,
+) -> io::Result<()>
+where
+    R: MxResolver,
+{
+
+    if !config.permits_client(state.context.client_addr) {
+        state.client_rejected = true;
+        write_reply(
+            stream,
+            554,
+            &format!("5.7.1 {} Client host rejected: relay access denied", config.banner_host),
+        )?;
+        return Ok(());
+    }
 
+    write_reply(stream, 220, &format!("{} ESMTP VERZOLA", config.banner_host))?;
     let mut reader = BufReader::new(stream.try_clone()?);
-    let mut state = SessionState::default();
     let mut relay: Option<RemoteMxRelay> = None;
 
     loop {
         let mut line = String::new();
-        let bytes_read = reader.read_line(&mut line)?;
-        if bytes_read == 0 {
-            break;
+        let transaction_active = state.staged_mail_from.is_some();
+        match shutdown::read_session_line(&mut reader, &mut line, shutdown, transaction_active)? {
+            LineRead::Line => {}
+            LineRead::Closed => break,
+            LineRead::Shutdown => {
+                close_for_shutdown(stream, &mut relay, state)?;
+                break;
+            }
         }
 
         if line.len() > config.max_line_len {
-            state.protocol_errors += 1;
-            write_reply(stream, 500, "5.5.2 Line too long")?;
+            state.protocol_error(stream, telemetry, 500, "5.5.2 Line too long")?;
             continue;
         }
 
         let command_line = line.trim_end_matches(['\r', '\n']);
         if command_line.is_empty() {
-            state.protocol_errors += 1;
-            write_reply(stream, 500, "5.5.2 Empty command")?;
+            state.protocol_error(stream, telemetry, 500, "5.5.2 Empty command")?;
             continue;
         }
 
@@ -531,74 +2048,151 @@ where
         match verb.as_str() {
             "EHLO" | "HELO" => {
                 state.ehlo_seen = true;
+                if !argument.is_empty() {
+                    state.helo_name = Some(argument.to_string());
+                }
                 let greeting_target = if argument.is_empty() {
                     "postfix"
                 } else {
                     argument
                 };
-                let lines = vec![
-                    format!("{} greets {}", config.banner_host, greeting_target),
-                    "SIZE 10485760".to_string(),
-                ];
+                let mut lines = vec![format!("{} greets {}", config.banner_host, greeting_target)];
+                if verb == "EHLO" {
+                    lines.push(format!("XFORWARD {}", XFORWARD_ATTRIBUTES.join(" ")));
+                    if !state.client_authenticated {
+                        match &config.client_auth {
+                            OutboundClientAuth::Sasl { .. } => {
+                                lines.push("AUTH PLAIN LOGIN".to_string());
+                            }
+                            OutboundClientAuth::SharedSecret(_) => {
+                                lines.push("XCLIENT SECRET".to_string());
+                            }
+                            OutboundClientAuth::None => {}
+                        }
+                    }
+                }
+                lines.push("SIZE 10485760".to_string());
                 write_multiline_reply(stream, 250, &lines)?;
+                state.emit(
+                    telemetry,
+                    TelemetryEvent::Ehlo {
+                        helo_name: argument.to_string(),
+                        starttls_offered: false,
+                    },
+                );
             }
             "MAIL" => {
                 if !state.ehlo_seen {
-                    state.protocol_errors += 1;
-                    write_reply(stream, 503, "5.5.1 Send EHLO before MAIL")?;
+                    state.protocol_error(stream, telemetry, 503, "5.5.1 Send EHLO before MAIL")?;
                     continue;
                 }
 
-                if !is_mail_from_argument(argument) {
-                    state.protocol_errors += 1;
-                    write_reply(stream, 501, "5.5.4 MAIL requires FROM:<address>")?;
+                if config.client_auth.is_required() && !state.client_authenticated {
+                    write_reply(stream, 530, "5.7.0 Authentication required")?;
                     continue;
                 }
 
+                let mail = match MailFrom::parse(argument) {
+                    Ok(mail) => mail,
+                    Err(error) => {
+                        state.protocol_error(stream, telemetry, error.code, error.message)?;
+                        continue;
+                    }
+                };
+
                 state.staged_mail_from = Some(command_line.to_string());
                 state.recipient_domain = None;
                 state.recipient_count = 0;
+                state.envelope_recipients.clear();
                 state.selected_mx = None;
                 state.selected_recipient_domain = None;
                 state.effective_tls_policy = None;
+                state.tls_policy_rule = None;
+                state.delivery_route = None;
+                state.relay_auth_mechanism = None;
                 state.tls_negotiated = false;
+                state.tls_parameters = None;
                 relay = None;
 
                 write_reply(stream, 250, "2.1.0 Sender staged for outbound relay")?;
+                state.postfix_queue_id = state.xforward_ident.take();
+                state
+                    .transactions
+                    .begin(&mail.address(), started_at.elapsed());
+                if let Some(record) = state.transactions.current_mut() {
+                    record.postfix_queue_id = state.postfix_queue_id.clone();
+                }
+                state.transactions.record_reply(
+                    TransactionStage::Mail,
+                    250,
+                    "250 2.1.0 Sender staged for outbound relay",
+                );
+                state.emit(
+                    telemetry,
+                    TelemetryEvent::Mail {
+                        sender: mail.address(),
+                        reply_code: 250,
+                    },
+                );
             }
             "RCPT" => {
                 if !state.ehlo_seen {
-                    state.protocol_errors += 1;
-                    write_reply(stream, 503, "5.5.1 Send EHLO before RCPT")?;
+                    state.protocol_error(stream, telemetry, 503, "5.5.1 Send EHLO before RCPT")?;
                     continue;
                 }
 
                 let staged_mail_command = match state.staged_mail_from.clone() {
                     Some(mail_command) => mail_command,
                     None => {
-                        state.protocol_errors += 1;
-                        write_reply(stream, 503, "5.5.1 Send MAIL before RCPT")?;
+                        state.protocol_error(
+                            stream,
+                            telemetry,
+                            503,
+                            "5.5.1 Send MAIL before RCPT",
+                        )?;
                         continue;
                     }
                 };
 
-                let domain = match parse_recipient_domain(argument) {
-                    Some(domain) => domain,
-                    None => {
-                        state.protocol_errors += 1;
-                        write_reply(stream, 501, "5.1.3 Bad recipient address syntax")?;
+                let smtputf8 = MailFrom::parse(split_command(&staged_mail_command).1)
+                    .is_ok_and(|mail| mail.smtputf8());
+                let parsed = RcptTo::parse(argument, smtputf8).and_then(|rcpt| {
+                    // A bare <Postmaster> has no domain to route to.
+                    let domain = rcpt
+                        .forward_path
+                        .domain
+                        .as_ref()
+                        .and_then(|domain| normalize_domain(domain.to_string()))
+                        .ok_or(CommandError::new(501, "5.1.3 Bad recipient address syntax"))?;
+                    Ok((rcpt, domain))
+                });
+                let (rcpt, domain) = match parsed {
+                    Ok(parsed) => parsed,
+                    Err(error) => {
+                        state.protocol_error(stream, telemetry, error.code, error.message)?;
                         continue;
                     }
                 };
 
                 if let Some(active_domain) = state.recipient_domain.as_deref() {
                     if active_domain != domain {
-                        state.temporary_failures += 1;
                         write_reply(
                             stream,
                             451,
                             "4.5.3 Mixed recipient domains are not supported in this bolt",
                         )?;
+                        state.transactions.record_reply(
+                            TransactionStage::Rcpt,
+                            451,
+                            "451 4.5.3 Mixed recipient domains are not supported in this bolt",
+                        );
+                        state.emit(
+                            telemetry,
+                            TelemetryEvent::Recipient {
+                                recipient: rcpt.address(),
+                                reply_code: 451,
+                            },
+                        );
                         continue;
                     }
                 } else {
@@ -607,8 +2201,10 @@ where
 
                 let outbound_relay = match ensure_remote_relay(
                     &mut relay,
-                    &mut state,
+                    state,
+                    telemetry,
                     config,
+                    routing,
                     resolver,
                     &domain,
                     &staged_mail_command,
@@ -616,30 +2212,56 @@ where
                     Ok(outbound_relay) => outbound_relay,
                     Err(error) => {
                         relay = None;
-                        state.temporary_failures += 1;
-                        if error.kind() == ErrorKind::PermissionDenied {
-                            state.policy_deferred_failures += 1;
-                            write_reply(
-                                stream,
-                                451,
-                                &format!("4.7.5 Outbound TLS policy defer: {}", error),
-                            )?;
+                        let budget_exhausted = matches!(
+                            mx_fallback_end(&error),
+                            Some(MxFallbackEnd::BudgetExhausted(_))
+                        );
+                        let (stage, reply_text) = if let Some(status) = relay_auth_status(&error) {
+                            (
+                                "relay-auth",
+                                format!(
+                                    "{} Outbound relay authentication failed: {}",
+                                    status, error
+                                ),
+                            )
+                        } else if budget_exhausted {
+                            (
+                                "delivery-budget",
+                                format!("4.4.7 Outbound delivery time budget exceeded: {}", error),
+                            )
+                        } else if error.kind() == ErrorKind::PermissionDenied {
+                            ("tls-policy", format!("4.7.5 Outbound TLS policy defer: {}", error))
                         } else {
-                            write_reply(
-                                stream,
-                                451,
-                                &format!("4.4.0 Outbound MX temporarily unavailable: {}", error),
-                            )?;
-                        }
+                            (
+                                "mx-connect",
+                                format!("4.4.0 Outbound MX temporarily unavailable: {}", error),
+                            )
+                        };
+                        state.relay_failed(telemetry, stage, error.to_string());
+                        let mut attempt = state.delivery_attempt(None, Some(error.to_string()));
+                        attempt.recipients.push(rcpt.address());
+                        state.emit(telemetry, TelemetryEvent::Data(Box::new(attempt)));
+                        write_reply(stream, 451, &reply_text)?;
+                        state.transactions.record_reply(
+                            TransactionStage::Rcpt,
+                            451,
+                            &format!("451 {}", reply_text),
+                        );
                         continue;
                     }
                 };
 
+                if let Some(record) = state.transactions.current_mut() {
+                    record.selected_mx = state.selected_mx.clone();
+                    record.tls_negotiated = state.tls_negotiated;
+                    record.tls_parameters = state.tls_parameters.clone();
+                }
+
                 let rcpt_reply = match outbound_relay.relay_command(command_line) {
                     Ok(reply) => reply,
                     Err(error) => {
                         relay = None;
-                        state.temporary_failures += 1;
+                        state.relay_failed(telemetry, "rcpt", error.to_string());
                         write_reply(
                             stream,
                             451,
@@ -650,39 +2272,50 @@ where
                 };
 
                 let mapped_rcpt_reply = map_delivery_reply(DeliveryStage::Recipient, &rcpt_reply);
-                if mapped_rcpt_reply.temporary_failure {
-                    state.temporary_failures += 1;
-                }
-
+                state.transactions.record_reply(
+                    TransactionStage::Rcpt,
+                    mapped_rcpt_reply.reply.code,
+                    &mapped_rcpt_reply.reply.last_line(),
+                );
                 if mapped_rcpt_reply.accepted {
+                    state.transactions.add_recipient();
                     state.recipient_count += 1;
+                    state.envelope_recipients.push(rcpt.address());
                 }
 
-                write_smtp_reply(stream, &mapped_rcpt_reply.reply)?;
+                mapped_rcpt_reply.reply.write_to(stream)?;
+                state.emit(
+                    telemetry,
+                    TelemetryEvent::Recipient {
+                        recipient: rcpt.address(),
+                        reply_code: mapped_rcpt_reply.reply.code,
+                    },
+                );
             }
             "DATA" => {
                 if !state.ehlo_seen {
-                    state.protocol_errors += 1;
-                    write_reply(stream, 503, "5.5.1 Send EHLO before DATA")?;
+                    state.protocol_error(stream, telemetry, 503, "5.5.1 Send EHLO before DATA")?;
                     continue;
                 }
 
                 if state.staged_mail_from.is_none() {
-                    state.protocol_errors += 1;
-                    write_reply(stream, 503, "5.5.1 Send MAIL before DATA")?;
+                    state.protocol_error(stream, telemetry, 503, "5.5.1 Send MAIL before DATA")?;
                     continue;
                 }
 
                 if state.recipient_count == 0 {
-                    state.protocol_errors += 1;
-                    write_reply(stream, 503, "5.5.1 Send RCPT before DATA")?;
+                    state.protocol_error(stream, telemetry, 503, "5.5.1 Send RCPT before DATA")?;
                     continue;
                 }
 
                 let outbound_relay = match relay.as_mut() {
                     Some(outbound_relay) => outbound_relay,
                     None => {
-                        state.temporary_failures += 1;
+                        state.relay_failed(
+                            telemetry,
+                            "data",
+                            "outbound relay session is unavailable".to_string(),
+                        );
                         write_reply(stream, 451, "4.4.0 Outbound relay session is unavailable")?;
                         continue;
                     }
@@ -692,7 +2325,7 @@ where
                     Ok(reply) => reply,
                     Err(error) => {
                         relay = None;
-                        state.temporary_failures += 1;
+                        state.relay_failed(telemetry, "data", error.to_string());
                         write_reply(
                             stream,
                             451,
@@ -703,53 +2336,265 @@ where
                 };
 
                 let mapped_data_reply = map_delivery_reply(DeliveryStage::DataCommand, &data_reply);
+                state.transactions.record_reply(
+                    TransactionStage::Data,
+                    mapped_data_reply.reply.code,
+                    &mapped_data_reply.reply.last_line(),
+                );
                 if mapped_data_reply.temporary_failure {
-                    state.temporary_failures += 1;
-                    write_smtp_reply(stream, &mapped_data_reply.reply)?;
+                    state.relay_failed(
+                        telemetry,
+                        "data",
+                        format!(
+                            "remote MX DATA was non-354 ({}): {}",
+                            data_reply.code,
+                            data_reply.summary()
+                        ),
+                    );
+                    mapped_data_reply.reply.write_to(stream)?;
                     continue;
                 }
-                write_smtp_reply(stream, &mapped_data_reply.reply)?;
-
-                let final_data_reply =
-                    match outbound_relay.relay_data_block(&mut reader, config.max_line_len) {
-                        Ok(reply) => reply,
-                        Err(error) => {
-                            relay = None;
-                            state.temporary_failures += 1;
-                            write_reply(
-                                stream,
-                                451,
-                                &format!("4.4.0 Remote DATA payload relay failure: {}", error),
-                            )?;
-                            continue;
-                        }
-                    };
+                mapped_data_reply.reply.write_to(stream)?;
+
+                // Once the terminator reaches the remote MX the reply is always read and
+                // forwarded, so Postfix never loses a message the remote side accepted.
+                let final_data_reply = match outbound_relay.relay_data_block(
+                    &mut reader,
+                    config.max_line_len,
+                    shutdown,
+                ) {
+                    Ok(relayed) => relayed,
+                    Err(error) if error.kind() == ErrorKind::Interrupted => {
+                        relay = None;
+                        state.transactions.finish(TransactionOutcome::Deferred, 0);
+                        state.relay_failed(telemetry, "data", error.to_string());
+                        let attempt = state.delivery_attempt(None, Some(error.to_string()));
+                        state.emit(telemetry, TelemetryEvent::Data(Box::new(attempt)));
+                        close_for_shutdown(stream, &mut relay, state)?;
+                        break;
+                    }
+                    Err(error) => {
+                        relay = None;
+                        state.transactions.finish(TransactionOutcome::Deferred, 0);
+                        state.relay_failed(telemetry, "data", error.to_string());
+                        let attempt = state.delivery_attempt(None, Some(error.to_string()));
+                        state.emit(telemetry, TelemetryEvent::Data(Box::new(attempt)));
+                        write_reply(
+                            stream,
+                            451,
+                            &format!("4.4.0 Remote DATA payload relay failure: {}", error),
+                        )?;
+                        continue;
+                    }
+                };
 
+                let (final_data_reply, message_bytes) = final_data_reply;
                 let mapped_final_data_reply =
                     map_delivery_reply(DeliveryStage::DataFinal, &final_data_reply);
-                if mapped_final_data_reply.temporary_failure {
-                    state.temporary_failures += 1;
-                }
+                state.transactions.record_reply(
+                    TransactionStage::DataFinal,
+                    mapped_final_data_reply.reply.code,
+                    &mapped_final_data_reply.reply.last_line(),
+                );
+                state.transactions.finish(
+                    TransactionOutcome::from_reply_code(mapped_final_data_reply.reply.code),
+                    message_bytes,
+                );
+                let attempt = state.delivery_attempt(Some(final_data_reply.code), None);
+                state.emit(telemetry, TelemetryEvent::Data(Box::new(attempt)));
 
                 if mapped_final_data_reply.accepted {
+                    state.postfix_queue_id = None;
                     state.staged_mail_from = None;
                     state.recipient_domain = None;
                     state.recipient_count = 0;
+                    state.envelope_recipients.clear();
+                }
+
+                mapped_final_data_reply.reply.write_to(stream)?;
+            }
+            "XFORWARD" => {
+                if state.staged_mail_from.is_some() {
+                    state.protocol_error(
+                        stream,
+                        telemetry,
+                        503,
+                        "5.5.1 XFORWARD not allowed in a mail transaction",
+                    )?;
+                    continue;
+                }
+
+                match parse_xforward_attributes(argument) {
+                    Ok(attributes) => {
+                        for (name, value) in attributes {
+                            if name == "IDENT" {
+                                state.xforward_ident = value;
+                            }
+                        }
+                        write_reply(stream, 250, "2.0.0 Ok")?;
+                    }
+                    Err(message) => {
+                        state.protocol_error(stream, telemetry, 501, message)?;
+                    }
+                }
+            }
+            "AUTH" => {
+                if !matches!(config.client_auth, OutboundClientAuth::Sasl { .. }) {
+                    state.protocol_error(stream, telemetry, 502, "5.5.1 AUTH not available")?;
+                    continue;
+                }
+
+                if !state.ehlo_seen {
+                    state.protocol_error(stream, telemetry, 503, "5.5.1 Send EHLO before AUTH")?;
+                    continue;
+                }
+
+                if state.client_authenticated {
+                    state.protocol_error(stream, telemetry, 503, "5.5.1 Already authenticated")?;
+                    continue;
+                }
+
+                if state.staged_mail_from.is_some() {
+                    state.protocol_error(
+                        stream,
+                        telemetry,
+                        503,
+                        "5.5.1 AUTH not allowed in a mail transaction",
+                    )?;
+                    continue;
+                }
+
+                let (mechanism_name, initial_response) = match argument.split_once(' ') {
+                    Some((mechanism_name, response)) => (mechanism_name, Some(response.trim())),
+                    None => (argument, None),
+                };
+                let Some(mechanism) = SaslMechanism::parse(mechanism_name) else {
+                    state.protocol_error(
+                        stream,
+                        telemetry,
+                        504,
+                        "5.5.4 Unrecognized authentication mechanism",
+                    )?;
+                    continue;
+                };
+
+                let exchange = auth::run_server_exchange(mechanism, initial_response, |challenge| {
+                    write_reply(stream, 334, challenge)?;
+                    let mut response = String::new();
+                    let read =
+                        shutdown::read_session_line(&mut reader, &mut response, shutdown, true)?;
+                    match read {
+                        LineRead::Line => {
+                            Ok(Some(response.trim_end_matches(['\r', '\n']).to_string()))
+                        }
+                        LineRead::Closed | LineRead::Shutdown => Ok(None),
+                    }
+                })?;
+
+                match exchange {
+                    SaslExchange::Credentials(credentials) => {
+                        let authzid_matches = credentials
+                            .authzid
+                            .as_deref()
+                            .is_none_or(|authzid| authzid == credentials.username);
+                        let accepted = authzid_matches
+                            && config
+                                .client_auth
+                                .accepts_sasl(&credentials.username, &credentials.password);
+                        if !client_authentication_result(
+                            stream,
+                            state,
+                            telemetry,
+                            mechanism.label(),
+                            accepted,
+                        )? {
+                            break;
+                        }
+                    }
+                    SaslExchange::Cancelled => {
+                        write_reply(stream, 501, "5.0.0 Authentication cancelled")?;
+                    }
+                    SaslExchange::Malformed => {
+                        state.protocol_error(
+                            stream,
+                            telemetry,
+                            501,
+                            "5.5.2 Cannot decode authentication response",
+                        )?;
+                    }
+                    SaslExchange::Disconnected => break,
+                }
+            }
+            "XCLIENT" => {
+                let OutboundClientAuth::SharedSecret(secret) = &config.client_auth else {
+                    state.protocol_error(stream, telemetry, 502, "5.5.1 Command not implemented")?;
+                    continue;
+                };
+
+                if state.client_authenticated {
+                    state.protocol_error(stream, telemetry, 503, "5.5.1 Already authenticated")?;
+                    continue;
+                }
+
+                if state.staged_mail_from.is_some() {
+                    state.protocol_error(
+                        stream,
+                        telemetry,
+                        503,
+                        "5.5.1 XCLIENT not allowed in a mail transaction",
+                    )?;
+                    continue;
                 }
 
-                write_smtp_reply(stream, &mapped_final_data_reply.reply)?;
+                let Some(offered_secret) = parse_xclient_secret(argument) else {
+                    state.protocol_error(
+                        stream,
+                        telemetry,
+                        501,
+                        "5.5.4 XCLIENT requires SECRET=<xtext>",
+                    )?;
+                    continue;
+                };
+
+                let accepted = auth::constant_time_eq(offered_secret.as_bytes(), secret.as_bytes());
+                if accepted {
+                    // Like Postfix XCLIENT, success restarts the session: a fresh greeting
+                    // and the client must EHLO again.
+                    state.client_authenticated = true;
+                    state.ehlo_seen = false;
+                    state.emit(
+                        telemetry,
+                        TelemetryEvent::Auth {
+                            mechanism: "XCLIENT",
+                            succeeded: true,
+                        },
+                    );
+                    write_reply(stream, 220, &format!("{} ESMTP VERZOLA", config.banner_host))?;
+                } else if !client_authentication_result(
+                    stream,
+                    state,
+                    telemetry,
+                    "XCLIENT",
+                    false,
+                )? {
+                    break;
+                }
             }
             "RSET" => {
+                state.transactions.abandon();
+                state.xforward_ident = None;
+                state.postfix_queue_id = None;
                 state.staged_mail_from = None;
                 state.recipient_domain = None;
                 state.recipient_count = 0;
+                state.envelope_recipients.clear();
 
-                if relay.is_some() {
-                    match relay.as_mut().unwrap().relay_command(command_line) {
-                        Ok(reply) => write_smtp_reply(stream, &reply)?,
+                if let Some(outbound_relay) = relay.as_mut() {
+                    match outbound_relay.relay_command(command_line) {
+                        Ok(reply) => reply.write_to(stream)?,
                         Err(error) => {
                             relay = None;
-                            state.temporary_failures += 1;
+                            state.relay_failed(telemetry, "rset", error.to_string());
                             write_reply(
                                 stream,
                                 451,
@@ -762,12 +2607,12 @@ where
                 }
             }
             "NOOP" => {
-                if relay.is_some() {
-                    match relay.as_mut().unwrap().relay_command(command_line) {
-                        Ok(reply) => write_smtp_reply(stream, &reply)?,
+                if let Some(outbound_relay) = relay.as_mut() {
+                    match outbound_relay.relay_command(command_line) {
+                        Ok(reply) => reply.write_to(stream)?,
                         Err(error) => {
                             relay = None;
-                            state.temporary_failures += 1;
+                            state.relay_failed(telemetry, "noop", error.to_string());
                             write_reply(
                                 stream,
                                 451,
@@ -780,9 +2625,9 @@ where
                 }
             }
             "QUIT" => {
-                if relay.is_some() {
-                    match relay.as_mut().unwrap().relay_command(command_line) {
-                        Ok(reply) => write_smtp_reply(stream, &reply)?,
+                if let Some(outbound_relay) = relay.as_mut() {
+                    match outbound_relay.relay_command(command_line) {
+                        Ok(reply) => reply.write_to(stream)?,
                         Err(_) => write_reply(stream, 221, "2.0.0 Bye")?,
                     }
                 } else {
@@ -791,54 +2636,70 @@ where
                 break;
             }
             _ => {
-                state.protocol_errors += 1;
-                write_reply(stream, 502, "5.5.1 Command not implemented")?;
+                state.protocol_error(stream, telemetry, 502, "5.5.1 Command not implemented")?;
             }
         }
     }
 
-    Ok(OutboundSessionSummary {
-        command_count: state.command_count,
-        protocol_errors: state.protocol_errors,
-        temporary_failures: state.temporary_failures,
-        resolver_lookups: state.resolver_lookups,
-        mx_candidates_attempted: state.mx_candidates_attempted,
-        remote_session_established: state.remote_session_established,
-        selected_mx: state.selected_mx,
-        selected_recipient_domain: state.selected_recipient_domain,
-        effective_tls_policy: state.effective_tls_policy,
-        tls_negotiated: state.tls_negotiated,
-        opportunistic_tls_fallbacks: state.opportunistic_tls_fallbacks,
-        policy_deferred_failures: state.policy_deferred_failures,
-    })
+    Ok(())
 }
 
-fn resolve_outbound_tls_policy(
-    config: &OutboundListenerConfig,
-    recipient_domain: &str,
-) -> OutboundTlsPolicy {
-    let normalized_recipient_domain =
-        match normalize_domain(recipient_domain.to_string()) {
-            Some(domain) => domain,
-            None => return config.outbound_tls_policy,
-        };
+// Returns false once the client has used up its authentication attempts and the
+// session has been closed.
+fn client_authentication_result(
+    stream: &mut TcpStream,
+    state: &mut SessionState,
+    telemetry: &TelemetrySinks,
+    mechanism: &'static str,
+    accepted: bool,
+) -> io::Result<bool> {
+    state.emit(
+        telemetry,
+        TelemetryEvent::Auth {
+            mechanism,
+            succeeded: accepted,
+        },
+    );
+
+    if accepted {
+        state.client_authenticated = true;
+        write_reply(stream, 235, "2.7.0 Authentication successful")?;
+        return Ok(true);
+    }
 
-    config
-        .per_domain_tls_policies
-        .iter()
-        .find(|rule| {
-            normalize_domain(rule.recipient_domain.clone())
-                .map(|normalized_rule_domain| normalized_rule_domain == normalized_recipient_domain)
-                .unwrap_or(false)
-        })
-        .map(|rule| rule.policy)
-        .unwrap_or(config.outbound_tls_policy)
+    if state.telemetry.auth_failures >= MAX_CLIENT_AUTH_FAILURES {
+        write_reply(stream, 421, "4.7.0 Too many authentication failures")?;
+        return Ok(false);
+    }
+    write_reply(stream, 535, "5.7.8 Authentication credentials invalid")?;
+    Ok(true)
 }
 
+fn close_for_shutdown(
+    stream: &mut TcpStream,
+    relay: &mut Option<RemoteMxRelay>,
+    state: &mut SessionState,
+) -> io::Result<()> {
+    if let Some(outbound_relay) = relay.as_mut() {
+        let _ = outbound_relay.relay_command("QUIT");
+    }
+    *relay = None;
+    state.transactions.abandon();
+    state.staged_mail_from = None;
+    state.recipient_domain = None;
+    state.recipient_count = 0;
+    state.envelope_recipients.clear();
+    state.closed_by_shutdown = true;
+    write_reply(stream, 421, "4.3.2 Service shutting down")
+}
+
+#[allow(clippy::too_many_arguments)]
 fn ensure_remote_relay<'a, R>(
     relay: &'a mut Option<RemoteMxRelay>,
     state: &mut SessionState,
+    telemetry: &TelemetrySinks,
     config: &OutboundListenerConfig,
+    routing: &OutboundRouting,
     resolver: &R,
     recipient_domain: &str,
     mail_command: &str,
@@ -847,14 +2708,38 @@ where
     R: MxResolver,
 {
     if relay.is_none() {
-        state.resolver_lookups += 1;
-        let effective_tls_policy = resolve_outbound_tls_policy(config, recipient_domain);
+        let connect_started_at = Instant::now();
+        let tls_policies = &routing.tls_policies;
+        // Until a candidate is picked only a domain rule or the global policy can apply.
+        let (effective_tls_policy, tls_policy_rule) =
+            tls_policies.resolve(config.outbound_tls_policy, recipient_domain, None);
         state.effective_tls_policy = Some(effective_tls_policy);
+        state.tls_policy_rule = tls_policy_rule;
         state.tls_negotiated = false;
-
-        let mut candidates = resolver
-            .resolve(recipient_domain)
-            .map_err(mx_resolution_error_to_io)?;
+        state.tls_parameters = None;
+
+        let delivery_route = routing
+            .transport_map
+            .route(recipient_domain)
+            .unwrap_or(DeliveryRoute::Mx);
+        state.delivery_route = Some(delivery_route.clone());
+        let credentials = match &delivery_route {
+            DeliveryRoute::Transport { next_hop, .. } => {
+                routing.transport_map.credentials(next_hop)
+            }
+            DeliveryRoute::Mx => None,
+        };
+        let mut candidates = match &delivery_route {
+            DeliveryRoute::Transport { next_hop, .. } => {
+                routing.transport_map.candidates(next_hop)?
+            }
+            DeliveryRoute::Mx => {
+                state.resolver_lookups += 1;
+                resolver
+                    .resolve(recipient_domain)
+                    .map_err(mx_resolution_error_to_io)?
+            }
+        };
 
         if candidates.is_empty() {
             return Err(io::Error::new(
@@ -863,38 +2748,130 @@ where
             ));
         }
 
-        candidates.sort_by(|left, right| compare_mx_candidates(left, right));
+        // RFC 5321 section 5.1: hosts of equal preference are tried in random order so load
+        // spreads across them. Resolver order is kept until then, for addresses of one host.
+        candidates.sort_by_key(|candidate| candidate.preference);
+        let mut candidates = merge_mx_candidates(candidates);
+        shuffle_equal_preferences(&mut candidates);
+        candidates.truncate(config.max_mx_hosts);
 
+        let deadline = connect_started_at + config.delivery_time_budget;
+        let mut addresses_left = config.max_mx_addresses;
+        let mut hosts_tried = 0;
         let mut last_error: Option<io::Error> = None;
         for candidate in candidates {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || addresses_left == 0 {
+                break;
+            }
+            hosts_tried += 1;
             state.mx_candidates_attempted += 1;
-
-            match RemoteMxRelay::connect(
-                &candidate,
-                &config.banner_host,
-                mail_command,
-                effective_tls_policy,
-            ) {
+            let (effective_tls_policy, tls_policy_rule) = tls_policies.resolve(
+                config.outbound_tls_policy,
+                recipient_domain,
+                Some(&candidate.exchange),
+            );
+            state.effective_tls_policy = Some(effective_tls_policy);
+            state.tls_policy_rule = tls_policy_rule;
+
+            let mut addresses = order_addresses(&candidate.addresses, config.address_family);
+            addresses.truncate(addresses_left);
+            let connect_timeout = config.mx_connect_timeout.min(remaining);
+            let (stream, address_attempts) = race_connections(
+                &candidate.exchange,
+                &addresses,
+                config.connection_attempt_delay,
+                connect_timeout,
+            );
+            addresses_left -= address_attempts.len();
+            state.mx_address_attempts.extend(address_attempts);
+
+            let connected = stream.and_then(|stream| {
+                // A host that accepts the connection but stalls cannot outlast the budget.
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                stream.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+                let outbound_relay = RemoteMxRelay::connect(
+                    stream,
+                    &candidate.exchange,
+                    &config.banner_host,
+                    mail_command,
+                    effective_tls_policy,
+                    routing.tls_upgrader.as_ref(),
+                    credentials,
+                    connect_timeout,
+                )?;
+                outbound_relay.writer.set_read_timeout(None)?;
+                Ok(outbound_relay)
+            });
+            match connected {
                 Ok(outbound_relay) => {
+                    if outbound_relay.tls_negotiated {
+                        let parameters = outbound_relay.tls_parameters.clone();
+                        state.emit(
+                            telemetry,
+                            TelemetryEvent::StartTls(StartTlsResult::Negotiated(parameters)),
+                        );
+                    } else if let Some(reason) = outbound_relay.starttls_failure.clone() {
+                        state.emit(
+                            telemetry,
+                            TelemetryEvent::StartTls(StartTlsResult::Failed { reason }),
+                        );
+                        state.emit(
+                            telemetry,
+                            TelemetryEvent::StartTls(StartTlsResult::PlaintextFallback),
+                        );
+                    }
                     state.remote_session_established = true;
                     state.selected_mx = Some(outbound_relay.exchange.clone());
                     state.selected_recipient_domain = Some(recipient_domain.to_string());
                     state.tls_negotiated = outbound_relay.tls_negotiated;
-                    if outbound_relay.opportunistic_fallback_used {
-                        state.opportunistic_tls_fallbacks += 1;
-                    }
+                    state.tls_parameters = outbound_relay.tls_parameters.clone();
+                    state.relay_auth_mechanism = outbound_relay.auth_mechanism;
+                    state.mx_connect_durations.push(connect_started_at.elapsed());
                     *relay = Some(outbound_relay);
                     break;
                 }
                 Err(error) => {
-                    last_error = Some(io::Error::new(
-                        error.kind(),
-                        format!("candidate {} failed: {}", candidate.exchange, error),
-                    ));
+                    if let Some(StartTlsFailure(reason)) = starttls_failure(&error) {
+                        let reason = reason.clone();
+                        state.emit(
+                            telemetry,
+                            TelemetryEvent::StartTls(StartTlsResult::Failed { reason }),
+                        );
+                    }
+                    let message = format!("candidate {} failed: {}", candidate.exchange, error);
+                    let permanent =
+                        matches!(mx_fallback_end(&error), Some(MxFallbackEnd::PermanentReply(_)));
+                    last_error = Some(match relay_auth_status(&error) {
+                        Some(status) => relay_auth_error(status, message),
+                        None if permanent => io::Error::new(
+                            error.kind(),
+                            MxFallbackEnd::PermanentReply(message),
+                        ),
+                        None => io::Error::new(error.kind(), message),
+                    });
+                    if permanent {
+                        break;
+                    }
                 }
             }
         }
 
+        if relay.is_none() && Instant::now() >= deadline {
+            let mut message = format!(
+                "delivery time budget of {} ms exhausted after {} MX hosts",
+                config.delivery_time_budget.as_millis(),
+                hosts_tried
+            );
+            if let Some(error) = last_error {
+                message.push_str(&format!(", last: {}", error));
+            }
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                MxFallbackEnd::BudgetExhausted(message),
+            ));
+        }
+
         if relay.is_none() {
             return Err(last_error.unwrap_or_else(|| {
                 io::Error::new(
@@ -914,107 +2891,246 @@ where
     }
 }
 
-fn reply_advertises_starttls(reply: &SmtpReply) -> bool {
-    reply
-        .lines
-        .iter()
-        .filter_map(|line| smtp_reply_capability(line))
-        .any(|capability| {
-            capability.eq_ignore_ascii_case("STARTTLS")
-                || capability
-                    .to_ascii_uppercase()
-                    .starts_with("STARTTLS ")
-        })
+fn normalize_domain(raw_domain: String) -> Option<String> {
+    let domain = raw_domain.trim().trim_matches('.');
+    if domain.starts_with('[') {
+        // Address literals are routed as is; there is nothing to map.
+        return Some(domain.to_ascii_lowercase());
+    }
+    idna::to_ascii(domain)
 }
 
-fn smtp_reply_capability(line: &str) -> Option<&str> {
-    let line_bytes = line.as_bytes();
-    if line_bytes.len() < 4 {
-        return None;
+fn normalize_next_hop_host(raw_host: &str) -> Option<String> {
+    let host = raw_host.trim();
+    let address = host
+        .get(..5)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("ipv6:"))
+        .map_or(host, |_| &host[5..]);
+    if let Ok(ip) = address.parse::<IpAddr>() {
+        return Some(ip.to_string());
     }
-
-    if !line_bytes[..3].iter().all(|byte| byte.is_ascii_digit()) {
+    if host.starts_with('[') {
         return None;
     }
+    normalize_domain(host.to_string())
+}
 
-    if line_bytes[3] != b' ' && line_bytes[3] != b'-' {
-        return None;
+// Fisher-Yates over each run of equal preference. Expects candidates sorted by preference.
+fn shuffle_equal_preferences(candidates: &mut [MxCandidate]) {
+    let random = RandomState::new();
+    let mut start = 0;
+    while start < candidates.len() {
+        let preference = candidates[start].preference;
+        let end = start
+            + candidates[start..]
+                .iter()
+                .take_while(|candidate| candidate.preference == preference)
+                .count();
+        for index in (start + 1..end).rev() {
+            let span = (index - start + 1) as u64;
+            let pick = start + (random.hash_one(index) % span) as usize;
+            candidates.swap(index, pick);
+        }
+        start = end;
     }
-
-    Some(line[4..].trim())
 }
 
-fn normalize_domain(raw_domain: String) -> Option<String> {
-    let domain = raw_domain.trim().trim_matches('.').to_ascii_lowercase();
-    if domain.is_empty() {
-        None
-    } else {
-        Some(domain)
+// Resolvers that still return one candidate per address get one candidate per host, at the
+// best preference the host was listed with. Expects candidates sorted by preference.
+fn merge_mx_candidates(candidates: Vec<MxCandidate>) -> Vec<MxCandidate> {
+    let mut merged: Vec<MxCandidate> = Vec::with_capacity(candidates.len());
+    for candidate in candidates {
+        let existing = merged
+            .iter_mut()
+            .find(|known| known.exchange.eq_ignore_ascii_case(&candidate.exchange));
+        match existing {
+            Some(known) => {
+                for address in candidate.addresses {
+                    if !known.addresses.contains(&address) {
+                        known.addresses.push(address);
+                    }
+                }
+            }
+            None => merged.push(candidate),
+        }
     }
+    merged
 }
 
-fn parse_recipient_domain(argument: &str) -> Option<String> {
-    let trimmed = argument.trim();
-    if trimmed.is_empty() {
-        return None;
+// RFC 8305 section 4 with a First Address Family Count of 1.
+fn order_addresses(
+    addresses: &[SocketAddr],
+    preference: AddressFamilyPreference,
+) -> Vec<SocketAddr> {
+    match preference {
+        AddressFamilyPreference::Ipv4 => {
+            addresses.iter().copied().filter(SocketAddr::is_ipv4).collect()
+        }
+        AddressFamilyPreference::Ipv6 => {
+            addresses.iter().copied().filter(SocketAddr::is_ipv6).collect()
+        }
+        AddressFamilyPreference::Both => {
+            let Some(first) = addresses.first() else {
+                return Vec::new();
+            };
+            let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addresses
+                .iter()
+                .partition(|address| address.is_ipv6() == first.is_ipv6());
+            let mut ordered = Vec::with_capacity(addresses.len());
+            let mut preferred = preferred.into_iter();
+            let mut other = other.into_iter();
+            loop {
+                match (preferred.next(), other.next()) {
+                    (None, None) => return ordered,
+                    (left, right) => ordered.extend(left.into_iter().chain(right)),
+                }
+            }
+        }
     }
+}
 
-    let value = if starts_with_case_insensitive(trimmed, "TO:") {
-        &trimmed[3..]
-    } else {
-        trimmed
-    };
+// RFC 8305 section 5: a new attempt starts every `attempt_delay`, or as soon as the previous
+// one fails, and the first connection established wins. Attempts still in flight are left to
+// finish on their own thread and their sockets are dropped.
+fn race_connections(
+    exchange: &str,
+    addresses: &[SocketAddr],
+    attempt_delay: Duration,
+    connect_timeout: Duration,
+) -> (io::Result<TcpStream>, Vec<MxAddressAttempt>) {
+    let (sender, receiver) = mpsc::channel();
+    let mut outcomes: Vec<Option<MxAddressOutcome>> = vec![None; addresses.len()];
+    let mut next = 0;
+    let mut in_flight = 0;
+    let mut start_next = true;
+    let mut connected = None;
+    let mut last_error = None;
+
+    while connected.is_none() {
+        if start_next && next < addresses.len() {
+            let sender = sender.clone();
+            let address = addresses[next];
+            let index = next;
+            thread::spawn(move || {
+                let _ = sender.send((index, TcpStream::connect_timeout(&address, connect_timeout)));
+            });
+            next += 1;
+            in_flight += 1;
+            start_next = false;
+        }
+        if in_flight == 0 {
+            break;
+        }
 
-    let address_token = value.trim().split_whitespace().next()?;
-    let address = address_token.trim_matches(['<', '>']);
-    let (_, domain) = address.rsplit_once('@')?;
+        let received = if next < addresses.len() {
+            receiver.recv_timeout(attempt_delay).ok()
+        } else {
+            receiver.recv().ok()
+        };
+        let Some((index, result)) = received else {
+            start_next = true;
+            continue;
+        };
+        in_flight -= 1;
+        match result {
+            Ok(stream) => {
+                outcomes[index] = Some(MxAddressOutcome::Connected);
+                connected = Some(stream);
+            }
+            Err(error) => {
+                outcomes[index] = Some(MxAddressOutcome::Failed(error.to_string()));
+                last_error = Some(error);
+                start_next = true;
+            }
+        }
+    }
 
-    let domain = domain.trim().trim_end_matches('>');
-    normalize_domain(domain.to_string())
+    let attempts = addresses[..next]
+        .iter()
+        .zip(outcomes)
+        .map(|(address, outcome)| MxAddressAttempt {
+            exchange: exchange.to_string(),
+            address: *address,
+            outcome: outcome.unwrap_or(MxAddressOutcome::Abandoned),
+        })
+        .collect();
+    let stream = connected.ok_or_else(|| match last_error {
+        Some(error) if addresses.len() == 1 => error,
+        Some(error) => io::Error::new(
+            error.kind(),
+            format!("all {} addresses failed, last: {}", addresses.len(), error),
+        ),
+        None => io::Error::new(ErrorKind::NotFound, "no addresses in the configured family"),
+    });
+    (stream, attempts)
 }
 
-fn is_mail_from_argument(argument: &str) -> bool {
-    let trimmed = argument.trim();
-    if !starts_with_case_insensitive(trimmed, "FROM:") {
-        return false;
+fn mx_resolution_error_to_io(error: MxResolutionError) -> io::Error {
+    match error {
+        MxResolutionError::Temporary(message) => io::Error::new(ErrorKind::WouldBlock, message),
     }
-
-    let value = trimmed[5..].trim();
-    !value.is_empty()
 }
 
-fn starts_with_case_insensitive(value: &str, prefix: &str) -> bool {
-    value
-        .get(..prefix.len())
-        .map(|head| head.eq_ignore_ascii_case(prefix))
-        .unwrap_or(false)
-}
+// Values arrive xtext-encoded (RFC 3461); "[UNAVAILABLE]" means Postfix had no value.
+fn parse_xforward_attributes(
+    argument: &str,
+) -> Result<Vec<(String, Option<String>)>, &'static str> {
+    let mut attributes = Vec::new();
+    for token in argument.split_whitespace() {
+        let (name, value) = token
+            .split_once('=')
+            .ok_or("5.5.4 Bad XFORWARD attribute syntax")?;
+        let name = name.to_ascii_uppercase();
+        if !XFORWARD_ATTRIBUTES.contains(&name.as_str()) {
+            return Err("5.5.4 Bad XFORWARD attribute name");
+        }
+
+        let value = decode_xtext(value).ok_or("5.5.4 Bad XFORWARD attribute value")?;
+        let value = if value.eq_ignore_ascii_case("[UNAVAILABLE]") || value.is_empty() {
+            None
+        } else {
+            Some(value)
+        };
+        attributes.push((name, value));
+    }
 
-fn compare_mx_candidates(left: &MxCandidate, right: &MxCandidate) -> Ordering {
-    left.preference
-        .cmp(&right.preference)
-        .then_with(|| left.exchange.cmp(&right.exchange))
+    if attributes.is_empty() {
+        return Err("5.5.4 XFORWARD requires attributes");
+    }
+    Ok(attributes)
 }
 
-fn mx_resolution_error_to_io(error: MxResolutionError) -> io::Error {
-    match error {
-        MxResolutionError::Temporary(message) => io::Error::new(ErrorKind::WouldBlock, message),
+fn parse_xclient_secret(argument: &str) -> Option<String> {
+    let (name, value) = argument.trim().split_once('=')?;
+    if !name.eq_ignore_ascii_case("SECRET") || value.is_empty() || value.contains(' ') {
+        return None;
     }
+    decode_xtext(value)
 }
 
-fn split_command(line: &str) -> (String, &str) {
-    let mut parts = line.splitn(2, |character: char| character.is_whitespace());
-    let verb = parts.next().unwrap_or("").trim().to_ascii_uppercase();
-    let argument = parts.next().unwrap_or("").trim();
-    (verb, argument)
+fn decode_xtext(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'+' {
+            let hex = value.get(index + 1..index + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
 }
 
-fn map_delivery_reply(stage: DeliveryStage, remote_reply: &SmtpReply) -> MappedDeliveryReply {
+fn map_delivery_reply(stage: DeliveryStage, remote_reply: &Reply) -> MappedDeliveryReply {
     match stage {
         DeliveryStage::Recipient => {
             if remote_reply.code / 100 == 2 {
                 MappedDeliveryReply {
-                    reply: smtp_reply(250, "2.1.5 Recipient accepted for remote delivery"),
+                    reply: Reply::new(250, "2.1.5 Recipient accepted for remote delivery"),
                     accepted: true,
                     temporary_failure: false,
                 }
@@ -1025,7 +3141,7 @@ fn map_delivery_reply(stage: DeliveryStage, remote_reply: &SmtpReply) -> MappedD
         DeliveryStage::DataCommand => {
             if remote_reply.code / 100 == 3 {
                 MappedDeliveryReply {
-                    reply: smtp_reply(354, "End data with <CR><LF>.<CR><LF>"),
+                    reply: Reply::new(354, "End data with <CR><LF>.<CR><LF>"),
                     accepted: true,
                     temporary_failure: false,
                 }
@@ -1036,7 +3152,7 @@ fn map_delivery_reply(stage: DeliveryStage, remote_reply: &SmtpReply) -> MappedD
         DeliveryStage::DataFinal => {
             if remote_reply.code / 100 == 2 {
                 MappedDeliveryReply {
-                    reply: smtp_reply(250, "2.0.0 Message accepted by remote MX"),
+                    reply: Reply::new(250, "2.0.0 Message accepted by remote MX"),
                     accepted: true,
                     temporary_failure: false,
                 }
@@ -1055,7 +3171,7 @@ fn deferred_delivery_reply(stage: DeliveryStage, remote_code: u16) -> MappedDeli
     };
 
     MappedDeliveryReply {
-        reply: smtp_reply(
+        reply: Reply::new(
             451,
             &format!(
                 "4.4.0 Delivery deferred for retry (stage={}, class={}, upstream={})",
@@ -1068,114 +3184,3 @@ fn deferred_delivery_reply(stage: DeliveryStage, remote_code: u16) -> MappedDeli
         temporary_failure: true,
     }
 }
-
-fn smtp_reply(code: u16, message: &str) -> SmtpReply {
-    SmtpReply {
-        code,
-        lines: vec![format!("{} {}", code, message)],
-    }
-}
-
-fn read_smtp_reply(reader: &mut BufReader<TcpStream>) -> io::Result<SmtpReply> {
-    let mut lines = Vec::new();
-    let mut code: Option<u16> = None;
-
-    loop {
-        let mut raw = String::new();
-        let bytes_read = reader.read_line(&mut raw)?;
-        if bytes_read == 0 {
-            return Err(io::Error::new(
-                ErrorKind::UnexpectedEof,
-                "remote side closed connection while reading SMTP reply",
-            ));
-        }
-
-        let line = raw.trim_end_matches(['\r', '\n']).to_string();
-        let line_bytes = line.as_bytes();
-
-        if line_bytes.len() < 3 || !line_bytes[..3].iter().all(|byte| byte.is_ascii_digit()) {
-            return Err(io::Error::new(
-                ErrorKind::InvalidData,
-                format!("invalid SMTP reply line: {}", line),
-            ));
-        }
-
-        let parsed_code = line[..3].parse::<u16>().map_err(|error| {
-            io::Error::new(
-                ErrorKind::InvalidData,
-                format!("invalid SMTP reply code: {}", error),
-            )
-        })?;
-
-        if let Some(expected_code) = code {
-            if expected_code != parsed_code {
-                return Err(io::Error::new(
-                    ErrorKind::InvalidData,
-                    format!(
-                        "inconsistent SMTP reply codes in multiline response: expected {}, got {}",
-                        expected_code, parsed_code
-                    ),
-                ));
-            }
-        } else {
-            code = Some(parsed_code);
-        }
-
-        let separator = if line_bytes.len() > 3 {
-            line_bytes[3]
-        } else {
-            b' '
-        };
-        lines.push(line);
-
-        if separator == b' ' {
-            break;
-        }
-        if separator != b'-' {
-            return Err(io::Error::new(
-                ErrorKind::InvalidData,
-                "invalid SMTP multiline reply separator",
-            ));
-        }
-    }
-
-    Ok(SmtpReply {
-        code: code.unwrap_or(500),
-        lines,
-    })
-}
-
-fn write_command_line(stream: &mut TcpStream, line: &str) -> io::Result<()> {
-    write!(stream, "{}\r\n", line)?;
-    stream.flush()
-}
-
-fn write_smtp_reply(stream: &mut TcpStream, reply: &SmtpReply) -> io::Result<()> {
-    for line in &reply.lines {
-        write!(stream, "{}\r\n", line)?;
-    }
-
-    stream.flush()
-}
-
-fn is_data_terminator(line: &str) -> bool {
-    line == ".\r\n" || line == ".\n"
-}
-
-fn write_reply(stream: &mut TcpStream, code: u16, message: &str) -> io::Result<()> {
-    write!(stream, "{} {}\r\n", code, message)?;
-    stream.flush()
-}
-
-fn write_multiline_reply(stream: &mut TcpStream, code: u16, lines: &[String]) -> io::Result<()> {
-    if lines.is_empty() {
-        return write_reply(stream, code, "OK");
-    }
-
-    for (index, line) in lines.iter().enumerate() {
-        let separator = if index + 1 == lines.len() { ' ' } else { '-' };
-        write!(stream, "{}{}{}\r\n", code, separator, line)?;
-    }
-
-    stream.flush()
-}
@@ -0,0 +1,266 @@
+use std::io::{self, BufRead, ErrorKind};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+pub(crate) const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownSignal {
+    inner: Arc<ShutdownState>,
+}
+
+#[derive(Debug, Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    requested_at: Mutex<Option<Instant>>,
+}
+
+impl ShutdownSignal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn request(&self) {
+        let mut requested_at = self
+            .inner
+            .requested_at
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if requested_at.is_none() {
+            *requested_at = Some(Instant::now());
+        }
+        self.inner.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    pub fn requested_at(&self) -> Option<Instant> {
+        *self
+            .inner
+            .requested_at
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    pub sessions_served: usize,
+    pub sessions_failed: usize,
+    pub sessions_closed_by_shutdown: usize,
+}
+
+#[cfg(unix)]
+static SIGTERM_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn record_termination_signal(_signal: i32) {
+    SIGTERM_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+pub fn install_termination_handler(shutdown: &ShutdownSignal) -> io::Result<()> {
+    const SIGINT: i32 = 2;
+    const SIGTERM: i32 = 15;
+    const SIG_ERR: usize = usize::MAX;
+
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+
+    for signum in [SIGTERM, SIGINT] {
+        // The handler only stores to an atomic, which keeps it async-signal-safe;
+        // the watcher thread below turns that flag into a shutdown request.
+        let previous = unsafe { signal(signum, record_termination_signal) };
+        if previous == SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    let shutdown = shutdown.clone();
+    thread::Builder::new()
+        .name("verzola-signal-watch".to_string())
+        .spawn(move || loop {
+            if SIGTERM_RECEIVED.load(Ordering::SeqCst) {
+                shutdown.request();
+                break;
+            }
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        })?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn install_termination_handler(_shutdown: &ShutdownSignal) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "termination signal handling is only available on unix targets",
+    ))
+}
+
+// Session workers report whether they were closed with `421` because of the
+// shutdown request; errors from individual sessions never stop the listener.
+pub(crate) fn serve_until_shutdown<F>(
+    listener: &TcpListener,
+    shutdown: &ShutdownSignal,
+    mut spawn_session: F,
+) -> io::Result<ShutdownReport>
+where
+    F: FnMut(TcpStream) -> JoinHandle<io::Result<bool>>,
+{
+    listener.set_nonblocking(true)?;
+    let mut report = ShutdownReport::default();
+    let mut handles: Vec<JoinHandle<io::Result<bool>>> = Vec::new();
+
+    let accept_result = loop {
+        if shutdown.is_requested() {
+            break Ok(());
+        }
+
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if prepare_session_stream(&stream).is_err() {
+                    report.sessions_failed += 1;
+                    continue;
+                }
+                handles.push(spawn_session(stream));
+            }
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(SHUTDOWN_POLL_INTERVAL);
+            }
+            Err(error) if error.kind() == ErrorKind::Interrupted => {}
+            Err(error) => break Err(error),
+        }
+
+        let (finished, running): (Vec<_>, Vec<_>) =
+            handles.into_iter().partition(|handle| handle.is_finished());
+        handles = running;
+        for handle in finished {
+            record_session_outcome(&mut report, handle);
+        }
+    };
+
+    for handle in handles {
+        record_session_outcome(&mut report, handle);
+    }
+    listener.set_nonblocking(false)?;
+
+    accept_result.map(|()| report)
+}
+
+fn prepare_session_stream(stream: &TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))
+}
+
+fn record_session_outcome(report: &mut ShutdownReport, handle: JoinHandle<io::Result<bool>>) {
+    match handle.join() {
+        Ok(Ok(closed_by_shutdown)) => {
+            report.sessions_served += 1;
+            if closed_by_shutdown {
+                report.sessions_closed_by_shutdown += 1;
+            }
+        }
+        Ok(Err(_)) | Err(_) => report.sessions_failed += 1,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SessionShutdown<'a> {
+    signal: &'a ShutdownSignal,
+    grace_period: Duration,
+}
+
+impl<'a> SessionShutdown<'a> {
+    pub(crate) fn new(signal: &'a ShutdownSignal, grace_period: Duration) -> Self {
+        Self {
+            signal,
+            grace_period,
+        }
+    }
+
+    pub(crate) fn should_stop(&self, transaction_active: bool) -> bool {
+        let requested_at = match self.signal.requested_at() {
+            Some(requested_at) => requested_at,
+            None => return false,
+        };
+
+        !transaction_active || requested_at.elapsed() >= self.grace_period
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LineRead {
+    Line,
+    Closed,
+    Shutdown,
+}
+
+// Sessions served under a shutdown signal run with a short socket read timeout so
+// they can notice the signal while blocked on the client. The line is collected as
+// bytes and decoded once complete: `read_line` drops everything read in a call that
+// times out in the middle of a multibyte character.
+pub(crate) fn read_session_line<R>(
+    reader: &mut R,
+    line: &mut String,
+    shutdown: Option<&SessionShutdown<'_>>,
+    transaction_active: bool,
+) -> io::Result<LineRead>
+where
+    R: BufRead,
+{
+    let mut bytes = Vec::new();
+    let read = read_session_bytes(reader, &mut bytes, shutdown, transaction_active)?;
+    if read == LineRead::Line {
+        let decoded = String::from_utf8(bytes).map_err(|_| {
+            io::Error::new(ErrorKind::InvalidData, "stream did not contain valid UTF-8")
+        })?;
+        line.push_str(&decoded);
+    }
+    Ok(read)
+}
+
+// DATA lines are relayed as raw bytes so 8BITMIME bodies that are not valid UTF-8
+// survive the relay path unchanged.
+pub(crate) fn read_session_bytes<R>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+    shutdown: Option<&SessionShutdown<'_>>,
+    transaction_active: bool,
+) -> io::Result<LineRead>
+where
+    R: BufRead,
+{
+    loop {
+        match reader.read_until(b'\n', line) {
+            Ok(_) if line.is_empty() => return Ok(LineRead::Closed),
+            Ok(_) => return Ok(LineRead::Line),
+            Err(error) if is_poll_timeout(&error) && shutdown.is_some() => {
+                let busy = transaction_active || !line.is_empty();
+                if shutdown.is_some_and(|shutdown| shutdown.should_stop(busy)) {
+                    return Ok(LineRead::Shutdown);
+                }
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+pub(crate) fn is_poll_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+pub(crate) fn shutdown_interrupted_error() -> io::Error {
+    io::Error::new(
+        ErrorKind::Interrupted,
+        "shutdown grace period expired before the transaction completed",
+    )
+}
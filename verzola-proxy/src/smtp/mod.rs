@@ -0,0 +1,789 @@
+use std::fmt::{Display, Formatter};
+use std::io::{self, BufRead, ErrorKind, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// Framing and grammar from RFC 5321, shared by the inbound and outbound listeners.
+// Parse errors carry the reply the client should get.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Helo(String),
+    Ehlo(String),
+    Mail(MailFrom),
+    Rcpt(RcptTo),
+    Data,
+    Rset,
+    Noop,
+    Quit,
+    StartTls,
+    Auth(String),
+    Other { verb: String, argument: String },
+}
+
+impl Command {
+    // `smtputf8` is whether the current transaction declared SMTPUTF8, for RCPT.
+    pub fn parse(line: &str, smtputf8: bool) -> Result<Self, CommandError> {
+        let (verb, argument) = split_command(line.trim_end_matches(['\r', '\n']));
+        let command = match verb.as_str() {
+            "HELO" => Command::Helo(argument.to_string()),
+            "EHLO" => Command::Ehlo(argument.to_string()),
+            "MAIL" => Command::Mail(MailFrom::parse(argument)?),
+            "RCPT" => Command::Rcpt(RcptTo::parse(argument, smtputf8)?),
+            "AUTH" => Command::Auth(argument.to_string()),
+            "DATA" | "RSET" | "QUIT" | "STARTTLS" if !argument.is_empty() => {
+                return Err(CommandError::syntax("5.5.4 Syntax error, no parameters allowed"));
+            }
+            "DATA" => Command::Data,
+            "RSET" => Command::Rset,
+            "QUIT" => Command::Quit,
+            "STARTTLS" => Command::StartTls,
+            // NOOP may carry a string, which is ignored (RFC 5321 4.1.1.9).
+            "NOOP" => Command::Noop,
+            _ => Command::Other {
+                verb,
+                argument: argument.to_string(),
+            },
+        };
+        Ok(command)
+    }
+
+    pub fn verb(&self) -> &str {
+        match self {
+            Command::Helo(_) => "HELO",
+            Command::Ehlo(_) => "EHLO",
+            Command::Mail(_) => "MAIL",
+            Command::Rcpt(_) => "RCPT",
+            Command::Data => "DATA",
+            Command::Rset => "RSET",
+            Command::Noop => "NOOP",
+            Command::Quit => "QUIT",
+            Command::StartTls => "STARTTLS",
+            Command::Auth(_) => "AUTH",
+            Command::Other { verb, .. } => verb,
+        }
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Helo(argument) | Command::Ehlo(argument) | Command::Auth(argument) => {
+                write!(formatter, "{} {}", self.verb(), argument)
+            }
+            Command::Mail(mail) => write!(formatter, "MAIL {}", mail),
+            Command::Rcpt(rcpt) => write!(formatter, "RCPT {}", rcpt),
+            Command::Other { verb, argument } if !argument.is_empty() => {
+                write!(formatter, "{} {}", verb, argument)
+            }
+            _ => write!(formatter, "{}", self.verb()),
+        }
+    }
+}
+
+// The keyword is upper-cased; the value is kept as sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EsmtpParameter {
+    pub keyword: String,
+    pub value: Option<String>,
+}
+
+impl EsmtpParameter {
+    fn parse(token: &str) -> Option<Self> {
+        let (keyword, value) = match token.split_once('=') {
+            Some((keyword, value)) => (keyword, Some(value)),
+            None => (token, None),
+        };
+
+        // esmtp-keyword = (ALPHA / DIGIT) *(ALPHA / DIGIT / "-")
+        let keyword_valid = keyword
+            .bytes()
+            .next()
+            .is_some_and(|byte| byte.is_ascii_alphanumeric())
+            && keyword
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-');
+        // esmtp-value = 1*(%d33-60 / %d62-126), plus UTF-8 under SMTPUTF8 (RFC 6531).
+        let value_valid = value.is_none_or(|value| {
+            !value.is_empty()
+                && value
+                    .chars()
+                    .all(|character| !character.is_control() && character != '=')
+        });
+        if !keyword_valid || !value_valid {
+            return None;
+        }
+
+        Some(Self {
+            keyword: keyword.to_ascii_uppercase(),
+            value: value.map(str::to_string),
+        })
+    }
+}
+
+impl Display for EsmtpParameter {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.value {
+            Some(value) => write!(formatter, "{}={}", self.keyword, value),
+            None => write!(formatter, "{}", self.keyword),
+        }
+    }
+}
+
+// `reverse_path` is None for the null sender `<>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailFrom {
+    pub reverse_path: Option<Mailbox>,
+    pub parameters: Vec<EsmtpParameter>,
+}
+
+impl MailFrom {
+    pub fn parse(argument: &str) -> Result<Self, CommandError> {
+        let rest = strip_prefix_ignore_case(argument.trim(), "FROM:")
+            .ok_or(CommandError::syntax("5.5.4 MAIL requires FROM:<address>"))?;
+        let (path, parameters) = parse_path_and_parameters(rest).map_err(|error| match error {
+            PathError::Path => CommandError::syntax(BAD_SENDER_MESSAGE),
+            PathError::Parameters => CommandError::syntax("5.5.4 Invalid MAIL parameters"),
+        })?;
+        let smtputf8 = match find_parameter(&parameters, "SMTPUTF8") {
+            Some(parameter) if parameter.value.is_some() => {
+                return Err(CommandError::syntax("5.5.4 Invalid MAIL parameters"));
+            }
+            Some(_) => true,
+            None => false,
+        };
+
+        let reverse_path = if path.is_empty() {
+            None
+        } else {
+            let mailbox =
+                Mailbox::parse(path).ok_or(CommandError::syntax(BAD_SENDER_MESSAGE))?;
+            if mailbox.requires_smtputf8() && !smtputf8 {
+                return Err(CommandError::new(
+                    553,
+                    "5.6.7 Non-ASCII sender address requires SMTPUTF8",
+                ));
+            }
+            Some(mailbox)
+        };
+        Ok(Self {
+            reverse_path,
+            parameters,
+        })
+    }
+
+    pub fn parameter(&self, keyword: &str) -> Option<&EsmtpParameter> {
+        find_parameter(&self.parameters, keyword)
+    }
+
+    pub fn smtputf8(&self) -> bool {
+        self.parameter("SMTPUTF8").is_some()
+    }
+
+    // Empty for the null sender.
+    pub fn address(&self) -> String {
+        self.reverse_path
+            .as_ref()
+            .map(Mailbox::to_string)
+            .unwrap_or_default()
+    }
+}
+
+impl Display for MailFrom {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "FROM:<{}>", self.address())?;
+        write_parameters(formatter, &self.parameters)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RcptTo {
+    pub forward_path: Mailbox,
+    pub parameters: Vec<EsmtpParameter>,
+}
+
+impl RcptTo {
+    // `smtputf8` is whether the transaction's MAIL command carried SMTPUTF8.
+    pub fn parse(argument: &str, smtputf8: bool) -> Result<Self, CommandError> {
+        let rest = strip_prefix_ignore_case(argument.trim(), "TO:")
+            .ok_or(CommandError::syntax("5.5.4 RCPT requires TO:<address>"))?;
+        let (path, parameters) = parse_path_and_parameters(rest).map_err(|error| match error {
+            PathError::Path => CommandError::syntax(BAD_RECIPIENT_MESSAGE),
+            PathError::Parameters => CommandError::syntax("5.5.4 Invalid RCPT parameters"),
+        })?;
+
+        // RFC 5321 4.5.1: Postmaster without a domain must always be accepted.
+        let forward_path = if path.eq_ignore_ascii_case("postmaster") {
+            Mailbox {
+                local_part: path.to_string(),
+                domain: None,
+            }
+        } else {
+            Mailbox::parse(path).ok_or(CommandError::syntax(BAD_RECIPIENT_MESSAGE))?
+        };
+        if forward_path.requires_smtputf8() && !smtputf8 {
+            return Err(CommandError::new(
+                553,
+                "5.6.7 Non-ASCII recipient address requires SMTPUTF8",
+            ));
+        }
+        Ok(Self {
+            forward_path,
+            parameters,
+        })
+    }
+
+    pub fn parameter(&self, keyword: &str) -> Option<&EsmtpParameter> {
+        find_parameter(&self.parameters, keyword)
+    }
+
+    pub fn address(&self) -> String {
+        self.forward_path.to_string()
+    }
+}
+
+impl Display for RcptTo {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "TO:<{}>", self.forward_path)?;
+        write_parameters(formatter, &self.parameters)
+    }
+}
+
+const BAD_SENDER_MESSAGE: &str = "5.1.7 Bad sender address syntax";
+const BAD_RECIPIENT_MESSAGE: &str = "5.1.3 Bad recipient address syntax";
+
+// The reply a malformed command gets, e.g. 501 and "5.1.3 Bad recipient address syntax".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandError {
+    pub code: u16,
+    pub message: &'static str,
+}
+
+impl CommandError {
+    pub fn new(code: u16, message: &'static str) -> Self {
+        Self { code, message }
+    }
+
+    fn syntax(message: &'static str) -> Self {
+        Self::new(501, message)
+    }
+}
+
+impl Display for CommandError {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "{} {}", self.code, self.message)
+    }
+}
+
+// RFC 5321 4.5.3.1 size limits, in octets.
+const MAX_LOCAL_PART_LEN: usize = 64;
+const MAX_DOMAIN_LEN: usize = 255;
+const MAX_PATH_LEN: usize = 256;
+
+// `local_part` is kept as sent, quotes included. `domain` is only None for the
+// `<Postmaster>` recipient.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mailbox {
+    pub local_part: String,
+    pub domain: Option<MailboxDomain>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MailboxDomain {
+    Name(String),
+    AddressLiteral(IpAddr),
+}
+
+impl Mailbox {
+    // Mailbox = Local-part "@" ( Domain / address-literal ), with the UTF-8 extensions
+    // of RFC 6531 for local parts and U-labels.
+    pub fn parse(value: &str) -> Option<Self> {
+        if value.len() > MAX_PATH_LEN - 2 {
+            return None;
+        }
+
+        let at = if value.starts_with('"') {
+            closing_quote(value)? + 1
+        } else {
+            value.find('@')?
+        };
+        let (local_part, domain) = (&value[..at], value[at..].strip_prefix('@')?);
+        if local_part.len() > MAX_LOCAL_PART_LEN || !is_local_part(local_part) {
+            return None;
+        }
+
+        Some(Self {
+            local_part: local_part.to_string(),
+            domain: Some(MailboxDomain::parse(domain)?),
+        })
+    }
+
+    pub fn requires_smtputf8(&self) -> bool {
+        !self.local_part.is_ascii()
+            || matches!(&self.domain, Some(MailboxDomain::Name(name)) if !name.is_ascii())
+    }
+
+    pub fn domain_name(&self) -> Option<&str> {
+        match &self.domain {
+            Some(MailboxDomain::Name(name)) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Mailbox {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.domain {
+            Some(domain) => write!(formatter, "{}@{}", self.local_part, domain),
+            None => write!(formatter, "{}", self.local_part),
+        }
+    }
+}
+
+impl MailboxDomain {
+    fn parse(value: &str) -> Option<Self> {
+        let Some(literal) = value.strip_prefix('[') else {
+            return is_domain(value).then(|| MailboxDomain::Name(value.to_string()));
+        };
+
+        // address-literal = "[" ( IPv4-address-literal / IPv6-address-literal ) "]"
+        let literal = literal.strip_suffix(']')?;
+        let address = match strip_prefix_ignore_case(literal, "IPv6:") {
+            Some(ipv6) => IpAddr::V6(ipv6.parse::<Ipv6Addr>().ok()?),
+            None => IpAddr::V4(literal.parse::<Ipv4Addr>().ok()?),
+        };
+        Some(MailboxDomain::AddressLiteral(address))
+    }
+}
+
+impl Display for MailboxDomain {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MailboxDomain::Name(name) => write!(formatter, "{}", name),
+            MailboxDomain::AddressLiteral(IpAddr::V4(address)) => {
+                write!(formatter, "[{}]", address)
+            }
+            MailboxDomain::AddressLiteral(IpAddr::V6(address)) => {
+                write!(formatter, "[IPv6:{}]", address)
+            }
+        }
+    }
+}
+
+// Local-part = Dot-string / Quoted-string
+fn is_local_part(value: &str) -> bool {
+    if value.starts_with('"') {
+        return closing_quote(value) == Some(value.len() - 1) && is_quoted_string(value);
+    }
+    value.split('.').all(|atom| {
+        !atom.is_empty()
+            && atom
+                .chars()
+                .all(|character| !character.is_ascii() || is_atext(character))
+    })
+}
+
+fn is_atext(character: char) -> bool {
+    character.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(character)
+}
+
+// qtextSMTP = %d32-33 / %d35-91 / %d93-126, quoted-pairSMTP = "\" %d32-126
+fn is_quoted_string(value: &str) -> bool {
+    let inner = &value[1..value.len() - 1];
+    let mut characters = inner.chars();
+    while let Some(character) = characters.next() {
+        let valid = match character {
+            '\\' => characters.next().is_some_and(|escaped| (' '..='~').contains(&escaped)),
+            '"' => false,
+            _ => !character.is_ascii() || (' '..='~').contains(&character),
+        };
+        if !valid {
+            return false;
+        }
+    }
+    true
+}
+
+// Index of the quote that closes a quoted string starting at index 0.
+fn closing_quote(value: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (index, character) in value.char_indices().skip(1) {
+        match character {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+// Domain = sub-domain *("." sub-domain); U-labels are allowed (RFC 6531).
+fn is_domain(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_DOMAIN_LEN
+        && value.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|character| {
+                    !character.is_ascii() || character.is_ascii_alphanumeric() || character == '-'
+                })
+        })
+}
+
+enum PathError {
+    Path,
+    Parameters,
+}
+
+// Lenient where real clients are: a space after the colon and a path without angle
+// brackets are both accepted, as Postfix does.
+fn parse_path_and_parameters(rest: &str) -> Result<(&str, Vec<EsmtpParameter>), PathError> {
+    let rest = rest.trim_start();
+    let (path, parameters) = match rest.strip_prefix('<') {
+        Some(inner) => {
+            let end = closing_bracket(inner).ok_or(PathError::Path)?;
+            let parameters = &inner[end + 1..];
+            if !parameters.is_empty() && !parameters.starts_with(' ') {
+                return Err(PathError::Path);
+            }
+            (&inner[..end], parameters)
+        }
+        None => {
+            let end = rest.find(' ').unwrap_or(rest.len());
+            if rest[..end].is_empty() || rest[..end].contains(['<', '>']) {
+                return Err(PathError::Path);
+            }
+            (&rest[..end], &rest[end..])
+        }
+    };
+
+    // A source route (`@relay1,@relay2:user@example.com`) must be accepted and should
+    // be ignored (RFC 5321 4.1.2 and appendix C).
+    let path = match path.strip_prefix('@') {
+        Some(route) => {
+            let (route, mailbox) = route.split_once(':').ok_or(PathError::Path)?;
+            let route_valid = route.split(',').enumerate().all(|(index, hop)| {
+                let hop = if index == 0 { Some(hop) } else { hop.strip_prefix('@') };
+                hop.is_some_and(|hop| MailboxDomain::parse(hop).is_some())
+            });
+            if !route_valid {
+                return Err(PathError::Path);
+            }
+            mailbox
+        }
+        None => path,
+    };
+
+    let mut parsed = Vec::new();
+    for token in parameters.split(' ').filter(|token| !token.is_empty()) {
+        let parameter = EsmtpParameter::parse(token).ok_or(PathError::Parameters)?;
+        if find_parameter(&parsed, &parameter.keyword).is_some() {
+            return Err(PathError::Parameters);
+        }
+        parsed.push(parameter);
+    }
+
+    Ok((path, parsed))
+}
+
+// Index of the `>` that closes the path; a quoted local part may contain one.
+fn closing_bracket(inner: &str) -> Option<usize> {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, character) in inner.char_indices() {
+        match character {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '>' if !quoted => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn find_parameter<'a>(
+    parameters: &'a [EsmtpParameter],
+    keyword: &str,
+) -> Option<&'a EsmtpParameter> {
+    parameters
+        .iter()
+        .find(|parameter| parameter.keyword.eq_ignore_ascii_case(keyword))
+}
+
+fn write_parameters(
+    formatter: &mut Formatter<'_>,
+    parameters: &[EsmtpParameter],
+) -> std::fmt::Result {
+    for parameter in parameters {
+        write!(formatter, " {}", parameter)?;
+    }
+    Ok(())
+}
+
+fn strip_prefix_ignore_case<'a>(value: &'a str, prefix: &str) -> Option<&'a str> {
+    match value.get(..prefix.len()) {
+        Some(head) if head.eq_ignore_ascii_case(prefix) => Some(&value[prefix.len()..]),
+        _ => None,
+    }
+}
+
+// RFC 3463: class.subject.detail, e.g. 4.7.1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnhancedStatusCode {
+    pub class: u8,
+    pub subject: u16,
+    pub detail: u16,
+}
+
+impl EnhancedStatusCode {
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split('.');
+        let class = parts.next()?;
+        let subject = parse_status_number(parts.next()?)?;
+        let detail = parse_status_number(parts.next()?)?;
+        if parts.next().is_some() {
+            return None;
+        }
+
+        let class = match class {
+            "2" => 2,
+            "4" => 4,
+            "5" => 5,
+            _ => return None,
+        };
+        Some(Self {
+            class,
+            subject,
+            detail,
+        })
+    }
+}
+
+impl Display for EnhancedStatusCode {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
+}
+
+fn parse_status_number(value: &str) -> Option<u16> {
+    if value.is_empty() || value.len() > 3 || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+// `lines` holds the text of each reply line, without the code and separator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub code: u16,
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    pub fn new(code: u16, text: &str) -> Self {
+        Self {
+            code,
+            lines: vec![text.to_string()],
+        }
+    }
+
+    pub fn multiline(code: u16, lines: Vec<String>) -> Self {
+        if lines.is_empty() {
+            return Self::new(code, "OK");
+        }
+        Self { code, lines }
+    }
+
+    pub fn read_from<R: BufRead>(reader: &mut R) -> io::Result<Self> {
+        let mut lines = Vec::new();
+        let mut code: Option<u16> = None;
+
+        loop {
+            let mut raw = String::new();
+            if reader.read_line(&mut raw)? == 0 {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "peer closed connection while reading SMTP reply",
+                ));
+            }
+
+            // Reply-code = %x32-35 %x30-35 %x30-39
+            let line = raw.trim_end_matches(['\r', '\n']);
+            let line_bytes = line.as_bytes();
+            let code_valid = line_bytes.len() >= 3
+                && (b'2'..=b'5').contains(&line_bytes[0])
+                && (b'0'..=b'5').contains(&line_bytes[1])
+                && line_bytes[2].is_ascii_digit();
+            if !code_valid {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid SMTP reply line: {}", line),
+                ));
+            }
+
+            let parsed_code = line[..3].parse::<u16>().map_err(|error| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid SMTP reply code: {}", error),
+                )
+            })?;
+            if let Some(expected_code) = code {
+                if expected_code != parsed_code {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "inconsistent SMTP reply codes in multiline response: expected {}, \
+                             got {}",
+                            expected_code, parsed_code
+                        ),
+                    ));
+                }
+            } else {
+                code = Some(parsed_code);
+            }
+
+            let separator = line_bytes.get(3).copied().unwrap_or(b' ');
+            lines.push(line.get(4..).unwrap_or("").to_string());
+            match separator {
+                b' ' => break,
+                b'-' => {}
+                _ => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "invalid SMTP multiline reply separator",
+                    ));
+                }
+            }
+        }
+
+        Ok(Self {
+            code: code.unwrap_or(500),
+            lines,
+        })
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for line in self.wire_lines() {
+            write!(writer, "{}\r\n", line)?;
+        }
+        writer.flush()
+    }
+
+    // Lines as sent, e.g. "250-PIPELINING" and "250 SIZE 10240000".
+    pub fn wire_lines(&self) -> impl Iterator<Item = String> + '_ {
+        self.lines.iter().enumerate().map(|(index, text)| {
+            let separator = if index + 1 == self.lines.len() { ' ' } else { '-' };
+            format!("{}{}{}", self.code, separator, text)
+        })
+    }
+
+    pub fn last_line(&self) -> String {
+        format!("{} {}", self.code, self.lines.last().map(String::as_str).unwrap_or(""))
+    }
+
+    // For logs and error messages.
+    pub fn summary(&self) -> String {
+        self.wire_lines().collect::<Vec<_>>().join(" | ")
+    }
+
+    // Only trusted when its class agrees with the reply code (RFC 2034).
+    pub fn enhanced_status(&self) -> Option<EnhancedStatusCode> {
+        let first_word = self.lines.first()?.split(' ').next()?;
+        EnhancedStatusCode::parse(first_word)
+            .filter(|status| u16::from(status.class) == self.code / 100)
+    }
+}
+
+// A parsed EHLO (or LHLO) reply. Keywords are upper-cased; parameters keep their case.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EhloCapabilities {
+    pub domain: String,
+    pub extensions: Vec<(String, Vec<String>)>,
+}
+
+impl EhloCapabilities {
+    pub fn from_reply(reply: &Reply) -> Self {
+        let mut lines = reply.lines.iter();
+        let domain = lines
+            .next()
+            .and_then(|greeting| greeting.split_whitespace().next())
+            .unwrap_or("")
+            .to_string();
+
+        let mut extensions: Vec<(String, Vec<String>)> = Vec::new();
+        for line in lines {
+            let mut words = line.split_whitespace();
+            let Some(keyword) = words.next() else {
+                continue;
+            };
+            let keyword = keyword.to_ascii_uppercase();
+            if extensions.iter().any(|(known, _)| *known == keyword) {
+                continue;
+            }
+            extensions.push((keyword, words.map(str::to_string).collect()));
+        }
+
+        Self { domain, extensions }
+    }
+
+    pub fn has(&self, keyword: &str) -> bool {
+        self.parameters(keyword).is_some()
+    }
+
+    pub fn parameters(&self, keyword: &str) -> Option<&[String]> {
+        self.extensions
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(keyword))
+            .map(|(_, parameters)| parameters.as_slice())
+    }
+
+    pub fn has_parameter(&self, keyword: &str, parameter: &str) -> bool {
+        self.parameters(keyword).is_some_and(|parameters| {
+            parameters
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(parameter))
+        })
+    }
+
+    // Outer None: SIZE not offered. Inner None: offered without a limit (RFC 1870).
+    pub fn size_limit(&self) -> Option<Option<u64>> {
+        let parameters = self.parameters("SIZE")?;
+        Some(
+            parameters
+                .first()
+                .and_then(|value| value.parse::<u64>().ok())
+                .filter(|size| *size > 0),
+        )
+    }
+}
+
+pub fn split_command(line: &str) -> (String, &str) {
+    let mut parts = line.splitn(2, |character: char| character.is_whitespace());
+    let verb = parts.next().unwrap_or("").trim().to_ascii_uppercase();
+    let argument = parts.next().unwrap_or("").trim();
+    (verb, argument)
+}
+
+pub fn is_data_terminator(line: &[u8]) -> bool {
+    line == b".\r\n" || line == b".\n"
+}
+
+pub fn write_command_line<W: Write>(writer: &mut W, line: &str) -> io::Result<()> {
+    write!(writer, "{}\r\n", line)?;
+    writer.flush()
+}
+
+pub fn write_reply<W: Write>(writer: &mut W, code: u16, message: &str) -> io::Result<()> {
+    Reply::new(code, message).write_to(writer)
+}
+
+pub fn write_multiline_reply<W: Write>(
+    writer: &mut W,
+    code: u16,
+    lines: &[String],
+) -> io::Result<()> {
+    Reply::multiline(code, lines.to_vec()).write_to(writer)
+}
@@ -0,0 +1,444 @@
+use std::collections::HashSet;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+
+use crate::auth::Authenticator;
+use crate::inbound::{InboundListener, ListenerConfig, TlsUpgrader};
+use crate::logging::JsonLogger;
+use crate::metrics::MetricsRegistry;
+use crate::outbound::{MxResolver, OutboundListener, OutboundListenerConfig};
+use crate::shutdown::{ShutdownReport, ShutdownSignal};
+use crate::telemetry::{JsonLogSink, TelemetrySink};
+use crate::tls_policy_map::PostfixTlsPolicyMap;
+
+#[derive(Debug, Clone)]
+pub enum ProfileListener {
+    Inbound(ListenerConfig),
+    Outbound(OutboundListenerConfig),
+}
+
+impl ProfileListener {
+    fn default_bind_addr(&self) -> SocketAddr {
+        match self {
+            ProfileListener::Inbound(config) => config.bind_addr,
+            ProfileListener::Outbound(config) => config.bind_addr,
+        }
+    }
+
+    fn with_bind_addr(&self, bind_addr: SocketAddr) -> Self {
+        match self {
+            ProfileListener::Inbound(config) => ProfileListener::Inbound(ListenerConfig {
+                bind_addr,
+                ..config.clone()
+            }),
+            ProfileListener::Outbound(config) => {
+                ProfileListener::Outbound(OutboundListenerConfig {
+                    bind_addr,
+                    ..config.clone()
+                })
+            }
+        }
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        match self {
+            ProfileListener::Inbound(config) => config.validate(),
+            ProfileListener::Outbound(config) => config.validate(),
+        }
+    }
+}
+
+// `bind_addrs` overrides the listener config's own `bind_addr`, so one profile can
+// listen on IPv4 and IPv6 with identical policy.
+#[derive(Debug, Clone)]
+pub struct ListenerProfile {
+    pub name: String,
+    pub bind_addrs: Vec<SocketAddr>,
+    pub listener: ProfileListener,
+}
+
+impl ListenerProfile {
+    pub fn inbound(name: impl Into<String>, config: ListenerConfig) -> Self {
+        Self {
+            name: name.into(),
+            bind_addrs: Vec::new(),
+            listener: ProfileListener::Inbound(config),
+        }
+    }
+
+    pub fn outbound(name: impl Into<String>, config: OutboundListenerConfig) -> Self {
+        Self {
+            name: name.into(),
+            bind_addrs: Vec::new(),
+            listener: ProfileListener::Outbound(config),
+        }
+    }
+
+    pub fn with_bind_addrs(mut self, bind_addrs: Vec<SocketAddr>) -> Self {
+        self.bind_addrs = bind_addrs;
+        self
+    }
+
+    fn effective_bind_addrs(&self) -> Vec<SocketAddr> {
+        if self.bind_addrs.is_empty() {
+            vec![self.listener.default_bind_addr()]
+        } else {
+            self.bind_addrs.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SupervisorConfig {
+    pub profiles: Vec<ListenerProfile>,
+}
+
+impl SupervisorConfig {
+    pub fn validate(&self) -> io::Result<()> {
+        if self.profiles.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "supervisor needs at least one listener profile",
+            ));
+        }
+
+        let mut names = HashSet::new();
+        let mut bind_addrs = HashSet::new();
+        for profile in &self.profiles {
+            // Names become metrics labels and log fields, so keep them simple.
+            let valid_name = !profile.name.is_empty()
+                && profile
+                    .name
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.'));
+            if !valid_name {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "profile name {:?} must be non-empty and use only [A-Za-z0-9._-]",
+                        profile.name
+                    ),
+                ));
+            }
+            if !names.insert(profile.name.as_str()) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("duplicate profile name: {}", profile.name),
+                ));
+            }
+
+            for bind_addr in profile.effective_bind_addrs() {
+                profile
+                    .listener
+                    .with_bind_addr(bind_addr)
+                    .validate()
+                    .map_err(|error| {
+                        io::Error::new(
+                            error.kind(),
+                            format!("profile {} ({}): {}", profile.name, bind_addr, error),
+                        )
+                    })?;
+                // Port 0 asks the OS for a fresh port, so it can never collide.
+                if bind_addr.port() != 0 && !bind_addrs.insert(bind_addr) {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "bind address {} is used by more than one profile (second: {})",
+                            bind_addr, profile.name
+                        ),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileDirection {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BoundProfile {
+    pub name: String,
+    pub direction: ProfileDirection,
+    pub local_addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SupervisorReport {
+    pub listeners: Vec<(BoundProfile, ShutdownReport)>,
+}
+
+impl SupervisorReport {
+    pub fn total(&self) -> ShutdownReport {
+        self.listeners
+            .iter()
+            .fold(ShutdownReport::default(), |mut total, (_, report)| {
+                total.sessions_served += report.sessions_served;
+                total.sessions_failed += report.sessions_failed;
+                total.sessions_closed_by_shutdown += report.sessions_closed_by_shutdown;
+                total
+            })
+    }
+
+    pub fn profile(&self, name: &str) -> ShutdownReport {
+        SupervisorReport {
+            listeners: self
+                .listeners
+                .iter()
+                .filter(|(bound, _)| bound.name == name)
+                .cloned()
+                .collect(),
+        }
+        .total()
+    }
+}
+
+enum SupervisedListener<U, R>
+where
+    U: TlsUpgrader,
+    R: MxResolver,
+{
+    Inbound(InboundListener<Arc<U>>),
+    Outbound(OutboundListener<Arc<R>>),
+}
+
+impl<U, R> SupervisedListener<U, R>
+where
+    U: TlsUpgrader,
+    R: MxResolver,
+{
+    fn with_metrics(self, registry: Arc<MetricsRegistry>, name: &str) -> Self {
+        match self {
+            SupervisedListener::Inbound(listener) => {
+                SupervisedListener::Inbound(listener.with_metrics(registry, name))
+            }
+            SupervisedListener::Outbound(listener) => {
+                SupervisedListener::Outbound(listener.with_metrics(registry, name))
+            }
+        }
+    }
+
+    fn with_telemetry_sink(self, sink: Arc<dyn TelemetrySink>) -> Self {
+        match self {
+            SupervisedListener::Inbound(listener) => {
+                SupervisedListener::Inbound(listener.with_telemetry_sink(sink))
+            }
+            SupervisedListener::Outbound(listener) => {
+                SupervisedListener::Outbound(listener.with_telemetry_sink(sink))
+            }
+        }
+    }
+
+    fn ensure_authenticator(&self) -> io::Result<()> {
+        match self {
+            SupervisedListener::Inbound(listener) => listener.ensure_authenticator(),
+            SupervisedListener::Outbound(_) => Ok(()),
+        }
+    }
+
+    fn serve_until_shutdown(&self, shutdown: &ShutdownSignal) -> io::Result<ShutdownReport> {
+        match self {
+            SupervisedListener::Inbound(listener) => listener.serve_until_shutdown(shutdown),
+            SupervisedListener::Outbound(listener) => listener.serve_until_shutdown(shutdown),
+        }
+    }
+}
+
+// Runs every profile from one process. Inbound profiles share the TLS upgrader and
+// outbound profiles share the MX resolver; each listener socket gets its own accept
+// thread but all of them stop on the same `ShutdownSignal`.
+pub struct Supervisor<U, R>
+where
+    U: TlsUpgrader,
+    R: MxResolver,
+{
+    listeners: Vec<(BoundProfile, SupervisedListener<U, R>)>,
+}
+
+impl<U, R> Supervisor<U, R>
+where
+    U: TlsUpgrader,
+    R: MxResolver,
+{
+    pub fn bind(config: SupervisorConfig, tls_upgrader: U, resolver: R) -> io::Result<Self> {
+        config.validate()?;
+        let tls_upgrader = Arc::new(tls_upgrader);
+        let resolver = Arc::new(resolver);
+
+        let mut listeners = Vec::new();
+        for profile in &config.profiles {
+            for bind_addr in profile.effective_bind_addrs() {
+                let bind_error = |error: io::Error| {
+                    io::Error::new(
+                        error.kind(),
+                        format!("profile {} could not bind {}: {}", profile.name, bind_addr, error),
+                    )
+                };
+                let (direction, listener) = match profile.listener.with_bind_addr(bind_addr) {
+                    ProfileListener::Inbound(config) => {
+                        let listener = InboundListener::bind(config, Arc::clone(&tls_upgrader))
+                            .map_err(bind_error)?
+                            .with_name(profile.name.clone());
+                        (ProfileDirection::Inbound, SupervisedListener::Inbound(listener))
+                    }
+                    ProfileListener::Outbound(config) => {
+                        let listener = OutboundListener::bind(config, Arc::clone(&resolver))
+                            .map_err(bind_error)?
+                            .with_name(profile.name.clone());
+                        (ProfileDirection::Outbound, SupervisedListener::Outbound(listener))
+                    }
+                };
+                let local_addr = match &listener {
+                    SupervisedListener::Inbound(listener) => listener.local_addr()?,
+                    SupervisedListener::Outbound(listener) => listener.local_addr()?,
+                };
+                listeners.push((
+                    BoundProfile {
+                        name: profile.name.clone(),
+                        direction,
+                        local_addr,
+                    },
+                    listener,
+                ));
+            }
+        }
+
+        Ok(Self { listeners })
+    }
+
+    // Metrics are labelled with the profile name.
+    pub fn with_metrics(mut self, registry: Arc<MetricsRegistry>) -> Self {
+        self.listeners = self
+            .listeners
+            .into_iter()
+            .map(|(bound, listener)| {
+                let listener = listener.with_metrics(Arc::clone(&registry), &bound.name);
+                (bound, listener)
+            })
+            .collect();
+        self
+    }
+
+    pub fn with_json_logger(self, logger: Arc<JsonLogger>) -> Self {
+        self.with_telemetry_sink(Arc::new(JsonLogSink::new(logger)))
+    }
+
+    pub fn with_telemetry_sink(mut self, sink: Arc<dyn TelemetrySink>) -> Self {
+        self.listeners = self
+            .listeners
+            .into_iter()
+            .map(|(bound, listener)| (bound, listener.with_telemetry_sink(Arc::clone(&sink))))
+            .collect();
+        self
+    }
+
+    // Only used by inbound profiles in submission mode.
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.listeners = self
+            .listeners
+            .into_iter()
+            .map(|(bound, listener)| match listener {
+                SupervisedListener::Inbound(listener) => (
+                    bound,
+                    SupervisedListener::Inbound(
+                        listener.with_authenticator(Arc::clone(&authenticator)),
+                    ),
+                ),
+                outbound => (bound, outbound),
+            })
+            .collect();
+        self
+    }
+
+    // Only used by outbound profiles; every one of them shares the same table.
+    pub fn with_tls_policy_map(mut self, policy_map: Arc<PostfixTlsPolicyMap>) -> Self {
+        self.listeners = self
+            .listeners
+            .into_iter()
+            .map(|(bound, listener)| match listener {
+                SupervisedListener::Outbound(listener) => (
+                    bound,
+                    SupervisedListener::Outbound(
+                        listener.with_tls_policy_map(Arc::clone(&policy_map)),
+                    ),
+                ),
+                inbound => (bound, inbound),
+            })
+            .collect();
+        self
+    }
+
+    pub fn bound_profiles(&self) -> Vec<BoundProfile> {
+        self.listeners.iter().map(|(bound, _)| bound.clone()).collect()
+    }
+
+    pub fn local_addrs(&self, profile: &str) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter(|(bound, _)| bound.name == profile)
+            .map(|(bound, _)| bound.local_addr)
+            .collect()
+    }
+
+    // A listener that fails requests shutdown for the rest, so the process never keeps
+    // running with a profile silently missing.
+    pub fn serve_until_shutdown(&self, shutdown: &ShutdownSignal) -> io::Result<SupervisorReport> {
+        // Checked up front so no profile starts serving next to one that cannot.
+        for (bound, listener) in &self.listeners {
+            listener.ensure_authenticator().map_err(|error| {
+                io::Error::new(
+                    error.kind(),
+                    format!("profile {} ({}): {}", bound.name, bound.local_addr, error),
+                )
+            })?;
+        }
+
+        let results = thread::scope(|scope| {
+            let handles = self
+                .listeners
+                .iter()
+                .map(|(bound, listener)| {
+                    let handle = scope.spawn(move || {
+                        let result = listener.serve_until_shutdown(shutdown);
+                        if result.is_err() {
+                            shutdown.request();
+                        }
+                        result
+                    });
+                    (bound, handle)
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .map(|(bound, handle)| {
+                    let result = handle
+                        .join()
+                        .map_err(|_| io::Error::other("listener thread panicked"))
+                        .and_then(|result| result);
+                    (bound, result)
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let mut report = SupervisorReport::default();
+        for (bound, result) in results {
+            let listener_report = result.map_err(|error| {
+                io::Error::new(
+                    error.kind(),
+                    format!("profile {} ({}): {}", bound.name, bound.local_addr, error),
+                )
+            })?;
+            report.listeners.push((bound.clone(), listener_report));
+        }
+
+        Ok(report)
+    }
+}
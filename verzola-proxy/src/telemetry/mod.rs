@@ -0,0 +1,210 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::inbound::{SessionSummary, TlsSessionParameters};
+use crate::logging::{self, DeliveryAttempt, JsonLogger, SessionDirection};
+use crate::metrics::MetricsRegistry;
+use crate::outbound::OutboundSessionSummary;
+
+pub trait TelemetrySink: Send + Sync {
+    fn on_event(&self, context: &SessionContext, event: &TelemetryEvent);
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionContext {
+    pub direction: SessionDirection,
+    pub session_id: String,
+    pub client_addr: Option<SocketAddr>,
+    pub listener: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TelemetryEvent {
+    Connected,
+    Ehlo {
+        helo_name: String,
+        starttls_offered: bool,
+    },
+    StartTls(StartTlsResult),
+    ImplicitTls(StartTlsResult),
+    Mail {
+        sender: String,
+        reply_code: u16,
+    },
+    Recipient {
+        recipient: String,
+        reply_code: u16,
+    },
+    TlsRequired {
+        command: String,
+    },
+    Auth {
+        mechanism: &'static str,
+        succeeded: bool,
+    },
+    ProtocolError {
+        reply_code: u16,
+    },
+    Data(Box<DeliveryAttempt>),
+    RelayFailure {
+        stage: &'static str,
+        reason: String,
+    },
+    Disconnected(Box<SessionReport>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartTlsResult {
+    Negotiated(Option<TlsSessionParameters>),
+    Refused { reply_code: u16 },
+    Failed { reason: String },
+    PlaintextFallback,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionReport {
+    Inbound(SessionSummary),
+    Outbound(OutboundSessionSummary),
+}
+
+pub struct MetricsSink {
+    registry: Arc<MetricsRegistry>,
+    listener_name: String,
+}
+
+impl MetricsSink {
+    pub fn new(registry: Arc<MetricsRegistry>, listener_name: impl Into<String>) -> Self {
+        Self {
+            registry,
+            listener_name: listener_name.into(),
+        }
+    }
+}
+
+impl TelemetrySink for MetricsSink {
+    fn on_event(&self, _context: &SessionContext, event: &TelemetryEvent) {
+        if let TelemetryEvent::Disconnected(report) = event {
+            match report.as_ref() {
+                SessionReport::Inbound(summary) => {
+                    self.registry
+                        .record_inbound_session(&self.listener_name, summary);
+                }
+                SessionReport::Outbound(summary) => {
+                    self.registry
+                        .record_outbound_session(&self.listener_name, summary);
+                }
+            }
+        }
+    }
+}
+
+pub struct JsonLogSink {
+    logger: Arc<JsonLogger>,
+}
+
+impl JsonLogSink {
+    pub fn new(logger: Arc<JsonLogger>) -> Self {
+        Self { logger }
+    }
+}
+
+impl TelemetrySink for JsonLogSink {
+    // Write errors are dropped so a full disk never changes SMTP replies.
+    fn on_event(&self, _context: &SessionContext, event: &TelemetryEvent) {
+        match event {
+            TelemetryEvent::Data(attempt) => {
+                let _ = self.logger.log_delivery_attempt(attempt);
+            }
+            TelemetryEvent::Disconnected(report) => {
+                let _ = match report.as_ref() {
+                    SessionReport::Inbound(summary) => self.logger.log_inbound_session(summary),
+                    SessionReport::Outbound(summary) => self.logger.log_outbound_session(summary),
+                };
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedEvent {
+    pub context: SessionContext,
+    pub event: TelemetryEvent,
+}
+
+#[derive(Debug, Default)]
+pub struct TelemetryRecorder {
+    events: Mutex<Vec<RecordedEvent>>,
+}
+
+impl TelemetryRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn events(&self) -> Vec<RecordedEvent> {
+        self.lock().clone()
+    }
+
+    pub fn session_events(&self, session_id: &str) -> Vec<TelemetryEvent> {
+        self.lock()
+            .iter()
+            .filter(|recorded| recorded.context.session_id == session_id)
+            .map(|recorded| recorded.event.clone())
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<RecordedEvent>> {
+        self.events
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl TelemetrySink for TelemetryRecorder {
+    fn on_event(&self, context: &SessionContext, event: &TelemetryEvent) {
+        self.lock().push(RecordedEvent {
+            context: context.clone(),
+            event: event.clone(),
+        });
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct TelemetrySinks {
+    sinks: Vec<Arc<dyn TelemetrySink>>,
+    listener: Option<String>,
+}
+
+impl TelemetrySinks {
+    pub(crate) fn push(&mut self, sink: Arc<dyn TelemetrySink>) {
+        self.sinks.push(sink);
+    }
+
+    pub(crate) fn set_listener(&mut self, listener: String) {
+        self.listener = Some(listener);
+    }
+
+    pub(crate) fn session_context(
+        &self,
+        direction: SessionDirection,
+        client_addr: Option<SocketAddr>,
+    ) -> SessionContext {
+        SessionContext {
+            direction,
+            session_id: logging::new_session_id(),
+            client_addr,
+            listener: self.listener.clone(),
+        }
+    }
+
+    pub(crate) fn emit(&self, context: &SessionContext, event: &TelemetryEvent) {
+        for sink in &self.sinks {
+            sink.on_event(context, event);
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::outbound::{
+    OutboundDomainTlsPolicy, OutboundMxTlsPolicy, OutboundTlsPolicy, TlsPolicyRules,
+};
+
+// Attributes Postfix accepts after the security level. None of them change what VERZOLA
+// enforces, so they are reported and skipped.
+const POSTFIX_POLICY_ATTRIBUTES: [&str; 8] = [
+    "ciphers",
+    "connection_reuse",
+    "enable_rpk",
+    "exclude",
+    "match",
+    "protocols",
+    "servername",
+    "tafile",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticSeverity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsPolicyMapDiagnostic {
+    pub line: usize,
+    pub severity: DiagnosticSeverity,
+    pub message: String,
+}
+
+impl Display for TlsPolicyMapDiagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            DiagnosticSeverity::Warning => "warning",
+            DiagnosticSeverity::Error => "error",
+        };
+        write!(f, "line {}: {}: {}", self.line, severity, self.message)
+    }
+}
+
+// The result of reading a Postfix `smtp_tls_policy_maps` source file (the text `postmap`
+// compiles). Entries with errors are left out; entries with warnings are kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsPolicyMapEntries {
+    pub domain_policies: Vec<OutboundDomainTlsPolicy>,
+    pub mx_policies: Vec<OutboundMxTlsPolicy>,
+    pub diagnostics: Vec<TlsPolicyMapDiagnostic>,
+}
+
+impl TlsPolicyMapEntries {
+    pub fn parse(source: &str) -> Self {
+        let mut entries = Self::default();
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        for (line, text) in logical_lines(source, &mut entries.diagnostics) {
+            let Some(entry) = parse_entry(line, &text, &mut entries.diagnostics) else {
+                continue;
+            };
+
+            // postmap keeps the first of two entries with the same key.
+            if let Some(first_line) = seen.get(&entry.key()) {
+                entries.diagnostics.push(warning(
+                    line,
+                    format!(
+                        "duplicate entry for {}; the entry on line {} is kept",
+                        entry.key(),
+                        first_line
+                    ),
+                ));
+                continue;
+            }
+            seen.insert(entry.key(), line);
+            match entry {
+                MapEntry::Domain(rule) => entries.domain_policies.push(rule),
+                MapEntry::Mx(rule) => entries.mx_policies.push(rule),
+            }
+        }
+        entries
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &TlsPolicyMapDiagnostic> {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == DiagnosticSeverity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &TlsPolicyMapDiagnostic> {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == DiagnosticSeverity::Warning)
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.domain_policies.len() + self.mx_policies.len()
+    }
+}
+
+#[derive(Debug)]
+struct LoadedPolicyMap {
+    entries: TlsPolicyMapEntries,
+    rules: Arc<TlsPolicyRules>,
+}
+
+// A Postfix policy table attached to outbound listeners with `with_tls_policy_map`.
+#[derive(Debug)]
+pub struct PostfixTlsPolicyMap {
+    path: PathBuf,
+    loaded: RwLock<LoadedPolicyMap>,
+}
+
+impl PostfixTlsPolicyMap {
+    // Any error diagnostic fails the load: a skipped `secure` entry would silently weaken the
+    // policy for that destination.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let loaded = load_policy_map(&path)?;
+        Ok(Self {
+            path,
+            loaded: RwLock::new(loaded),
+        })
+    }
+
+    // On error the previously loaded rules stay in place.
+    pub fn reload(&self) -> io::Result<usize> {
+        let loaded = load_policy_map(&self.path)?;
+        let count = loaded.entries.rule_count();
+        *self
+            .loaded
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = loaded;
+        Ok(count)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn entries(&self) -> TlsPolicyMapEntries {
+        self.loaded
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entries
+            .clone()
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.loaded
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entries
+            .rule_count()
+    }
+
+    pub(crate) fn rules(&self) -> Arc<TlsPolicyRules> {
+        Arc::clone(
+            &self
+                .loaded
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .rules,
+        )
+    }
+}
+
+fn load_policy_map(path: &Path) -> io::Result<LoadedPolicyMap> {
+    let contents = fs::read_to_string(path)?;
+    let entries = TlsPolicyMapEntries::parse(&contents);
+    if entries.has_errors() {
+        let errors = entries
+            .errors()
+            .map(|error| format!("{}:{}: {}", path.display(), error.line, error.message))
+            .collect::<Vec<_>>();
+        return Err(io::Error::new(ErrorKind::InvalidData, errors.join("; ")));
+    }
+
+    let rules = TlsPolicyRules::build(&entries.domain_policies, &entries.mx_policies)
+        .map_err(|error| {
+            io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+        })?;
+    Ok(LoadedPolicyMap {
+        entries,
+        rules: Arc::new(rules),
+    })
+}
+
+enum MapEntry {
+    Domain(OutboundDomainTlsPolicy),
+    Mx(OutboundMxTlsPolicy),
+}
+
+impl MapEntry {
+    fn key(&self) -> String {
+        match self {
+            MapEntry::Domain(rule) => rule.recipient_domain.clone(),
+            MapEntry::Mx(rule) => format!("[{}]", rule.mx_hostname),
+        }
+    }
+}
+
+// postmap(1) input format: `#` starts a comment line, and a line starting with whitespace
+// continues the previous logical line. Yields the logical line with its first line number.
+fn logical_lines(
+    source: &str,
+    diagnostics: &mut Vec<TlsPolicyMapDiagnostic>,
+) -> Vec<(usize, String)> {
+    let mut lines: Vec<(usize, String)> = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if line.starts_with(char::is_whitespace) {
+            match lines.last_mut() {
+                Some((_, logical)) => {
+                    logical.push(' ');
+                    logical.push_str(trimmed);
+                }
+                None => diagnostics.push(error(
+                    index + 1,
+                    "continuation line without a preceding entry".to_string(),
+                )),
+            }
+            continue;
+        }
+        lines.push((index + 1, trimmed.to_string()));
+    }
+    lines
+}
+
+fn parse_entry(
+    line: usize,
+    text: &str,
+    diagnostics: &mut Vec<TlsPolicyMapDiagnostic>,
+) -> Option<MapEntry> {
+    parse_key_and_value(line, text, diagnostics)
+        .map_err(|message| diagnostics.push(error(line, message)))
+        .ok()
+}
+
+fn parse_key_and_value(
+    line: usize,
+    text: &str,
+    diagnostics: &mut Vec<TlsPolicyMapDiagnostic>,
+) -> Result<MapEntry, String> {
+    let (key, value) = text
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| format!("entry {} has no security level", text))?;
+    let mut fields = tokenize(value)?.into_iter();
+    let level = fields
+        .next()
+        .ok_or_else(|| format!("entry {} has no security level", key))?;
+    let policy = parse_level(line, &level, diagnostics)?;
+
+    for attribute in fields {
+        let Some((name, _)) = attribute.split_once('=') else {
+            return Err(format!("malformed attribute {}; expected name=value", attribute));
+        };
+        let name = name.trim().to_ascii_lowercase();
+        if !POSTFIX_POLICY_ATTRIBUTES.contains(&name.as_str()) {
+            return Err(format!("unknown attribute {}", name));
+        }
+        diagnostics.push(warning(
+            line,
+            format!("attribute {} is not supported and is ignored", name),
+        ));
+    }
+
+    parse_key(line, key, policy, diagnostics)
+}
+
+// Postfix 3.0+ also accepts `{ name = value with spaces }`.
+fn tokenize(value: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = value.trim_start();
+    while !rest.is_empty() {
+        let (token, remainder) = match rest.strip_prefix('{') {
+            Some(braced) => {
+                let end = braced
+                    .find('}')
+                    .ok_or_else(|| "unterminated { in attribute list".to_string())?;
+                (braced[..end].trim(), &braced[end + 1..])
+            }
+            None => {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                rest.split_at(end)
+            }
+        };
+        tokens.push(token.to_string());
+        rest = remainder.trim_start();
+    }
+    Ok(tokens)
+}
+
+fn parse_level(
+    line: usize,
+    level: &str,
+    diagnostics: &mut Vec<TlsPolicyMapDiagnostic>,
+) -> Result<OutboundTlsPolicy, String> {
+    let level = level.to_ascii_lowercase();
+    let (policy, note) = match level.as_str() {
+        "may" => (OutboundTlsPolicy::Opportunistic, None),
+        "encrypt" => (OutboundTlsPolicy::RequireTls, None),
+        "none" => (
+            OutboundTlsPolicy::Opportunistic,
+            Some(
+                "level none has no equivalent; mapped to opportunistic, so STARTTLS is still tried"
+                    .to_string(),
+            ),
+        ),
+        "dane" => (
+            OutboundTlsPolicy::Opportunistic,
+            Some(
+                "DANE is not supported; level dane is mapped to opportunistic, as Postfix does \
+                 without usable TLSA records"
+                    .to_string(),
+            ),
+        ),
+        "dane-only" | "fingerprint" | "verify" | "secure" => (
+            OutboundTlsPolicy::RequireTls,
+            Some(format!(
+                "certificate verification is not supported; level {} is enforced as encrypt \
+                 (require-tls)",
+                level
+            )),
+        ),
+        _ => return Err(format!("unknown security level {}", level)),
+    };
+    if let Some(note) = note {
+        diagnostics.push(warning(line, note));
+    }
+    Ok(policy)
+}
+
+// `example.com` and `.example.com` are recipient domains; `[mx.example.net]` and
+// `[mx.example.net]:25` name the next-hop host, which maps to an MX hostname rule.
+fn parse_key(
+    line: usize,
+    key: &str,
+    policy: OutboundTlsPolicy,
+    diagnostics: &mut Vec<TlsPolicyMapDiagnostic>,
+) -> Result<MapEntry, String> {
+    if let Some(parent) = key.strip_prefix("*.") {
+        return Err(format!("{} is not a Postfix key; use .{} for all subdomains", key, parent));
+    }
+
+    let (host, port, bracketed) = match key.strip_prefix('[') {
+        Some(rest) => {
+            let (host, port) = rest
+                .split_once(']')
+                .ok_or_else(|| format!("unterminated [ in key {}", key))?;
+            (host, port, true)
+        }
+        None => match key.find(':') {
+            Some(colon) => (&key[..colon], &key[colon..], false),
+            None => (key, "", false),
+        },
+    };
+    if let Some(port) = port.strip_prefix(':') {
+        if port.is_empty() || !port.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(format!("invalid port in key {}", key));
+        }
+        if port != "25" {
+            diagnostics.push(warning(
+                line,
+                format!(
+                    "port {} in key {} is ignored; rules match on the host name only",
+                    port, key
+                ),
+            ));
+        }
+    } else if !port.is_empty() {
+        return Err(format!("unexpected text after ] in key {}", key));
+    }
+
+    if !bracketed {
+        return OutboundDomainTlsPolicy::new(host, policy)
+            .map(MapEntry::Domain)
+            .map_err(|_| format!("invalid domain in key {}", key));
+    }
+    if host.parse::<IpAddr>().is_ok() {
+        return Err(format!("key {} names an IP address; MX rules match host names only", key));
+    }
+    if host.starts_with('.') {
+        return Err(format!("invalid host name in key {}", key));
+    }
+    OutboundMxTlsPolicy::new(host, policy)
+        .map(MapEntry::Mx)
+        .map_err(|_| format!("invalid host name in key {}", key))
+}
+
+fn warning(line: usize, message: String) -> TlsPolicyMapDiagnostic {
+    TlsPolicyMapDiagnostic {
+        line,
+        severity: DiagnosticSeverity::Warning,
+        message,
+    }
+}
+
+fn error(line: usize, message: String) -> TlsPolicyMapDiagnostic {
+    TlsPolicyMapDiagnostic {
+        line,
+        severity: DiagnosticSeverity::Error,
+        message,
+    }
+}
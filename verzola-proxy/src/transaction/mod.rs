@@ -0,0 +1,162 @@
+use std::time::{Duration, Instant};
+
+use crate::inbound::TlsSessionParameters;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionStage {
+    Mail,
+    Rcpt,
+    Data,
+    DataFinal,
+}
+
+impl TransactionStage {
+    pub fn label(self) -> &'static str {
+        match self {
+            TransactionStage::Mail => "mail",
+            TransactionStage::Rcpt => "rcpt",
+            TransactionStage::Data => "data",
+            TransactionStage::DataFinal => "data-final",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageReply {
+    pub stage: TransactionStage,
+    pub reply_code: u16,
+    pub reply_line: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransactionOutcome {
+    Accepted,
+    Deferred,
+    Rejected,
+    #[default]
+    Abandoned,
+}
+
+impl TransactionOutcome {
+    pub fn from_reply_code(reply_code: u16) -> Self {
+        match reply_code / 100 {
+            2 => TransactionOutcome::Accepted,
+            5 => TransactionOutcome::Rejected,
+            _ => TransactionOutcome::Deferred,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            TransactionOutcome::Accepted => "accepted",
+            TransactionOutcome::Deferred => "deferred",
+            TransactionOutcome::Rejected => "rejected",
+            TransactionOutcome::Abandoned => "abandoned",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransactionTimings {
+    pub started_after: Duration,
+    pub duration: Duration,
+    pub data_duration: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransactionRecord {
+    pub sender_domain: Option<String>,
+    pub recipient_count: usize,
+    pub message_bytes: u64,
+    pub selected_mx: Option<String>,
+    pub postfix_queue_id: Option<String>,
+    pub tls_negotiated: bool,
+    pub tls_parameters: Option<TlsSessionParameters>,
+    pub replies: Vec<StageReply>,
+    pub outcome: TransactionOutcome,
+    pub timings: TransactionTimings,
+}
+
+#[derive(Debug)]
+struct OpenTransaction {
+    record: TransactionRecord,
+    started_at: Instant,
+    data_started_at: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TransactionLog {
+    open: Option<OpenTransaction>,
+    records: Vec<TransactionRecord>,
+}
+
+impl TransactionLog {
+    // Any transaction still open is closed as abandoned before the new one starts.
+    pub(crate) fn begin(&mut self, sender: &str, started_after: Duration) {
+        self.abandon();
+        self.open = Some(OpenTransaction {
+            record: TransactionRecord {
+                sender_domain: sender_domain(sender),
+                timings: TransactionTimings {
+                    started_after,
+                    ..TransactionTimings::default()
+                },
+                ..TransactionRecord::default()
+            },
+            started_at: Instant::now(),
+            data_started_at: None,
+        });
+    }
+
+    pub(crate) fn current_mut(&mut self) -> Option<&mut TransactionRecord> {
+        self.open.as_mut().map(|open| &mut open.record)
+    }
+
+    pub(crate) fn record_reply(&mut self, stage: TransactionStage, reply_code: u16, line: &str) {
+        if let Some(open) = self.open.as_mut() {
+            if stage == TransactionStage::Data && reply_code / 100 == 3 {
+                open.data_started_at = Some(Instant::now());
+            }
+            open.record.replies.push(StageReply {
+                stage,
+                reply_code,
+                reply_line: line.to_string(),
+            });
+        }
+    }
+
+    pub(crate) fn add_recipient(&mut self) {
+        if let Some(record) = self.current_mut() {
+            record.recipient_count += 1;
+        }
+    }
+
+    pub(crate) fn finish(&mut self, outcome: TransactionOutcome, message_bytes: u64) {
+        if let Some(mut open) = self.open.take() {
+            open.record.outcome = outcome;
+            open.record.message_bytes = message_bytes;
+            open.record.timings.duration = open.started_at.elapsed();
+            open.record.timings.data_duration =
+                open.data_started_at.map(|data_started_at| data_started_at.elapsed());
+            self.records.push(open.record);
+        }
+    }
+
+    pub(crate) fn abandon(&mut self) {
+        self.finish(TransactionOutcome::Abandoned, 0);
+    }
+
+    pub(crate) fn into_records(mut self) -> Vec<TransactionRecord> {
+        self.abandon();
+        self.records
+    }
+}
+
+fn sender_domain(sender: &str) -> Option<String> {
+    let (_, domain) = sender.rsplit_once('@')?;
+    let domain = domain.trim().trim_end_matches('.');
+    if domain.is_empty() {
+        return None;
+    }
+    Some(domain.to_ascii_lowercase())
+}
@@ -0,0 +1,720 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::metrics::MetricsRegistry;
+use crate::smtp::{self, EhloCapabilities, Reply};
+
+pub const DEFAULT_BACKEND_WEIGHT: u32 = 1;
+pub const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+pub const DEFAULT_UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+pub const DEFAULT_EJECTION_PERIOD: Duration = Duration::from_secs(30);
+pub const DEFAULT_MAX_CONNECT_ATTEMPTS: usize = 3;
+pub const DEFAULT_MAX_MESSAGE_SIZE: u64 = 10 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum UpstreamTarget {
+    Tcp(SocketAddr),
+    // For example a Postfix `unix` smtpd service or Dovecot's `lmtp` socket in a
+    // shared volume. Only available on Unix platforms.
+    Unix(PathBuf),
+}
+
+impl fmt::Display for UpstreamTarget {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamTarget::Tcp(addr) => write!(formatter, "{}", addr),
+            UpstreamTarget::Unix(path) => write!(formatter, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for UpstreamTarget {
+    fn from(addr: SocketAddr) -> Self {
+        UpstreamTarget::Tcp(addr)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UpstreamProtocol {
+    #[default]
+    Smtp,
+    // RFC 2033: LHLO instead of EHLO, and one reply per accepted recipient after DATA.
+    Lmtp,
+}
+
+impl UpstreamProtocol {
+    pub(crate) fn greeting_verb(self) -> &'static str {
+        match self {
+            UpstreamProtocol::Smtp => "EHLO",
+            UpstreamProtocol::Lmtp => "LHLO",
+        }
+    }
+}
+
+// Lower priority values are tried first; weight splits new connections between
+// backends of the same priority.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PostfixBackend {
+    pub target: UpstreamTarget,
+    pub priority: u32,
+    pub weight: u32,
+}
+
+impl PostfixBackend {
+    pub fn new(target: impl Into<UpstreamTarget>) -> Self {
+        Self {
+            target: target.into(),
+            priority: 0,
+            weight: DEFAULT_BACKEND_WEIGHT,
+        }
+    }
+
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpstreamPoolConfig {
+    pub health_check_interval: Duration,
+    pub health_check_timeout: Duration,
+    pub connect_timeout: Duration,
+    pub failure_threshold: u32,
+    pub ejection_period: Duration,
+    pub max_connect_attempts: usize,
+}
+
+impl UpstreamPoolConfig {
+    pub fn validate(&self) -> io::Result<()> {
+        let durations = [
+            ("health_check_interval", self.health_check_interval),
+            ("health_check_timeout", self.health_check_timeout),
+            ("connect_timeout", self.connect_timeout),
+            ("ejection_period", self.ejection_period),
+        ];
+        for (name, duration) in durations {
+            if duration.is_zero() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("upstream_pool.{} must be greater than zero", name),
+                ));
+            }
+        }
+
+        if self.failure_threshold == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "upstream_pool.failure_threshold must be at least 1",
+            ));
+        }
+
+        if self.max_connect_attempts == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "upstream_pool.max_connect_attempts must be at least 1",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for UpstreamPoolConfig {
+    fn default() -> Self {
+        Self {
+            health_check_interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+            health_check_timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            connect_timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            ejection_period: DEFAULT_EJECTION_PERIOD,
+            max_connect_attempts: DEFAULT_MAX_CONNECT_ATTEMPTS,
+        }
+    }
+}
+
+// ESMTP extensions the inbound EHLO reply may advertise. Everything except PIPELINING
+// is passed through to the upstream, so it is only offered when the upstream has it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RelayExtension {
+    Size,
+    EightBitMime,
+    SmtpUtf8,
+    Dsn,
+    EnhancedStatusCodes,
+    Pipelining,
+}
+
+impl RelayExtension {
+    pub const ALL: [RelayExtension; 6] = [
+        RelayExtension::Size,
+        RelayExtension::EightBitMime,
+        RelayExtension::SmtpUtf8,
+        RelayExtension::Dsn,
+        RelayExtension::EnhancedStatusCodes,
+        RelayExtension::Pipelining,
+    ];
+
+    pub fn keyword(self) -> &'static str {
+        match self {
+            RelayExtension::Size => "SIZE",
+            RelayExtension::EightBitMime => "8BITMIME",
+            RelayExtension::SmtpUtf8 => "SMTPUTF8",
+            RelayExtension::Dsn => "DSN",
+            RelayExtension::EnhancedStatusCodes => "ENHANCEDSTATUSCODES",
+            RelayExtension::Pipelining => "PIPELINING",
+        }
+    }
+
+    // Commands reach the upstream one at a time, so client pipelining never depends
+    // on the upstream.
+    fn needs_upstream(self) -> bool {
+        self != RelayExtension::Pipelining
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EhloExtensionConfig {
+    pub disabled: Vec<RelayExtension>,
+    // Upper bound for the advertised SIZE, also used in local mode.
+    pub max_message_size: Option<u64>,
+    // Offered before any upstream EHLO reply has been seen, e.g. right after start-up.
+    pub assume_before_upstream_ehlo: Vec<RelayExtension>,
+}
+
+impl EhloExtensionConfig {
+    pub fn validate(&self) -> io::Result<()> {
+        if self.max_message_size == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "ehlo_extensions.max_message_size must be greater than zero",
+            ));
+        }
+
+        if let Some(extension) = self
+            .assume_before_upstream_ehlo
+            .iter()
+            .find(|extension| self.disabled.contains(extension))
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "ehlo_extensions lists {} as both disabled and assumed",
+                    extension.keyword()
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // EHLO keyword lines in a fixed order. `upstream` is None in local mode, and
+    // Some(None) while no upstream EHLO reply is known yet.
+    pub(crate) fn advertised(
+        &self,
+        upstream: Option<Option<&UpstreamCapabilities>>,
+    ) -> Vec<String> {
+        RelayExtension::ALL
+            .into_iter()
+            .filter(|extension| !self.disabled.contains(extension))
+            .filter(|extension| match upstream {
+                _ if !extension.needs_upstream() => true,
+                None => true,
+                Some(None) => self.assume_before_upstream_ehlo.contains(extension),
+                Some(Some(capabilities)) => capabilities.extensions.contains(extension),
+            })
+            .map(|extension| match (extension, self.size_limit(upstream)) {
+                (RelayExtension::Size, Some(size)) => format!("SIZE {}", size),
+                _ => extension.keyword().to_string(),
+            })
+            .collect()
+    }
+
+    // An upstream SIZE without a value means no limit (RFC 1870), which is passed on
+    // as a bare keyword unless the operator sets a cap.
+    fn size_limit(&self, upstream: Option<Option<&UpstreamCapabilities>>) -> Option<u64> {
+        match upstream.flatten() {
+            Some(capabilities) => match (capabilities.size, self.max_message_size) {
+                (Some(limit), Some(cap)) => Some(limit.min(cap)),
+                (limit, cap) => limit.or(cap),
+            },
+            None => Some(self.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)),
+        }
+    }
+}
+
+// What one upstream announced in its EHLO (or LHLO) reply, limited to the extensions
+// VERZOLA can relay. `size` is None when SIZE came without a limit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct UpstreamCapabilities {
+    extensions: Vec<RelayExtension>,
+    size: Option<u64>,
+}
+
+impl UpstreamCapabilities {
+    pub(crate) fn from_ehlo_reply(reply: &Reply) -> Self {
+        let ehlo = EhloCapabilities::from_reply(reply);
+        Self {
+            extensions: RelayExtension::ALL
+                .into_iter()
+                .filter(|extension| ehlo.has(extension.keyword()))
+                .collect(),
+            size: ehlo.size_limit().flatten(),
+        }
+    }
+
+    fn intersect(&self, other: &Self) -> Self {
+        let size = match (self.size, other.size) {
+            (Some(left), Some(right)) => Some(left.min(right)),
+            (left, right) => left.or(right),
+        };
+        Self {
+            extensions: self
+                .extensions
+                .iter()
+                .copied()
+                .filter(|extension| other.extensions.contains(extension))
+                .collect(),
+            size,
+        }
+    }
+}
+
+pub(crate) fn validate_backends(
+    backends: &[PostfixBackend],
+    bind_addr: SocketAddr,
+) -> io::Result<()> {
+    let mut seen = HashSet::new();
+    for backend in backends {
+        if backend.weight == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("postfix upstream {} must have a weight of at least 1", backend.target),
+            ));
+        }
+        match &backend.target {
+            UpstreamTarget::Tcp(addr) if *addr == bind_addr => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "postfix upstream must not equal bind_addr",
+                ));
+            }
+            UpstreamTarget::Unix(_) if !cfg!(unix) => {
+                return Err(io::Error::new(
+                    ErrorKind::Unsupported,
+                    "unix socket upstreams are only supported on Unix platforms",
+                ));
+            }
+            UpstreamTarget::Unix(path) if path.as_os_str().is_empty() => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "postfix upstream socket path must not be empty",
+                ));
+            }
+            _ => {}
+        }
+        if !seen.insert(&backend.target) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("postfix upstream {} is listed twice", backend.target),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendHealth {
+    pub backend: PostfixBackend,
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    pub total_failures: u64,
+    pub ejections: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct BackendState {
+    consecutive_failures: u32,
+    total_failures: u64,
+    ejections: u64,
+    ejected_until: Option<Instant>,
+    last_error: Option<String>,
+    capabilities: Option<UpstreamCapabilities>,
+}
+
+impl BackendState {
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| until > now)
+    }
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    backends: Vec<BackendState>,
+    rotation: u64,
+}
+
+// Shared by every session of one listener. Health is tracked from both sides: the
+// active probe and real relay connections (passive), and a backend is ejected for
+// `ejection_period` once `failure_threshold` failures happen in a row.
+pub(crate) struct UpstreamPool {
+    backends: Vec<PostfixBackend>,
+    config: UpstreamPoolConfig,
+    protocol: UpstreamProtocol,
+    state: Mutex<PoolState>,
+    metrics: Mutex<Option<(Arc<MetricsRegistry>, String)>>,
+}
+
+impl UpstreamPool {
+    pub(crate) fn new(
+        backends: Vec<PostfixBackend>,
+        config: UpstreamPoolConfig,
+        protocol: UpstreamProtocol,
+    ) -> Self {
+        let state = PoolState {
+            backends: backends.iter().map(|_| BackendState::default()).collect(),
+            rotation: 0,
+        };
+        Self {
+            backends,
+            config,
+            protocol,
+            state: Mutex::new(state),
+            metrics: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn is_configured(&self) -> bool {
+        !self.backends.is_empty()
+    }
+
+    pub(crate) fn config(&self) -> &UpstreamPoolConfig {
+        &self.config
+    }
+
+    pub(crate) fn protocol(&self) -> UpstreamProtocol {
+        self.protocol
+    }
+
+    pub(crate) fn attach_metrics(&self, registry: Arc<MetricsRegistry>, listener: String) {
+        for backend in &self.backends {
+            registry.set_upstream_backend_health(&listener, &backend.target.to_string(), true);
+        }
+        *self
+            .metrics
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some((registry, listener));
+    }
+
+    // Available backends by priority, rotated by weight inside each priority, followed
+    // by ejected ones as a last resort so a stale health view never blocks all mail.
+    pub(crate) fn connect_order(&self) -> Vec<PostfixBackend> {
+        let now = Instant::now();
+        let mut state = self.lock_state();
+        let rotation = state.rotation;
+        state.rotation = state.rotation.wrapping_add(1);
+
+        let mut priorities = self
+            .backends
+            .iter()
+            .map(|backend| backend.priority)
+            .collect::<Vec<_>>();
+        priorities.sort_unstable();
+        priorities.dedup();
+
+        let mut available = Vec::new();
+        let mut ejected = Vec::new();
+        for priority in priorities {
+            let group = self
+                .backends
+                .iter()
+                .enumerate()
+                .filter(|(_, backend)| backend.priority == priority)
+                .collect::<Vec<_>>();
+            let total_weight = group
+                .iter()
+                .map(|(_, backend)| u64::from(backend.weight))
+                .sum::<u64>();
+            let mut position = rotation % total_weight;
+            let start = group
+                .iter()
+                .position(|(_, backend)| {
+                    if position < u64::from(backend.weight) {
+                        true
+                    } else {
+                        position -= u64::from(backend.weight);
+                        false
+                    }
+                })
+                .unwrap_or(0);
+
+            for offset in 0..group.len() {
+                let (index, backend) = group[(start + offset) % group.len()];
+                if state.backends[index].is_ejected(now) {
+                    ejected.push(backend.clone());
+                } else {
+                    available.push(backend.clone());
+                }
+            }
+        }
+
+        available.extend(ejected);
+        available
+    }
+
+    pub(crate) fn record_success(&self, target: &UpstreamTarget) {
+        let Some(index) = self.index_of(target) else {
+            return;
+        };
+        let was_ejected = {
+            let mut state = self.lock_state();
+            let backend = &mut state.backends[index];
+            let was_ejected = backend.ejected_until.is_some();
+            backend.consecutive_failures = 0;
+            backend.ejected_until = None;
+            was_ejected
+        };
+        if was_ejected {
+            self.with_metrics(|registry, listener| {
+                registry.set_upstream_backend_health(listener, &target.to_string(), true);
+            });
+        }
+    }
+
+    pub(crate) fn record_failure(&self, target: &UpstreamTarget, error: &io::Error) {
+        let Some(index) = self.index_of(target) else {
+            return;
+        };
+        let newly_ejected = {
+            let now = Instant::now();
+            let mut state = self.lock_state();
+            let backend = &mut state.backends[index];
+            backend.consecutive_failures = backend.consecutive_failures.saturating_add(1);
+            backend.total_failures += 1;
+            backend.last_error = Some(error.to_string());
+            let newly_ejected = backend.consecutive_failures >= self.config.failure_threshold
+                && !backend.is_ejected(now);
+            if newly_ejected {
+                backend.ejections += 1;
+                backend.ejected_until = Some(now + self.config.ejection_period);
+            }
+            newly_ejected
+        };
+        self.with_metrics(|registry, listener| {
+            let backend = target.to_string();
+            registry.record_upstream_backend_failure(listener, &backend, newly_ejected);
+            if newly_ejected {
+                registry.set_upstream_backend_health(listener, &backend, false);
+            }
+        });
+    }
+
+    pub(crate) fn health(&self) -> Vec<BackendHealth> {
+        let now = Instant::now();
+        let state = self.lock_state();
+        self.backends
+            .iter()
+            .zip(&state.backends)
+            .map(|(backend, backend_state)| BackendHealth {
+                backend: backend.clone(),
+                healthy: !backend_state.is_ejected(now),
+                consecutive_failures: backend_state.consecutive_failures,
+                total_failures: backend_state.total_failures,
+                ejections: backend_state.ejections,
+                last_error: backend_state.last_error.clone(),
+            })
+            .collect()
+    }
+
+    // Active check: banner and EHLO (LHLO for LMTP) must both be 2xx. Runs one
+    // backend at a time.
+    pub(crate) fn check_all(&self, ehlo_host: &str) {
+        for backend in &self.backends {
+            match probe_backend(
+                &backend.target,
+                self.protocol,
+                ehlo_host,
+                self.config.health_check_timeout,
+            ) {
+                Ok(capabilities) => {
+                    self.record_success(&backend.target);
+                    self.record_capabilities(&backend.target, capabilities);
+                }
+                Err(error) => self.record_failure(&backend.target, &error),
+            }
+        }
+    }
+
+    pub(crate) fn record_capabilities(
+        &self,
+        target: &UpstreamTarget,
+        capabilities: UpstreamCapabilities,
+    ) {
+        if let Some(index) = self.index_of(target) {
+            self.lock_state().backends[index].capabilities = Some(capabilities);
+        }
+    }
+
+    // Any backend may take the next transaction, so clients only see what every
+    // backend with a known EHLO reply supports. None until one reply has been seen.
+    pub(crate) fn known_capabilities(&self) -> Option<UpstreamCapabilities> {
+        let state = self.lock_state();
+        state
+            .backends
+            .iter()
+            .filter_map(|backend| backend.capabilities.as_ref())
+            .fold(None, |known, capabilities| match known {
+                None => Some(capabilities.clone()),
+                Some(known) => Some(capabilities.intersect(&known)),
+            })
+    }
+
+    fn index_of(&self, target: &UpstreamTarget) -> Option<usize> {
+        self.backends
+            .iter()
+            .position(|backend| backend.target == *target)
+    }
+
+    fn with_metrics<F>(&self, update: F)
+    where
+        F: FnOnce(&MetricsRegistry, &str),
+    {
+        let metrics = self
+            .metrics
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some((registry, listener)) = metrics.as_ref() {
+            update(registry, listener);
+        }
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, PoolState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn probe_backend(
+    target: &UpstreamTarget,
+    protocol: UpstreamProtocol,
+    ehlo_host: &str,
+    timeout: Duration,
+) -> io::Result<UpstreamCapabilities> {
+    let mut writer = UpstreamStream::connect(target, timeout)?;
+    writer.set_read_timeout(Some(timeout))?;
+    writer.set_write_timeout(Some(timeout))?;
+    let mut reader = BufReader::new(writer.try_clone()?);
+
+    let banner = Reply::read_from(&mut reader)?;
+    if banner.code / 100 != 2 {
+        return Err(io::Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("health check banner was {}", banner.code),
+        ));
+    }
+
+    let verb = protocol.greeting_verb();
+    smtp::write_command_line(&mut writer, &format!("{} {}", verb, ehlo_host))?;
+    let ehlo_reply = Reply::read_from(&mut reader)?;
+    let _ = smtp::write_command_line(&mut writer, "QUIT");
+    if ehlo_reply.code / 100 != 2 {
+        return Err(io::Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("health check {} was {}", verb, ehlo_reply.code),
+        ));
+    }
+
+    Ok(UpstreamCapabilities::from_ehlo_reply(&ehlo_reply))
+}
+
+pub(crate) enum UpstreamStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl UpstreamStream {
+    // Unix sockets connect immediately or fail, so the timeout only applies to TCP.
+    pub(crate) fn connect(target: &UpstreamTarget, timeout: Duration) -> io::Result<Self> {
+        match target {
+            UpstreamTarget::Tcp(addr) => {
+                TcpStream::connect_timeout(addr, timeout).map(UpstreamStream::Tcp)
+            }
+            #[cfg(unix)]
+            UpstreamTarget::Unix(path) => UnixStream::connect(path).map(UpstreamStream::Unix),
+            #[cfg(not(unix))]
+            UpstreamTarget::Unix(_) => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "unix socket upstreams are only supported on Unix platforms",
+            )),
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            UpstreamStream::Tcp(stream) => stream.try_clone().map(UpstreamStream::Tcp),
+            #[cfg(unix)]
+            UpstreamStream::Unix(stream) => stream.try_clone().map(UpstreamStream::Unix),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            UpstreamStream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            UpstreamStream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            UpstreamStream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            UpstreamStream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+}
+
+impl Read for UpstreamStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            UpstreamStream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            UpstreamStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for UpstreamStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            UpstreamStream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            UpstreamStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            UpstreamStream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            UpstreamStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
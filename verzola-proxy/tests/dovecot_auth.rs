@@ -0,0 +1,188 @@
+#![cfg(unix)]
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::auth::{AuthRequest, AuthenticationError, Authenticator, DovecotAuthenticator};
+
+const HANDSHAKE: &[&str] = &[
+    "VERSION\t1\t2",
+    "MECH\tPLAIN\tplaintext",
+    "MECH\tLOGIN\tplaintext",
+    "SPID\t4242",
+    "CUID\t7",
+    "COOKIE\t0123456789abcdef0123456789abcdef",
+    "DONE",
+];
+
+#[test]
+fn successful_auth_returns_canonical_user_and_sends_request_fields() {
+    let (socket_path, server) = spawn_auth_server("ok", HANDSHAKE, "OK\t1\tuser=alice@example.com");
+    let authenticator = DovecotAuthenticator::new(&socket_path).with_service("submission");
+
+    assert_eq!(
+        authenticator.authenticate(&request(None, "Alice", "correct horse")),
+        Ok("alice@example.com".to_string())
+    );
+
+    let received = join_server(server);
+    assert_eq!(received[0], "VERSION\t1\t2");
+    assert!(received[1].starts_with("CPID\t"));
+    let fields = received[2].split('\t').collect::<Vec<_>>();
+    assert_eq!(&fields[..5], &["AUTH", "1", "PLAIN", "service=submission", "nologin"]);
+    assert!(fields.contains(&"secured"));
+    assert!(fields.contains(&"rip=192.0.2.10"));
+    // base64("\0Alice\0correct horse")
+    assert!(fields.contains(&"resp=AEFsaWNlAGNvcnJlY3QgaG9yc2U="));
+    let _ = fs::remove_file(&socket_path);
+}
+
+#[test]
+fn failures_map_to_invalid_or_temporary_errors() {
+    let (socket_path, server) = spawn_auth_server(
+        "fail",
+        HANDSHAKE,
+        "FAIL\t1\tuser=alice\treason=Password mismatch",
+    );
+    let authenticator = DovecotAuthenticator::new(&socket_path);
+    assert_eq!(
+        authenticator.authenticate(&request(None, "alice", "wrong")),
+        Err(AuthenticationError::InvalidCredentials)
+    );
+    join_server(server);
+    let _ = fs::remove_file(&socket_path);
+
+    let (socket_path, server) = spawn_auth_server(
+        "temp",
+        HANDSHAKE,
+        "FAIL\t1\ttemp\treason=passdb\u{1}tunavailable",
+    );
+    let authenticator = DovecotAuthenticator::new(&socket_path);
+    assert_eq!(
+        authenticator.authenticate(&request(None, "alice", "secret")),
+        Err(AuthenticationError::Temporary("passdb\tunavailable".to_string()))
+    );
+    join_server(server);
+    let _ = fs::remove_file(&socket_path);
+}
+
+#[test]
+fn protocol_and_socket_problems_are_temporary() {
+    let missing = socket_path("missing");
+    let _ = fs::remove_file(&missing);
+    let authenticator = DovecotAuthenticator::new(&missing);
+    match authenticator.authenticate(&request(None, "alice", "secret")) {
+        Err(AuthenticationError::Temporary(reason)) => {
+            assert!(reason.contains("dovecot auth socket"));
+            assert!(!reason.contains("secret"));
+        }
+        other => panic!("missing socket should be a temporary failure, got {:?}", other),
+    }
+
+    let (socket_path, server) =
+        spawn_auth_server("version", &["VERSION\t2\t0", "MECH\tPLAIN", "DONE"], "OK\t1");
+    let authenticator = DovecotAuthenticator::new(&socket_path);
+    match authenticator.authenticate(&request(None, "alice", "secret")) {
+        Err(AuthenticationError::Temporary(reason)) => assert!(reason.contains("version")),
+        other => panic!("unsupported version should be a temporary failure, got {:?}", other),
+    }
+    join_server(server);
+    let _ = fs::remove_file(&socket_path);
+
+    let (socket_path, server) = spawn_auth_server(
+        "nomech",
+        &["VERSION\t1\t2", "MECH\tCRAM-MD5", "DONE"],
+        "OK\t1",
+    );
+    let authenticator = DovecotAuthenticator::new(&socket_path)
+        .with_timeout(Duration::from_millis(500));
+    match authenticator.authenticate(&request(None, "alice", "secret")) {
+        Err(AuthenticationError::Temporary(reason)) => assert!(reason.contains("PLAIN")),
+        other => panic!("missing PLAIN should be a temporary failure, got {:?}", other),
+    }
+    join_server(server);
+    let _ = fs::remove_file(&socket_path);
+}
+
+fn request(authzid: Option<&str>, username: &str, password: &str) -> AuthRequest {
+    AuthRequest {
+        mechanism: "LOGIN",
+        authzid: authzid.map(str::to_string),
+        username: username.to_string(),
+        password: password.to_string(),
+        client_addr: Some(
+            "192.0.2.10:52344"
+                .parse()
+                .expect("hard-coded socket address must parse"),
+        ),
+        secured: true,
+    }
+}
+
+fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "verzola-dovecot-{}-{}.sock",
+        name,
+        std::process::id()
+    ))
+}
+
+// Plays the server side of one auth connection and returns the client's lines.
+fn spawn_auth_server(
+    name: &str,
+    handshake: &'static [&'static str],
+    reply: &'static str,
+) -> (PathBuf, thread::JoinHandle<Vec<String>>) {
+    let path = socket_path(name);
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).expect("test auth socket should bind");
+
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().expect("auth server should accept");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(3)))
+            .expect("auth server read timeout should set");
+        let mut reader = BufReader::new(
+            stream
+                .try_clone()
+                .expect("auth server socket clone should succeed"),
+        );
+        for line in handshake {
+            write_line(&mut stream, line);
+        }
+
+        let mut received = Vec::new();
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            let line = line.trim_end_matches('\n').to_string();
+            let is_auth = line.starts_with("AUTH\t");
+            received.push(line);
+            if is_auth {
+                write_line(&mut stream, reply);
+                break;
+            }
+        }
+
+        received
+    });
+
+    (path, handle)
+}
+
+fn join_server(handle: thread::JoinHandle<Vec<String>>) -> Vec<String> {
+    handle.join().expect("auth server thread should not panic")
+}
+
+fn write_line(stream: &mut UnixStream, line: &str) {
+    // The client may already have hung up after a handshake it rejects.
+    let _ = stream.write_all(format!("{}\n", line).as_bytes());
+    let _ = stream.flush();
+}
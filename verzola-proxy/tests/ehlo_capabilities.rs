@@ -0,0 +1,422 @@
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::inbound::{InboundListener, ListenerConfig, NoopTlsUpgrader};
+use verzola_proxy::upstream::{EhloExtensionConfig, PostfixBackend, RelayExtension};
+
+// A Postfix stand-in with a fixed EHLO keyword list that records every DATA body.
+struct MockPostfix {
+    addr: SocketAddr,
+    bodies: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+#[test]
+fn local_mode_advertises_everything_not_disabled() {
+    let listener = InboundListener::bind(local_config(), NoopTlsUpgrader)
+        .expect("local listener should bind");
+    assert_eq!(
+        ehlo_keywords(&listener),
+        [
+            "STARTTLS",
+            "SIZE 10485760",
+            "8BITMIME",
+            "SMTPUTF8",
+            "DSN",
+            "ENHANCEDSTATUSCODES",
+            "PIPELINING"
+        ]
+    );
+
+    let listener = InboundListener::bind(
+        ListenerConfig {
+            ehlo_extensions: EhloExtensionConfig {
+                disabled: vec![RelayExtension::Dsn, RelayExtension::SmtpUtf8],
+                max_message_size: Some(2048),
+                ..EhloExtensionConfig::default()
+            },
+            ..local_config()
+        },
+        NoopTlsUpgrader,
+    )
+    .expect("local listener should bind");
+    assert_eq!(
+        ehlo_keywords(&listener),
+        ["STARTTLS", "SIZE 2048", "8BITMIME", "ENHANCEDSTATUSCODES", "PIPELINING"]
+    );
+}
+
+#[test]
+fn relay_mode_offers_only_assumed_extensions_until_upstream_is_known() {
+    let postfix = spawn_mock_postfix(&["SIZE 20480000", "8BITMIME", "PIPELINING"]);
+    let config = relay_config(vec![PostfixBackend::new(postfix.addr)]);
+    let listener =
+        InboundListener::bind(config.clone(), NoopTlsUpgrader).expect("relay listener should bind");
+    assert_eq!(ehlo_keywords(&listener), ["STARTTLS", "PIPELINING"]);
+
+    let listener = InboundListener::bind(
+        ListenerConfig {
+            ehlo_extensions: EhloExtensionConfig {
+                assume_before_upstream_ehlo: vec![RelayExtension::Size, RelayExtension::Dsn],
+                ..EhloExtensionConfig::default()
+            },
+            ..config
+        },
+        NoopTlsUpgrader,
+    )
+    .expect("relay listener should bind");
+    assert_eq!(
+        ehlo_keywords(&listener),
+        ["STARTTLS", "SIZE 10485760", "DSN", "PIPELINING"]
+    );
+}
+
+#[test]
+fn relay_connection_teaches_the_upstream_capabilities() {
+    let postfix = spawn_mock_postfix(&[
+        "PIPELINING",
+        "SIZE 20480000",
+        "VRFY",
+        "ETRN",
+        "ENHANCEDSTATUSCODES",
+        "8BITMIME",
+        "DSN",
+        "CHUNKING",
+    ]);
+    let listener = InboundListener::bind(
+        relay_config(vec![PostfixBackend::new(postfix.addr)]),
+        NoopTlsUpgrader,
+    )
+    .expect("relay listener should bind");
+
+    deliver(&listener, b"Subject: first\r\n\r\nhello\r\n");
+    assert_eq!(
+        ehlo_keywords(&listener),
+        [
+            "STARTTLS",
+            "SIZE 20480000",
+            "8BITMIME",
+            "DSN",
+            "ENHANCEDSTATUSCODES",
+            "PIPELINING"
+        ]
+    );
+}
+
+#[test]
+fn health_checks_intersect_backends_and_cap_size() {
+    let large = spawn_mock_postfix(&["SIZE 50000000", "8BITMIME", "SMTPUTF8", "DSN"]);
+    let small = spawn_mock_postfix(&["SIZE 20000000", "8BITMIME", "SMTPUTF8"]);
+    let unlimited = spawn_mock_postfix(&["SIZE", "8BITMIME", "SMTPUTF8", "DSN"]);
+    let config = relay_config(vec![
+        PostfixBackend::new(large.addr),
+        PostfixBackend::new(small.addr).with_priority(1),
+        PostfixBackend::new(unlimited.addr).with_priority(2),
+    ]);
+
+    let listener =
+        InboundListener::bind(config.clone(), NoopTlsUpgrader).expect("relay listener should bind");
+    listener.check_upstreams();
+    assert_eq!(
+        ehlo_keywords(&listener),
+        ["STARTTLS", "SIZE 20000000", "8BITMIME", "SMTPUTF8", "PIPELINING"]
+    );
+
+    let listener = InboundListener::bind(
+        ListenerConfig {
+            ehlo_extensions: EhloExtensionConfig {
+                disabled: vec![RelayExtension::Pipelining, RelayExtension::SmtpUtf8],
+                max_message_size: Some(1_000_000),
+                ..EhloExtensionConfig::default()
+            },
+            ..config
+        },
+        NoopTlsUpgrader,
+    )
+    .expect("relay listener should bind");
+    listener.check_upstreams();
+    assert_eq!(ehlo_keywords(&listener), ["STARTTLS", "SIZE 1000000", "8BITMIME"]);
+
+    // SIZE without a value means the upstream has no limit of its own.
+    let listener = InboundListener::bind(
+        relay_config(vec![PostfixBackend::new(unlimited.addr)]),
+        NoopTlsUpgrader,
+    )
+    .expect("relay listener should bind");
+    listener.check_upstreams();
+    assert_eq!(
+        ehlo_keywords(&listener),
+        ["STARTTLS", "SIZE", "8BITMIME", "SMTPUTF8", "DSN", "PIPELINING"]
+    );
+}
+
+#[test]
+fn eight_bit_body_is_relayed_unchanged() {
+    let postfix = spawn_mock_postfix(&["8BITMIME"]);
+    let listener = InboundListener::bind(
+        relay_config(vec![PostfixBackend::new(postfix.addr)]),
+        NoopTlsUpgrader,
+    )
+    .expect("relay listener should bind");
+
+    // Latin-1 bytes are not valid UTF-8.
+    let body = b"Subject: caf\xe9\r\n\r\nd\xe9j\xe0 vu \xff\r\n".to_vec();
+    assert!(deliver(&listener, &body).starts_with("250"));
+
+    let bodies = postfix
+        .bodies
+        .lock()
+        .expect("body log should not be poisoned");
+    assert_eq!(bodies.as_slice(), [body]);
+}
+
+#[test]
+fn commands_pipelined_behind_starttls_are_discarded() {
+    let listener = InboundListener::bind(local_config(), NoopTlsUpgrader)
+        .expect("local listener should bind");
+    let address = listener.local_addr().expect("listener address must resolve");
+    thread::scope(|scope| {
+        let server = scope.spawn(|| listener.serve_one());
+
+        let (mut stream, mut reader) = connect(address);
+        let _banner = read_reply(&mut reader);
+        send(&mut stream, "EHLO sender.example\r\n");
+        let _ehlo_reply = read_reply(&mut reader);
+        send(&mut stream, "STARTTLS\r\nMAIL FROM:<mallory@example.com>\r\n");
+        assert!(read_reply(&mut reader)[0].starts_with("220"));
+        send(&mut stream, "NOOP\r\n");
+        assert_eq!(read_reply(&mut reader), ["250 2.0.0 OK"]);
+        send(&mut stream, "QUIT\r\n");
+        let _quit_reply = read_reply(&mut reader);
+
+        server
+            .join()
+            .expect("listener thread should not panic")
+            .expect("listener should return a session summary");
+    });
+}
+
+#[test]
+fn validate_rejects_inconsistent_extension_overrides() {
+    let zero_size = ListenerConfig {
+        ehlo_extensions: EhloExtensionConfig {
+            max_message_size: Some(0),
+            ..EhloExtensionConfig::default()
+        },
+        ..local_config()
+    };
+    assert_eq!(
+        zero_size.validate().map_err(|error| error.kind()),
+        Err(ErrorKind::InvalidInput)
+    );
+
+    let contradictory = ListenerConfig {
+        ehlo_extensions: EhloExtensionConfig {
+            disabled: vec![RelayExtension::EightBitMime],
+            assume_before_upstream_ehlo: vec![RelayExtension::EightBitMime],
+            ..EhloExtensionConfig::default()
+        },
+        ..local_config()
+    };
+    let error = contradictory
+        .validate()
+        .expect_err("an extension cannot be both disabled and assumed");
+    assert!(error.to_string().contains("8BITMIME"));
+}
+
+fn local_config() -> ListenerConfig {
+    ListenerConfig {
+        bind_addr: "127.0.0.1:0".parse().expect("hard-coded address must parse"),
+        banner_host: "mx.verzola.test".to_string(),
+        ..ListenerConfig::default()
+    }
+}
+
+fn relay_config(backends: Vec<PostfixBackend>) -> ListenerConfig {
+    ListenerConfig {
+        postfix_upstreams: backends,
+        ..local_config()
+    }
+}
+
+// Runs one session with just EHLO and returns the keyword lines without the reply
+// code or the greeting.
+fn ehlo_keywords(listener: &InboundListener<NoopTlsUpgrader>) -> Vec<String> {
+    let address = listener.local_addr().expect("listener address must resolve");
+    thread::scope(|scope| {
+        let server = scope.spawn(|| listener.serve_one());
+
+        let (mut stream, mut reader) = connect(address);
+        let _banner = read_reply(&mut reader);
+        send(&mut stream, "EHLO sender.example\r\n");
+        let ehlo_reply = read_reply(&mut reader);
+        send(&mut stream, "QUIT\r\n");
+        let _quit_reply = read_reply(&mut reader);
+
+        server
+            .join()
+            .expect("listener thread should not panic")
+            .expect("listener should return a session summary");
+        ehlo_reply
+            .iter()
+            .skip(1)
+            .map(|line| line[4..].to_string())
+            .collect()
+    })
+}
+
+// Runs one full transaction and returns the reply to the end of DATA.
+fn deliver(listener: &InboundListener<NoopTlsUpgrader>, body: &[u8]) -> String {
+    let address = listener.local_addr().expect("listener address must resolve");
+    thread::scope(|scope| {
+        let server = scope.spawn(|| listener.serve_one());
+
+        let (mut stream, mut reader) = connect(address);
+        let _banner = read_reply(&mut reader);
+        for command in [
+            "EHLO sender.example",
+            "MAIL FROM:<alice@example.com> BODY=8BITMIME",
+            "RCPT TO:<bob@example.net>",
+            "DATA",
+        ] {
+            send(&mut stream, &format!("{}\r\n", command));
+            let _reply = read_reply(&mut reader);
+        }
+        stream
+            .write_all(body)
+            .expect("test client should write the message body");
+        send(&mut stream, ".\r\n");
+        let data_reply = read_reply(&mut reader).join("\n");
+        send(&mut stream, "QUIT\r\n");
+        let _quit_reply = read_reply(&mut reader);
+
+        server
+            .join()
+            .expect("listener thread should not panic")
+            .expect("listener should return a session summary");
+        data_reply
+    })
+}
+
+fn spawn_mock_postfix(keywords: &[&str]) -> MockPostfix {
+    let listener =
+        TcpListener::bind("127.0.0.1:0").expect("mock postfix listener should bind to localhost");
+    let addr = listener
+        .local_addr()
+        .expect("mock postfix listener address should resolve");
+    let bodies = Arc::new(Mutex::new(Vec::new()));
+
+    let keywords = keywords
+        .iter()
+        .map(|keyword| keyword.to_string())
+        .collect::<Vec<_>>();
+    let body_log = Arc::clone(&bodies);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let _ = handle_postfix_session(stream, &keywords, &body_log);
+        }
+    });
+
+    MockPostfix { addr, bodies }
+}
+
+fn handle_postfix_session(
+    mut stream: TcpStream,
+    keywords: &[String],
+    bodies: &Mutex<Vec<Vec<u8>>>,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(3)))?;
+    write_line(&mut stream, "220 postfix.verzola.test ESMTP Postfix")?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let verb = line
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_ascii_uppercase();
+        match verb.as_str() {
+            "EHLO" => {
+                write_line(&mut stream, "250-postfix.verzola.test")?;
+                for (index, keyword) in keywords.iter().enumerate() {
+                    let separator = if index + 1 == keywords.len() { ' ' } else { '-' };
+                    write_line(&mut stream, &format!("250{}{}", separator, keyword))?;
+                }
+            }
+            "DATA" => {
+                write_line(&mut stream, "354 End data with <CR><LF>.<CR><LF>")?;
+                let mut body = Vec::new();
+                loop {
+                    let mut data_line = Vec::new();
+                    if reader.read_until(b'\n', &mut data_line)? == 0 {
+                        return Ok(());
+                    }
+                    if data_line == b".\r\n" {
+                        break;
+                    }
+                    body.extend_from_slice(&data_line);
+                }
+                bodies
+                    .lock()
+                    .expect("body log should not be poisoned")
+                    .push(body);
+                write_line(&mut stream, "250 2.0.0 Ok: queued as 4XyZ1k2LmNz")?;
+            }
+            "QUIT" => return write_line(&mut stream, "221 2.0.0 Bye"),
+            _ => write_line(&mut stream, "250 2.0.0 OK")?,
+        }
+    }
+}
+
+fn write_line(stream: &mut TcpStream, line: &str) -> std::io::Result<()> {
+    write!(stream, "{}\r\n", line)?;
+    stream.flush()
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("test client read timeout should set");
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, command: &str) {
+    stream
+        .write_all(command.as_bytes())
+        .expect("test client should write command bytes");
+    stream.flush().expect("test client flush should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut raw = String::new();
+        let bytes = reader
+            .read_line(&mut raw)
+            .expect("test client should read server reply");
+        assert!(bytes > 0, "server closed connection unexpectedly");
+        let line = raw.trim_end_matches(['\r', '\n']).to_string();
+        lines.push(line.clone());
+
+        let line_bytes = line.as_bytes();
+        if line_bytes.len() < 4 || line_bytes[3] == b' ' {
+            break;
+        }
+    }
+    lines
+}
@@ -0,0 +1,354 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::inbound::{InboundListener, ListenerConfig, NoopTlsUpgrader};
+use verzola_proxy::outbound::{
+    MxCandidate, MxResolutionError, MxResolver, OutboundListener, OutboundListenerConfig,
+};
+use verzola_proxy::shutdown::{ShutdownReport, ShutdownSignal};
+
+#[derive(Debug, Clone)]
+struct SingleMxResolver {
+    candidate: MxCandidate,
+}
+
+impl MxResolver for SingleMxResolver {
+    fn resolve(&self, _recipient_domain: &str) -> Result<Vec<MxCandidate>, MxResolutionError> {
+        Ok(vec![self.candidate.clone()])
+    }
+}
+
+#[derive(Debug, Default)]
+struct RemoteMxStats {
+    messages: usize,
+    quit_received: bool,
+}
+
+#[test]
+fn idle_inbound_session_receives_421_on_shutdown() {
+    let shutdown = ShutdownSignal::new();
+    let (listener_addr, listener_handle) =
+        spawn_inbound_listener(&shutdown, Duration::from_secs(5));
+
+    let (mut stream, mut reader) = connect(listener_addr);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO sender.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+
+    shutdown.request();
+
+    assert_eq!(
+        read_reply(&mut reader),
+        vec!["421 4.3.2 Service shutting down".to_string()]
+    );
+
+    let report = join_listener(listener_handle);
+    assert_eq!(report.sessions_served, 1);
+    assert_eq!(report.sessions_failed, 0);
+    assert_eq!(report.sessions_closed_by_shutdown, 1);
+}
+
+#[test]
+fn utf8_character_split_across_read_timeouts_is_kept() {
+    let shutdown = ShutdownSignal::new();
+    let (listener_addr, listener_handle) =
+        spawn_inbound_listener(&shutdown, Duration::from_secs(5));
+
+    let (mut stream, mut reader) = connect(listener_addr);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO sender.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+
+    // The pause outlasts the session read timeout between the two bytes of "ü".
+    let command = "MAIL FROM:<jürgen@example.org> SMTPUTF8\r\n".as_bytes();
+    let split = command
+        .iter()
+        .position(|byte| *byte == 0xc3)
+        .expect("command should contain a multibyte character")
+        + 1;
+    stream
+        .write_all(&command[..split])
+        .expect("test client write should succeed");
+    stream.flush().expect("test client flush should succeed");
+    thread::sleep(Duration::from_millis(300));
+    stream
+        .write_all(&command[split..])
+        .expect("test client write should succeed");
+
+    let mail_reply = read_reply(&mut reader);
+    assert!(
+        mail_reply[0].starts_with("250 "),
+        "unexpected MAIL reply: {}",
+        mail_reply.join(" | ")
+    );
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    shutdown.request();
+    let report = join_listener(listener_handle);
+    assert_eq!(report.sessions_served, 1);
+    assert_eq!(report.sessions_failed, 0);
+}
+
+#[test]
+fn inbound_session_in_data_finishes_within_grace_period() {
+    let shutdown = ShutdownSignal::new();
+    let (listener_addr, listener_handle) =
+        spawn_inbound_listener(&shutdown, Duration::from_secs(5));
+
+    let (mut stream, mut reader) = connect(listener_addr);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO sender.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.com>\r\n");
+    let _mail_reply = read_reply(&mut reader);
+    send(&mut stream, "RCPT TO:<bob@example.net>\r\n");
+    let _rcpt_reply = read_reply(&mut reader);
+    send(&mut stream, "DATA\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("354 "));
+    send(&mut stream, "Subject: draining\r\n");
+
+    shutdown.request();
+    thread::sleep(Duration::from_millis(300));
+
+    send(&mut stream, "\r\nbody\r\n.\r\n");
+    assert_eq!(read_reply(&mut reader), vec!["250 2.0.0 Queued".to_string()]);
+    assert_eq!(
+        read_reply(&mut reader),
+        vec!["421 4.3.2 Service shutting down".to_string()]
+    );
+
+    let report = join_listener(listener_handle);
+    assert_eq!(report.sessions_served, 1);
+    assert_eq!(report.sessions_closed_by_shutdown, 1);
+}
+
+#[test]
+fn inbound_session_in_data_is_closed_when_grace_period_expires() {
+    let shutdown = ShutdownSignal::new();
+    let (listener_addr, listener_handle) =
+        spawn_inbound_listener(&shutdown, Duration::from_millis(200));
+
+    let (mut stream, mut reader) = connect(listener_addr);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO sender.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.com>\r\n");
+    let _mail_reply = read_reply(&mut reader);
+    send(&mut stream, "RCPT TO:<bob@example.net>\r\n");
+    let _rcpt_reply = read_reply(&mut reader);
+    send(&mut stream, "DATA\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("354 "));
+    send(&mut stream, "Subject: stalled\r\n");
+
+    shutdown.request();
+
+    assert_eq!(
+        read_reply(&mut reader),
+        vec!["421 4.3.2 Service shutting down".to_string()]
+    );
+
+    let report = join_listener(listener_handle);
+    assert_eq!(report.sessions_closed_by_shutdown, 1);
+}
+
+#[test]
+fn outbound_relay_completes_in_flight_delivery_before_closing() {
+    let (remote_addr, remote_handle) = spawn_mock_remote_mx();
+    let shutdown = ShutdownSignal::new();
+    let resolver = SingleMxResolver {
+        candidate: MxCandidate::new(10, "mx.example.net", remote_addr)
+            .expect("candidate should be valid"),
+    };
+
+    let config = OutboundListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "relay.verzola.test".to_string(),
+        shutdown_grace_period: Duration::from_secs(5),
+        ..OutboundListenerConfig::default()
+    };
+    let listener = OutboundListener::bind(config, resolver)
+        .expect("outbound listener should bind for shutdown test");
+    let listener_addr = listener.local_addr().expect("listener address must resolve");
+    let listener_shutdown = shutdown.clone();
+    let listener_handle =
+        thread::spawn(move || listener.serve_until_shutdown(&listener_shutdown));
+
+    let (mut stream, mut reader) = connect(listener_addr);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.org>\r\n");
+    let _mail_reply = read_reply(&mut reader);
+    send(&mut stream, "RCPT TO:<bob@example.net>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "DATA\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("354 "));
+    send(&mut stream, "Subject: in flight\r\n");
+
+    shutdown.request();
+    thread::sleep(Duration::from_millis(300));
+
+    send(&mut stream, "\r\nbody\r\n.\r\n");
+    assert_eq!(
+        read_reply(&mut reader),
+        vec!["250 2.0.0 Message accepted by remote MX".to_string()]
+    );
+    assert_eq!(
+        read_reply(&mut reader),
+        vec!["421 4.3.2 Service shutting down".to_string()]
+    );
+
+    let report = join_listener(listener_handle);
+    assert_eq!(report.sessions_served, 1);
+    assert_eq!(report.sessions_closed_by_shutdown, 1);
+
+    let remote_stats = remote_handle
+        .join()
+        .expect("remote thread should not panic")
+        .expect("mock remote should return stats");
+    assert_eq!(remote_stats.messages, 1);
+    assert!(remote_stats.quit_received);
+}
+
+fn spawn_inbound_listener(
+    shutdown: &ShutdownSignal,
+    grace_period: Duration,
+) -> (SocketAddr, thread::JoinHandle<std::io::Result<ShutdownReport>>) {
+    let config = ListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "mx.verzola.test".to_string(),
+        shutdown_grace_period: grace_period,
+        ..ListenerConfig::default()
+    };
+
+    let listener = InboundListener::bind(config, NoopTlsUpgrader)
+        .expect("listener should bind for shutdown test");
+    let address = listener.local_addr().expect("listener address must resolve");
+    let shutdown = shutdown.clone();
+    let handle = thread::spawn(move || listener.serve_until_shutdown(&shutdown));
+
+    (address, handle)
+}
+
+fn spawn_mock_remote_mx() -> (SocketAddr, thread::JoinHandle<std::io::Result<RemoteMxStats>>) {
+    let listener =
+        TcpListener::bind("127.0.0.1:0").expect("mock remote listener should bind to localhost");
+    let address = listener
+        .local_addr()
+        .expect("mock remote listener address should resolve");
+
+    let handle = thread::spawn(move || -> std::io::Result<RemoteMxStats> {
+        let (mut stream, _) = listener.accept()?;
+        stream.set_read_timeout(Some(Duration::from_secs(3)))?;
+        write_line(&mut stream, "220 remote.example ESMTP")?;
+
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut stats = RemoteMxStats::default();
+        let mut reading_data = false;
+
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+
+            if reading_data {
+                if line == ".\r\n" {
+                    reading_data = false;
+                    stats.messages += 1;
+                    write_line(&mut stream, "250 2.0.0 Accepted")?;
+                }
+                continue;
+            }
+
+            let verb = line
+                .split_whitespace()
+                .next()
+                .unwrap_or("")
+                .to_ascii_uppercase();
+            match verb.as_str() {
+                "EHLO" => write_line(&mut stream, "250 remote.example")?,
+                "MAIL" | "RCPT" => write_line(&mut stream, "250 2.1.0 OK")?,
+                "DATA" => {
+                    reading_data = true;
+                    write_line(&mut stream, "354 Go ahead")?;
+                }
+                "QUIT" => {
+                    stats.quit_received = true;
+                    write_line(&mut stream, "221 2.0.0 Bye")?;
+                    break;
+                }
+                _ => write_line(&mut stream, "502 5.5.1 Command not implemented")?,
+            }
+        }
+
+        Ok(stats)
+    });
+
+    (address, handle)
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .expect("test client read timeout should set");
+
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, command: &str) {
+    stream
+        .write_all(command.as_bytes())
+        .expect("test client should write command bytes");
+    stream.flush().expect("test client flush should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    loop {
+        let mut raw = String::new();
+        let bytes = reader
+            .read_line(&mut raw)
+            .expect("test client should read server reply");
+        assert!(bytes > 0, "server closed connection unexpectedly");
+
+        let line = raw.trim_end_matches(['\r', '\n']).to_string();
+        lines.push(line.clone());
+
+        let line_bytes = line.as_bytes();
+        if line_bytes.len() < 4 || line_bytes[3] == b' ' {
+            break;
+        }
+    }
+
+    lines
+}
+
+fn write_line(stream: &mut TcpStream, line: &str) -> std::io::Result<()> {
+    write!(stream, "{}\r\n", line)?;
+    stream.flush()
+}
+
+fn join_listener(
+    handle: thread::JoinHandle<std::io::Result<ShutdownReport>>,
+) -> ShutdownReport {
+    handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a shutdown report")
+}
@@ -0,0 +1,339 @@
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use verzola_proxy::outbound::{
+    AddressFamilyPreference, MxAddressAttempt, MxAddressOutcome, MxCandidate, MxResolutionError,
+    MxResolver, OutboundListener, OutboundListenerConfig, OutboundSessionSummary,
+};
+
+#[derive(Debug, Clone)]
+struct StaticResolver {
+    candidates: Vec<MxCandidate>,
+}
+
+impl MxResolver for StaticResolver {
+    fn resolve(&self, _recipient_domain: &str) -> Result<Vec<MxCandidate>, MxResolutionError> {
+        Ok(self.candidates.clone())
+    }
+}
+
+#[test]
+fn candidates_hold_every_address_of_an_exchange() {
+    let v4: SocketAddr = "192.0.2.1:25".parse().expect("address must parse");
+    let v6: SocketAddr = "[2001:db8::1]:25".parse().expect("address must parse");
+
+    let candidate = MxCandidate::with_addresses(10, "mx.dual.test", vec![v6, v4])
+        .expect("candidate should be valid");
+    assert_eq!(candidate.addresses, [v6, v4]);
+    assert_eq!(
+        MxCandidate::new(10, "mx.dual.test", v4)
+            .expect("candidate should be valid")
+            .addresses,
+        [v4]
+    );
+
+    let error = MxCandidate::with_addresses(10, "mx.dual.test", Vec::new())
+        .expect_err("a candidate without addresses should be rejected");
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+
+    let mut config = OutboundListenerConfig {
+        connection_attempt_delay: Duration::from_millis(5),
+        ..OutboundListenerConfig::default()
+    };
+    assert_eq!(
+        config
+            .validate()
+            .expect_err("a delay below 10 ms should be rejected")
+            .to_string(),
+        "connection_attempt_delay must be between 10 ms and 2 s"
+    );
+    config.connection_attempt_delay = Duration::from_secs(3);
+    assert!(config.validate().is_err());
+    config.connection_attempt_delay = Duration::from_millis(100);
+    config.mx_connect_timeout = Duration::ZERO;
+    assert_eq!(
+        config
+            .validate()
+            .expect_err("a zero connect timeout should be rejected")
+            .to_string(),
+        "mx_connect_timeout must be greater than zero"
+    );
+}
+
+#[test]
+fn failed_addresses_hand_over_without_waiting_for_the_attempt_delay() {
+    let refused_v6 = reserve_unused_addr("[::1]:0");
+    let refused_v4 = reserve_unused_addr("127.0.0.1:0");
+    let (live_v4, mx) = spawn_plaintext_mx("127.0.0.1:0");
+    let resolver = StaticResolver {
+        candidates: vec![MxCandidate::with_addresses(
+            10,
+            "mx.dual.test",
+            vec![refused_v6, refused_v4, live_v4],
+        )
+        .expect("candidate should be valid")],
+    };
+    let config = OutboundListenerConfig {
+        connection_attempt_delay: Duration::from_secs(2),
+        ..test_config()
+    };
+
+    let started_at = Instant::now();
+    let (summary, rcpt_reply) = deliver(config, resolver);
+
+    assert!(rcpt_reply.starts_with("250 "), "unexpected RCPT reply: {}", rcpt_reply);
+    assert!(
+        started_at.elapsed() < Duration::from_secs(2),
+        "refused addresses should not wait for the attempt delay"
+    );
+    assert_eq!(summary.mx_candidates_attempted, 1);
+    assert_eq!(summary.selected_mx.as_deref(), Some("mx.dual.test"));
+    let attempts: Vec<(SocketAddr, bool)> = summary
+        .mx_address_attempts
+        .iter()
+        .map(|attempt| (attempt.address, attempt.outcome == MxAddressOutcome::Connected))
+        .collect();
+    assert_eq!(
+        attempts,
+        [(refused_v6, false), (refused_v4, false), (live_v4, true)]
+    );
+    assert!(matches!(
+        &summary.mx_address_attempts[0],
+        MxAddressAttempt {
+            exchange,
+            outcome: MxAddressOutcome::Failed(_),
+            ..
+        } if exchange == "mx.dual.test"
+    ));
+    assert_eq!(join_mx(mx), 1);
+}
+
+#[test]
+fn address_families_are_interleaved_starting_with_the_first_address() {
+    let refused: Vec<SocketAddr> = vec![
+        reserve_unused_addr("127.0.0.1:0"),
+        reserve_unused_addr("127.0.0.1:0"),
+        reserve_unused_addr("[::1]:0"),
+        reserve_unused_addr("[::1]:0"),
+    ];
+    let resolver = StaticResolver {
+        candidates: vec![MxCandidate::with_addresses(10, "mx.dual.test", refused.clone())
+            .expect("candidate should be valid")],
+    };
+
+    let (summary, rcpt_reply) = deliver(test_config(), resolver);
+
+    assert!(rcpt_reply.starts_with("451 4.4.0 "), "unexpected RCPT reply: {}", rcpt_reply);
+    assert!(rcpt_reply.contains("all 4 addresses failed"), "{}", rcpt_reply);
+    let order: Vec<SocketAddr> = summary
+        .mx_address_attempts
+        .iter()
+        .map(|attempt| attempt.address)
+        .collect();
+    assert_eq!(order, [refused[0], refused[2], refused[1], refused[3]]);
+}
+
+#[test]
+fn family_preference_restricts_the_addresses_tried() {
+    for (family, bind_addr) in [
+        (AddressFamilyPreference::Ipv4, "127.0.0.1:0"),
+        (AddressFamilyPreference::Ipv6, "[::1]:0"),
+    ] {
+        let (live, mx) = spawn_plaintext_mx(bind_addr);
+        let other_family = if live.is_ipv4() {
+            reserve_unused_addr("[::1]:0")
+        } else {
+            reserve_unused_addr("127.0.0.1:0")
+        };
+        let resolver = StaticResolver {
+            candidates: vec![MxCandidate::with_addresses(
+                10,
+                "mx.dual.test",
+                vec![other_family, live],
+            )
+            .expect("candidate should be valid")],
+        };
+        let config = OutboundListenerConfig {
+            address_family: family,
+            ..test_config()
+        };
+
+        let (summary, rcpt_reply) = deliver(config, resolver);
+
+        assert!(rcpt_reply.starts_with("250 "), "unexpected RCPT reply: {}", rcpt_reply);
+        assert_eq!(
+            summary.mx_address_attempts,
+            [MxAddressAttempt {
+                exchange: "mx.dual.test".to_string(),
+                address: live,
+                outcome: MxAddressOutcome::Connected,
+            }],
+            "{:?}",
+            family
+        );
+        assert_eq!(join_mx(mx), 1);
+    }
+
+    let resolver = StaticResolver {
+        candidates: vec![MxCandidate::new(10, "mx.v6only.test", reserve_unused_addr("[::1]:0"))
+            .expect("candidate should be valid")],
+    };
+    let config = OutboundListenerConfig {
+        address_family: AddressFamilyPreference::Ipv4,
+        ..test_config()
+    };
+    let (summary, rcpt_reply) = deliver(config, resolver);
+    assert!(rcpt_reply.starts_with("451 4.4.0 "), "unexpected RCPT reply: {}", rcpt_reply);
+    assert!(rcpt_reply.contains("no addresses in the configured family"), "{}", rcpt_reply);
+    assert_eq!(summary.mx_candidates_attempted, 1);
+    assert!(summary.mx_address_attempts.is_empty());
+}
+
+#[test]
+fn flattened_resolver_results_count_as_one_host() {
+    let refused = reserve_unused_addr("127.0.0.1:0");
+    let (live, mx) = spawn_plaintext_mx("127.0.0.1:0");
+    let resolver = StaticResolver {
+        candidates: vec![
+            MxCandidate::new(10, "MX.flat.test", refused).expect("candidate should be valid"),
+            MxCandidate::new(10, "mx.flat.test", live).expect("candidate should be valid"),
+        ],
+    };
+
+    let (summary, rcpt_reply) = deliver(test_config(), resolver);
+
+    assert!(rcpt_reply.starts_with("250 "), "unexpected RCPT reply: {}", rcpt_reply);
+    assert_eq!(summary.mx_candidates_attempted, 1);
+    let attempted: Vec<SocketAddr> = summary
+        .mx_address_attempts
+        .iter()
+        .map(|attempt| attempt.address)
+        .collect();
+    assert_eq!(attempted, [refused, live]);
+    assert_eq!(join_mx(mx), 1);
+}
+
+fn test_config() -> OutboundListenerConfig {
+    OutboundListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        connection_attempt_delay: Duration::from_millis(100),
+        mx_connect_timeout: Duration::from_secs(2),
+        ..OutboundListenerConfig::default()
+    }
+}
+
+// Returns the session summary and the first line of the RCPT reply.
+fn deliver(
+    config: OutboundListenerConfig,
+    resolver: StaticResolver,
+) -> (OutboundSessionSummary, String) {
+    let listener = OutboundListener::bind(config, resolver)
+        .expect("outbound listener should bind for happy eyeballs test");
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.org>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "RCPT TO:<bob@dual.example>\r\n");
+    let rcpt_reply = read_reply(&mut reader).remove(0);
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary = handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary");
+    (summary, rcpt_reply)
+}
+
+fn reserve_unused_addr(bind_addr: &str) -> SocketAddr {
+    let listener = TcpListener::bind(bind_addr).expect("ephemeral listener should bind");
+    listener
+        .local_addr()
+        .expect("ephemeral listener address should resolve")
+}
+
+// A remote MX without STARTTLS. Returns how many recipients it accepted.
+fn spawn_plaintext_mx(bind_addr: &str) -> (SocketAddr, thread::JoinHandle<usize>) {
+    let listener = TcpListener::bind(bind_addr).expect("mock MX should bind");
+    let address = listener.local_addr().expect("mock MX address must resolve");
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().expect("mock MX should accept");
+        let mut reader = BufReader::new(stream.try_clone().expect("mock MX socket should clone"));
+        let mut accepted = 0;
+        stream
+            .write_all(b"220 mx.test ESMTP\r\n")
+            .expect("mock MX banner should write");
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return accepted;
+            }
+            let verb = line.split_whitespace().next().unwrap_or("").to_ascii_uppercase();
+            let reply: &[u8] = match verb.as_str() {
+                "EHLO" => b"250-mx.test\r\n250 PIPELINING\r\n",
+                "RCPT" => {
+                    accepted += 1;
+                    b"250 2.1.5 OK\r\n"
+                }
+                "QUIT" => {
+                    let _ = stream.write_all(b"221 2.0.0 Bye\r\n");
+                    return accepted;
+                }
+                _ => b"250 2.0.0 OK\r\n",
+            };
+            if stream.write_all(reply).is_err() {
+                return accepted;
+            }
+        }
+    });
+    (address, handle)
+}
+
+fn join_mx(handle: thread::JoinHandle<usize>) -> usize {
+    handle.join().expect("mock MX thread should not panic")
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("test client read timeout should set");
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, line: &str) {
+    stream
+        .write_all(line.as_bytes())
+        .expect("test client write should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        let read = reader
+            .read_line(&mut line)
+            .expect("test client read should succeed");
+        assert!(read > 0, "listener closed the connection before replying");
+        let line = line.trim_end().to_string();
+        let last = line.as_bytes().get(3) != Some(&b'-');
+        lines.push(line);
+        if last {
+            return lines;
+        }
+    }
+}
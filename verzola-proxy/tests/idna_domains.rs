@@ -0,0 +1,201 @@
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::outbound::{
+    MxCandidate, MxResolutionError, MxResolver, OutboundDomainTlsPolicy, OutboundListener,
+    OutboundListenerConfig, OutboundTlsPolicy,
+};
+
+#[derive(Debug, Clone, Default)]
+struct RecordingResolver {
+    lookups: Arc<Mutex<Vec<String>>>,
+}
+
+impl MxResolver for RecordingResolver {
+    fn resolve(&self, recipient_domain: &str) -> Result<Vec<MxCandidate>, MxResolutionError> {
+        self.lookups
+            .lock()
+            .expect("lookup log lock should not be poisoned")
+            .push(recipient_domain.to_string());
+        Err(MxResolutionError::Temporary(format!(
+            "no MX records found for {}",
+            recipient_domain
+        )))
+    }
+}
+
+#[test]
+fn domain_policies_store_the_canonical_a_label_form() {
+    let cases = [
+        ("bücher.example", "xn--bcher-kva.example"),
+        ("Bücher.Example.", "xn--bcher-kva.example"),
+        ("XN--BCHER-KVA.example", "xn--bcher-kva.example"),
+        ("MÜNCHEN.de", "xn--mnchen-3ya.de"),
+        ("例子。测试", "xn--fsqu00a.xn--0zwm56d"),
+        ("ñandú．example", "xn--and-6ma2c.example"),
+        // Non-transitional processing keeps sharp s instead of mapping it to "ss".
+        ("straße.de", "xn--strae-oqa.de"),
+        ("ｅｘａｍｐｌｅ.net", "example.net"),
+        ("ex\u{00ad}ample.net", "example.net"),
+    ];
+    for (configured, canonical) in cases {
+        let rule = OutboundDomainTlsPolicy::new(configured, OutboundTlsPolicy::RequireTls)
+            .expect("IDN domain policy should be valid");
+        assert_eq!(rule.recipient_domain, canonical, "normalizing {}", configured);
+    }
+
+    for invalid in [
+        "",
+        "..",
+        "xn--bcher-kva-.example",
+        "xn--bcher.example",
+        "xn--example.net",
+        "bücher-.example",
+        "ab--cd.example",
+        "bad_label.example",
+        "exa mple.net",
+        "\u{0301}accent.example",
+    ] {
+        let error = OutboundDomainTlsPolicy::new(invalid, OutboundTlsPolicy::RequireTls)
+            .expect_err("invalid domain should be rejected");
+        assert_eq!(error.kind(), ErrorKind::InvalidInput, "{} should be rejected", invalid);
+    }
+
+    let long_label = format!("{}.example", "ü".repeat(60));
+    assert!(OutboundDomainTlsPolicy::new(long_label, OutboundTlsPolicy::RequireTls).is_err());
+}
+
+#[test]
+fn config_validation_treats_u_labels_and_a_labels_as_duplicates() {
+    let mut config = OutboundListenerConfig {
+        bind_addr: "127.0.0.1:10025"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        per_domain_tls_policies: vec![
+            OutboundDomainTlsPolicy {
+                recipient_domain: "bücher.example".to_string(),
+                policy: OutboundTlsPolicy::RequireTls,
+            },
+            OutboundDomainTlsPolicy {
+                recipient_domain: "xn--bcher-kva.example".to_string(),
+                policy: OutboundTlsPolicy::Opportunistic,
+            },
+        ],
+        ..OutboundListenerConfig::default()
+    };
+
+    let error = config
+        .validate()
+        .expect_err("U-label and A-label rules for one domain should conflict");
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    assert!(
+        error
+            .to_string()
+            .contains("duplicate domain rule: xn--bcher-kva.example"),
+        "unexpected validation error: {}",
+        error
+    );
+
+    config.per_domain_tls_policies[1].recipient_domain = "xn--bcher-.example".to_string();
+    let error = config
+        .validate()
+        .expect_err("an undecodable A-label should be rejected");
+    assert!(
+        error
+            .to_string()
+            .contains("invalid recipient domain: xn--bcher-.example"),
+        "unexpected validation error: {}",
+        error
+    );
+}
+
+#[test]
+fn recipient_domains_match_policies_and_mx_lookups_in_a_label_form() {
+    let resolver = RecordingResolver::default();
+    let lookups = Arc::clone(&resolver.lookups);
+    let config = OutboundListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "relay.verzola.test".to_string(),
+        outbound_tls_policy: OutboundTlsPolicy::Opportunistic,
+        per_domain_tls_policies: vec![OutboundDomainTlsPolicy::new(
+            "xn--bcher-kva.example",
+            OutboundTlsPolicy::RequireTls,
+        )
+        .expect("domain policy should be valid")],
+        ..OutboundListenerConfig::default()
+    };
+    let listener = OutboundListener::bind(config, resolver)
+        .expect("outbound listener should bind for IDNA test");
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.org> SMTPUTF8\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "RCPT TO:<bob@Bücher.example>\r\n");
+    let rcpt_reply = read_reply(&mut reader);
+    assert!(
+        rcpt_reply[0].starts_with("451 "),
+        "unexpected RCPT reply: {}",
+        rcpt_reply.join(" | ")
+    );
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary = handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary");
+    assert_eq!(
+        summary.effective_tls_policy,
+        Some(OutboundTlsPolicy::RequireTls)
+    );
+    assert_eq!(
+        *lookups.lock().expect("lookup log lock should not be poisoned"),
+        ["xn--bcher-kva.example"]
+    );
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("test client read timeout should set");
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, line: &str) {
+    stream
+        .write_all(line.as_bytes())
+        .expect("test client write should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        let read = reader
+            .read_line(&mut line)
+            .expect("test client read should succeed");
+        assert!(read > 0, "listener closed the connection before replying");
+        let line = line.trim_end().to_string();
+        let last = line.as_bytes().get(3) != Some(&b'-');
+        lines.push(line);
+        if last {
+            return lines;
+        }
+    }
+}
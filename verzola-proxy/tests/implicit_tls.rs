@@ -0,0 +1,244 @@
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::auth::{AuthRequest, AuthenticationError, Authenticator};
+use verzola_proxy::inbound::{
+    InboundListener, InboundTlsMode, InboundTlsPolicy, ListenerConfig, ListenerMode,
+    SessionSummary, TlsSessionParameters, TlsUpgradeError, TlsUpgrader,
+};
+use verzola_proxy::telemetry::{StartTlsResult, TelemetryEvent, TelemetryRecorder};
+
+// Stands in for a TLS library: the "handshake" is one line each way, so the test can
+// check it happens before the 220 banner.
+#[derive(Debug, Clone, Copy)]
+struct HandshakeTlsUpgrader;
+
+impl TlsUpgrader for HandshakeTlsUpgrader {
+    fn upgrade(&self, stream: &mut TcpStream) -> Result<(), TlsUpgradeError> {
+        let mut reader = BufReader::new(
+            stream
+                .try_clone()
+                .map_err(|error| TlsUpgradeError::Temporary(error.to_string()))?,
+        );
+        let mut hello = String::new();
+        reader
+            .read_line(&mut hello)
+            .map_err(|error| TlsUpgradeError::Temporary(error.to_string()))?;
+        if hello != "CLIENT-HELLO\n" {
+            return Err(TlsUpgradeError::Temporary("bad client hello".to_string()));
+        }
+        stream
+            .write_all(b"SERVER-HELLO\n")
+            .map_err(|error| TlsUpgradeError::Temporary(error.to_string()))
+    }
+
+    fn session_parameters(&self, _stream: &TcpStream) -> Option<TlsSessionParameters> {
+        Some(TlsSessionParameters {
+            protocol_version: "TLSv1.3".to_string(),
+            cipher_suite: "TLS_AES_256_GCM_SHA384".to_string(),
+            key_exchange_group: Some("x25519".to_string()),
+        })
+    }
+}
+
+// Submission listeners must have an authenticator; these tests never log in.
+struct RejectingAuthenticator;
+
+impl Authenticator for RejectingAuthenticator {
+    fn authenticate(&self, _request: &AuthRequest) -> Result<String, AuthenticationError> {
+        Err(AuthenticationError::InvalidCredentials)
+    }
+}
+
+#[test]
+fn handshake_runs_before_banner_and_starttls_is_not_offered() {
+    let (address, handle, _recorder) = spawn_server(implicit_config());
+    let (mut stream, mut reader) = connect(address);
+
+    send(&mut stream, "CLIENT-HELLO\n");
+    let mut server_hello = String::new();
+    reader
+        .read_line(&mut server_hello)
+        .expect("server hello should arrive");
+    assert_eq!(server_hello, "SERVER-HELLO\n");
+
+    let banner = read_reply(&mut reader);
+    assert_eq!(banner, vec!["220 mx.verzola.test ESMTP VERZOLA".to_string()]);
+    send(&mut stream, "EHLO laptop.example\r\n");
+    let ehlo_reply = read_reply(&mut reader);
+    assert!(!ehlo_reply.iter().any(|line| line.contains("STARTTLS")));
+    assert!(ehlo_reply.iter().any(|line| line == "250-AUTH PLAIN LOGIN"));
+
+    send(&mut stream, "STARTTLS\r\n");
+    assert_eq!(
+        read_reply(&mut reader),
+        vec!["503 5.5.1 TLS already active".to_string()]
+    );
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary = join_server(handle);
+    assert!(summary.tls_negotiated);
+    assert_eq!(summary.tls_mode, InboundTlsMode::Implicit);
+    assert_eq!(
+        summary
+            .tls_parameters
+            .as_ref()
+            .map(|parameters| parameters.protocol_version.as_str()),
+        Some("TLSv1.3")
+    );
+    assert_eq!(summary.telemetry.starttls_attempts, 1);
+    assert!(!summary.telemetry.starttls_offered);
+}
+
+#[test]
+fn require_tls_policy_is_satisfied_from_the_start() {
+    let config = ListenerConfig {
+        mode: ListenerMode::Relay,
+        inbound_tls_policy: InboundTlsPolicy::RequireTls,
+        ..implicit_config()
+    };
+    let (address, handle, recorder) = spawn_server(config);
+    let (mut stream, mut reader) = connect(address);
+
+    send(&mut stream, "CLIENT-HELLO\n");
+    let mut server_hello = String::new();
+    reader
+        .read_line(&mut server_hello)
+        .expect("server hello should arrive");
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO partner.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.com>\r\n");
+    assert_eq!(read_reply(&mut reader), vec!["250 2.1.0 Sender OK".to_string()]);
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary = join_server(handle);
+    assert_eq!(summary.telemetry.require_tls_rejections, 0);
+    let events = recorder.session_events(&summary.session_id);
+    assert!(matches!(
+        events[1],
+        TelemetryEvent::ImplicitTls(StartTlsResult::Negotiated(Some(_)))
+    ));
+}
+
+#[test]
+fn failed_handshake_closes_without_banner() {
+    let (address, handle, recorder) = spawn_server(implicit_config());
+    let (mut stream, mut reader) = connect(address);
+
+    send(&mut stream, "GET / HTTP/1.1\r\n");
+    let mut remaining = Vec::new();
+    match reader.read_to_end(&mut remaining) {
+        Ok(_) => assert!(remaining.is_empty(), "no plaintext banner may be sent"),
+        Err(error) => assert_eq!(error.kind(), ErrorKind::ConnectionReset),
+    }
+
+    let summary = join_server(handle);
+    assert!(!summary.tls_negotiated);
+    assert_eq!(summary.command_count, 0);
+    assert_eq!(summary.telemetry.tls_upgrade_failures, 1);
+    assert_eq!(summary.telemetry.starttls_attempts, 0);
+    let events = recorder.session_events(&summary.session_id);
+    assert!(matches!(
+        events[1],
+        TelemetryEvent::ImplicitTls(StartTlsResult::Failed { .. })
+    ));
+}
+
+#[test]
+fn implicit_mode_satisfies_tls_requirements_without_starttls() {
+    let config = ListenerConfig {
+        advertise_starttls: false,
+        ..implicit_config()
+    };
+    config
+        .validate()
+        .expect("submission over implicit TLS needs no STARTTLS");
+
+    let starttls_config = ListenerConfig {
+        tls_mode: InboundTlsMode::StartTls,
+        ..config
+    };
+    assert!(starttls_config.validate().is_err());
+}
+
+fn implicit_config() -> ListenerConfig {
+    ListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "mx.verzola.test".to_string(),
+        mode: ListenerMode::Submission,
+        tls_mode: InboundTlsMode::Implicit,
+        ..ListenerConfig::default()
+    }
+}
+
+fn spawn_server(
+    config: ListenerConfig,
+) -> (
+    SocketAddr,
+    thread::JoinHandle<std::io::Result<SessionSummary>>,
+    Arc<TelemetryRecorder>,
+) {
+    let recorder = Arc::new(TelemetryRecorder::new());
+    let listener = InboundListener::bind(config, HandshakeTlsUpgrader)
+        .expect("implicit TLS listener should bind")
+        .with_authenticator(Arc::new(RejectingAuthenticator))
+        .with_telemetry_sink(recorder.clone());
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    (address, handle, recorder)
+}
+
+fn join_server(handle: thread::JoinHandle<std::io::Result<SessionSummary>>) -> SessionSummary {
+    handle
+        .join()
+        .expect("server thread should not panic")
+        .expect("server must return session summary")
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client must connect to listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .expect("test socket should accept read timeout");
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test socket clone for reader should succeed"),
+    );
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, command: &str) {
+    stream
+        .write_all(command.as_bytes())
+        .expect("test command write should succeed");
+    stream.flush().expect("test command flush should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut raw = String::new();
+        let bytes = reader
+            .read_line(&mut raw)
+            .expect("test should read SMTP server reply");
+        assert!(bytes > 0, "server closed connection unexpectedly");
+        let line = raw.trim_end_matches(['\r', '\n']).to_string();
+        lines.push(line.clone());
+
+        let line_bytes = line.as_bytes();
+        if line_bytes.len() < 4 || line_bytes[3] == b' ' {
+            break;
+        }
+    }
+    lines
+}
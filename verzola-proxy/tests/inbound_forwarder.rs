@@ -217,6 +217,7 @@ fn spawn_relay_listener(
         inbound_tls_policy: InboundTlsPolicy::Opportunistic,
         max_line_len: 4096,
         postfix_upstream_addr: Some(postfix_addr),
+        ..ListenerConfig::default()
     };
 
     let listener = InboundListener::bind(config, NoopTlsUpgrader)
@@ -251,7 +252,7 @@ fn spawn_mock_postfix(
         for handle in session_handles {
             let session_stats = handle
                 .join()
-                .map_err(|_| std::io::Error::new(ErrorKind::Other, "postfix worker panicked"))??;
+                .map_err(|_| std::io::Error::other("postfix worker panicked"))??;
             stats.sessions += 1;
             stats.messages += session_stats.message_count;
             stats.total_data_bytes += session_stats.data_bytes;
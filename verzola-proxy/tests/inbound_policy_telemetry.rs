@@ -114,6 +114,7 @@ fn require_tls_listener_config_requires_starttls_advertisement() {
         inbound_tls_policy: InboundTlsPolicy::RequireTls,
         max_line_len: 4096,
         postfix_upstream_addr: None,
+        ..ListenerConfig::default()
     };
 
     let error = match InboundListener::bind(config, NoopTlsUpgrader) {
@@ -191,6 +192,7 @@ where
         inbound_tls_policy: policy,
         max_line_len: 4096,
         postfix_upstream_addr: None,
+        ..ListenerConfig::default()
     };
 
     let listener =
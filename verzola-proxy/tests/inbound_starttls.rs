@@ -124,6 +124,7 @@ where
         inbound_tls_policy: InboundTlsPolicy::Opportunistic,
         max_line_len: 4096,
         postfix_upstream_addr: None,
+        ..ListenerConfig::default()
     };
 
     let listener = InboundListener::bind(config, tls_upgrader)
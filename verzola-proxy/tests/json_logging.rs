@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::inbound::{
+    InboundListener, InboundTlsPolicy, ListenerConfig, NoopTlsUpgrader, SessionSummary,
+    TlsSessionParameters, TlsUpgradeError,
+};
+use verzola_proxy::logging::{
+    DeliveryAttempt, DeliveryOutcome, EnvelopeRedaction, JsonLogConfig, JsonLogger, LogTarget,
+    SessionDirection,
+};
+use verzola_proxy::outbound::{
+    MxCandidate, MxResolutionError, MxResolver, OutboundDomainTlsPolicy, OutboundListener,
+    OutboundListenerConfig, OutboundTlsPolicy, OutboundTlsUpgrader,
+};
+
+#[derive(Debug, Clone)]
+struct StaticResolver {
+    candidates_by_domain: HashMap<String, Vec<MxCandidate>>,
+}
+
+impl MxResolver for StaticResolver {
+    fn resolve(&self, recipient_domain: &str) -> Result<Vec<MxCandidate>, MxResolutionError> {
+        self.candidates_by_domain
+            .get(recipient_domain)
+            .cloned()
+            .ok_or_else(|| {
+                MxResolutionError::Temporary(format!(
+                    "no MX records found for {}",
+                    recipient_domain
+                ))
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct EncryptingTlsUpgrader;
+
+impl OutboundTlsUpgrader for EncryptingTlsUpgrader {
+    fn upgrade(&self, _stream: &mut TcpStream, _exchange: &str) -> Result<(), TlsUpgradeError> {
+        Ok(())
+    }
+
+    fn session_parameters(&self, _stream: &TcpStream) -> Option<TlsSessionParameters> {
+        Some(TlsSessionParameters {
+            protocol_version: "TLSv1.3".to_string(),
+            cipher_suite: "TLS_AES_256_GCM_SHA384".to_string(),
+            key_exchange_group: Some("X25519".to_string()),
+        })
+    }
+}
+
+#[test]
+fn writes_session_and_delivery_events_with_hashed_envelope() {
+    let log_path = temp_log_path("hashed-envelope");
+    let logger = Arc::new(
+        JsonLogger::open(JsonLogConfig {
+            target: LogTarget::File {
+                path: log_path.clone(),
+                max_bytes: 1024 * 1024,
+                max_files: 2,
+            },
+            envelope_redaction: EnvelopeRedaction::Hash {
+                salt: "pepper".to_string(),
+            },
+        })
+        .expect("json logger should open log file"),
+    );
+
+    let config = ListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "mx.verzola.test".to_string(),
+        inbound_tls_policy: InboundTlsPolicy::Opportunistic,
+        ..ListenerConfig::default()
+    };
+    let listener = InboundListener::bind(config, NoopTlsUpgrader)
+        .expect("listener should bind for json logging test")
+        .with_json_logger(logger)
+        .with_name("mx-public");
+    let listener_addr = listener.local_addr().expect("listener address must resolve");
+    let listener_handle = thread::spawn(move || listener.serve_one());
+
+    let (mut stream, mut reader) = connect(listener_addr);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO sender.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<Alice@example.com> SIZE=128\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "RCPT TO:<bob@example.net>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "DATA\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("354 "));
+    send(&mut stream, "Subject: secret-body-marker\r\n\r\nhello\r\n.\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary: SessionSummary = listener_handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary");
+    assert!(!summary.session_id.is_empty());
+    assert_eq!(summary.helo_name.as_deref(), Some("sender.example"));
+    assert!(summary.client_addr.is_some());
+
+    let contents = fs::read_to_string(&log_path).expect("log file should be readable");
+    let lines: Vec<&str> = contents.lines().collect();
+    assert_eq!(lines.len(), 2, "expected delivery and session events: {}", contents);
+
+    let delivery = lines[0];
+    assert!(delivery.starts_with("{\"ts\":\""));
+    assert!(delivery.contains("\"event\":\"delivery_attempt\""));
+    assert!(delivery.contains("\"listener\":\"mx-public\""));
+    assert!(delivery.contains(&format!("\"session_id\":\"{}\"", summary.session_id)));
+    assert!(delivery.contains("\"helo\":\"sender.example\""));
+    assert!(delivery.contains("\"sender\":\"sha256:8b8d9adc4875c0dca816e3e17b7ac87b\""));
+    assert!(delivery.contains("\"recipients\":[\"sha256:4a1ea7577f3737e9e00672c2264793f8\"]"));
+    assert!(delivery.contains("\"reply_code\":250"));
+    assert!(delivery.contains("\"outcome\":\"accepted\""));
+    assert!(delivery.contains("\"policy_decision\":\"plaintext-allowed\""));
+
+    let session = lines[1];
+    assert!(session.contains("\"event\":\"smtp_session\""));
+    assert!(session.contains("\"direction\":\"inbound\""));
+    assert!(session.contains("\"listener\":\"mx-public\""));
+    assert!(session.contains("\"tls_mode\":\"starttls\""));
+    assert!(session.contains("\"tls_negotiated\":false"));
+
+    assert!(!contents.contains("example.com"));
+    assert!(!contents.contains("bob@"));
+    assert!(!contents.contains("secret-body-marker"));
+
+    let _ = fs::remove_file(&log_path);
+}
+
+#[test]
+fn writes_outbound_tls_parameters_and_policy_decision() {
+    let log_path = temp_log_path("outbound-tls");
+    let logger = Arc::new(
+        JsonLogger::open(JsonLogConfig {
+            target: LogTarget::File {
+                path: log_path.clone(),
+                max_bytes: 1024 * 1024,
+                max_files: 1,
+            },
+            envelope_redaction: EnvelopeRedaction::Plain,
+        })
+        .expect("json logger should open log file"),
+    );
+
+    let (remote_addr, remote_handle) = spawn_starttls_remote_mx();
+    let resolver = StaticResolver {
+        candidates_by_domain: HashMap::from([(
+            "example.net".to_string(),
+            vec![MxCandidate::new(10, "mx1.example.net", remote_addr)
+                .expect("candidate should be valid")],
+        )]),
+    };
+    let config = OutboundListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "relay.verzola.test".to_string(),
+        outbound_tls_policy: OutboundTlsPolicy::Opportunistic,
+        per_domain_tls_policies: vec![
+            OutboundDomainTlsPolicy::new("example.net", OutboundTlsPolicy::RequireTls)
+                .expect("domain policy should be valid"),
+        ],
+        ..OutboundListenerConfig::default()
+    };
+    let listener = OutboundListener::bind(config, resolver)
+        .expect("outbound listener should bind for json logging test")
+        .with_tls_upgrader(EncryptingTlsUpgrader)
+        .with_json_logger(logger)
+        .with_name("relay-outbound");
+    let listener_addr = listener.local_addr().expect("listener address must resolve");
+    let listener_handle = thread::spawn(move || listener.serve_one());
+
+    let (mut stream, mut reader) = connect(listener_addr);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.org>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "RCPT TO:<bob@example.net>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "DATA\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("354 "));
+    send(&mut stream, "Subject: hi\r\n\r\nhello\r\n.\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary = listener_handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary");
+    assert!(summary.tls_negotiated);
+    remote_handle
+        .join()
+        .expect("remote MX thread should not panic")
+        .expect("remote MX session should complete");
+
+    let contents = fs::read_to_string(&log_path).expect("log file should be readable");
+    let lines: Vec<&str> = contents.lines().collect();
+    assert_eq!(lines.len(), 2, "expected delivery and session events: {}", contents);
+
+    for (line, event) in [(lines[0], "delivery_attempt"), (lines[1], "smtp_session")] {
+        assert!(line.contains(&format!("\"event\":\"{}\"", event)), "{}", line);
+        assert!(line.contains("\"direction\":\"outbound\""), "{}", line);
+        assert!(line.contains("\"tls_negotiated\":true"), "{}", line);
+        assert!(line.contains("\"tls_version\":\"TLSv1.3\""), "{}", line);
+        assert!(line.contains("\"tls_cipher\":\"TLS_AES_256_GCM_SHA384\""), "{}", line);
+        assert!(line.contains("\"tls_group\":\"X25519\""), "{}", line);
+        assert!(line.contains("\"tls_policy\":\"require-tls\""), "{}", line);
+        assert!(line.contains("\"tls_policy_rule\":\"domain:example.net\""), "{}", line);
+        assert!(line.contains("\"policy_decision\":\"tls\""), "{}", line);
+    }
+    assert!(lines[0].contains("\"next_hop\":\"mx1.example.net\""));
+
+    let _ = fs::remove_file(&log_path);
+}
+
+#[test]
+fn redacts_envelope_addresses_by_default() {
+    let log_path = temp_log_path("redacted-envelope");
+    let logger = JsonLogger::open(JsonLogConfig {
+        target: LogTarget::File {
+            path: log_path.clone(),
+            max_bytes: 1024 * 1024,
+            max_files: 1,
+        },
+        ..JsonLogConfig::default()
+    })
+    .expect("json logger should open log file");
+
+    logger
+        .log_delivery_attempt(&sample_attempt("quote \"and\" backslash \\"))
+        .expect("delivery attempt should be logged");
+
+    let contents = fs::read_to_string(&log_path).expect("log file should be readable");
+    assert!(contents.contains("\"sender\":\"[redacted]\""));
+    assert!(contents.contains("\"recipients\":[\"[redacted]\"]"));
+    assert!(contents.contains("\"direction\":\"outbound\""));
+    assert!(contents.contains("\"next_hop\":\"mx1.example.net\""));
+    assert!(contents.contains("\"outcome\":\"deferred\""));
+    assert!(contents.contains("\"failure_reason\":\"quote \\\"and\\\" backslash \\\\\""));
+    assert!(!contents.contains("carol@example.org"));
+
+    let _ = fs::remove_file(&log_path);
+}
+
+#[test]
+fn rotates_log_file_when_size_limit_is_reached() {
+    let log_path = temp_log_path("rotation");
+    let logger = JsonLogger::open(JsonLogConfig {
+        target: LogTarget::File {
+            path: log_path.clone(),
+            max_bytes: 4096,
+            max_files: 2,
+        },
+        envelope_redaction: EnvelopeRedaction::Plain,
+    })
+    .expect("json logger should open log file");
+
+    let attempt = sample_attempt(&"x".repeat(512));
+    for _ in 0..40 {
+        logger
+            .log_delivery_attempt(&attempt)
+            .expect("delivery attempt should be logged");
+    }
+
+    let first_generation = rotated_path(&log_path, 1);
+    let second_generation = rotated_path(&log_path, 2);
+    assert!(first_generation.exists());
+    assert!(second_generation.exists());
+    assert!(!rotated_path(&log_path, 3).exists());
+
+    for path in [&log_path, &first_generation, &second_generation] {
+        let length = fs::metadata(path).expect("log file should exist").len();
+        assert!(length <= 4096, "{} grew to {} bytes", path.display(), length);
+        let contents = fs::read_to_string(path).expect("log file should be readable");
+        assert!(contents.lines().all(|line| line.ends_with('}')));
+    }
+
+    for path in [&log_path, &first_generation, &second_generation] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[test]
+fn validate_rejects_empty_hash_salt_and_tiny_rotation_limits() {
+    let empty_salt = JsonLogConfig {
+        envelope_redaction: EnvelopeRedaction::Hash {
+            salt: String::new(),
+        },
+        ..JsonLogConfig::default()
+    };
+    let error = empty_salt
+        .validate()
+        .expect_err("empty hash salt must be rejected");
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+
+    let tiny_rotation = JsonLogConfig {
+        target: LogTarget::File {
+            path: temp_log_path("tiny-rotation"),
+            max_bytes: 16,
+            max_files: 1,
+        },
+        ..JsonLogConfig::default()
+    };
+    let error = tiny_rotation
+        .validate()
+        .expect_err("tiny max_bytes must be rejected");
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+}
+
+fn sample_attempt(failure_reason: &str) -> DeliveryAttempt {
+    DeliveryAttempt {
+        session_id: "0000000000010001".to_string(),
+        direction: SessionDirection::Outbound,
+        sender: Some("carol@example.org".to_string()),
+        recipients: vec!["dave@example.net".to_string()],
+        next_hop: Some("mx1.example.net".to_string()),
+        reply_code: Some(451),
+        outcome: DeliveryOutcome::Deferred,
+        failure_reason: Some(failure_reason.to_string()),
+        ..DeliveryAttempt::default()
+    }
+}
+
+fn temp_log_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "verzola-json-logging-{}-{}.log",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+fn rotated_path(path: &Path, generation: usize) -> PathBuf {
+    PathBuf::from(format!("{}.{}", path.display(), generation))
+}
+
+// Serves one relay session that offers STARTTLS and accepts the message.
+fn spawn_starttls_remote_mx() -> (SocketAddr, thread::JoinHandle<std::io::Result<()>>) {
+    let listener =
+        TcpListener::bind("127.0.0.1:0").expect("remote MX listener should bind to localhost");
+    let address = listener
+        .local_addr()
+        .expect("remote MX listener address should resolve");
+
+    let handle = thread::spawn(move || -> std::io::Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        stream.set_read_timeout(Some(Duration::from_secs(3)))?;
+        stream.write_all(b"220 mx1.example.net ESMTP\r\n")?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut in_data = false;
+
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let command = line.trim_end_matches(['\r', '\n']);
+            if in_data {
+                if command == "." {
+                    in_data = false;
+                    stream.write_all(b"250 2.0.0 Queued\r\n")?;
+                }
+                continue;
+            }
+
+            let verb = command
+                .split_whitespace()
+                .next()
+                .unwrap_or("")
+                .to_ascii_uppercase();
+            let reply: &[u8] = match verb.as_str() {
+                "EHLO" => b"250-mx1.example.net\r\n250 STARTTLS\r\n",
+                "STARTTLS" => b"220 2.0.0 Ready to start TLS\r\n",
+                "MAIL" | "RCPT" | "RSET" => b"250 2.0.0 OK\r\n",
+                "DATA" => {
+                    in_data = true;
+                    b"354 End data with <CR><LF>.<CR><LF>\r\n"
+                }
+                "QUIT" => {
+                    stream.write_all(b"221 2.0.0 Bye\r\n")?;
+                    return Ok(());
+                }
+                _ => b"502 5.5.1 Command not implemented\r\n",
+            };
+            stream.write_all(reply)?;
+        }
+    });
+
+    (address, handle)
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .expect("test client read timeout should set");
+
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, command: &str) {
+    stream
+        .write_all(command.as_bytes())
+        .expect("test client should write command bytes");
+    stream.flush().expect("test client flush should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    loop {
+        let mut raw = String::new();
+        let bytes = reader
+            .read_line(&mut raw)
+            .expect("test client should read server reply");
+        assert!(bytes > 0, "server closed connection unexpectedly");
+
+        let line = raw.trim_end_matches(['\r', '\n']).to_string();
+        lines.push(line.clone());
+
+        let line_bytes = line.as_bytes();
+        if line_bytes.len() < 4 || line_bytes[3] == b' ' {
+            break;
+        }
+    }
+
+    lines
+}
@@ -0,0 +1,406 @@
+#![cfg(unix)]
+
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::inbound::{
+    InboundListener, InboundTlsPolicy, ListenerConfig, ListenerMode, NoopTlsUpgrader,
+    SessionSummary,
+};
+use verzola_proxy::logging::DeliveryAttempt;
+use verzola_proxy::telemetry::{TelemetryEvent, TelemetryRecorder};
+use verzola_proxy::upstream::{PostfixBackend, UpstreamProtocol, UpstreamTarget};
+
+// Serves sessions on a Unix socket until the test process exits. `data_replies` are
+// sent in order after the end of DATA: one line for SMTP, one per recipient for LMTP.
+struct MockUpstream {
+    path: PathBuf,
+    commands: Arc<Mutex<Vec<String>>>,
+}
+
+#[test]
+fn smtp_upstream_over_unix_socket_relays_the_message() {
+    let upstream = spawn_mock_upstream(
+        "smtp",
+        "EHLO",
+        &["250 2.0.0 Ok: queued as 4BQ9ZX1nqPz"],
+    );
+    let (summary, replies, attempt) = run_session(
+        relay_config(&upstream.path, UpstreamProtocol::Smtp),
+        &["bob@example.net"],
+    );
+
+    assert_eq!(
+        replies.last().map(String::as_str),
+        Some("250 2.0.0 Ok: queued as 4BQ9ZX1nqPz")
+    );
+    assert_eq!(summary.protocol_errors, 0);
+    assert_eq!(attempt.postfix_queue_id.as_deref(), Some("4BQ9ZX1nqPz"));
+    assert_eq!(
+        attempt.next_hop,
+        Some(format!("unix:{}", upstream.path.display()))
+    );
+    assert_eq!(upstream.commands()[0], "EHLO mx.verzola.test");
+    let _ = fs::remove_file(&upstream.path);
+}
+
+#[test]
+fn lmtp_uses_lhlo_and_reads_one_reply_per_recipient() {
+    let upstream = spawn_mock_upstream(
+        "lmtp-ok",
+        "LHLO",
+        &[
+            "250 2.0.0 <bob@example.net> Eg8kAB2s Saved",
+            "250 2.0.0 <carol@example.net> Eg8kAB2s Saved",
+        ],
+    );
+    let (_summary, replies, attempt) = run_session(
+        relay_config(&upstream.path, UpstreamProtocol::Lmtp),
+        &["bob@example.net", "carol@example.net"],
+    );
+
+    assert_eq!(
+        replies.last().map(String::as_str),
+        Some("250 2.0.0 <bob@example.net> Eg8kAB2s Saved")
+    );
+    assert_eq!(attempt.failure_reason, None);
+    assert_eq!(attempt.reply_code, Some(250));
+    assert_eq!(upstream.commands()[0], "LHLO mx.verzola.test");
+    let _ = fs::remove_file(&upstream.path);
+}
+
+#[test]
+fn lmtp_partial_temporary_failure_asks_the_client_to_retry() {
+    let upstream = spawn_mock_upstream(
+        "lmtp-temp",
+        "LHLO",
+        &[
+            "250 2.0.0 <bob@example.net> Saved",
+            "452 4.2.2 <carol@example.net> Quota exceeded",
+        ],
+    );
+    let (_summary, replies, attempt) = run_session(
+        relay_config(&upstream.path, UpstreamProtocol::Lmtp),
+        &["bob@example.net", "carol@example.net"],
+    );
+
+    assert_eq!(
+        replies.last().map(String::as_str),
+        Some("451 4.3.0 LMTP delivery failed for 1 of 2 recipients, try again later")
+    );
+    assert_eq!(attempt.reply_code, Some(451));
+    let failure_reason = attempt.failure_reason.unwrap_or_default();
+    assert_eq!(failure_reason, "recipient 2 of 2: 452 4.2.2");
+    let _ = fs::remove_file(&upstream.path);
+}
+
+#[test]
+fn lmtp_partial_permanent_failure_asks_the_client_to_retry() {
+    let upstream = spawn_mock_upstream(
+        "lmtp-perm",
+        "LHLO",
+        &[
+            "550 5.1.1 <bob@example.net> User doesn't exist",
+            "250 2.0.0 <carol@example.net> Saved",
+        ],
+    );
+    let (_summary, replies, attempt) = run_session(
+        relay_config(&upstream.path, UpstreamProtocol::Lmtp),
+        &["bob@example.net", "carol@example.net"],
+    );
+
+    assert_eq!(
+        replies.last().map(String::as_str),
+        Some("451 4.3.0 LMTP delivery failed for 1 of 2 recipients, try again later")
+    );
+    assert_eq!(attempt.reply_code, Some(451));
+    assert_eq!(
+        attempt.failure_reason.as_deref(),
+        Some("recipient 1 of 2: 550 5.1.1")
+    );
+    let _ = fs::remove_file(&upstream.path);
+}
+
+#[test]
+fn lmtp_total_failure_passes_the_most_retryable_reply_through() {
+    let upstream = spawn_mock_upstream(
+        "lmtp-fail",
+        "LHLO",
+        &[
+            "550 5.1.1 <bob@example.net> User doesn't exist",
+            "451 4.3.0 <carol@example.net> Temporary internal failure",
+        ],
+    );
+    let (_summary, replies, _attempt) = run_session(
+        relay_config(&upstream.path, UpstreamProtocol::Lmtp),
+        &["bob@example.net", "carol@example.net"],
+    );
+    assert_eq!(
+        replies.last().map(String::as_str),
+        Some("451 4.3.0 <carol@example.net> Temporary internal failure")
+    );
+    let _ = fs::remove_file(&upstream.path);
+
+    let upstream = spawn_mock_upstream(
+        "lmtp-reject",
+        "LHLO",
+        &["552 5.2.2 <bob@example.net> Mailbox full"],
+    );
+    let (_summary, replies, attempt) = run_session(
+        relay_config(&upstream.path, UpstreamProtocol::Lmtp),
+        &["bob@example.net"],
+    );
+    assert_eq!(
+        replies.last().map(String::as_str),
+        Some("552 5.2.2 <bob@example.net> Mailbox full")
+    );
+    assert_eq!(attempt.reply_code, Some(552));
+    let _ = fs::remove_file(&upstream.path);
+}
+
+#[test]
+fn health_checks_use_the_configured_protocol() {
+    let upstream = spawn_mock_upstream("lmtp-health", "LHLO", &[]);
+    let listener = InboundListener::bind(
+        relay_config(&upstream.path, UpstreamProtocol::Lmtp),
+        NoopTlsUpgrader,
+    )
+    .expect("relay listener should bind");
+
+    listener.check_upstreams();
+    let health = listener.upstream_health();
+    assert!(health[0].healthy);
+    assert_eq!(health[0].consecutive_failures, 0);
+    assert_eq!(upstream.commands()[0], "LHLO mx.verzola.test");
+    let _ = fs::remove_file(&upstream.path);
+
+    let missing = socket_path("missing");
+    let _ = fs::remove_file(&missing);
+    let listener = InboundListener::bind(
+        relay_config(&missing, UpstreamProtocol::Smtp),
+        NoopTlsUpgrader,
+    )
+    .expect("relay listener should bind");
+    listener.check_upstreams();
+    assert_eq!(listener.upstream_health()[0].total_failures, 1);
+}
+
+#[test]
+fn validate_rejects_lmtp_submission_and_empty_socket_paths() {
+    let submission = ListenerConfig {
+        mode: ListenerMode::Submission,
+        ..relay_config(&socket_path("unused"), UpstreamProtocol::Lmtp)
+    };
+    let error = submission
+        .validate()
+        .expect_err("LMTP cannot carry the submission identity");
+    assert!(error.to_string().contains("upstream_protocol=lmtp"));
+
+    let empty_path = relay_config(Path::new(""), UpstreamProtocol::Smtp);
+    assert_eq!(
+        empty_path.validate().map_err(|error| error.kind()),
+        Err(ErrorKind::InvalidInput)
+    );
+}
+
+impl MockUpstream {
+    fn commands(&self) -> Vec<String> {
+        self.commands
+            .lock()
+            .expect("mock upstream command log should lock")
+            .clone()
+    }
+}
+
+fn relay_config(path: &Path, protocol: UpstreamProtocol) -> ListenerConfig {
+    ListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "mx.verzola.test".to_string(),
+        inbound_tls_policy: InboundTlsPolicy::Opportunistic,
+        postfix_upstreams: vec![PostfixBackend::new(UpstreamTarget::Unix(path.to_path_buf()))],
+        upstream_protocol: protocol,
+        ..ListenerConfig::default()
+    }
+}
+
+fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "verzola-upstream-{}-{}.sock",
+        name,
+        std::process::id()
+    ))
+}
+
+// Sends one message to `recipients` and returns the summary, the client's final DATA
+// reply lines and the delivery attempt from telemetry.
+fn run_session(
+    config: ListenerConfig,
+    recipients: &[&str],
+) -> (SessionSummary, Vec<String>, DeliveryAttempt) {
+    let recorder = Arc::new(TelemetryRecorder::new());
+    let listener = InboundListener::bind(config, NoopTlsUpgrader)
+        .expect("relay listener should bind")
+        .with_telemetry_sink(recorder.clone());
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO sender.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.com>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    for recipient in recipients {
+        send(&mut stream, &format!("RCPT TO:<{}>\r\n", recipient));
+        assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    }
+    send(&mut stream, "DATA\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("354 "));
+    send(&mut stream, "Subject: hello\r\n\r\nHi there.\r\n.\r\n");
+    let data_reply = read_reply(&mut reader);
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary = handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary");
+    let attempt = recorder
+        .session_events(&summary.session_id)
+        .into_iter()
+        .find_map(|event| match event {
+            TelemetryEvent::Data(attempt) => Some(*attempt),
+            _ => None,
+        })
+        .expect("session should record a delivery attempt");
+
+    (summary, data_reply, attempt)
+}
+
+fn spawn_mock_upstream(
+    name: &str,
+    greeting_verb: &'static str,
+    data_replies: &'static [&'static str],
+) -> MockUpstream {
+    let path = socket_path(name);
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).expect("mock upstream socket should bind");
+    let commands = Arc::new(Mutex::new(Vec::new()));
+
+    let command_log = Arc::clone(&commands);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let _ = handle_upstream_session(stream, greeting_verb, data_replies, &command_log);
+        }
+    });
+
+    MockUpstream { path, commands }
+}
+
+fn handle_upstream_session(
+    mut stream: UnixStream,
+    greeting_verb: &str,
+    data_replies: &[&str],
+    command_log: &Mutex<Vec<String>>,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(3)))?;
+    write_line(&mut stream, "220 upstream.verzola.test ESMTP")?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut reading_data = false;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        if reading_data {
+            if line == ".\r\n" {
+                reading_data = false;
+                for reply in data_replies {
+                    write_line(&mut stream, reply)?;
+                }
+            }
+            continue;
+        }
+
+        let command = line.trim_end_matches(['\r', '\n']).to_string();
+        let verb = command
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_ascii_uppercase();
+        command_log
+            .lock()
+            .expect("mock upstream command log should lock")
+            .push(command);
+        match verb.as_str() {
+            "EHLO" | "LHLO" if verb == greeting_verb => {
+                write_line(&mut stream, "250-upstream.verzola.test")?;
+                write_line(&mut stream, "250 PIPELINING")?;
+            }
+            "EHLO" | "LHLO" | "HELO" => write_line(&mut stream, "500 5.5.1 Unknown command")?,
+            "DATA" => {
+                reading_data = true;
+                write_line(&mut stream, "354 End data with <CR><LF>.<CR><LF>")?;
+            }
+            "QUIT" => return write_line(&mut stream, "221 2.0.0 Bye"),
+            _ => write_line(&mut stream, "250 2.0.0 Ok")?,
+        }
+    }
+}
+
+fn write_line(stream: &mut UnixStream, line: &str) -> std::io::Result<()> {
+    write!(stream, "{}\r\n", line)?;
+    stream.flush()
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("test client read timeout should set");
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, command: &str) {
+    stream
+        .write_all(command.as_bytes())
+        .expect("test client should write command bytes");
+    stream.flush().expect("test client flush should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut raw = String::new();
+        let bytes = reader
+            .read_line(&mut raw)
+            .expect("test client should read server reply");
+        assert!(bytes > 0, "server closed connection unexpectedly");
+        let line = raw.trim_end_matches(['\r', '\n']).to_string();
+        lines.push(line.clone());
+
+        let line_bytes = line.as_bytes();
+        if line_bytes.len() < 4 || line_bytes[3] == b' ' {
+            break;
+        }
+    }
+    lines
+}
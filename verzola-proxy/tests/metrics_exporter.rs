@@ -0,0 +1,248 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::inbound::{
+    InboundListener, InboundTlsPolicy, ListenerConfig, NoopTlsUpgrader, SessionSummary,
+};
+use verzola_proxy::metrics::{MetricsExporter, MetricsRegistry};
+use verzola_proxy::outbound::OutboundSessionSummary;
+
+#[test]
+fn exports_inbound_session_counters_over_http() {
+    let registry = Arc::new(MetricsRegistry::default());
+    let config = ListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "mx.verzola.test".to_string(),
+        inbound_tls_policy: InboundTlsPolicy::RequireTls,
+        ..ListenerConfig::default()
+    };
+    let listener = InboundListener::bind(config, NoopTlsUpgrader)
+        .expect("listener should bind for metrics test")
+        .with_metrics(Arc::clone(&registry), "mx-25");
+    let listener_addr = listener.local_addr().expect("listener address must resolve");
+    let listener_handle = thread::spawn(move || listener.serve_one());
+
+    let (mut stream, mut reader) = connect(listener_addr);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO sender.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.com>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("530 "));
+    send(&mut stream, "STARTTLS\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("220 "));
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let _summary: SessionSummary = listener_handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary");
+
+    let exporter = MetricsExporter::bind(
+        "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        Arc::clone(&registry),
+    )
+    .expect("metrics exporter should bind");
+    let exporter_addr = exporter.local_addr().expect("exporter address must resolve");
+    let exporter_handle = thread::spawn(move || exporter.serve_one());
+
+    let response = http_get(exporter_addr, "/metrics");
+    exporter_handle
+        .join()
+        .expect("exporter thread should not panic")
+        .expect("exporter should serve one scrape");
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+    assert!(response.contains("# TYPE verzola_inbound_sessions_total counter"));
+    assert!(response.contains("verzola_inbound_sessions_total{listener=\"mx-25\"} 1\n"));
+    assert!(response.contains("verzola_inbound_starttls_offered_total{listener=\"mx-25\"} 1\n"));
+    assert!(response.contains("verzola_inbound_starttls_attempts_total{listener=\"mx-25\"} 1\n"));
+    assert!(response.contains(
+        "verzola_inbound_require_tls_rejections_total{listener=\"mx-25\"} 1\n"
+    ));
+    assert!(response.contains(
+        "verzola_inbound_session_duration_seconds_bucket{listener=\"mx-25\",le=\"+Inf\"} 1\n"
+    ));
+    assert!(response.contains(
+        "verzola_inbound_session_duration_seconds_count{listener=\"mx-25\"} 1\n"
+    ));
+}
+
+#[test]
+fn exporter_rejects_unknown_paths() {
+    let registry = Arc::new(MetricsRegistry::default());
+    let exporter = MetricsExporter::bind(
+        "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        registry,
+    )
+    .expect("metrics exporter should bind");
+    let exporter_addr = exporter.local_addr().expect("exporter address must resolve");
+    let exporter_handle = thread::spawn(move || exporter.serve_one());
+
+    let response = http_get(exporter_addr, "/debug");
+    exporter_handle
+        .join()
+        .expect("exporter thread should not panic")
+        .expect("exporter should serve one request");
+
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[test]
+fn exporter_stops_reading_an_unterminated_request_line() {
+    let registry = Arc::new(MetricsRegistry::default());
+    let exporter = MetricsExporter::bind(
+        "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        registry,
+    )
+    .expect("metrics exporter should bind");
+    let exporter_addr = exporter.local_addr().expect("exporter address must resolve");
+    let exporter_handle = thread::spawn(move || exporter.serve_one());
+
+    let mut stream = TcpStream::connect(exporter_addr).expect("client should connect to exporter");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .expect("test client read timeout should set");
+    // One byte over the 8 KiB header cap and no newline: the exporter must answer without
+    // waiting for the end of the line.
+    let line = format!("GET /metrics{}", "a".repeat(8193 - "GET /metrics".len()));
+    stream
+        .write_all(line.as_bytes())
+        .expect("test client should write the request line");
+
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    exporter_handle
+        .join()
+        .expect("exporter thread should not panic")
+        .expect("exporter should answer the oversized request");
+
+    assert!(
+        response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+        "unexpected response: {:?}",
+        response
+    );
+}
+
+#[test]
+fn label_sets_beyond_budget_fold_into_overflow_series() {
+    let registry = MetricsRegistry::new(3);
+    let summary = SessionSummary::default();
+
+    for listener in ["mx-a", "mx-b", "mx-c", "mx-d"] {
+        registry.record_inbound_session(listener, &summary);
+    }
+
+    let rendered = registry.render();
+    assert!(rendered.contains("verzola_inbound_sessions_total{listener=\"mx-a\"} 1\n"));
+    assert!(rendered.contains("verzola_inbound_sessions_total{listener=\"mx-b\"} 1\n"));
+    assert!(rendered.contains("verzola_inbound_sessions_total{listener=\"other\"} 2\n"));
+    assert!(!rendered.contains("listener=\"mx-c\""));
+    assert!(rendered.contains("verzola_metrics_label_overflow_total "));
+
+    // The overflow series counts against the budget.
+    let series = rendered
+        .lines()
+        .filter(|line| line.starts_with("verzola_inbound_sessions_total{"))
+        .count();
+    assert_eq!(series, 3);
+}
+
+#[test]
+fn exports_outbound_starttls_counters() {
+    let registry = MetricsRegistry::default();
+    let summary = OutboundSessionSummary {
+        starttls_attempts: 2,
+        tls_upgrade_failures: 1,
+        opportunistic_tls_fallbacks: 1,
+        ..OutboundSessionSummary::default()
+    };
+
+    registry.record_outbound_session("relay-10025", &summary);
+
+    let rendered = registry.render();
+    assert!(rendered.contains("# TYPE verzola_outbound_starttls_attempts_total counter\n"));
+    assert!(rendered
+        .contains("verzola_outbound_starttls_attempts_total{listener=\"relay-10025\"} 2\n"));
+    assert!(rendered
+        .contains("verzola_outbound_starttls_failures_total{listener=\"relay-10025\"} 1\n"));
+    assert!(rendered.contains(
+        "verzola_outbound_opportunistic_tls_fallbacks_total{listener=\"relay-10025\"} 1\n"
+    ));
+}
+
+fn http_get(address: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(address).expect("client should connect to exporter");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .expect("test client read timeout should set");
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nAccept: text/plain\r\n\r\n",
+        path
+    )
+    .expect("test client should write HTTP request");
+    stream.flush().expect("test client flush should succeed");
+
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .expect("test client should read HTTP response");
+    response
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .expect("test client read timeout should set");
+
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, command: &str) {
+    stream
+        .write_all(command.as_bytes())
+        .expect("test client should write command bytes");
+    stream.flush().expect("test client flush should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    loop {
+        let mut raw = String::new();
+        let bytes = reader
+            .read_line(&mut raw)
+            .expect("test client should read server reply");
+        assert!(bytes > 0, "server closed connection unexpectedly");
+
+        let line = raw.trim_end_matches(['\r', '\n']).to_string();
+        lines.push(line.clone());
+
+        let line_bytes = line.as_bytes();
+        if line_bytes.len() < 4 || line_bytes[3] == b' ' {
+            break;
+        }
+    }
+
+    lines
+}
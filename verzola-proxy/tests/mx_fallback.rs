@@ -0,0 +1,385 @@
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use verzola_proxy::outbound::{
+    MxCandidate, MxResolutionError, MxResolver, OutboundListener, OutboundListenerConfig,
+    OutboundSessionSummary,
+};
+
+#[derive(Debug, Clone)]
+struct StaticResolver {
+    candidates: Vec<MxCandidate>,
+}
+
+impl MxResolver for StaticResolver {
+    fn resolve(&self, _recipient_domain: &str) -> Result<Vec<MxCandidate>, MxResolutionError> {
+        Ok(self.candidates.clone())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MxScript {
+    greeting: &'static str,
+    ehlo: &'static str,
+    mail: &'static str,
+}
+
+const HEALTHY: MxScript = MxScript {
+    greeting: "220 mx.test ESMTP",
+    ehlo: "250 mx.test",
+    mail: "250 2.1.0 OK",
+};
+
+#[test]
+fn equal_preference_hosts_are_tried_in_random_order() {
+    const SESSIONS: usize = 16;
+    let refused = reserve_unused_addr();
+    let (live, _sessions) = spawn_scripted_mx(HEALTHY);
+    let resolver = StaticResolver {
+        candidates: vec![
+            candidate(10, "mx-a.pref.test", live),
+            candidate(10, "mx-b.pref.test", live),
+            candidate(5, "mx0.pref.test", refused),
+        ],
+    };
+    let listener = OutboundListener::bind(test_config(), resolver)
+        .expect("outbound listener should bind for MX fallback test");
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_n(SESSIONS));
+
+    for _ in 0..SESSIONS {
+        let rcpt_reply = attempt_delivery(address);
+        assert!(rcpt_reply.starts_with("250 "), "unexpected RCPT reply: {}", rcpt_reply);
+    }
+    let summaries = handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should serve every session");
+
+    let mut selected = HashSet::new();
+    for summary in summaries {
+        // The lower preference value is always tried first.
+        assert_eq!(summary.mx_address_attempts[0].exchange, "mx0.pref.test");
+        assert_eq!(summary.mx_candidates_attempted, 2);
+        selected.insert(summary.selected_mx.expect("a host should be selected"));
+    }
+    assert_eq!(
+        selected,
+        HashSet::from(["mx-a.pref.test".to_string(), "mx-b.pref.test".to_string()]),
+        "both equal-preference hosts should receive deliveries"
+    );
+}
+
+#[test]
+fn transient_greeting_and_ehlo_replies_fall_back_to_the_next_host() {
+    let (busy, busy_sessions) = spawn_scripted_mx(MxScript {
+        greeting: "421 4.3.2 Too many connections",
+        ..HEALTHY
+    });
+    let (deferring, deferring_sessions) = spawn_scripted_mx(MxScript {
+        ehlo: "451 4.3.0 Try again later",
+        ..HEALTHY
+    });
+    let (live, live_sessions) = spawn_scripted_mx(HEALTHY);
+    let resolver = StaticResolver {
+        candidates: vec![
+            candidate(10, "mx1.fallback.test", busy),
+            candidate(20, "mx2.fallback.test", deferring),
+            candidate(30, "mx3.fallback.test", live),
+        ],
+    };
+
+    let (summary, rcpt_reply) = deliver(test_config(), resolver);
+
+    assert!(rcpt_reply.starts_with("250 "), "unexpected RCPT reply: {}", rcpt_reply);
+    assert_eq!(summary.mx_candidates_attempted, 3);
+    assert_eq!(summary.selected_mx.as_deref(), Some("mx3.fallback.test"));
+    for sessions in [busy_sessions, deferring_sessions, live_sessions] {
+        assert_eq!(sessions.load(Ordering::SeqCst), 1);
+    }
+}
+
+#[test]
+fn permanent_mail_rejection_stops_the_fallback() {
+    let (rejecting, _rejecting_sessions) = spawn_scripted_mx(MxScript {
+        mail: "550 5.7.1 Sender rejected",
+        ..HEALTHY
+    });
+    let (live, live_sessions) = spawn_scripted_mx(HEALTHY);
+    let resolver = StaticResolver {
+        candidates: vec![
+            candidate(10, "mx1.reject.test", rejecting),
+            candidate(20, "mx2.reject.test", live),
+        ],
+    };
+
+    let (summary, rcpt_reply) = deliver(test_config(), resolver);
+
+    assert!(rcpt_reply.starts_with("451 4.4.0 "), "unexpected RCPT reply: {}", rcpt_reply);
+    assert!(rcpt_reply.contains("(550)"), "{}", rcpt_reply);
+    assert_eq!(summary.mx_candidates_attempted, 1);
+    assert!(!summary.remote_session_established);
+    assert_eq!(live_sessions.load(Ordering::SeqCst), 0);
+
+    // A transient MAIL reply still moves on to the next host.
+    let (greylisting, _greylisting_sessions) = spawn_scripted_mx(MxScript {
+        mail: "451 4.7.1 Greylisted",
+        ..HEALTHY
+    });
+    let resolver = StaticResolver {
+        candidates: vec![
+            candidate(10, "mx1.reject.test", greylisting),
+            candidate(20, "mx2.reject.test", live),
+        ],
+    };
+    let (summary, rcpt_reply) = deliver(test_config(), resolver);
+    assert!(rcpt_reply.starts_with("250 "), "unexpected RCPT reply: {}", rcpt_reply);
+    assert_eq!(summary.selected_mx.as_deref(), Some("mx2.reject.test"));
+}
+
+#[test]
+fn host_and_address_limits_cap_the_attempts() {
+    let resolver = StaticResolver {
+        candidates: (1..=3)
+            .map(|index| {
+                MxCandidate::with_addresses(
+                    index * 10,
+                    format!("mx{}.limit.test", index),
+                    vec![reserve_unused_addr(), reserve_unused_addr()],
+                )
+                .expect("candidate should be valid")
+            })
+            .collect(),
+    };
+
+    let config = OutboundListenerConfig {
+        max_mx_hosts: 2,
+        ..test_config()
+    };
+    let (summary, rcpt_reply) = deliver(config, resolver.clone());
+    assert!(rcpt_reply.starts_with("451 4.4.0 "), "unexpected RCPT reply: {}", rcpt_reply);
+    assert_eq!(summary.mx_candidates_attempted, 2);
+    assert_eq!(summary.mx_address_attempts.len(), 4);
+
+    let config = OutboundListenerConfig {
+        max_mx_addresses: 3,
+        ..test_config()
+    };
+    let (summary, _rcpt_reply) = deliver(config, resolver);
+    assert_eq!(summary.mx_candidates_attempted, 2);
+    let exchanges: Vec<&str> = summary
+        .mx_address_attempts
+        .iter()
+        .map(|attempt| attempt.exchange.as_str())
+        .collect();
+    assert_eq!(exchanges, ["mx1.limit.test", "mx1.limit.test", "mx2.limit.test"]);
+
+    for config in [
+        OutboundListenerConfig {
+            max_mx_hosts: 0,
+            ..test_config()
+        },
+        OutboundListenerConfig {
+            max_mx_addresses: 0,
+            ..test_config()
+        },
+    ] {
+        let error = config.validate().expect_err("a zero limit should be rejected");
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert_eq!(
+            error.to_string(),
+            "max_mx_hosts and max_mx_addresses must be greater than zero"
+        );
+    }
+}
+
+#[test]
+fn stalled_hosts_defer_once_the_time_budget_is_spent() {
+    // Connections complete in the backlog, but no banner is ever sent.
+    let silent = TcpListener::bind("127.0.0.1:0").expect("silent MX should bind");
+    let (live, live_sessions) = spawn_scripted_mx(HEALTHY);
+    let resolver = StaticResolver {
+        candidates: vec![
+            candidate(
+                10,
+                "mx1.stalled.test",
+                silent.local_addr().expect("silent MX address must resolve"),
+            ),
+            candidate(20, "mx2.stalled.test", live),
+        ],
+    };
+    let config = OutboundListenerConfig {
+        delivery_time_budget: Duration::from_millis(300),
+        ..test_config()
+    };
+
+    let started_at = Instant::now();
+    let (summary, rcpt_reply) = deliver(config, resolver);
+
+    assert!(rcpt_reply.starts_with("451 4.4.7 "), "unexpected RCPT reply: {}", rcpt_reply);
+    assert!(
+        rcpt_reply.contains("delivery time budget of 300 ms exhausted after 1 MX hosts"),
+        "{}",
+        rcpt_reply
+    );
+    assert!(started_at.elapsed() < Duration::from_secs(2));
+    assert_eq!(summary.mx_candidates_attempted, 1);
+    assert_eq!(summary.delivery_budget_exhaustions, 1);
+    assert_eq!(live_sessions.load(Ordering::SeqCst), 0);
+
+    let config = OutboundListenerConfig {
+        delivery_time_budget: Duration::ZERO,
+        ..test_config()
+    };
+    assert_eq!(
+        config
+            .validate()
+            .expect_err("a zero budget should be rejected")
+            .to_string(),
+        "delivery_time_budget must be greater than zero"
+    );
+}
+
+fn test_config() -> OutboundListenerConfig {
+    OutboundListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        connection_attempt_delay: Duration::from_millis(100),
+        mx_connect_timeout: Duration::from_secs(2),
+        ..OutboundListenerConfig::default()
+    }
+}
+
+fn candidate(preference: u16, exchange: &str, address: SocketAddr) -> MxCandidate {
+    MxCandidate::new(preference, exchange, address).expect("candidate should be valid")
+}
+
+// Returns the session summary and the first line of the RCPT reply.
+fn deliver(
+    config: OutboundListenerConfig,
+    resolver: StaticResolver,
+) -> (OutboundSessionSummary, String) {
+    let listener = OutboundListener::bind(config, resolver)
+        .expect("outbound listener should bind for MX fallback test");
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    let rcpt_reply = attempt_delivery(address);
+    let summary = handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary");
+    (summary, rcpt_reply)
+}
+
+fn attempt_delivery(address: SocketAddr) -> String {
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.org>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "RCPT TO:<bob@fallback.example>\r\n");
+    let rcpt_reply = read_reply(&mut reader).remove(0);
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+    rcpt_reply
+}
+
+fn reserve_unused_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("ephemeral listener should bind");
+    listener
+        .local_addr()
+        .expect("ephemeral listener address should resolve")
+}
+
+// A remote MX without STARTTLS that answers with the scripted replies. Serves connections until
+// the test process exits and counts them.
+fn spawn_scripted_mx(script: MxScript) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("mock MX should bind");
+    let address = listener.local_addr().expect("mock MX address must resolve");
+    let sessions = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&sessions);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            counter.fetch_add(1, Ordering::SeqCst);
+            thread::spawn(move || serve_script(stream, script));
+        }
+    });
+    (address, sessions)
+}
+
+fn serve_script(mut stream: TcpStream, script: MxScript) {
+    let mut reader = BufReader::new(stream.try_clone().expect("mock MX socket should clone"));
+    if stream
+        .write_all(format!("{}\r\n", script.greeting).as_bytes())
+        .is_err()
+        || !script.greeting.starts_with('2')
+    {
+        return;
+    }
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let verb = line.split_whitespace().next().unwrap_or("").to_ascii_uppercase();
+        let reply = match verb.as_str() {
+            "EHLO" => script.ehlo,
+            "MAIL" => script.mail,
+            "QUIT" => {
+                let _ = stream.write_all(b"221 2.0.0 Bye\r\n");
+                return;
+            }
+            _ => "250 2.0.0 OK",
+        };
+        if stream.write_all(format!("{}\r\n", reply).as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("test client read timeout should set");
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, line: &str) {
+    stream
+        .write_all(line.as_bytes())
+        .expect("test client write should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        let read = reader
+            .read_line(&mut line)
+            .expect("test client read should succeed");
+        assert!(read > 0, "listener closed the connection before replying");
+        let line = line.trim_end().to_string();
+        let last = line.as_bytes().get(3) != Some(&b'-');
+        lines.push(line);
+        if last {
+            return lines;
+        }
+    }
+}
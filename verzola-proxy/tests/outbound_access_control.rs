@@ -0,0 +1,271 @@
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::access::{CidrBlock, ClientAcl};
+use verzola_proxy::outbound::{
+    NoopMxResolver, OutboundClientAuth, OutboundListener, OutboundListenerConfig,
+    OutboundSessionSummary,
+};
+
+#[test]
+fn validate_refuses_non_loopback_bind_without_acl() {
+    let open_config = OutboundListenerConfig {
+        bind_addr: "0.0.0.0:10025"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        ..OutboundListenerConfig::default()
+    };
+    let error = open_config
+        .validate()
+        .expect_err("non-loopback bind without ACL must be refused");
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    assert!(error.to_string().contains("client_acl"));
+
+    let restricted_config = OutboundListenerConfig {
+        client_acl: Some(
+            ClientAcl::parse(["10.42.0.0/16", "fd00::/8"]).expect("ACL entries should parse"),
+        ),
+        ..open_config.clone()
+    };
+    restricted_config
+        .validate()
+        .expect("non-loopback bind with an explicit ACL should validate");
+
+    let weak_secret = OutboundListenerConfig {
+        client_auth: OutboundClientAuth::SharedSecret("short".to_string()),
+        ..OutboundListenerConfig::default()
+    };
+    assert!(weak_secret.validate().is_err());
+}
+
+#[test]
+fn cidr_blocks_match_networks_and_mapped_addresses() {
+    let block = CidrBlock::parse("192.0.2.77/24").expect("CIDR block should parse");
+    assert_eq!(block.to_string(), "192.0.2.0/24");
+    assert!(block.contains(ip("192.0.2.200")));
+    assert!(block.contains(ip("::ffff:192.0.2.9")));
+    assert!(!block.contains(ip("192.0.3.1")));
+
+    let host = CidrBlock::parse("2001:db8::1").expect("bare address should parse");
+    assert_eq!(host.prefix_len(), 128);
+    assert!(host.contains(ip("2001:db8::1")));
+    assert!(!host.contains(ip("2001:db8::2")));
+
+    assert!(CidrBlock::parse("192.0.2.0/33").is_err());
+    assert!(CidrBlock::parse("not-an-address/8").is_err());
+    assert!(ClientAcl::new(Vec::new()).is_err());
+
+    let loopback = ClientAcl::default();
+    assert!(loopback.permits(ip("127.0.0.2")));
+    assert!(loopback.permits(ip("::1")));
+    assert!(!loopback.permits(ip("10.0.0.1")));
+}
+
+#[test]
+fn client_outside_acl_is_refused_before_greeting() {
+    let config = OutboundListenerConfig {
+        client_acl: Some(ClientAcl::parse(["10.0.0.0/8"]).expect("ACL entry should parse")),
+        ..loopback_config()
+    };
+    let (address, handle) = spawn_listener(config);
+
+    let (_stream, mut reader) = connect(address);
+    let greeting = read_reply(&mut reader);
+    assert_eq!(greeting.len(), 1);
+    assert!(greeting[0].starts_with("554 5.7.1 "));
+
+    let summary = join_listener(handle);
+    assert!(summary.client_rejected);
+    assert_eq!(summary.command_count, 0);
+}
+
+#[test]
+fn sasl_auth_is_required_before_mail() {
+    let config = OutboundListenerConfig {
+        client_auth: OutboundClientAuth::Sasl {
+            username: "relay".to_string(),
+            password: "s3cret-pass".to_string(),
+        },
+        ..loopback_config()
+    };
+    let (address, handle) = spawn_listener(config);
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let ehlo_reply = read_reply(&mut reader);
+    assert!(ehlo_reply.iter().any(|line| line == "250-AUTH PLAIN LOGIN"));
+
+    send(&mut stream, "MAIL FROM:<alice@example.org>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("530 5.7.0 "));
+
+    send(&mut stream, "AUTH PLAIN AHJlbGF5AHdyb25n\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("535 5.7.8 "));
+
+    send(&mut stream, "AUTH CRAM-MD5\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("504 "));
+
+    send(&mut stream, "AUTH LOGIN\r\n");
+    assert_eq!(read_reply(&mut reader), vec!["334 VXNlcm5hbWU6".to_string()]);
+    send(&mut stream, "cmVsYXk=\r\n");
+    assert_eq!(read_reply(&mut reader), vec!["334 UGFzc3dvcmQ6".to_string()]);
+    send(&mut stream, "czNjcmV0LXBhc3M=\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("235 2.7.0 "));
+
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let ehlo_reply = read_reply(&mut reader);
+    assert!(!ehlo_reply.iter().any(|line| line.contains("AUTH")));
+
+    send(&mut stream, "MAIL FROM:<alice@example.org>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary = join_listener(handle);
+    assert!(summary.client_authenticated);
+    assert_eq!(summary.auth_failures, 1);
+}
+
+#[test]
+fn shared_secret_handshake_restarts_session() {
+    let config = OutboundListenerConfig {
+        client_auth: OutboundClientAuth::SharedSecret("postfix-sidecar-secret".to_string()),
+        ..loopback_config()
+    };
+    let (address, handle) = spawn_listener(config);
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let ehlo_reply = read_reply(&mut reader);
+    assert!(ehlo_reply.iter().any(|line| line == "250-XCLIENT SECRET"));
+
+    send(&mut stream, "AUTH PLAIN AHJlbGF5AHMzY3JldC1wYXNz\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("502 "));
+    send(&mut stream, "XCLIENT NAME=spike.example.org\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("501 "));
+    send(&mut stream, "XCLIENT SECRET=guess\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("535 "));
+
+    send(&mut stream, "XCLIENT SECRET=postfix-sidecar-secret\r\n");
+    assert_eq!(
+        read_reply(&mut reader),
+        vec!["220 relay.verzola.test ESMTP VERZOLA".to_string()]
+    );
+    send(&mut stream, "MAIL FROM:<alice@example.org>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("503 "));
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.org>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary = join_listener(handle);
+    assert!(summary.client_authenticated);
+    assert_eq!(summary.auth_failures, 1);
+}
+
+#[test]
+fn repeated_auth_failures_close_the_session() {
+    let config = OutboundListenerConfig {
+        client_auth: OutboundClientAuth::SharedSecret("postfix-sidecar-secret".to_string()),
+        ..loopback_config()
+    };
+    let (address, handle) = spawn_listener(config);
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    for _ in 0..2 {
+        send(&mut stream, "XCLIENT SECRET=guess\r\n");
+        assert!(read_reply(&mut reader)[0].starts_with("535 "));
+    }
+    send(&mut stream, "XCLIENT SECRET=guess\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("421 4.7.0 "));
+
+    let summary = join_listener(handle);
+    assert!(!summary.client_authenticated);
+    assert_eq!(summary.auth_failures, 3);
+}
+
+fn loopback_config() -> OutboundListenerConfig {
+    OutboundListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "relay.verzola.test".to_string(),
+        ..OutboundListenerConfig::default()
+    }
+}
+
+fn ip(value: &str) -> IpAddr {
+    value.parse().expect("hard-coded IP address must parse")
+}
+
+fn spawn_listener(
+    config: OutboundListenerConfig,
+) -> (SocketAddr, thread::JoinHandle<std::io::Result<OutboundSessionSummary>>) {
+    let listener = OutboundListener::bind(config, NoopMxResolver)
+        .expect("outbound listener should bind for access control test");
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    (address, handle)
+}
+
+fn join_listener(
+    handle: thread::JoinHandle<std::io::Result<OutboundSessionSummary>>,
+) -> OutboundSessionSummary {
+    handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return summary")
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .expect("test client read timeout should set");
+
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, command: &str) {
+    stream
+        .write_all(command.as_bytes())
+        .expect("test client should write command bytes");
+    stream.flush().expect("test client flush should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    loop {
+        let mut raw = String::new();
+        let bytes = reader
+            .read_line(&mut raw)
+            .expect("test client should read server reply");
+        assert!(bytes > 0, "server closed connection unexpectedly");
+
+        let line = raw.trim_end_matches(['\r', '\n']).to_string();
+        lines.push(line.clone());
+
+        let line_bytes = line.as_bytes();
+        if line_bytes.len() < 4 || line_bytes[3] == b' ' {
+            break;
+        }
+    }
+
+    lines
+}
@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::io::{BufRead, BufReader, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::thread;
 use std::time::Duration;
@@ -225,7 +225,7 @@ fn spawn_mock_remote_mx(
         for handle in session_handles {
             let session_stats = handle
                 .join()
-                .map_err(|_| std::io::Error::new(ErrorKind::Other, "remote MX worker panicked"))??;
+                .map_err(|_| std::io::Error::other("remote MX worker panicked"))??;
             stats.sessions += 1;
             stats.messages += session_stats.message_count;
             stats.data_bytes += session_stats.data_bytes;
@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::io::{BufRead, BufReader, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::thread;
 use std::time::Duration;
@@ -224,7 +224,7 @@ fn spawn_mock_remote_mx(
         for handle in session_handles {
             handle
                 .join()
-                .map_err(|_| std::io::Error::new(ErrorKind::Other, "remote MX worker panicked"))??;
+                .map_err(|_| std::io::Error::other("remote MX worker panicked"))??;
         }
 
         Ok(())
@@ -74,6 +74,7 @@ fn opportunistic_policy_allows_plaintext_when_starttls_is_unavailable() {
         outbound_tls_policy: OutboundTlsPolicy::Opportunistic,
         per_domain_tls_policies: Vec::new(),
         max_line_len: 4096,
+        ..OutboundListenerConfig::default()
     };
 
     let (listener_addr, listener_handle) = spawn_outbound_listener(resolver, config);
@@ -133,6 +134,7 @@ fn opportunistic_policy_falls_back_when_starttls_negotiation_fails() {
         outbound_tls_policy: OutboundTlsPolicy::Opportunistic,
         per_domain_tls_policies: Vec::new(),
         max_line_len: 4096,
+        ..OutboundListenerConfig::default()
     };
 
     let (listener_addr, listener_handle) = spawn_outbound_listener(resolver, config);
@@ -162,6 +164,8 @@ fn opportunistic_policy_falls_back_when_starttls_negotiation_fails() {
         Some(OutboundTlsPolicy::Opportunistic)
     );
     assert!(!summary.tls_negotiated);
+    assert_eq!(summary.starttls_attempts, 1);
+    assert_eq!(summary.tls_upgrade_failures, 1);
     assert_eq!(summary.opportunistic_tls_fallbacks, 1);
     assert_eq!(summary.policy_deferred_failures, 0);
     assert_eq!(summary.temporary_failures, 0);
@@ -188,6 +192,7 @@ fn require_tls_policy_defers_when_peer_does_not_advertise_starttls() {
         outbound_tls_policy: OutboundTlsPolicy::RequireTls,
         per_domain_tls_policies: Vec::new(),
         max_line_len: 4096,
+        ..OutboundListenerConfig::default()
     };
 
     let (listener_addr, listener_handle) = spawn_outbound_listener(resolver, config);
@@ -232,6 +237,59 @@ fn require_tls_policy_defers_when_peer_does_not_advertise_starttls() {
     assert_eq!(remote_stats.rcpt_commands, 0);
 }
 
+#[test]
+fn require_tls_policy_counts_a_failed_starttls_before_deferring() {
+    let behavior = RemoteBehavior {
+        advertise_starttls: true,
+        starttls_reply: "454 4.7.0 TLS unavailable",
+        ..RemoteBehavior::default()
+    };
+    let (remote_addr, remote_handle) = spawn_mock_remote_mx(1, behavior);
+
+    let resolver = resolver_for_domain("example.net", remote_addr, "mx-policy.verzola.test");
+    let config = OutboundListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "relay.verzola.test".to_string(),
+        outbound_tls_policy: OutboundTlsPolicy::RequireTls,
+        max_line_len: 4096,
+        ..OutboundListenerConfig::default()
+    };
+
+    let (listener_addr, listener_handle) = spawn_outbound_listener(resolver, config);
+    let (mut stream, mut reader) = connect(listener_addr);
+
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.org>\r\n");
+    let _mail_reply = read_reply(&mut reader);
+
+    send(&mut stream, "RCPT TO:<bob@example.net>\r\n");
+    let rcpt_reply = read_reply(&mut reader);
+    assert!(
+        rcpt_reply[0].starts_with("451 4.7.5 Outbound TLS policy defer:"),
+        "unexpected RCPT reply: {}",
+        rcpt_reply.join(" | ")
+    );
+
+    send(&mut stream, "QUIT\r\n");
+    assert_eq!(read_reply(&mut reader), vec!["221 2.0.0 Bye".to_string()]);
+
+    let summary = join_listener(listener_handle);
+    assert_eq!(summary.starttls_attempts, 1);
+    assert_eq!(summary.tls_upgrade_failures, 1);
+    assert_eq!(summary.opportunistic_tls_fallbacks, 0);
+    assert_eq!(summary.policy_deferred_failures, 1);
+    assert!(!summary.remote_session_established);
+
+    let remote_stats = join_remote(remote_handle);
+    assert_eq!(remote_stats.sessions, 1);
+    assert_eq!(remote_stats.starttls_commands, 1);
+    assert_eq!(remote_stats.mail_commands, 0);
+}
+
 #[test]
 fn per_domain_rule_overrides_global_policy_for_stricter_domain() {
     let behavior = RemoteBehavior::default();
@@ -249,6 +307,7 @@ fn per_domain_rule_overrides_global_policy_for_stricter_domain() {
                 .expect("domain policy should be valid"),
         ],
         max_line_len: 4096,
+        ..OutboundListenerConfig::default()
     };
 
     let (listener_addr, listener_handle) = spawn_outbound_listener(resolver, config);
@@ -304,6 +363,7 @@ fn per_domain_rule_can_relax_global_require_tls_policy() {
                 .expect("domain policy should be valid"),
         ],
         max_line_len: 4096,
+        ..OutboundListenerConfig::default()
     };
 
     let (listener_addr, listener_handle) = spawn_outbound_listener(resolver, config);
@@ -357,6 +417,7 @@ fn config_validation_rejects_duplicate_domain_policy_rules() {
                 .expect("second domain policy should be valid"),
         ],
         max_line_len: 4096,
+        ..OutboundListenerConfig::default()
     };
 
     let error = config
@@ -431,7 +492,7 @@ fn spawn_mock_remote_mx(
         for handle in session_handles {
             let session_stats = handle
                 .join()
-                .map_err(|_| std::io::Error::new(ErrorKind::Other, "remote MX worker panicked"))??;
+                .map_err(|_| std::io::Error::other("remote MX worker panicked"))??;
 
             stats.sessions += 1;
             stats.starttls_commands += session_stats.starttls_commands;
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::inbound::{
+    InboundListener, InboundTlsPolicy, ListenerConfig, NoopTlsUpgrader, SessionSummary,
+};
+use verzola_proxy::logging::{EnvelopeRedaction, JsonLogConfig, JsonLogger, LogTarget};
+use verzola_proxy::outbound::{
+    MxCandidate, MxResolutionError, MxResolver, OutboundListener, OutboundListenerConfig,
+    OutboundSessionSummary,
+};
+
+#[derive(Debug, Clone)]
+struct StaticResolver {
+    candidates_by_domain: HashMap<String, Vec<MxCandidate>>,
+}
+
+impl MxResolver for StaticResolver {
+    fn resolve(&self, recipient_domain: &str) -> Result<Vec<MxCandidate>, MxResolutionError> {
+        self.candidates_by_domain
+            .get(recipient_domain)
+            .cloned()
+            .ok_or_else(|| {
+                MxResolutionError::Temporary(format!(
+                    "no MX records found for {}",
+                    recipient_domain
+                ))
+            })
+    }
+}
+
+#[test]
+fn inbound_extracts_queue_id_from_postfix_final_reply() {
+    let (postfix_addr, postfix_handle) =
+        spawn_mock_smtp_server("250 2.0.0 Ok: queued as 4XyZ1k2LmNz");
+    let log_path = std::env::temp_dir().join(format!(
+        "verzola-queue-id-{}.log",
+        std::process::id()
+    ));
+    let _ = fs::remove_file(&log_path);
+    let logger = JsonLogger::open(JsonLogConfig {
+        target: LogTarget::File {
+            path: log_path.clone(),
+            max_bytes: 1024 * 1024,
+            max_files: 1,
+        },
+        envelope_redaction: EnvelopeRedaction::Redact,
+    })
+    .expect("json logger should open log file");
+
+    let config = ListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "mx.verzola.test".to_string(),
+        inbound_tls_policy: InboundTlsPolicy::Opportunistic,
+        postfix_upstream_addr: Some(postfix_addr),
+        ..ListenerConfig::default()
+    };
+    let listener = InboundListener::bind(config, NoopTlsUpgrader)
+        .expect("listener should bind for queue id test")
+        .with_json_logger(Arc::new(logger));
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO sender.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.com>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "RCPT TO:<bob@example.net>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "DATA\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("354 "));
+    send(&mut stream, "hello\r\n.\r\n");
+    assert_eq!(
+        read_reply(&mut reader),
+        vec!["250 2.0.0 Ok: queued as 4XyZ1k2LmNz".to_string()]
+    );
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary: SessionSummary = handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary");
+    postfix_handle
+        .join()
+        .expect("mock postfix thread should not panic")
+        .expect("mock postfix should finish cleanly");
+
+    assert_eq!(summary.transactions.len(), 1);
+    assert_eq!(
+        summary.transactions[0].postfix_queue_id.as_deref(),
+        Some("4XyZ1k2LmNz")
+    );
+
+    let contents = fs::read_to_string(&log_path).expect("log file should be readable");
+    assert!(contents.contains("\"postfix_queue_id\":\"4XyZ1k2LmNz\""));
+    let _ = fs::remove_file(&log_path);
+}
+
+#[test]
+fn outbound_advertises_xforward_and_records_ident() {
+    let (remote_addr, remote_handle) = spawn_mock_smtp_server("250 2.0.0 Ok: queued as REMOTE1");
+    let resolver = StaticResolver {
+        candidates_by_domain: HashMap::from([(
+            "example.net".to_string(),
+            vec![MxCandidate::new(10, "mx1.example.net", remote_addr)
+                .expect("candidate should be valid")],
+        )]),
+    };
+    let config = OutboundListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "relay.verzola.test".to_string(),
+        ..OutboundListenerConfig::default()
+    };
+    let listener = OutboundListener::bind(config, resolver)
+        .expect("outbound listener should bind for queue id test");
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let ehlo_reply = read_reply(&mut reader);
+    assert!(ehlo_reply
+        .iter()
+        .any(|line| line == "250-XFORWARD NAME ADDR PROTO HELO SOURCE IDENT"));
+
+    send(&mut stream, "XFORWARD BOGUS=1\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("501 5.5.4 "));
+    send(
+        &mut stream,
+        "XFORWARD NAME=spike.example.org ADDR=192.0.2.7 PROTO=ESMTP\r\n",
+    );
+    assert_eq!(read_reply(&mut reader), vec!["250 2.0.0 Ok".to_string()]);
+    send(&mut stream, "XFORWARD HELO=spike SOURCE=LOCAL IDENT=4Q1+2Bab9\r\n");
+    assert_eq!(read_reply(&mut reader), vec!["250 2.0.0 Ok".to_string()]);
+
+    send(&mut stream, "MAIL FROM:<alice@example.org>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "XFORWARD IDENT=LATE\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("503 "));
+    send(&mut stream, "RCPT TO:<bob@example.net>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "DATA\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("354 "));
+    send(&mut stream, "hello\r\n.\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary: OutboundSessionSummary = handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return summary");
+    remote_handle
+        .join()
+        .expect("remote thread should not panic")
+        .expect("remote MX should finish cleanly");
+
+    assert_eq!(summary.transactions.len(), 1);
+    assert_eq!(
+        summary.transactions[0].postfix_queue_id.as_deref(),
+        Some("4Q1+ab9")
+    );
+}
+
+fn spawn_mock_smtp_server(
+    final_reply: &'static str,
+) -> (SocketAddr, thread::JoinHandle<std::io::Result<()>>) {
+    let listener =
+        TcpListener::bind("127.0.0.1:0").expect("mock SMTP listener should bind to localhost");
+    let address = listener
+        .local_addr()
+        .expect("mock SMTP listener address should resolve");
+
+    let handle = thread::spawn(move || -> std::io::Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        stream
+            .set_read_timeout(Some(Duration::from_secs(3)))
+            .expect("mock SMTP read timeout should set");
+        write_line(&mut stream, "220 mock.verzola.test ESMTP")?;
+
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut reading_data = false;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+
+            if reading_data {
+                if line == ".\r\n" {
+                    reading_data = false;
+                    write_line(&mut stream, final_reply)?;
+                }
+                continue;
+            }
+
+            let verb = line
+                .split_whitespace()
+                .next()
+                .unwrap_or("")
+                .to_ascii_uppercase();
+            match verb.as_str() {
+                "DATA" => {
+                    reading_data = true;
+                    write_line(&mut stream, "354 End data with <CR><LF>.<CR><LF>")?;
+                }
+                "QUIT" => {
+                    write_line(&mut stream, "221 2.0.0 Bye")?;
+                    return Ok(());
+                }
+                _ => write_line(&mut stream, "250 2.0.0 OK")?,
+            }
+        }
+    });
+
+    (address, handle)
+}
+
+fn write_line(stream: &mut TcpStream, line: &str) -> std::io::Result<()> {
+    write!(stream, "{}\r\n", line)?;
+    stream.flush()
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .expect("test client read timeout should set");
+
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, command: &str) {
+    stream
+        .write_all(command.as_bytes())
+        .expect("test client should write command bytes");
+    stream.flush().expect("test client flush should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    loop {
+        let mut raw = String::new();
+        let bytes = reader
+            .read_line(&mut raw)
+            .expect("test client should read server reply");
+        assert!(bytes > 0, "server closed connection unexpectedly");
+
+        let line = raw.trim_end_matches(['\r', '\n']).to_string();
+        lines.push(line.clone());
+
+        let line_bytes = line.as_bytes();
+        if line_bytes.len() < 4 || line_bytes[3] == b' ' {
+            break;
+        }
+    }
+
+    lines
+}
@@ -0,0 +1,377 @@
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::outbound::{
+    MxCandidate, MxResolutionError, MxResolver, OutboundDomainTlsPolicy, OutboundListener,
+    OutboundListenerConfig, OutboundMxTlsPolicy, OutboundTlsPolicy, TlsPolicyRule,
+};
+use verzola_proxy::tls_policy_map::{
+    DiagnosticSeverity, PostfixTlsPolicyMap, TlsPolicyMapDiagnostic, TlsPolicyMapEntries,
+};
+
+#[derive(Debug, Clone, Copy)]
+struct UnreachableResolver;
+
+impl MxResolver for UnreachableResolver {
+    fn resolve(&self, recipient_domain: &str) -> Result<Vec<MxCandidate>, MxResolutionError> {
+        Err(MxResolutionError::Temporary(format!(
+            "no MX records found for {}",
+            recipient_domain
+        )))
+    }
+}
+
+#[test]
+fn postfix_table_entries_map_to_domain_and_mx_rules() {
+    let source = "\
+# Postfix smtp_tls_policy_maps source
+example.com         encrypt
+.example.com        may
+Partner.ORG         verify match=hostname:nexthop
+    ciphers=high
+[mx.x.net]:25       secure
+[mx2.x.net]         encrypt protocols=!SSLv2,!SSLv3
+legacy.example      none
+dane.example        dane
+";
+    let entries = TlsPolicyMapEntries::parse(source);
+
+    assert_eq!(
+        entries.domain_policies,
+        vec![
+            domain_rule("example.com", OutboundTlsPolicy::RequireTls),
+            domain_rule(".example.com", OutboundTlsPolicy::Opportunistic),
+            domain_rule("partner.org", OutboundTlsPolicy::RequireTls),
+            domain_rule("legacy.example", OutboundTlsPolicy::Opportunistic),
+            domain_rule("dane.example", OutboundTlsPolicy::Opportunistic),
+        ]
+    );
+    assert_eq!(
+        entries.mx_policies,
+        vec![
+            mx_rule("mx.x.net", OutboundTlsPolicy::RequireTls),
+            mx_rule("mx2.x.net", OutboundTlsPolicy::RequireTls),
+        ]
+    );
+    assert!(!entries.has_errors());
+    assert_eq!(entries.rule_count(), 7);
+
+    let warnings: Vec<String> = entries.warnings().map(ToString::to_string).collect();
+    assert_eq!(
+        warnings,
+        [
+            "line 4: warning: certificate verification is not supported; level verify is \
+             enforced as encrypt (require-tls)",
+            "line 4: warning: attribute match is not supported and is ignored",
+            "line 4: warning: attribute ciphers is not supported and is ignored",
+            "line 6: warning: certificate verification is not supported; level secure is \
+             enforced as encrypt (require-tls)",
+            "line 7: warning: attribute protocols is not supported and is ignored",
+            "line 8: warning: level none has no equivalent; mapped to opportunistic, so \
+             STARTTLS is still tried",
+            "line 9: warning: DANE is not supported; level dane is mapped to opportunistic, as \
+             Postfix does without usable TLSA records",
+        ]
+    );
+}
+
+#[test]
+fn malformed_entries_produce_error_diagnostics() {
+    // The string continuation would eat the indentation of the first line.
+    let source = "  continued.example encrypt
+missing-level.example
+typo.example        encrpyt
+attr.example        encrypt tls_ciphers=high
+bare.example        encrypt match
+[192.0.2.1]:25      encrypt
+*.wild.example      encrypt
+port.example:587    encrypt
+[mx.bad.example]x   encrypt
+brace.example       encrypt { match = nexthop dot-nexthop }
+dup.example         encrypt
+DUP.example.        may
+";
+    let entries = TlsPolicyMapEntries::parse(source);
+    let errors: Vec<String> = entries.errors().map(ToString::to_string).collect();
+    assert_eq!(
+        errors,
+        [
+            "line 1: error: continuation line without a preceding entry",
+            "line 2: error: entry missing-level.example has no security level",
+            "line 3: error: unknown security level encrpyt",
+            "line 4: error: unknown attribute tls_ciphers",
+            "line 5: error: malformed attribute match; expected name=value",
+            "line 6: error: key [192.0.2.1]:25 names an IP address; MX rules match host names \
+             only",
+            "line 7: error: *.wild.example is not a Postfix key; use .wild.example for all \
+             subdomains",
+            "line 9: error: unexpected text after ] in key [mx.bad.example]x",
+        ]
+    );
+    assert_eq!(
+        entries.warnings().cloned().collect::<Vec<_>>(),
+        vec![
+            TlsPolicyMapDiagnostic {
+                line: 8,
+                severity: DiagnosticSeverity::Warning,
+                message: "port 587 in key port.example:587 is ignored; rules match on the host \
+                          name only"
+                    .to_string(),
+            },
+            TlsPolicyMapDiagnostic {
+                line: 10,
+                severity: DiagnosticSeverity::Warning,
+                message: "attribute match is not supported and is ignored".to_string(),
+            },
+            TlsPolicyMapDiagnostic {
+                line: 12,
+                severity: DiagnosticSeverity::Warning,
+                message: "duplicate entry for dup.example; the entry on line 11 is kept"
+                    .to_string(),
+            },
+        ]
+    );
+    assert_eq!(
+        entries.domain_policies,
+        vec![
+            domain_rule("port.example", OutboundTlsPolicy::RequireTls),
+            domain_rule("brace.example", OutboundTlsPolicy::RequireTls),
+            domain_rule("dup.example", OutboundTlsPolicy::RequireTls),
+        ]
+    );
+}
+
+#[test]
+fn policy_map_applies_to_outbound_sessions_and_follows_reload() {
+    let path = write_policy_file("reload", "partner.example encrypt\n");
+    let policy_map = Arc::new(PostfixTlsPolicyMap::open(&path).expect("policy map should load"));
+    assert_eq!(policy_map.rule_count(), 1);
+
+    let config = OutboundListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        outbound_tls_policy: OutboundTlsPolicy::Opportunistic,
+        per_domain_tls_policies: vec![domain_rule(
+            "override.partner.example",
+            OutboundTlsPolicy::Opportunistic,
+        )],
+        ..OutboundListenerConfig::default()
+    };
+    let listener = OutboundListener::bind(config, UnreachableResolver)
+        .expect("outbound listener should bind")
+        .with_tls_policy_map(Arc::clone(&policy_map));
+    let address = listener.local_addr().expect("listener address must resolve");
+
+    thread::scope(|scope| {
+        let server = scope.spawn(|| listener.serve_n(4));
+
+        attempt_delivery(address, "bob@partner.example");
+
+        fs::write(&path, "partner.example may\n.partner.example secure\n")
+            .expect("policy file should be writable");
+        assert_eq!(policy_map.reload().expect("reload should succeed"), 2);
+        attempt_delivery(address, "bob@eu.partner.example");
+        attempt_delivery(address, "bob@override.partner.example");
+
+        // A broken file is refused and the previous rules stay active.
+        fs::write(&path, "partner.example may\npartner.net maybe\n")
+            .expect("policy file should be writable");
+        let error = policy_map
+            .reload()
+            .expect_err("unknown security level should fail the reload");
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(
+            error.to_string().ends_with(":2: unknown security level maybe"),
+            "unexpected reload error: {}",
+            error
+        );
+        assert_eq!(policy_map.rule_count(), 2);
+        attempt_delivery(address, "bob@mx.partner.example");
+
+        let summaries = server
+            .join()
+            .expect("listener thread should not panic")
+            .expect("listener should serve every session");
+        let decisions: Vec<_> = summaries
+            .into_iter()
+            .map(|summary| (summary.effective_tls_policy, summary.tls_policy_rule))
+            .collect();
+        assert_eq!(
+            decisions,
+            vec![
+                (
+                    Some(OutboundTlsPolicy::RequireTls),
+                    Some(TlsPolicyRule::RecipientDomain("partner.example".to_string())),
+                ),
+                (
+                    Some(OutboundTlsPolicy::RequireTls),
+                    Some(TlsPolicyRule::RecipientDomain(".partner.example".to_string())),
+                ),
+                // The configured exact rule is more specific than the imported pattern.
+                (
+                    Some(OutboundTlsPolicy::Opportunistic),
+                    Some(TlsPolicyRule::RecipientDomain("override.partner.example".to_string())),
+                ),
+                (
+                    Some(OutboundTlsPolicy::RequireTls),
+                    Some(TlsPolicyRule::RecipientDomain(".partner.example".to_string())),
+                ),
+            ]
+        );
+    });
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn most_specific_rule_wins_across_configured_and_imported_rules() {
+    let path = write_policy_file(
+        "specificity",
+        "eu.partner.example secure\n.partner.example may\n",
+    );
+    let policy_map = Arc::new(PostfixTlsPolicyMap::open(&path).expect("policy map should load"));
+
+    let config = OutboundListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        outbound_tls_policy: OutboundTlsPolicy::Opportunistic,
+        per_domain_tls_policies: vec![
+            domain_rule(".partner.example", OutboundTlsPolicy::RequireTls),
+            domain_rule("*.eu.partner.example", OutboundTlsPolicy::Opportunistic),
+        ],
+        ..OutboundListenerConfig::default()
+    };
+    let listener = OutboundListener::bind(config, UnreachableResolver)
+        .expect("outbound listener should bind")
+        .with_tls_policy_map(Arc::clone(&policy_map));
+    let address = listener.local_addr().expect("listener address must resolve");
+
+    thread::scope(|scope| {
+        let server = scope.spawn(|| listener.serve_n(3));
+
+        attempt_delivery(address, "bob@eu.partner.example");
+        attempt_delivery(address, "bob@us.partner.example");
+        attempt_delivery(address, "bob@lyon.eu.partner.example");
+
+        let summaries = server
+            .join()
+            .expect("listener thread should not panic")
+            .expect("listener should serve every session");
+        let decisions: Vec<_> = summaries
+            .into_iter()
+            .map(|summary| (summary.effective_tls_policy, summary.tls_policy_rule))
+            .collect();
+        assert_eq!(
+            decisions,
+            vec![
+                // The imported exact rule beats the configured `.partner.example`.
+                (
+                    Some(OutboundTlsPolicy::RequireTls),
+                    Some(TlsPolicyRule::RecipientDomain("eu.partner.example".to_string())),
+                ),
+                // Both sources have `.partner.example`; the configured rule wins the tie.
+                (
+                    Some(OutboundTlsPolicy::RequireTls),
+                    Some(TlsPolicyRule::RecipientDomain(".partner.example".to_string())),
+                ),
+                (
+                    Some(OutboundTlsPolicy::Opportunistic),
+                    Some(TlsPolicyRule::RecipientDomain("*.eu.partner.example".to_string())),
+                ),
+            ]
+        );
+    });
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn opening_a_missing_or_invalid_map_fails() {
+    let missing = std::env::temp_dir().join("verzola-tls-policy-does-not-exist");
+    let error = PostfixTlsPolicyMap::open(&missing).expect_err("missing file should fail");
+    assert_eq!(error.kind(), ErrorKind::NotFound);
+
+    let path = write_policy_file("invalid", "good.example encrypt\nbad.example\n");
+    let error = PostfixTlsPolicyMap::open(&path).expect_err("invalid entry should fail the load");
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert!(
+        error
+            .to_string()
+            .contains(&format!("{}:2: entry bad.example has no security level", path.display())),
+        "unexpected open error: {}",
+        error
+    );
+    let _ = fs::remove_file(&path);
+}
+
+fn attempt_delivery(address: SocketAddr, recipient: &str) {
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.org>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, &format!("RCPT TO:<{}>\r\n", recipient));
+    assert!(read_reply(&mut reader)[0].starts_with("451 "));
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+}
+
+fn domain_rule(domain: &str, policy: OutboundTlsPolicy) -> OutboundDomainTlsPolicy {
+    OutboundDomainTlsPolicy::new(domain, policy).expect("test domain rule should be valid")
+}
+
+fn mx_rule(hostname: &str, policy: OutboundTlsPolicy) -> OutboundMxTlsPolicy {
+    OutboundMxTlsPolicy::new(hostname, policy).expect("test MX rule should be valid")
+}
+
+fn write_policy_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "verzola-tls-policy-{}-{}",
+        name,
+        std::process::id()
+    ));
+    fs::write(&path, contents).expect("policy file should be writable");
+    path
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("test client read timeout should set");
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, line: &str) {
+    stream
+        .write_all(line.as_bytes())
+        .expect("test client write should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        let read = reader
+            .read_line(&mut line)
+            .expect("test client read should succeed");
+        assert!(read > 0, "listener closed the connection before replying");
+        let line = line.trim_end().to_string();
+        let last = line.as_bytes().get(3) != Some(&b'-');
+        lines.push(line);
+        if last {
+            return lines;
+        }
+    }
+}
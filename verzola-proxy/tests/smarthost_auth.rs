@@ -0,0 +1,433 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::inbound::{TlsSessionParameters, TlsUpgradeError};
+use verzola_proxy::outbound::{
+    NoopMxResolver, NoopOutboundTlsUpgrader, OutboundListener, OutboundListenerConfig,
+    OutboundNextHop, OutboundSessionSummary, OutboundSmarthost, OutboundTlsPolicy,
+    OutboundTlsUpgrader, OutboundTransportRoute, RelayAuthMechanism, SecretSource,
+    SmarthostCredentials,
+};
+
+// Reports an encrypted session without touching the stream, so the mock smarthost keeps
+// reading plain SMTP after STARTTLS.
+#[derive(Debug, Clone, Copy)]
+struct EncryptingTlsUpgrader;
+
+impl OutboundTlsUpgrader for EncryptingTlsUpgrader {
+    fn upgrade(&self, _stream: &mut TcpStream, _exchange: &str) -> Result<(), TlsUpgradeError> {
+        Ok(())
+    }
+
+    fn session_parameters(&self, _stream: &TcpStream) -> Option<TlsSessionParameters> {
+        Some(TlsSessionParameters {
+            protocol_version: "TLSv1.3".to_string(),
+            cipher_suite: "TLS_AES_256_GCM_SHA384".to_string(),
+            key_exchange_group: Some("X25519".to_string()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SmarthostBehavior {
+    starttls: bool,
+    // AUTH mechanisms advertised once STARTTLS has been negotiated.
+    auth_mechanisms: &'static str,
+    auth_reply: &'static str,
+}
+
+const ACCEPTING_SMARTHOST: SmarthostBehavior = SmarthostBehavior {
+    starttls: true,
+    auth_mechanisms: "PLAIN LOGIN XOAUTH2",
+    auth_reply: "235 2.7.0 Authentication successful",
+};
+
+#[test]
+fn plain_auth_reads_the_password_file_after_starttls() {
+    let password_file = write_secret_file("plain", "s3cret\n");
+    let (smarthost_addr, smarthost) = spawn_smarthost(ACCEPTING_SMARTHOST);
+    let credentials = SmarthostCredentials::Password {
+        username: "relay-user".to_string(),
+        password: SecretSource::File(password_file.clone()),
+    };
+
+    let (summary, rcpt_reply) = deliver(smarthost_addr, credentials, EncryptingTlsUpgrader);
+
+    assert!(rcpt_reply.starts_with("250 "), "unexpected RCPT reply: {}", rcpt_reply);
+    assert_eq!(summary.relay_auth_mechanism, Some(RelayAuthMechanism::Plain));
+    assert!(summary.tls_negotiated);
+    let commands = join_smarthost(smarthost);
+    assert_eq!(
+        commands,
+        [
+            "EHLO relay.verzola.test",
+            "STARTTLS",
+            "EHLO relay.verzola.test",
+            "AUTH PLAIN AHJlbGF5LXVzZXIAczNjcmV0",
+            "MAIL FROM:<alice@example.org>",
+            "RCPT TO:<bob@partner.example>",
+            "QUIT",
+        ]
+    );
+    let _ = fs::remove_file(&password_file);
+}
+
+#[test]
+fn login_auth_is_used_when_plain_is_not_offered() {
+    let password_file = write_secret_file("login", "file-pass\n");
+    let (smarthost_addr, smarthost) = spawn_smarthost(SmarthostBehavior {
+        auth_mechanisms: "LOGIN",
+        ..ACCEPTING_SMARTHOST
+    });
+    let credentials = SmarthostCredentials::Password {
+        username: "relay-user".to_string(),
+        password: SecretSource::File(password_file.clone()),
+    };
+
+    let (summary, rcpt_reply) = deliver(smarthost_addr, credentials, EncryptingTlsUpgrader);
+
+    assert!(rcpt_reply.starts_with("250 "), "unexpected RCPT reply: {}", rcpt_reply);
+    assert_eq!(summary.relay_auth_mechanism, Some(RelayAuthMechanism::Login));
+    let commands = join_smarthost(smarthost);
+    assert_eq!(commands[3..6], ["AUTH LOGIN", "cmVsYXktdXNlcg==", "ZmlsZS1wYXNz"]);
+    let _ = fs::remove_file(&password_file);
+}
+
+#[test]
+fn xoauth2_sends_the_bearer_token() {
+    let (smarthost_addr, smarthost) = spawn_smarthost(ACCEPTING_SMARTHOST);
+    let credentials = SmarthostCredentials::OAuth2 {
+        username: "relay@example.org".to_string(),
+        token: SecretSource::Value("ya29.token".to_string()),
+    };
+
+    let (summary, rcpt_reply) = deliver(smarthost_addr, credentials, EncryptingTlsUpgrader);
+
+    assert!(rcpt_reply.starts_with("250 "), "unexpected RCPT reply: {}", rcpt_reply);
+    assert_eq!(summary.relay_auth_mechanism, Some(RelayAuthMechanism::XOAuth2));
+    let commands = join_smarthost(smarthost);
+    assert_eq!(
+        commands[3],
+        "AUTH XOAUTH2 dXNlcj1yZWxheUBleGFtcGxlLm9yZwFhdXRoPUJlYXJlciB5YTI5LnRva2VuAQE="
+    );
+}
+
+#[test]
+fn credentials_are_not_sent_when_starttls_leaves_the_session_unencrypted() {
+    // The smarthost answers 220 to STARTTLS, but the upgrader never encrypts the stream.
+    let (smarthost_addr, smarthost) = spawn_smarthost(ACCEPTING_SMARTHOST);
+
+    let (summary, rcpt_reply) =
+        deliver(smarthost_addr, password_credentials("s3cret"), NoopOutboundTlsUpgrader);
+
+    assert!(
+        rcpt_reply.starts_with("451 4.7.11 Outbound relay authentication failed: "),
+        "unexpected RCPT reply: {}",
+        rcpt_reply
+    );
+    assert!(rcpt_reply.contains("left the session unencrypted"), "{}", rcpt_reply);
+    assert!(!rcpt_reply.contains("s3cret"), "secret leaked: {}", rcpt_reply);
+    assert_eq!(summary.relay_auth_failures, 1);
+    assert_eq!(summary.relay_auth_mechanism, None);
+    let commands = join_smarthost(smarthost);
+    assert!(
+        !commands.iter().any(|command| command.to_ascii_uppercase().starts_with("AUTH")),
+        "AUTH sent in plaintext: {:?}",
+        commands
+    );
+    assert_eq!(commands, ["EHLO relay.verzola.test", "STARTTLS", "EHLO relay.verzola.test"]);
+}
+
+#[test]
+fn credentials_are_never_sent_without_starttls() {
+    let (smarthost_addr, smarthost) = spawn_smarthost(SmarthostBehavior {
+        starttls: false,
+        ..ACCEPTING_SMARTHOST
+    });
+
+    let (summary, rcpt_reply) =
+        deliver(smarthost_addr, password_credentials("s3cret"), EncryptingTlsUpgrader);
+
+    assert!(
+        rcpt_reply.starts_with("451 4.7.11 Outbound relay authentication failed: "),
+        "unexpected RCPT reply: {}",
+        rcpt_reply
+    );
+    assert!(rcpt_reply.contains("never sent in plaintext"), "{}", rcpt_reply);
+    assert_eq!(summary.relay_auth_failures, 1);
+    assert_eq!(summary.policy_deferred_failures, 0);
+    assert_eq!(summary.relay_auth_mechanism, None);
+    assert_eq!(join_smarthost(smarthost), ["EHLO relay.verzola.test"]);
+}
+
+#[test]
+fn auth_failures_defer_with_4_7_x() {
+    let cases = [
+        (
+            SmarthostBehavior {
+                auth_reply: "535 5.7.8 Authentication credentials invalid",
+                ..ACCEPTING_SMARTHOST
+            },
+            password_credentials("wrong"),
+            "451 4.7.8 Outbound relay authentication failed: ",
+            "rejected AUTH PLAIN (535)",
+        ),
+        (
+            SmarthostBehavior {
+                auth_reply: "454 4.7.0 Temporary authentication failure",
+                ..ACCEPTING_SMARTHOST
+            },
+            password_credentials("s3cret"),
+            "451 4.7.0 Outbound relay authentication failed: ",
+            "temporarily refused (454)",
+        ),
+        (
+            SmarthostBehavior {
+                auth_mechanisms: "CRAM-MD5",
+                ..ACCEPTING_SMARTHOST
+            },
+            password_credentials("s3cret"),
+            "451 4.7.0 Outbound relay authentication failed: ",
+            "does not offer AUTH PLAIN or LOGIN",
+        ),
+        (
+            ACCEPTING_SMARTHOST,
+            SmarthostCredentials::Password {
+                username: "relay-user".to_string(),
+                password: SecretSource::File(PathBuf::from("/nonexistent/verzola-smarthost")),
+            },
+            "451 4.7.0 Outbound relay authentication failed: ",
+            "cannot read secret file /nonexistent/verzola-smarthost",
+        ),
+    ];
+
+    for (behavior, credentials, expected_prefix, expected_detail) in cases {
+        let (smarthost_addr, smarthost) = spawn_smarthost(behavior);
+        let (summary, rcpt_reply) = deliver(smarthost_addr, credentials, EncryptingTlsUpgrader);
+
+        assert!(rcpt_reply.starts_with(expected_prefix), "unexpected RCPT reply: {}", rcpt_reply);
+        assert!(rcpt_reply.contains(expected_detail), "unexpected RCPT reply: {}", rcpt_reply);
+        assert!(!rcpt_reply.contains("s3cret"), "secret leaked: {}", rcpt_reply);
+        assert_eq!(summary.relay_auth_failures, 1);
+        assert_eq!(summary.relay_auth_mechanism, None);
+        let commands = join_smarthost(smarthost);
+        assert!(
+            !commands.iter().any(|command| command.starts_with("MAIL")),
+            "MAIL sent after failed AUTH: {:?}",
+            commands
+        );
+    }
+}
+
+#[test]
+fn secrets_are_redacted_in_debug_output() {
+    let config = OutboundListenerConfig {
+        smarthosts: vec![
+            smarthost("password", SocketAddr::from(([192, 0, 2, 1], 587)))
+                .with_credentials(password_credentials("hunter2-password")),
+            OutboundSmarthost::new("oauth", "[smtp.provider.test]:587")
+                .expect("smarthost should be valid")
+                .with_credentials(SmarthostCredentials::OAuth2 {
+                    username: "relay@example.org".to_string(),
+                    token: SecretSource::Value("ya29.hunter2-token".to_string()),
+                }),
+            OutboundSmarthost::new("file", "[smtp.provider.test]:587")
+                .expect("smarthost should be valid")
+                .with_credentials(SmarthostCredentials::Password {
+                    username: "relay-user".to_string(),
+                    password: SecretSource::File(PathBuf::from("/run/secrets/smarthost")),
+                }),
+        ],
+        ..OutboundListenerConfig::default()
+    };
+
+    let dump = format!("{:?}", config);
+    assert!(!dump.contains("hunter2"), "secret leaked: {}", dump);
+    assert!(dump.contains("Value(<redacted>)"), "{}", dump);
+    assert!(dump.contains("/run/secrets/smarthost"), "{}", dump);
+
+    let config = OutboundListenerConfig {
+        smarthosts: vec![smarthost("password", SocketAddr::from(([192, 0, 2, 1], 587)))
+            .with_credentials(SmarthostCredentials::Password {
+                username: "relay-user".to_string(),
+                password: SecretSource::Env(String::new()),
+            })],
+        ..OutboundListenerConfig::default()
+    };
+    let error = config
+        .validate()
+        .expect_err("an empty variable name should be rejected");
+    assert_eq!(error.to_string(), "smarthost password credentials must not be empty");
+}
+
+fn password_credentials(password: &str) -> SmarthostCredentials {
+    SmarthostCredentials::Password {
+        username: "relay-user".to_string(),
+        password: SecretSource::Value(password.to_string()),
+    }
+}
+
+fn smarthost(name: &str, address: SocketAddr) -> OutboundSmarthost {
+    OutboundSmarthost::new(name, &format!("[{}]:{}", address.ip(), address.port()))
+        .expect("smarthost should be valid")
+}
+
+fn write_secret_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "verzola-smarthost-secret-{}-{}",
+        name,
+        std::process::id()
+    ));
+    fs::write(&path, contents).expect("secret file should be writable");
+    path
+}
+
+// Returns the session summary and the first line of the RCPT reply.
+fn deliver(
+    smarthost_addr: SocketAddr,
+    credentials: SmarthostCredentials,
+    tls_upgrader: impl OutboundTlsUpgrader,
+) -> (OutboundSessionSummary, String) {
+    let config = OutboundListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "relay.verzola.test".to_string(),
+        outbound_tls_policy: OutboundTlsPolicy::Opportunistic,
+        transport_routes: vec![OutboundTransportRoute::new(
+            "partner.example",
+            OutboundNextHop::Smarthost("gateway".to_string()),
+        )
+        .expect("route should be valid")],
+        smarthosts: vec![smarthost("gateway", smarthost_addr).with_credentials(credentials)],
+        ..OutboundListenerConfig::default()
+    };
+    let listener = OutboundListener::bind(config, NoopMxResolver)
+        .expect("outbound listener should bind for smarthost test")
+        .with_tls_upgrader(tls_upgrader);
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.org>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "RCPT TO:<bob@partner.example>\r\n");
+    let rcpt_reply = read_reply(&mut reader).remove(0);
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary = handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary");
+    (summary, rcpt_reply)
+}
+
+// A smarthost whose STARTTLS is only the SMTP exchange, like the relay's own. Returns every
+// command line it received.
+fn spawn_smarthost(behavior: SmarthostBehavior) -> (SocketAddr, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("mock smarthost should bind");
+    let address = listener.local_addr().expect("mock smarthost address must resolve");
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().expect("mock smarthost should accept");
+        let mut reader =
+            BufReader::new(stream.try_clone().expect("mock smarthost socket should clone"));
+        let mut commands = Vec::new();
+        let mut tls_started = false;
+        let mut login_prompts = Vec::new();
+        stream
+            .write_all(b"220 smarthost.test ESMTP\r\n")
+            .expect("mock smarthost banner should write");
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return commands;
+            }
+            let line = line.trim_end().to_string();
+            let verb = line.split_whitespace().next().unwrap_or("").to_ascii_uppercase();
+            commands.push(line.clone());
+            let reply = if let Some(prompt) = login_prompts.pop() {
+                prompt
+            } else {
+                match verb.as_str() {
+                    "EHLO" if tls_started => format!(
+                        "250-smarthost.test\r\n250-AUTH {}\r\n250 PIPELINING",
+                        behavior.auth_mechanisms
+                    ),
+                    "EHLO" if behavior.starttls => {
+                        "250-smarthost.test\r\n250-STARTTLS\r\n250 PIPELINING".to_string()
+                    }
+                    "EHLO" => "250-smarthost.test\r\n250 PIPELINING".to_string(),
+                    "STARTTLS" => {
+                        tls_started = true;
+                        "220 2.0.0 Ready to start TLS".to_string()
+                    }
+                    "AUTH" if line.eq_ignore_ascii_case("AUTH LOGIN") => {
+                        login_prompts = vec![
+                            behavior.auth_reply.to_string(),
+                            "334 UGFzc3dvcmQ6".to_string(),
+                        ];
+                        "334 VXNlcm5hbWU6".to_string()
+                    }
+                    "AUTH" => behavior.auth_reply.to_string(),
+                    "QUIT" => {
+                        let _ = stream.write_all(b"221 2.0.0 Bye\r\n");
+                        return commands;
+                    }
+                    _ => "250 2.0.0 OK".to_string(),
+                }
+            };
+            if stream.write_all(format!("{}\r\n", reply).as_bytes()).is_err() {
+                return commands;
+            }
+        }
+    });
+    (address, handle)
+}
+
+fn join_smarthost(handle: thread::JoinHandle<Vec<String>>) -> Vec<String> {
+    handle.join().expect("mock smarthost thread should not panic")
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("test client read timeout should set");
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, line: &str) {
+    stream
+        .write_all(line.as_bytes())
+        .expect("test client write should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        let read = reader
+            .read_line(&mut line)
+            .expect("test client read should succeed");
+        assert!(read > 0, "listener closed the connection before replying");
+        let line = line.trim_end().to_string();
+        let last = line.as_bytes().get(3) != Some(&b'-');
+        lines.push(line);
+        if last {
+            return lines;
+        }
+    }
+}
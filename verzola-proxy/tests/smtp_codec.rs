@@ -0,0 +1,432 @@
+use std::io::{BufRead, BufReader, Cursor, ErrorKind, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::inbound::{InboundListener, ListenerConfig, NoopTlsUpgrader};
+use verzola_proxy::smtp::{
+    Command, CommandError, EhloCapabilities, EnhancedStatusCode, EsmtpParameter, MailFrom,
+    Mailbox, MailboxDomain, RcptTo, Reply,
+};
+
+#[test]
+fn commands_parse_case_insensitively() {
+    assert_eq!(
+        Command::parse("ehlo client.example\r\n", false),
+        Ok(Command::Ehlo("client.example".to_string()))
+    );
+    assert_eq!(Command::parse("Data", false), Ok(Command::Data));
+    assert_eq!(Command::parse("NOOP ignored text", false), Ok(Command::Noop));
+    assert_eq!(Command::parse("StartTLS", false), Ok(Command::StartTls));
+    assert_eq!(
+        Command::parse("XFORWARD NAME=spike.example.org", false),
+        Ok(Command::Other {
+            verb: "XFORWARD".to_string(),
+            argument: "NAME=spike.example.org".to_string(),
+        })
+    );
+    assert!(Command::parse("DATA now", false).is_err());
+    assert!(Command::parse("QUIT please", false).is_err());
+
+    let Ok(Command::Mail(mail)) = Command::parse("mail from:<Alice@Example.com>", false) else {
+        panic!("MAIL should parse");
+    };
+    assert_eq!(mail.address(), "Alice@Example.com");
+    assert_eq!(
+        Command::parse("rcpt to:<bob@example.net> notify=never", false)
+            .expect("RCPT should parse")
+            .to_string(),
+        "RCPT TO:<bob@example.net> NOTIFY=never"
+    );
+}
+
+#[test]
+fn mail_from_reads_reverse_path_and_parameters() {
+    let mail = MailFrom::parse("FROM:<alice@example.com> SIZE=512 BODY=8BITMIME SMTPUTF8")
+        .expect("MAIL with parameters should parse");
+    assert_eq!(
+        mail.reverse_path,
+        Some(Mailbox {
+            local_part: "alice".to_string(),
+            domain: Some(MailboxDomain::Name("example.com".to_string())),
+        })
+    );
+    assert_eq!(
+        mail.parameters,
+        vec![
+            parameter("SIZE", Some("512")),
+            parameter("BODY", Some("8BITMIME")),
+            parameter("SMTPUTF8", None),
+        ]
+    );
+    assert!(mail.smtputf8());
+    assert_eq!(
+        mail.parameter("body").and_then(|body| body.value.as_deref()),
+        Some("8BITMIME")
+    );
+    assert_eq!(
+        mail.to_string(),
+        "FROM:<alice@example.com> SIZE=512 BODY=8BITMIME SMTPUTF8"
+    );
+
+    // The null reverse-path is used for bounces.
+    let bounce = MailFrom::parse("FROM:<>").expect("null sender should parse");
+    assert_eq!(bounce.reverse_path, None);
+    assert_eq!(bounce.address(), "");
+    assert!(bounce.parameters.is_empty());
+
+    // Tolerated like Postfix does: a space after the colon, and no angle brackets.
+    for argument in ["FROM: <alice@example.com>", "FROM:alice@example.com"] {
+        assert_eq!(
+            MailFrom::parse(argument).map(|mail| mail.address()),
+            Ok("alice@example.com".to_string())
+        );
+    }
+}
+
+#[test]
+fn mailboxes_follow_rfc_5321_grammar() {
+    // A source route must be accepted and is ignored (RFC 5321 appendix C).
+    let routed = RcptTo::parse("TO:<@relay1.example,@relay2.example:bob@example.net>", false)
+        .expect("source route should parse");
+    assert_eq!(routed.address(), "bob@example.net");
+    assert_eq!(routed.forward_path.domain_name(), Some("example.net"));
+
+    // A quoted local part may contain spaces, '>' and '@'.
+    let quoted_argument = r#"TO:<"john doe>@x"@example.net> NOTIFY=SUCCESS,FAILURE"#;
+    let quoted = RcptTo::parse(quoted_argument, false).expect("quoted local part should parse");
+    assert_eq!(quoted.forward_path.local_part, r#""john doe>@x""#);
+    assert_eq!(quoted.forward_path.domain_name(), Some("example.net"));
+    assert_eq!(
+        quoted.parameters,
+        vec![parameter("NOTIFY", Some("SUCCESS,FAILURE"))]
+    );
+    let escaped = Mailbox::parse(r#""a\"b\\c"@example.net"#).expect("quoted-pair should parse");
+    assert_eq!(escaped.local_part, r#""a\"b\\c""#);
+
+    let postmaster = RcptTo::parse("TO:<Postmaster>", false).expect("postmaster should parse");
+    assert_eq!(postmaster.forward_path.domain, None);
+    assert_eq!(postmaster.address(), "Postmaster");
+
+    let ipv4 = RcptTo::parse("TO:<bob@[192.0.2.1]>", false).expect("IPv4 literal should parse");
+    assert_eq!(
+        ipv4.forward_path.domain,
+        Some(MailboxDomain::AddressLiteral("192.0.2.1".parse().expect("literal IP")))
+    );
+    let ipv6 = Mailbox::parse("bob@[IPv6:2001:db8::1]").expect("IPv6 literal should parse");
+    assert_eq!(ipv6.to_string(), "bob@[IPv6:2001:db8::1]");
+    assert_eq!(ipv6.domain_name(), None);
+
+    for malformed in [
+        "bob",
+        "bob@",
+        "@example.net",
+        "bob..smith@example.net",
+        ".bob@example.net",
+        "bob smith@example.net",
+        "bob(comment)@example.net",
+        "\"unterminated@example.net",
+        "\"a\"b@example.net",
+        "bob@-example.net",
+        "bob@example..net",
+        "bob@example_net.test",
+        "bob@[192.0.2.256]",
+        "bob@[2001:db8::1]",
+        "bob@[IPv6:192.0.2.1]",
+        "bob@[192.0.2.1",
+    ] {
+        assert_eq!(Mailbox::parse(malformed), None, "{} should be rejected", malformed);
+    }
+
+    let long_local_part = format!("{}@example.net", "a".repeat(65));
+    assert_eq!(Mailbox::parse(&long_local_part), None);
+    let long_label = format!("bob@{}.example", "a".repeat(64));
+    assert_eq!(Mailbox::parse(&long_label), None);
+}
+
+#[test]
+fn non_ascii_mailboxes_require_smtputf8() {
+    let mail = MailFrom::parse("FROM:<jöran@bücher.example> SMTPUTF8")
+        .expect("UTF-8 sender with SMTPUTF8 should parse");
+    assert!(mail
+        .reverse_path
+        .as_ref()
+        .is_some_and(|mailbox| mailbox.requires_smtputf8()));
+    assert_eq!(
+        rejection(MailFrom::parse("FROM:<jöran@bücher.example>")),
+        "553 5.6.7 Non-ASCII sender address requires SMTPUTF8"
+    );
+    assert_eq!(
+        rejection(MailFrom::parse("FROM:<alice@example.com> SMTPUTF8=yes")),
+        "501 5.5.4 Invalid MAIL parameters"
+    );
+
+    let rcpt = RcptTo::parse("TO:<用户@例子.广告>", true).expect("UTF-8 recipient should parse");
+    assert_eq!(rcpt.forward_path.domain_name(), Some("例子.广告"));
+    assert_eq!(
+        rejection(RcptTo::parse("TO:<用户@例子.广告>", false)),
+        "553 5.6.7 Non-ASCII recipient address requires SMTPUTF8"
+    );
+    assert!(!Mailbox::parse("bob@example.net")
+        .expect("ASCII mailbox should parse")
+        .requires_smtputf8());
+}
+
+#[test]
+fn malformed_paths_and_parameters_are_rejected() {
+    let cases = [
+        (MailFrom::parse("<alice@example.com>").err(), "501 5.5.4 MAIL requires FROM:<address>"),
+        (MailFrom::parse("FROM:<alice@example.com").err(), "501 5.1.7 Bad sender address syntax"),
+        (
+            MailFrom::parse("FROM:<alice@example.com>SIZE=1").err(),
+            "501 5.1.7 Bad sender address syntax",
+        ),
+        (MailFrom::parse("FROM:<alice>").err(), "501 5.1.7 Bad sender address syntax"),
+        (
+            MailFrom::parse("FROM:<alice@exa mple.com>").err(),
+            "501 5.1.7 Bad sender address syntax",
+        ),
+        (RcptTo::parse("bob@example.net", false).err(), "501 5.5.4 RCPT requires TO:<address>"),
+        (RcptTo::parse("TO:<>", false).err(), "501 5.1.3 Bad recipient address syntax"),
+        (
+            RcptTo::parse("TO:<\"john doe\"@>", false).err(),
+            "501 5.1.3 Bad recipient address syntax",
+        ),
+        (
+            RcptTo::parse("TO:<@relay.example bob@example.net>", false).err(),
+            "501 5.1.3 Bad recipient address syntax",
+        ),
+        (
+            RcptTo::parse("TO:<@relay_1:bob@example.net>", false).err(),
+            "501 5.1.3 Bad recipient address syntax",
+        ),
+    ];
+    for (error, expected) in cases {
+        assert_eq!(error.map(|error| error.to_string()).as_deref(), Some(expected));
+    }
+
+    for argument in [
+        "FROM:<alice@example.com> SIZE=",
+        "FROM:<alice@example.com> -SIZE=1",
+        "FROM:<alice@example.com> SIZE=1=2",
+        "FROM:<alice@example.com> SIZE=1 size=2",
+    ] {
+        assert_eq!(
+            rejection(MailFrom::parse(argument)),
+            "501 5.5.4 Invalid MAIL parameters",
+            "{} should be rejected",
+            argument
+        );
+    }
+}
+
+#[test]
+fn replies_parse_multiline_and_enhanced_codes() {
+    let mut wire = Cursor::new(
+        "250-postfix.example Hello\r\n250-PIPELINING\r\n250-SIZE 10240000\r\n\
+         250-AUTH PLAIN LOGIN\r\n250-XCLIENT NAME ADDR LOGIN\r\n250 8BITMIME\r\n",
+    );
+    let ehlo = Reply::read_from(&mut wire).expect("multiline reply should parse");
+    assert_eq!(ehlo.code, 250);
+    assert_eq!(ehlo.lines.len(), 6);
+    assert_eq!(ehlo.last_line(), "250 8BITMIME");
+    assert_eq!(ehlo.enhanced_status(), None);
+
+    let capabilities = EhloCapabilities::from_reply(&ehlo);
+    assert_eq!(capabilities.domain, "postfix.example");
+    assert!(capabilities.has("pipelining"));
+    assert!(capabilities.has("8BITMIME"));
+    assert!(!capabilities.has("STARTTLS"));
+    assert_eq!(capabilities.size_limit(), Some(Some(10_240_000)));
+    assert!(capabilities.has_parameter("AUTH", "login"));
+    assert!(capabilities.has_parameter("XCLIENT", "LOGIN"));
+    assert!(!capabilities.has_parameter("XCLIENT", "SECRET"));
+
+    let mut rendered = Vec::new();
+    ehlo.write_to(&mut rendered)
+        .expect("writing to a Vec should succeed");
+    assert_eq!(rendered, wire.into_inner().as_bytes());
+
+    let deferred = Reply::new(451, "4.7.1 Try again later");
+    assert_eq!(
+        deferred.enhanced_status(),
+        Some(EnhancedStatusCode {
+            class: 4,
+            subject: 7,
+            detail: 1,
+        })
+    );
+    assert_eq!(deferred.summary(), "451 4.7.1 Try again later");
+    // The enhanced class has to agree with the reply code.
+    assert_eq!(Reply::new(250, "5.1.1 odd").enhanced_status(), None);
+    assert_eq!(EnhancedStatusCode::parse("5.7.1000"), None);
+    assert_eq!(EnhancedStatusCode::parse("3.0.0"), None);
+    assert_eq!(
+        EnhancedStatusCode::parse("5.1.10").map(|status| status.to_string()),
+        Some("5.1.10".to_string())
+    );
+
+    // Reply-line may be just the code.
+    let bare = Reply::read_from(&mut Cursor::new("354\r\n")).expect("bare code should parse");
+    assert_eq!((bare.code, bare.lines.as_slice()), (354, [String::new()].as_slice()));
+}
+
+#[test]
+fn malformed_replies_are_rejected() {
+    for wire in [
+        "250-first\r\n251 second\r\n",
+        "250*oops\r\n",
+        "hello\r\n",
+        "650 out of range\r\n",
+        "",
+    ] {
+        let error = Reply::read_from(&mut Cursor::new(wire))
+            .expect_err("malformed reply should be rejected");
+        assert!(
+            matches!(error.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof),
+            "{:?} gave {:?}",
+            wire,
+            error
+        );
+    }
+}
+
+#[test]
+fn inbound_listener_rejects_malformed_envelope_commands() {
+    let listener = InboundListener::bind(
+        ListenerConfig {
+            bind_addr: "127.0.0.1:0".parse().expect("hard-coded address must parse"),
+            ..ListenerConfig::default()
+        },
+        NoopTlsUpgrader,
+    )
+    .expect("local listener should bind");
+    let address = listener.local_addr().expect("listener address must resolve");
+
+    thread::scope(|scope| {
+        let server = scope.spawn(|| listener.serve_one());
+
+        let (mut stream, mut reader) = connect(address);
+        let _banner = read_reply(&mut reader);
+        send(&mut stream, "EHLO sender.example\r\n");
+        let _ehlo_reply = read_reply(&mut reader);
+        send(&mut stream, "MAIL FROM:<alice@example.com> SIZE=\r\n");
+        assert_eq!(read_reply(&mut reader), ["501 5.5.4 Invalid MAIL parameters"]);
+        send(&mut stream, "MAIL FROM:<alice@example.com>\r\n");
+        assert!(read_reply(&mut reader)[0].starts_with("250 "));
+        send(&mut stream, "RCPT bob@example.net\r\n");
+        assert_eq!(read_reply(&mut reader), ["501 5.5.4 RCPT requires TO:<address>"]);
+        send(&mut stream, "QUIT\r\n");
+        let _quit_reply = read_reply(&mut reader);
+
+        let summary = server
+            .join()
+            .expect("listener thread should not panic")
+            .expect("listener should return a session summary");
+        assert_eq!(summary.protocol_errors, 2);
+    });
+}
+
+#[test]
+fn inbound_listener_gates_utf8_mailboxes_on_smtputf8() {
+    let listener = InboundListener::bind(
+        ListenerConfig {
+            bind_addr: "127.0.0.1:0".parse().expect("hard-coded address must parse"),
+            ..ListenerConfig::default()
+        },
+        NoopTlsUpgrader,
+    )
+    .expect("local listener should bind");
+    let address = listener.local_addr().expect("listener address must resolve");
+
+    thread::scope(|scope| {
+        let server = scope.spawn(|| listener.serve_one());
+
+        let (mut stream, mut reader) = connect(address);
+        let _banner = read_reply(&mut reader);
+        send(&mut stream, "EHLO sender.example\r\n");
+        let _ehlo_reply = read_reply(&mut reader);
+        send(&mut stream, "MAIL FROM:<jöran@bücher.example>\r\n");
+        assert_eq!(
+            read_reply(&mut reader),
+            ["553 5.6.7 Non-ASCII sender address requires SMTPUTF8"]
+        );
+        send(&mut stream, "MAIL FROM:<alice@example.com>\r\n");
+        assert!(read_reply(&mut reader)[0].starts_with("250 "));
+        send(&mut stream, "RCPT TO:<用户@例子.广告>\r\n");
+        assert_eq!(
+            read_reply(&mut reader),
+            ["553 5.6.7 Non-ASCII recipient address requires SMTPUTF8"]
+        );
+        send(&mut stream, "RCPT TO:<bob..smith@example.net>\r\n");
+        assert_eq!(read_reply(&mut reader), ["501 5.1.3 Bad recipient address syntax"]);
+        send(&mut stream, "RSET\r\n");
+        assert!(read_reply(&mut reader)[0].starts_with("250 "));
+
+        send(&mut stream, "MAIL FROM:<jöran@bücher.example> SMTPUTF8\r\n");
+        assert!(read_reply(&mut reader)[0].starts_with("250 "));
+        send(&mut stream, "RCPT TO:<用户@例子.广告>\r\n");
+        assert!(read_reply(&mut reader)[0].starts_with("250 "));
+        send(&mut stream, "QUIT\r\n");
+        let _quit_reply = read_reply(&mut reader);
+
+        let summary = server
+            .join()
+            .expect("listener thread should not panic")
+            .expect("listener should return a session summary");
+        assert_eq!(summary.protocol_errors, 3);
+    });
+}
+
+fn rejection<T>(result: Result<T, CommandError>) -> String {
+    match result {
+        Ok(_) => panic!("command should have been rejected"),
+        Err(error) => error.to_string(),
+    }
+}
+
+fn parameter(keyword: &str, value: Option<&str>) -> EsmtpParameter {
+    EsmtpParameter {
+        keyword: keyword.to_string(),
+        value: value.map(str::to_string),
+    }
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("test client read timeout should set");
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, command: &str) {
+    stream
+        .write_all(command.as_bytes())
+        .expect("test client should write command bytes");
+    stream.flush().expect("test client flush should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut raw = String::new();
+        let bytes = reader
+            .read_line(&mut raw)
+            .expect("test client should read server reply");
+        assert!(bytes > 0, "server closed connection unexpectedly");
+        let line = raw.trim_end_matches(['\r', '\n']).to_string();
+        lines.push(line.clone());
+
+        let line_bytes = line.as_bytes();
+        if line_bytes.len() < 4 || line_bytes[3] == b' ' {
+            break;
+        }
+    }
+    lines
+}
@@ -0,0 +1,505 @@
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::auth::{
+    AuthRequest, AuthenticationError, Authenticator, PasswordFileAuthenticator,
+};
+use verzola_proxy::inbound::{
+    InboundListener, ListenerConfig, ListenerMode, NoopTlsUpgrader, SessionSummary,
+};
+
+const ALICE_ENTRY: &str =
+    "alice@example.com:{SSHA256}U+kc1alrfU/YkBSrUMVgRVRSDIXIhJKFdZfdaHQ8IbROYUNsMTIzNA==";
+const BOB_ENTRY: &str = concat!(
+    "bob:{SSHA256.HEX}",
+    "c7d322cdbd31b6c9fb0b84c09b7b721c896475113d4f7a118cc3990f73e7518e626f6273616c7431",
+    ":1000:1000"
+);
+
+#[test]
+fn submission_requires_tls_and_auth_and_forwards_login() {
+    let password_file = write_password_file("forward", &[ALICE_ENTRY, BOB_ENTRY]);
+    let (postfix_addr, postfix_handle) = spawn_mock_postfix(true, XCLIENT_ACCEPTED);
+    let authenticator = PasswordFileAuthenticator::open(&password_file)
+        .expect("password file should load");
+    let (address, handle) = spawn_submission_listener(Some(postfix_addr), Arc::new(authenticator));
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO laptop.example\r\n");
+    let ehlo_reply = read_reply(&mut reader);
+    assert!(ehlo_reply.iter().any(|line| line == "250-STARTTLS"));
+    assert!(!ehlo_reply.iter().any(|line| line.contains("AUTH")));
+
+    send(&mut stream, "AUTH PLAIN AGFsaWNlQGV4YW1wbGUuY29tAGNvcnJlY3QgaG9yc2U=\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("538 5.7.11 "));
+    send(&mut stream, "MAIL FROM:<alice@example.com>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("530 5.7.0 Authentication required"));
+
+    send(&mut stream, "STARTTLS\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("220 "));
+    send(&mut stream, "EHLO laptop.example\r\n");
+    let ehlo_reply = read_reply(&mut reader);
+    assert!(ehlo_reply.iter().any(|line| line == "250-AUTH PLAIN LOGIN"));
+
+    send(&mut stream, "MAIL FROM:<alice@example.com>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("530 "));
+    send(&mut stream, "AUTH PLAIN AGFsaWNlQGV4YW1wbGUuY29tAHdyb25n\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("535 5.7.8 "));
+    send(&mut stream, "AUTH PLAIN\r\n");
+    assert_eq!(read_reply(&mut reader), vec!["334 ".to_string()]);
+    send(&mut stream, "AGFsaWNlQGV4YW1wbGUuY29tAGNvcnJlY3QgaG9yc2U=\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("235 2.7.0 "));
+
+    send(&mut stream, "AUTH PLAIN AGFsaWNlQGV4YW1wbGUuY29tAGNvcnJlY3QgaG9yc2U=\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("503 "));
+    send(&mut stream, "MAIL FROM:<alice@example.com>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "RCPT TO:<carol@example.net>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "DATA\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("354 "));
+    send(&mut stream, "hello\r\n.\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary = join_listener(handle);
+    assert_eq!(summary.authenticated_identity.as_deref(), Some("alice@example.com"));
+    assert_eq!(summary.telemetry.auth_failures, 1);
+
+    let upstream_commands = postfix_handle
+        .join()
+        .expect("mock postfix thread should not panic")
+        .expect("mock postfix should finish cleanly");
+    assert_eq!(upstream_commands[0], "EHLO mx.verzola.test");
+    assert_eq!(upstream_commands[1], "XCLIENT LOGIN=alice@example.com");
+    assert_eq!(upstream_commands[2], "EHLO mx.verzola.test");
+    assert_eq!(upstream_commands[3], "MAIL FROM:<alice@example.com>");
+    let _ = fs::remove_file(&password_file);
+}
+
+#[test]
+fn auth_login_exchange_and_failure_limit() {
+    let password_file = write_password_file("login", &[ALICE_ENTRY, BOB_ENTRY]);
+    let authenticator = PasswordFileAuthenticator::open(&password_file)
+        .expect("password file should load");
+    let (address, handle) = spawn_submission_listener(None, Arc::new(authenticator));
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO laptop.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "STARTTLS\r\n");
+    let _starttls_reply = read_reply(&mut reader);
+    send(&mut stream, "EHLO laptop.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+
+    send(&mut stream, "AUTH LOGIN\r\n");
+    assert_eq!(read_reply(&mut reader), vec!["334 VXNlcm5hbWU6".to_string()]);
+    send(&mut stream, "*\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("501 "));
+
+    for _ in 0..2 {
+        send(&mut stream, "AUTH LOGIN Ym9i\r\n");
+        assert_eq!(read_reply(&mut reader), vec!["334 UGFzc3dvcmQ6".to_string()]);
+        send(&mut stream, "d3Jvbmc=\r\n");
+        assert!(read_reply(&mut reader)[0].starts_with("535 "));
+    }
+    send(&mut stream, "AUTH PLAIN AGFsaWNlQGV4YW1wbGUuY29tAHdyb25n\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("421 4.7.0 "));
+
+    let summary = join_listener(handle);
+    assert_eq!(summary.authenticated_identity, None);
+    assert_eq!(summary.telemetry.auth_failures, 3);
+    let _ = fs::remove_file(&password_file);
+}
+
+#[test]
+fn upstream_without_xclient_defers_mail() {
+    let password_file = write_password_file("no-xclient", &[BOB_ENTRY]);
+    let (postfix_addr, _postfix_handle) = spawn_mock_postfix(false, XCLIENT_ACCEPTED);
+    let authenticator = PasswordFileAuthenticator::open(&password_file)
+        .expect("password file should load");
+    let (address, handle) = spawn_submission_listener(Some(postfix_addr), Arc::new(authenticator));
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO laptop.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "STARTTLS\r\n");
+    let _starttls_reply = read_reply(&mut reader);
+    send(&mut stream, "EHLO laptop.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "AUTH LOGIN Ym9i\r\n");
+    let _password_prompt = read_reply(&mut reader);
+    send(&mut stream, "YmF0dGVyeSBzdGFwbGU=\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("235 "));
+
+    send(&mut stream, "MAIL FROM:<bob@example.com>\r\n");
+    let mail_reply = read_reply(&mut reader);
+    assert!(mail_reply[0].starts_with("451 4.4.0 "));
+    assert!(mail_reply[0].contains("XCLIENT LOGIN"));
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary = join_listener(handle);
+    assert_eq!(summary.authenticated_identity.as_deref(), Some("bob"));
+    let _ = fs::remove_file(&password_file);
+}
+
+#[test]
+fn rejected_xclient_goes_back_to_the_client_and_keeps_the_backend_healthy() {
+    let password_file = write_password_file("xclient-rejected", &[BOB_ENTRY]);
+    let (postfix_addr, postfix_handle) =
+        spawn_mock_postfix(true, "550 5.7.0 Error: insufficient authorization");
+    let authenticator = PasswordFileAuthenticator::open(&password_file)
+        .expect("password file should load");
+    let config = ListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "mx.verzola.test".to_string(),
+        mode: ListenerMode::Submission,
+        postfix_upstream_addr: Some(postfix_addr),
+        ..ListenerConfig::default()
+    };
+    let listener = InboundListener::bind(config, NoopTlsUpgrader)
+        .expect("submission listener should bind")
+        .with_authenticator(Arc::new(authenticator));
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || {
+        let summary = listener.serve_one();
+        (listener, summary)
+    });
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO laptop.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "STARTTLS\r\n");
+    let _starttls_reply = read_reply(&mut reader);
+    send(&mut stream, "EHLO laptop.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "AUTH LOGIN Ym9i\r\n");
+    let _password_prompt = read_reply(&mut reader);
+    send(&mut stream, "YmF0dGVyeSBzdGFwbGU=\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("235 "));
+
+    send(&mut stream, "MAIL FROM:<bob@example.com>\r\n");
+    assert_eq!(
+        read_reply(&mut reader),
+        vec!["550 5.7.0 Error: insufficient authorization".to_string()]
+    );
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let (listener, summary) = handle.join().expect("listener thread should not panic");
+    let summary = summary.expect("listener should return a session summary");
+    assert_eq!(summary.telemetry.relay_temporary_failures, 0);
+    let health = listener.upstream_health();
+    assert!(health[0].healthy);
+    assert_eq!(health[0].total_failures, 0);
+
+    let upstream_commands = postfix_handle
+        .join()
+        .expect("mock postfix thread should not panic")
+        .expect("mock postfix should finish cleanly");
+    assert_eq!(upstream_commands, ["EHLO mx.verzola.test", "XCLIENT LOGIN=bob"]);
+    let _ = fs::remove_file(&password_file);
+}
+
+#[test]
+fn relay_mode_rejects_auth_and_submission_requires_starttls() {
+    let config = ListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "mx.verzola.test".to_string(),
+        ..ListenerConfig::default()
+    };
+    let listener = InboundListener::bind(config.clone(), NoopTlsUpgrader)
+        .expect("relay listener should bind");
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO laptop.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "AUTH PLAIN AGFsaWNlQGV4YW1wbGUuY29tAGNvcnJlY3QgaG9yc2U=\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("502 "));
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+    let _summary = join_listener(handle);
+
+    let submission_without_tls = ListenerConfig {
+        mode: ListenerMode::Submission,
+        advertise_starttls: false,
+        ..config
+    };
+    let error = submission_without_tls
+        .validate()
+        .expect_err("submission without STARTTLS must be refused");
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn password_file_backend_checks_schemes_and_reloads() {
+    let password_file = write_password_file("reload", &["# users", "", ALICE_ENTRY]);
+    let authenticator = PasswordFileAuthenticator::open(&password_file)
+        .expect("password file should load");
+    assert_eq!(authenticator.user_count(), 1);
+
+    assert_eq!(
+        authenticator.authenticate(&request(None, "alice@example.com", "correct horse")),
+        Ok("alice@example.com".to_string())
+    );
+    assert_eq!(
+        authenticator.authenticate(&request(None, "alice@example.com", "correct horsE")),
+        Err(AuthenticationError::InvalidCredentials)
+    );
+    assert_eq!(
+        authenticator.authenticate(&request(Some("root"), "alice@example.com", "correct horse")),
+        Err(AuthenticationError::InvalidCredentials)
+    );
+    assert_eq!(
+        authenticator.authenticate(&request(None, "bob", "battery staple")),
+        Err(AuthenticationError::InvalidCredentials)
+    );
+
+    fs::write(
+        &password_file,
+        format!(
+            "{}\n{}\ncarol:{{SSHA256}}/ejV3TEr5yZa8FVFSbCTmfRPI3jYWT2ipS6X/Z7Ah7tjYXJvbHNsdA==\n",
+            ALICE_ENTRY, BOB_ENTRY
+        ),
+    )
+    .expect("password file should be writable");
+    assert_eq!(authenticator.reload().expect("reload should succeed"), 3);
+    assert_eq!(
+        authenticator.authenticate(&request(None, "bob", "battery staple")),
+        Ok("bob".to_string())
+    );
+    assert_eq!(
+        authenticator.authenticate(&request(None, "carol", "new-pass")),
+        Ok("carol".to_string())
+    );
+
+    fs::write(&password_file, "dave:{PLAIN}hunter2\n").expect("password file should be writable");
+    let error = authenticator
+        .reload()
+        .expect_err("plaintext passwords must be refused");
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert!(error.to_string().contains(":1:"));
+    assert_eq!(authenticator.user_count(), 3);
+
+    fs::write(&password_file, "erin:{SHA256}sLeuPFhKrtDd09ZZG+LcrqOY5GCZQNKyiTSqsQhLKb8=\n")
+        .expect("password file should be writable");
+    let error = authenticator
+        .reload()
+        .expect_err("unsalted hashes must be refused");
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert!(error.to_string().contains("unsalted {SHA256} hashes are not accepted"));
+    assert_eq!(authenticator.user_count(), 3);
+
+    let debug = format!("{:?}", request(None, "bob", "battery staple"));
+    assert!(!debug.contains("battery staple"));
+    let _ = fs::remove_file(&password_file);
+}
+
+fn request(authzid: Option<&str>, username: &str, password: &str) -> AuthRequest {
+    AuthRequest {
+        mechanism: "PLAIN",
+        authzid: authzid.map(str::to_string),
+        username: username.to_string(),
+        password: password.to_string(),
+        client_addr: None,
+        secured: true,
+    }
+}
+
+fn write_password_file(name: &str, lines: &[&str]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "verzola-passwd-{}-{}",
+        name,
+        std::process::id()
+    ));
+    write_lines(&path, lines);
+    path
+}
+
+fn write_lines(path: &Path, lines: &[&str]) {
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+    fs::write(path, contents).expect("password file should be writable");
+}
+
+#[test]
+fn submission_listener_without_authenticator_refuses_to_serve() {
+    let config = ListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "mx.verzola.test".to_string(),
+        mode: ListenerMode::Submission,
+        ..ListenerConfig::default()
+    };
+    let listener = InboundListener::bind(config, NoopTlsUpgrader)
+        .expect("submission listener should bind");
+
+    let error = listener
+        .serve_one()
+        .expect_err("AUTH could never succeed, so the listener must not serve");
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    assert!(error.to_string().contains("mode=submission requires an authenticator"));
+}
+
+fn spawn_submission_listener(
+    postfix_upstream_addr: Option<SocketAddr>,
+    authenticator: Arc<dyn Authenticator>,
+) -> (SocketAddr, thread::JoinHandle<std::io::Result<SessionSummary>>) {
+    let config = ListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "mx.verzola.test".to_string(),
+        mode: ListenerMode::Submission,
+        postfix_upstream_addr,
+        ..ListenerConfig::default()
+    };
+    let listener = InboundListener::bind(config, NoopTlsUpgrader)
+        .expect("submission listener should bind")
+        .with_authenticator(authenticator);
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    (address, handle)
+}
+
+fn join_listener(handle: thread::JoinHandle<std::io::Result<SessionSummary>>) -> SessionSummary {
+    handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary")
+}
+
+fn spawn_mock_postfix(
+    offers_xclient: bool,
+    xclient_reply: &'static str,
+) -> (SocketAddr, thread::JoinHandle<std::io::Result<Vec<String>>>) {
+    let listener =
+        TcpListener::bind("127.0.0.1:0").expect("mock postfix listener should bind to localhost");
+    let address = listener
+        .local_addr()
+        .expect("mock postfix listener address should resolve");
+
+    let handle = thread::spawn(move || -> std::io::Result<Vec<String>> {
+        let (mut stream, _) = listener.accept()?;
+        stream
+            .set_read_timeout(Some(Duration::from_secs(3)))
+            .expect("mock postfix read timeout should set");
+        write_line(&mut stream, "220 postfix.verzola.test ESMTP Postfix")?;
+
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut commands = Vec::new();
+        let mut reading_data = false;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(commands);
+            }
+
+            if reading_data {
+                if line == ".\r\n" {
+                    reading_data = false;
+                    write_line(&mut stream, "250 2.0.0 Ok: queued as SUBMIT1")?;
+                }
+                continue;
+            }
+
+            let command = line.trim_end_matches(['\r', '\n']).to_string();
+            let verb = command
+                .split_whitespace()
+                .next()
+                .unwrap_or("")
+                .to_ascii_uppercase();
+            commands.push(command);
+            match verb.as_str() {
+                "EHLO" if offers_xclient => {
+                    write_line(&mut stream, "250-postfix.verzola.test")?;
+                    write_line(&mut stream, "250 XCLIENT NAME ADDR PROTO HELO LOGIN")?;
+                }
+                "EHLO" => write_line(&mut stream, "250 postfix.verzola.test")?,
+                "XCLIENT" => write_line(&mut stream, xclient_reply)?,
+                "DATA" => {
+                    reading_data = true;
+                    write_line(&mut stream, "354 End data with <CR><LF>.<CR><LF>")?;
+                }
+                "QUIT" => {
+                    write_line(&mut stream, "221 2.0.0 Bye")?;
+                    return Ok(commands);
+                }
+                _ => write_line(&mut stream, "250 2.0.0 OK")?,
+            }
+        }
+    });
+
+    (address, handle)
+}
+
+const XCLIENT_ACCEPTED: &str = "220 postfix.verzola.test ESMTP Postfix";
+
+fn write_line(stream: &mut TcpStream, line: &str) -> std::io::Result<()> {
+    write!(stream, "{}\r\n", line)?;
+    stream.flush()
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .expect("test client read timeout should set");
+
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, command: &str) {
+    stream
+        .write_all(command.as_bytes())
+        .expect("test client should write command bytes");
+    stream.flush().expect("test client flush should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    loop {
+        let mut raw = String::new();
+        let bytes = reader
+            .read_line(&mut raw)
+            .expect("test client should read server reply");
+        assert!(bytes > 0, "server closed connection unexpectedly");
+
+        let line = raw.trim_end_matches(['\r', '\n']).to_string();
+        lines.push(line.clone());
+
+        let line_bytes = line.as_bytes();
+        if line_bytes.len() < 4 || line_bytes[3] == b' ' {
+            break;
+        }
+    }
+
+    lines
+}
@@ -0,0 +1,301 @@
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::auth::{AuthRequest, AuthenticationError, Authenticator};
+use verzola_proxy::inbound::{
+    InboundTlsMode, InboundTlsPolicy, ListenerConfig, ListenerMode, NoopTlsUpgrader,
+};
+use verzola_proxy::metrics::MetricsRegistry;
+use verzola_proxy::outbound::{NoopMxResolver, OutboundListenerConfig};
+use verzola_proxy::shutdown::ShutdownSignal;
+use verzola_proxy::supervisor::{
+    ListenerProfile, ProfileDirection, Supervisor, SupervisorConfig, SupervisorReport,
+};
+use verzola_proxy::telemetry::{SessionReport, TelemetryEvent, TelemetryRecorder};
+
+// Submission profiles must have an authenticator; these tests never log in.
+struct RejectingAuthenticator;
+
+impl Authenticator for RejectingAuthenticator {
+    fn authenticate(&self, _request: &AuthRequest) -> Result<String, AuthenticationError> {
+        Err(AuthenticationError::InvalidCredentials)
+    }
+}
+
+#[test]
+fn validate_checks_names_and_bind_addresses() {
+    assert!(SupervisorConfig::default().validate().is_err());
+
+    let duplicate_names = SupervisorConfig {
+        profiles: vec![
+            ListenerProfile::inbound("mx", inbound_config(ListenerMode::Relay)),
+            ListenerProfile::inbound("mx", inbound_config(ListenerMode::Relay)),
+        ],
+    };
+    let error = duplicate_names
+        .validate()
+        .expect_err("duplicate profile names must be refused");
+    assert!(error.to_string().contains("duplicate profile name: mx"));
+
+    let bad_name = SupervisorConfig {
+        profiles: vec![ListenerProfile::inbound(
+            "mx public",
+            inbound_config(ListenerMode::Relay),
+        )],
+    };
+    assert_eq!(
+        bad_name.validate().map_err(|error| error.kind()),
+        Err(ErrorKind::InvalidInput)
+    );
+
+    let shared_port = SupervisorConfig {
+        profiles: vec![
+            ListenerProfile::inbound("mx", inbound_config(ListenerMode::Relay))
+                .with_bind_addrs(vec![addr("127.0.0.1:2525"), addr("[::1]:2525")]),
+            ListenerProfile::inbound("submission", inbound_config(ListenerMode::Submission))
+                .with_bind_addrs(vec![addr("[::1]:2525")]),
+        ],
+    };
+    let error = shared_port
+        .validate()
+        .expect_err("two profiles on one address must be refused");
+    assert!(error.to_string().contains("[::1]:2525"));
+
+    let invalid_listener = SupervisorConfig {
+        profiles: vec![ListenerProfile::outbound(
+            "relay",
+            OutboundListenerConfig {
+                bind_addr: addr("0.0.0.0:10025"),
+                ..OutboundListenerConfig::default()
+            },
+        )],
+    };
+    let error = invalid_listener
+        .validate()
+        .expect_err("listener config errors must surface");
+    assert!(error.to_string().starts_with("profile relay (0.0.0.0:10025): "));
+    assert!(error.to_string().contains("client_acl"));
+}
+
+#[test]
+fn profiles_share_shutdown_and_tag_telemetry_with_their_name() {
+    let implicit_config = ListenerConfig {
+        tls_mode: InboundTlsMode::Implicit,
+        ..inbound_config(ListenerMode::Submission)
+    };
+    let config = SupervisorConfig {
+        profiles: vec![
+            ListenerProfile::inbound("mx", inbound_config(ListenerMode::Relay))
+                .with_bind_addrs(vec![addr("127.0.0.1:0"), addr("[::1]:0")]),
+            ListenerProfile::inbound("smtps", implicit_config),
+            ListenerProfile::outbound(
+                "relay",
+                OutboundListenerConfig {
+                    bind_addr: addr("127.0.0.1:0"),
+                    banner_host: "relay.verzola.test".to_string(),
+                    ..OutboundListenerConfig::default()
+                },
+            ),
+        ],
+    };
+    let registry = Arc::new(MetricsRegistry::default());
+    let recorder = Arc::new(TelemetryRecorder::new());
+    let supervisor = Supervisor::bind(config, NoopTlsUpgrader, NoopMxResolver)
+        .expect("supervisor should bind every profile")
+        .with_metrics(Arc::clone(&registry))
+        .with_telemetry_sink(recorder.clone())
+        .with_authenticator(Arc::new(RejectingAuthenticator));
+
+    let bound = supervisor.bound_profiles();
+    assert_eq!(bound.len(), 4);
+    assert_eq!(bound[3].direction, ProfileDirection::Outbound);
+    let mx_addrs = supervisor.local_addrs("mx");
+    assert_eq!(mx_addrs.len(), 2);
+    assert!(mx_addrs[0].is_ipv4());
+    assert!(mx_addrs[1].is_ipv6());
+
+    let shutdown = ShutdownSignal::new();
+    let (smtps_addr, relay_addr) = (supervisor.local_addrs("smtps")[0], bound[3].local_addr);
+    let serve_shutdown = shutdown.clone();
+    let handle = thread::spawn(move || supervisor.serve_until_shutdown(&serve_shutdown));
+
+    for address in &mx_addrs {
+        greet_and_quit(*address, "220 mx.verzola.test ESMTP VERZOLA");
+    }
+    greet_and_quit(smtps_addr, "220 mx.verzola.test ESMTP VERZOLA");
+    greet_and_quit(relay_addr, "220 relay.verzola.test ESMTP VERZOLA");
+
+    let (mut idle_stream, mut idle_reader) = connect(mx_addrs[0]);
+    let _banner = read_reply(&mut idle_reader);
+    send(&mut idle_stream, "EHLO idle.example\r\n");
+    let _ehlo_reply = read_reply(&mut idle_reader);
+    shutdown.request();
+    assert_eq!(
+        read_reply(&mut idle_reader),
+        vec!["421 4.3.2 Service shutting down".to_string()]
+    );
+
+    let report = join_supervisor(handle);
+    assert_eq!(report.listeners.len(), 4);
+    assert_eq!(report.profile("mx").sessions_served, 3);
+    assert_eq!(report.profile("mx").sessions_closed_by_shutdown, 1);
+    assert_eq!(report.profile("smtps").sessions_served, 1);
+    assert_eq!(report.profile("relay").sessions_served, 1);
+    assert_eq!(report.total().sessions_served, 5);
+
+    let summaries = recorder
+        .events()
+        .into_iter()
+        .filter_map(|recorded| match recorded.event {
+            TelemetryEvent::Disconnected(report) => Some((recorded.context, report)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(summaries.len(), 5);
+    for (context, report) in &summaries {
+        match report.as_ref() {
+            SessionReport::Inbound(summary) => {
+                assert_eq!(summary.listener, context.listener);
+                let expected = if summary.tls_negotiated { "smtps" } else { "mx" };
+                assert_eq!(context.listener.as_deref(), Some(expected));
+            }
+            SessionReport::Outbound(summary) => {
+                assert_eq!(summary.listener.as_deref(), Some("relay"));
+            }
+        }
+    }
+
+    let rendered = registry.render();
+    assert!(rendered.contains("verzola_inbound_sessions_total{listener=\"mx\"} 3"));
+    assert!(rendered.contains("verzola_inbound_sessions_total{listener=\"smtps\"} 1"));
+    assert!(rendered.contains("verzola_outbound_sessions_total{listener=\"relay\"} 1"));
+}
+
+#[test]
+fn bind_failure_names_the_profile() {
+    let occupied = TcpListener::bind("127.0.0.1:0").expect("placeholder socket should bind");
+    let occupied_addr = occupied
+        .local_addr()
+        .expect("placeholder address must resolve");
+    let config = SupervisorConfig {
+        profiles: vec![
+            ListenerProfile::inbound("mx", inbound_config(ListenerMode::Relay)),
+            ListenerProfile::inbound("submission", inbound_config(ListenerMode::Submission))
+                .with_bind_addrs(vec![occupied_addr]),
+        ],
+    };
+
+    let error = match Supervisor::bind(config, NoopTlsUpgrader, NoopMxResolver) {
+        Ok(_) => panic!("binding an occupied port must fail"),
+        Err(error) => error,
+    };
+    assert_eq!(error.kind(), ErrorKind::AddrInUse);
+    assert!(error.to_string().contains("profile submission could not bind"));
+}
+
+#[test]
+fn submission_profile_without_authenticator_refuses_to_serve() {
+    let config = SupervisorConfig {
+        profiles: vec![
+            ListenerProfile::inbound("mx", inbound_config(ListenerMode::Relay)),
+            ListenerProfile::inbound("submission", inbound_config(ListenerMode::Submission)),
+        ],
+    };
+    let supervisor = Supervisor::bind(config, NoopTlsUpgrader, NoopMxResolver)
+        .expect("supervisor should bind every profile");
+    let mx_addr = supervisor.local_addrs("mx")[0];
+
+    let error = supervisor
+        .serve_until_shutdown(&ShutdownSignal::new())
+        .expect_err("a submission profile without an authenticator must not serve");
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    assert!(error.to_string().starts_with("profile submission ("));
+    assert!(error.to_string().contains("requires an authenticator"));
+
+    // No profile started: the relay listener never answers with a banner.
+    let stream = TcpStream::connect(mx_addr).expect("the bound socket still accepts");
+    stream
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .expect("test client read timeout should set");
+    let mut banner = String::new();
+    let read = BufReader::new(stream).read_line(&mut banner);
+    assert!(read.is_err(), "unexpected banner: {:?}", banner);
+}
+
+fn inbound_config(mode: ListenerMode) -> ListenerConfig {
+    ListenerConfig {
+        bind_addr: addr("127.0.0.1:0"),
+        banner_host: "mx.verzola.test".to_string(),
+        mode,
+        inbound_tls_policy: InboundTlsPolicy::Opportunistic,
+        shutdown_grace_period: Duration::from_secs(5),
+        ..ListenerConfig::default()
+    }
+}
+
+fn addr(value: &str) -> SocketAddr {
+    value.parse().expect("hard-coded socket address must parse")
+}
+
+fn greet_and_quit(address: SocketAddr, expected_banner: &str) {
+    let (mut stream, mut reader) = connect(address);
+    assert_eq!(read_reply(&mut reader), vec![expected_banner.to_string()]);
+    send(&mut stream, "QUIT\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("221 "));
+}
+
+fn join_supervisor(
+    handle: thread::JoinHandle<std::io::Result<SupervisorReport>>,
+) -> SupervisorReport {
+    handle
+        .join()
+        .expect("supervisor thread should not panic")
+        .expect("supervisor should return a report")
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to supervisor");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("test client read timeout should set");
+
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, command: &str) {
+    stream
+        .write_all(command.as_bytes())
+        .expect("test client should write command bytes");
+    stream.flush().expect("test client flush should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    loop {
+        let mut raw = String::new();
+        let bytes = reader
+            .read_line(&mut raw)
+            .expect("test client should read server reply");
+        assert!(bytes > 0, "server closed connection unexpectedly");
+
+        let line = raw.trim_end_matches(['\r', '\n']).to_string();
+        lines.push(line.clone());
+
+        let line_bytes = line.as_bytes();
+        if line_bytes.len() < 4 || line_bytes[3] == b' ' {
+            break;
+        }
+    }
+
+    lines
+}
@@ -0,0 +1,304 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use verzola_proxy::inbound::{
+    InboundListener, InboundTlsPolicy, ListenerConfig, SessionSummary, SessionTelemetry,
+    TlsSessionParameters, TlsUpgradeError, TlsUpgrader,
+};
+use verzola_proxy::logging::{DeliveryOutcome, SessionDirection};
+use verzola_proxy::outbound::{
+    NoopMxResolver, OutboundListener, OutboundListenerConfig, OutboundSessionTelemetry,
+};
+use verzola_proxy::telemetry::{
+    SessionReport, StartTlsResult, TelemetryEvent, TelemetryRecorder,
+};
+
+#[derive(Debug, Clone, Copy)]
+struct RecordingTlsUpgrader;
+
+impl TlsUpgrader for RecordingTlsUpgrader {
+    fn upgrade(&self, _stream: &mut TcpStream) -> Result<(), TlsUpgradeError> {
+        Ok(())
+    }
+
+    fn session_parameters(&self, _stream: &TcpStream) -> Option<TlsSessionParameters> {
+        Some(TlsSessionParameters {
+            protocol_version: "TLSv1.3".to_string(),
+            cipher_suite: "TLS_AES_128_GCM_SHA256".to_string(),
+            key_exchange_group: Some("x25519".to_string()),
+        })
+    }
+}
+
+#[test]
+fn recorder_receives_events_while_session_is_running() {
+    let recorder = Arc::new(TelemetryRecorder::new());
+    let (address, handle) = spawn_server(InboundTlsPolicy::RequireTls, Arc::clone(&recorder));
+    let (mut stream, mut reader) = connect(address);
+
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO live.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+
+    wait_for_events(&recorder, 2);
+    let live_events = recorder.events();
+    assert_eq!(live_events[0].event, TelemetryEvent::Connected);
+    assert_eq!(live_events[0].context.direction, SessionDirection::Inbound);
+    assert_eq!(
+        live_events[1].event,
+        TelemetryEvent::Ehlo {
+            helo_name: "live.example".to_string(),
+            starttls_offered: true,
+        }
+    );
+
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+    let summary = join_server(handle);
+
+    let session_events = recorder.session_events(&summary.session_id);
+    assert_eq!(session_events.len(), 3);
+    assert_eq!(
+        session_events[2],
+        TelemetryEvent::Disconnected(Box::new(SessionReport::Inbound(summary)))
+    );
+}
+
+#[test]
+fn recorder_sees_policy_starttls_and_data_events_in_order() {
+    let recorder = Arc::new(TelemetryRecorder::new());
+    let (address, handle) = spawn_server(InboundTlsPolicy::RequireTls, Arc::clone(&recorder));
+    let (mut stream, mut reader) = connect(address);
+
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO secure.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.com>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("530 "));
+    send(&mut stream, "STARTTLS\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("220 "));
+    send(&mut stream, "EHLO secure.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.com>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "RCPT TO:<bob@example.net>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "DATA\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("354 "));
+    send(&mut stream, "hello\r\n.\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary = join_server(handle);
+    let events = recorder.session_events(&summary.session_id);
+
+    assert_eq!(
+        events[2],
+        TelemetryEvent::TlsRequired {
+            command: "MAIL".to_string(),
+        }
+    );
+    match &events[3] {
+        TelemetryEvent::StartTls(StartTlsResult::Negotiated(Some(parameters))) => {
+            assert_eq!(parameters.protocol_version, "TLSv1.3");
+            assert_eq!(parameters.key_exchange_group.as_deref(), Some("x25519"));
+        }
+        other => panic!("expected negotiated STARTTLS event, got {:?}", other),
+    }
+    assert_eq!(
+        events[5],
+        TelemetryEvent::Mail {
+            sender: "alice@example.com".to_string(),
+            reply_code: 250,
+        }
+    );
+    assert_eq!(
+        events[6],
+        TelemetryEvent::Recipient {
+            recipient: "bob@example.net".to_string(),
+            reply_code: 250,
+        }
+    );
+    match &events[7] {
+        TelemetryEvent::Data(attempt) => {
+            assert_eq!(attempt.outcome, DeliveryOutcome::Accepted);
+            assert_eq!(attempt.recipients, vec!["bob@example.net".to_string()]);
+            assert!(attempt.tls_negotiated);
+        }
+        other => panic!("expected DATA event, got {:?}", other),
+    }
+    assert!(matches!(events[8], TelemetryEvent::Disconnected(_)));
+
+    let mut folded = SessionTelemetry::default();
+    for event in &events {
+        folded.observe(event);
+    }
+    assert_eq!(folded, summary.telemetry);
+    assert_eq!(folded.require_tls_rejections, 1);
+    assert_eq!(folded.starttls_attempts, 1);
+}
+
+#[test]
+fn session_reset_by_the_client_still_reports_disconnected() {
+    let recorder = Arc::new(TelemetryRecorder::new());
+    let (address, handle) = spawn_server(InboundTlsPolicy::Opportunistic, Arc::clone(&recorder));
+    let (mut stream, reader) = connect(address);
+
+    send(&mut stream, "EHLO reset.example\r\n");
+    wait_for_events(&recorder, 2);
+    // Closing with the banner and EHLO reply still unread makes the kernel send a reset.
+    thread::sleep(Duration::from_millis(100));
+    drop(reader);
+    drop(stream);
+
+    let error = handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect_err("a reset session should end with an I/O error");
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset);
+
+    let events = recorder.events();
+    assert_eq!(events.len(), 3);
+    match &events[2].event {
+        TelemetryEvent::Disconnected(report) => match report.as_ref() {
+            SessionReport::Inbound(summary) => {
+                assert_eq!(summary.helo_name.as_deref(), Some("reset.example"));
+                assert_eq!(summary.session_id, events[0].context.session_id);
+            }
+            other => panic!("expected an inbound session report, got {:?}", other),
+        },
+        other => panic!("expected a disconnect event, got {:?}", other),
+    }
+}
+
+#[test]
+fn outbound_counters_fold_from_the_event_stream() {
+    let recorder = Arc::new(TelemetryRecorder::new());
+    let config = OutboundListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "relay.verzola.test".to_string(),
+        ..OutboundListenerConfig::default()
+    };
+    let listener = OutboundListener::bind(config, NoopMxResolver)
+        .expect("outbound listener should bind for telemetry sink test")
+        .with_telemetry_sink(recorder.clone());
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+    let (mut stream, mut reader) = connect(address);
+
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "BOGUS\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("502 "));
+    send(&mut stream, "MAIL FROM:<alice@example.org>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "RCPT TO:<bob@unresolved.example>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("451 4.4.0 "));
+    send(&mut stream, "RCPT TO:<carol@other.example>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("451 4.5.3 "));
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary = handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary");
+    let events = recorder.session_events(&summary.session_id);
+    assert!(events.contains(&TelemetryEvent::ProtocolError { reply_code: 502 }));
+
+    let mut folded = OutboundSessionTelemetry::default();
+    for event in &events {
+        folded.observe(event);
+    }
+    assert_eq!(folded, summary.telemetry());
+    assert_eq!(folded.protocol_errors, 1);
+    assert_eq!(folded.temporary_failures, 2);
+}
+
+fn spawn_server(
+    policy: InboundTlsPolicy,
+    recorder: Arc<TelemetryRecorder>,
+) -> (SocketAddr, thread::JoinHandle<std::io::Result<SessionSummary>>) {
+    let config = ListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "mx.verzola.test".to_string(),
+        inbound_tls_policy: policy,
+        ..ListenerConfig::default()
+    };
+
+    let listener = InboundListener::bind(config, RecordingTlsUpgrader)
+        .expect("listener should bind for telemetry sink test")
+        .with_telemetry_sink(recorder);
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    (address, handle)
+}
+
+fn join_server(handle: thread::JoinHandle<std::io::Result<SessionSummary>>) -> SessionSummary {
+    handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary")
+}
+
+fn wait_for_events(recorder: &TelemetryRecorder, count: usize) {
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while recorder.events().len() < count {
+        assert!(Instant::now() < deadline, "telemetry events did not arrive in time");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .expect("test client read timeout should set");
+
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, command: &str) {
+    stream
+        .write_all(command.as_bytes())
+        .expect("test client should write command bytes");
+    stream.flush().expect("test client flush should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    loop {
+        let mut raw = String::new();
+        let bytes = reader
+            .read_line(&mut raw)
+            .expect("test client should read server reply");
+        assert!(bytes > 0, "server closed connection unexpectedly");
+
+        let line = raw.trim_end_matches(['\r', '\n']).to_string();
+        lines.push(line.clone());
+
+        let line_bytes = line.as_bytes();
+        if line_bytes.len() < 4 || line_bytes[3] == b' ' {
+            break;
+        }
+    }
+
+    lines
+}
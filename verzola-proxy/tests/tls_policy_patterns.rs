@@ -0,0 +1,424 @@
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::outbound::{
+    MxCandidate, MxResolutionError, MxResolver, OutboundDomainTlsPolicy, OutboundListener,
+    OutboundListenerConfig, OutboundMxTlsPolicy, OutboundSessionSummary, OutboundTlsPolicy,
+    TlsPolicyRule,
+};
+
+#[derive(Debug, Clone, Copy)]
+struct UnreachableResolver;
+
+impl MxResolver for UnreachableResolver {
+    fn resolve(&self, recipient_domain: &str) -> Result<Vec<MxCandidate>, MxResolutionError> {
+        Err(MxResolutionError::Temporary(format!(
+            "no MX records found for {}",
+            recipient_domain
+        )))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct StaticResolver {
+    candidates: Vec<MxCandidate>,
+}
+
+impl MxResolver for StaticResolver {
+    fn resolve(&self, _recipient_domain: &str) -> Result<Vec<MxCandidate>, MxResolutionError> {
+        Ok(self.candidates.clone())
+    }
+}
+
+#[test]
+fn domain_patterns_are_normalized() {
+    let cases = [
+        ("Example.COM", "example.com"),
+        (".Example.COM", ".example.com"),
+        ("*.Example.COM.", "*.example.com"),
+        ("*.bücher.example", "*.xn--bcher-kva.example"),
+        (" .partner.example ", ".partner.example"),
+    ];
+    for (configured, canonical) in cases {
+        let rule = OutboundDomainTlsPolicy::new(configured, OutboundTlsPolicy::RequireTls)
+            .expect("domain pattern should be valid");
+        assert_eq!(rule.recipient_domain, canonical);
+    }
+
+    for invalid in ["*", "*.", ".", "..example.com", "*.*.example.com", "mail.*.example.com"] {
+        let error = OutboundDomainTlsPolicy::new(invalid, OutboundTlsPolicy::RequireTls)
+            .expect_err("malformed pattern should be rejected");
+        assert_eq!(error.kind(), ErrorKind::InvalidInput, "{} should be rejected", invalid);
+    }
+}
+
+#[test]
+fn most_specific_rule_wins() {
+    let rules = vec![
+        rule("example.com", OutboundTlsPolicy::RequireTls),
+        rule(".example.com", OutboundTlsPolicy::RequireTls),
+        rule("*.legacy.example.com", OutboundTlsPolicy::Opportunistic),
+        rule(".eu.example.com", OutboundTlsPolicy::Opportunistic),
+        rule("secure.eu.example.com", OutboundTlsPolicy::RequireTls),
+    ];
+    let cases = [
+        ("example.com", OutboundTlsPolicy::RequireTls),
+        ("mail.example.com", OutboundTlsPolicy::RequireTls),
+        ("a.b.c.example.com", OutboundTlsPolicy::RequireTls),
+        // The wildcard covers one label only; deeper names fall back to `.example.com`.
+        ("mx.legacy.example.com", OutboundTlsPolicy::Opportunistic),
+        ("a.mx.legacy.example.com", OutboundTlsPolicy::RequireTls),
+        ("legacy.example.com", OutboundTlsPolicy::RequireTls),
+        ("eu.example.com", OutboundTlsPolicy::RequireTls),
+        ("fr.eu.example.com", OutboundTlsPolicy::Opportunistic),
+        ("a.fr.eu.example.com", OutboundTlsPolicy::Opportunistic),
+        ("secure.eu.example.com", OutboundTlsPolicy::RequireTls),
+        ("x.secure.eu.example.com", OutboundTlsPolicy::Opportunistic),
+        // No rule for the bare parent of a subdomain rule, and no suffix match on a label.
+        ("notexample.com", OutboundTlsPolicy::Opportunistic),
+        ("example.org", OutboundTlsPolicy::Opportunistic),
+    ];
+    for (recipient_domain, expected) in cases {
+        assert_eq!(
+            effective_policy(rules.clone(), recipient_domain),
+            Some(expected),
+            "policy for {}",
+            recipient_domain
+        );
+    }
+}
+
+#[test]
+fn config_validation_rejects_overlapping_rules() {
+    let cases = [
+        (
+            vec![
+                rule(".example.com", OutboundTlsPolicy::RequireTls),
+                rule(".EXAMPLE.com", OutboundTlsPolicy::Opportunistic),
+            ],
+            "per_domain_tls_policies contains duplicate domain rule: .example.com",
+        ),
+        (
+            vec![
+                rule("*.example.com", OutboundTlsPolicy::RequireTls),
+                rule(".example.com", OutboundTlsPolicy::Opportunistic),
+            ],
+            "per_domain_tls_policies contains overlapping rules: *.example.com and .example.com",
+        ),
+        (
+            vec![
+                OutboundDomainTlsPolicy {
+                    recipient_domain: "*.[192.0.2.1]".to_string(),
+                    policy: OutboundTlsPolicy::RequireTls,
+                },
+            ],
+            "per_domain_tls_policies contains an invalid recipient domain: *.[192.0.2.1]",
+        ),
+    ];
+    for (rules, expected) in cases {
+        let config = OutboundListenerConfig {
+            per_domain_tls_policies: rules,
+            ..OutboundListenerConfig::default()
+        };
+        let error = config.validate().expect_err("overlapping rules should be rejected");
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), expected);
+    }
+
+    // Nested rules and an exact rule next to a pattern are resolved by specificity.
+    let config = OutboundListenerConfig {
+        per_domain_tls_policies: vec![
+            rule("example.com", OutboundTlsPolicy::RequireTls),
+            rule(".example.com", OutboundTlsPolicy::RequireTls),
+            rule("*.mail.example.com", OutboundTlsPolicy::Opportunistic),
+            rule(".eu.example.com", OutboundTlsPolicy::Opportunistic),
+        ],
+        ..OutboundListenerConfig::default()
+    };
+    config.validate().expect("nested rules should be accepted");
+}
+
+#[test]
+fn large_rule_sets_resolve_through_the_index() {
+    let mut rules = Vec::new();
+    for partner in 0..20_000 {
+        let domain = format!("partner{}.example", partner);
+        rules.push(rule(&format!(".{}", domain), OutboundTlsPolicy::RequireTls));
+        rules.push(rule(&domain, OutboundTlsPolicy::Opportunistic));
+    }
+
+    assert_eq!(
+        effective_policy(rules.clone(), "mx.partner19999.example"),
+        Some(OutboundTlsPolicy::RequireTls)
+    );
+    assert_eq!(
+        effective_policy(rules, "partner19999.example"),
+        Some(OutboundTlsPolicy::Opportunistic)
+    );
+}
+
+#[test]
+fn mx_hostname_rules_apply_to_each_candidate() {
+    // The provider's MX requires TLS but offers no STARTTLS, so delivery moves on to the
+    // backup MX, which only the global policy covers.
+    let (provider_addr, provider) = spawn_plaintext_mx();
+    let (backup_addr, backup) = spawn_plaintext_mx();
+    let resolver = StaticResolver {
+        candidates: vec![
+            candidate(10, "Tenant-1.mail.protection.outlook.test.", provider_addr),
+            candidate(20, "mx.backup.test", backup_addr),
+        ],
+    };
+    let summary = deliver(
+        resolver,
+        Vec::new(),
+        vec![mx_rule("*.mail.protection.outlook.test", OutboundTlsPolicy::RequireTls)],
+        "bob@hosted.example",
+    );
+
+    assert_eq!(summary.selected_mx.as_deref(), Some("mx.backup.test"));
+    assert_eq!(summary.mx_candidates_attempted, 2);
+    assert_eq!(summary.effective_tls_policy, Some(OutboundTlsPolicy::Opportunistic));
+    assert_eq!(summary.tls_policy_rule, None);
+    assert_eq!(join_mx(provider), 0);
+    assert_eq!(join_mx(backup), 1);
+}
+
+#[test]
+fn mx_hostname_rule_is_recorded_when_it_decides_the_policy() {
+    let (provider_addr, provider) = spawn_plaintext_mx();
+    let resolver = StaticResolver {
+        candidates: vec![candidate(10, "aspmx.l.google.test", provider_addr)],
+    };
+    let summary = deliver(
+        resolver,
+        Vec::new(),
+        vec![
+            mx_rule(".google.test", OutboundTlsPolicy::RequireTls),
+            mx_rule("aspmx.l.google.test", OutboundTlsPolicy::Opportunistic),
+        ],
+        "bob@hosted.example",
+    );
+
+    assert_eq!(summary.selected_mx.as_deref(), Some("aspmx.l.google.test"));
+    assert_eq!(summary.effective_tls_policy, Some(OutboundTlsPolicy::Opportunistic));
+    assert_eq!(
+        summary.tls_policy_rule,
+        Some(TlsPolicyRule::MxHostname("aspmx.l.google.test".to_string()))
+    );
+    assert_eq!(join_mx(provider), 1);
+}
+
+#[test]
+fn domain_rules_take_precedence_over_mx_rules() {
+    let (provider_addr, provider) = spawn_plaintext_mx();
+    let resolver = StaticResolver {
+        candidates: vec![candidate(10, "mx1.provider.test", provider_addr)],
+    };
+    let summary = deliver(
+        resolver,
+        vec![rule(".legacy.example", OutboundTlsPolicy::Opportunistic)],
+        vec![mx_rule(".provider.test", OutboundTlsPolicy::RequireTls)],
+        "bob@eu.legacy.example",
+    );
+
+    assert_eq!(summary.selected_mx.as_deref(), Some("mx1.provider.test"));
+    assert_eq!(summary.effective_tls_policy, Some(OutboundTlsPolicy::Opportunistic));
+    assert_eq!(
+        summary.tls_policy_rule,
+        Some(TlsPolicyRule::RecipientDomain(".legacy.example".to_string()))
+    );
+    assert_eq!(join_mx(provider), 1);
+}
+
+#[test]
+fn config_validation_checks_mx_rules() {
+    let error = OutboundMxTlsPolicy::new("*.*.provider.test", OutboundTlsPolicy::RequireTls)
+        .expect_err("malformed MX pattern should be rejected");
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+
+    let config = OutboundListenerConfig {
+        per_mx_tls_policies: vec![
+            mx_rule("*.provider.test", OutboundTlsPolicy::RequireTls),
+            mx_rule("*.Provider.TEST", OutboundTlsPolicy::Opportunistic),
+        ],
+        ..OutboundListenerConfig::default()
+    };
+    let error = config.validate().expect_err("duplicate MX rules should be rejected");
+    assert_eq!(
+        error.to_string(),
+        "per_mx_tls_policies contains duplicate MX rule: *.provider.test"
+    );
+
+    // The same pattern may appear once as a domain rule and once as an MX rule.
+    let config = OutboundListenerConfig {
+        per_domain_tls_policies: vec![rule(".provider.test", OutboundTlsPolicy::RequireTls)],
+        per_mx_tls_policies: vec![mx_rule(".provider.test", OutboundTlsPolicy::RequireTls)],
+        ..OutboundListenerConfig::default()
+    };
+    config.validate().expect("domain and MX rules live in separate tables");
+}
+
+fn deliver(
+    resolver: StaticResolver,
+    per_domain_tls_policies: Vec<OutboundDomainTlsPolicy>,
+    per_mx_tls_policies: Vec<OutboundMxTlsPolicy>,
+    recipient: &str,
+) -> OutboundSessionSummary {
+    let config = OutboundListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        outbound_tls_policy: OutboundTlsPolicy::Opportunistic,
+        per_domain_tls_policies,
+        per_mx_tls_policies,
+        ..OutboundListenerConfig::default()
+    };
+    let listener = OutboundListener::bind(config, resolver)
+        .expect("outbound listener should bind for policy test");
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.org>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, &format!("RCPT TO:<{}>\r\n", recipient));
+    let rcpt_reply = read_reply(&mut reader);
+    assert!(rcpt_reply[0].starts_with("250 "), "unexpected RCPT reply: {:?}", rcpt_reply);
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary")
+}
+
+// A remote MX without STARTTLS. Returns how many recipients it accepted.
+fn spawn_plaintext_mx() -> (SocketAddr, thread::JoinHandle<usize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("mock MX should bind");
+    let address = listener.local_addr().expect("mock MX address must resolve");
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().expect("mock MX should accept");
+        let mut reader = BufReader::new(stream.try_clone().expect("mock MX socket should clone"));
+        let mut accepted = 0;
+        stream
+            .write_all(b"220 mx.test ESMTP\r\n")
+            .expect("mock MX banner should write");
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return accepted;
+            }
+            let verb = line.split_whitespace().next().unwrap_or("").to_ascii_uppercase();
+            let reply: &[u8] = match verb.as_str() {
+                "EHLO" => b"250-mx.test\r\n250 PIPELINING\r\n",
+                "RCPT" => {
+                    accepted += 1;
+                    b"250 2.1.5 OK\r\n"
+                }
+                "QUIT" => {
+                    let _ = stream.write_all(b"221 2.0.0 Bye\r\n");
+                    return accepted;
+                }
+                _ => b"250 2.0.0 OK\r\n",
+            };
+            if stream.write_all(reply).is_err() {
+                return accepted;
+            }
+        }
+    });
+    (address, handle)
+}
+
+fn join_mx(handle: thread::JoinHandle<usize>) -> usize {
+    handle.join().expect("mock MX thread should not panic")
+}
+
+fn candidate(preference: u16, exchange: &str, address: SocketAddr) -> MxCandidate {
+    MxCandidate::new(preference, exchange, address).expect("candidate should be valid")
+}
+
+fn mx_rule(pattern: &str, policy: OutboundTlsPolicy) -> OutboundMxTlsPolicy {
+    OutboundMxTlsPolicy::new(pattern, policy).expect("test MX rule should be valid")
+}
+
+fn rule(pattern: &str, policy: OutboundTlsPolicy) -> OutboundDomainTlsPolicy {
+    OutboundDomainTlsPolicy::new(pattern, policy).expect("test rule should be valid")
+}
+
+fn effective_policy(
+    rules: Vec<OutboundDomainTlsPolicy>,
+    recipient_domain: &str,
+) -> Option<OutboundTlsPolicy> {
+    let config = OutboundListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        outbound_tls_policy: OutboundTlsPolicy::Opportunistic,
+        per_domain_tls_policies: rules,
+        ..OutboundListenerConfig::default()
+    };
+    let listener = OutboundListener::bind(config, UnreachableResolver)
+        .expect("outbound listener should bind for policy test");
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.org>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, &format!("RCPT TO:<bob@{}>\r\n", recipient_domain));
+    assert!(read_reply(&mut reader)[0].starts_with("451 "));
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary")
+        .effective_tls_policy
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("test client read timeout should set");
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, line: &str) {
+    stream
+        .write_all(line.as_bytes())
+        .expect("test client write should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        let read = reader
+            .read_line(&mut line)
+            .expect("test client read should succeed");
+        assert!(read > 0, "listener closed the connection before replying");
+        let line = line.trim_end().to_string();
+        let last = line.as_bytes().get(3) != Some(&b'-');
+        lines.push(line);
+        if last {
+            return lines;
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::inbound::{
+    InboundListener, InboundTlsPolicy, ListenerConfig, NoopTlsUpgrader, SessionSummary,
+};
+use verzola_proxy::outbound::{
+    MxCandidate, MxResolutionError, MxResolver, OutboundListener, OutboundListenerConfig,
+    OutboundSessionSummary,
+};
+use verzola_proxy::transaction::{TransactionOutcome, TransactionStage};
+
+#[derive(Debug, Clone)]
+struct StaticResolver {
+    candidates_by_domain: HashMap<String, Vec<MxCandidate>>,
+}
+
+impl MxResolver for StaticResolver {
+    fn resolve(&self, recipient_domain: &str) -> Result<Vec<MxCandidate>, MxResolutionError> {
+        self.candidates_by_domain
+            .get(recipient_domain)
+            .cloned()
+            .ok_or_else(|| {
+                MxResolutionError::Temporary(format!(
+                    "no MX records found for {}",
+                    recipient_domain
+                ))
+            })
+    }
+}
+
+#[test]
+fn inbound_summary_records_each_transaction() {
+    let config = ListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "mx.verzola.test".to_string(),
+        inbound_tls_policy: InboundTlsPolicy::Opportunistic,
+        ..ListenerConfig::default()
+    };
+    let listener = InboundListener::bind(config, NoopTlsUpgrader)
+        .expect("listener should bind for transaction record test");
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO sender.example\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+
+    send(&mut stream, "MAIL FROM:<alice@Example.COM>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "RCPT TO:<bob@example.net>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "RCPT TO:<carol@example.net>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "DATA\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("354 "));
+    send(&mut stream, "Subject: one\r\n\r\nbody\r\n.\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+
+    send(&mut stream, "MAIL FROM:<>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "RCPT TO:<dave@example.org>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "RSET\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary: SessionSummary = handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary");
+    assert_eq!(summary.transactions.len(), 2);
+
+    let delivered = &summary.transactions[0];
+    assert_eq!(delivered.sender_domain.as_deref(), Some("example.com"));
+    assert_eq!(delivered.recipient_count, 2);
+    assert_eq!(delivered.message_bytes, "Subject: one\r\n\r\nbody\r\n".len() as u64);
+    assert_eq!(delivered.outcome, TransactionOutcome::Accepted);
+    assert_eq!(delivered.selected_mx, None);
+    assert!(!delivered.tls_negotiated);
+    let stages: Vec<TransactionStage> =
+        delivered.replies.iter().map(|reply| reply.stage).collect();
+    assert_eq!(
+        stages,
+        vec![
+            TransactionStage::Mail,
+            TransactionStage::Rcpt,
+            TransactionStage::Rcpt,
+            TransactionStage::Data,
+            TransactionStage::DataFinal,
+        ]
+    );
+    assert_eq!(delivered.replies[4].reply_line, "250 2.0.0 Queued");
+    assert!(delivered.timings.data_duration.is_some());
+    assert!(delivered.timings.duration >= delivered.timings.data_duration.unwrap_or_default());
+
+    let abandoned = &summary.transactions[1];
+    assert_eq!(abandoned.sender_domain, None);
+    assert_eq!(abandoned.recipient_count, 1);
+    assert_eq!(abandoned.message_bytes, 0);
+    assert_eq!(abandoned.outcome, TransactionOutcome::Abandoned);
+    assert!(abandoned.timings.started_after >= delivered.timings.started_after);
+    assert_eq!(abandoned.timings.data_duration, None);
+}
+
+#[test]
+fn outbound_summary_keeps_selected_mx_per_message() {
+    let (remote_addr, remote_handle) = spawn_mock_remote_mx(2);
+    let resolver = StaticResolver {
+        candidates_by_domain: HashMap::from([(
+            "example.net".to_string(),
+            vec![MxCandidate::new(10, "mx1.example.net", remote_addr)
+                .expect("candidate should be valid")],
+        )]),
+    };
+    let config = OutboundListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "relay.verzola.test".to_string(),
+        ..OutboundListenerConfig::default()
+    };
+    let listener = OutboundListener::bind(config, resolver)
+        .expect("outbound listener should bind for transaction record test");
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+
+    for (sender, payload) in [("alice@example.org", "first\r\n"), ("erin@example.com", "2\r\n")] {
+        send(&mut stream, &format!("MAIL FROM:<{}>\r\n", sender));
+        assert!(read_reply(&mut reader)[0].starts_with("250 "));
+        send(&mut stream, "RCPT TO:<bob@example.net>\r\n");
+        assert!(read_reply(&mut reader)[0].starts_with("250 "));
+        send(&mut stream, "DATA\r\n");
+        assert!(read_reply(&mut reader)[0].starts_with("354 "));
+        send(&mut stream, payload);
+        send(&mut stream, ".\r\n");
+        assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    }
+
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary: OutboundSessionSummary = handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return summary");
+    remote_handle
+        .join()
+        .expect("remote thread should not panic")
+        .expect("remote MX should finish cleanly");
+
+    assert_eq!(summary.transactions.len(), 2);
+    let senders: Vec<Option<&str>> = summary
+        .transactions
+        .iter()
+        .map(|record| record.sender_domain.as_deref())
+        .collect();
+    assert_eq!(senders, vec![Some("example.org"), Some("example.com")]);
+    assert_eq!(summary.transactions[0].message_bytes, 7);
+    assert_eq!(summary.transactions[1].message_bytes, 3);
+
+    for record in &summary.transactions {
+        assert_eq!(record.selected_mx.as_deref(), Some("mx1.example.net"));
+        assert_eq!(record.recipient_count, 1);
+        assert_eq!(record.outcome, TransactionOutcome::Accepted);
+        let final_reply = record
+            .replies
+            .last()
+            .expect("transaction should record replies");
+        assert_eq!(final_reply.stage, TransactionStage::DataFinal);
+        assert_eq!(final_reply.reply_code, 250);
+    }
+}
+
+fn spawn_mock_remote_mx(
+    expected_sessions: usize,
+) -> (SocketAddr, thread::JoinHandle<std::io::Result<()>>) {
+    let listener =
+        TcpListener::bind("127.0.0.1:0").expect("remote MX listener should bind to localhost");
+    let address = listener
+        .local_addr()
+        .expect("remote MX listener address should resolve");
+
+    let handle = thread::spawn(move || -> std::io::Result<()> {
+        for _ in 0..expected_sessions {
+            let (stream, _) = listener.accept()?;
+            handle_remote_session(stream)?;
+        }
+        Ok(())
+    });
+
+    (address, handle)
+}
+
+fn handle_remote_session(mut stream: TcpStream) -> std::io::Result<()> {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .expect("remote MX read timeout should set");
+
+    write_line(&mut stream, "220 mx1.example.net ESMTP")?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut reading_data = false;
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        if reading_data {
+            if line == ".\r\n" {
+                reading_data = false;
+                write_line(&mut stream, "250 2.0.0 Ok: queued as TXN1")?;
+            }
+            continue;
+        }
+
+        let verb = line
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_ascii_uppercase();
+        match verb.as_str() {
+            "EHLO" | "HELO" => write_line(&mut stream, "250 mx1.example.net")?,
+            "DATA" => {
+                reading_data = true;
+                write_line(&mut stream, "354 End data with <CR><LF>.<CR><LF>")?;
+            }
+            "QUIT" => {
+                write_line(&mut stream, "221 2.0.0 Bye")?;
+                return Ok(());
+            }
+            _ => write_line(&mut stream, "250 2.0.0 OK")?,
+        }
+    }
+}
+
+fn write_line(stream: &mut TcpStream, line: &str) -> std::io::Result<()> {
+    write!(stream, "{}\r\n", line)?;
+    stream.flush()
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .expect("test client read timeout should set");
+
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, command: &str) {
+    stream
+        .write_all(command.as_bytes())
+        .expect("test client should write command bytes");
+    stream.flush().expect("test client flush should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    loop {
+        let mut raw = String::new();
+        let bytes = reader
+            .read_line(&mut raw)
+            .expect("test client should read server reply");
+        assert!(bytes > 0, "server closed connection unexpectedly");
+
+        let line = raw.trim_end_matches(['\r', '\n']).to_string();
+        lines.push(line.clone());
+
+        let line_bytes = line.as_bytes();
+        if line_bytes.len() < 4 || line_bytes[3] == b' ' {
+            break;
+        }
+    }
+
+    lines
+}
@@ -0,0 +1,416 @@
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::outbound::{
+    DeliveryRoute, MxCandidate, MxResolutionError, MxResolver, OutboundDomainTlsPolicy,
+    OutboundListener, OutboundListenerConfig, OutboundNextHop, OutboundSessionSummary,
+    OutboundSmarthost, OutboundTlsPolicy, OutboundTransportRoute, SecretSource,
+    SmarthostCredentials, TlsPolicyRule,
+};
+
+// Answers every lookup with the given candidates and records which domains were asked for.
+#[derive(Debug, Clone, Default)]
+struct RecordingResolver {
+    candidates: Vec<MxCandidate>,
+    lookups: Arc<Mutex<Vec<String>>>,
+}
+
+impl MxResolver for RecordingResolver {
+    fn resolve(&self, recipient_domain: &str) -> Result<Vec<MxCandidate>, MxResolutionError> {
+        self.lookups
+            .lock()
+            .expect("lookup log lock should not be poisoned")
+            .push(recipient_domain.to_string());
+        if self.candidates.is_empty() {
+            return Err(MxResolutionError::Temporary(format!(
+                "no MX records found for {}",
+                recipient_domain
+            )));
+        }
+        Ok(self.candidates.clone())
+    }
+}
+
+#[test]
+fn next_hops_are_parsed_and_normalized() {
+    let cases = [
+        ("[Mail.Internal.]:2525", "mail.internal", 2525),
+        ("[192.0.2.10]", "192.0.2.10", 25),
+        ("[ipv6:2001:DB8::1]:587", "2001:db8::1", 587),
+        ("[2001:db8::1]", "2001:db8::1", 25),
+        (" [relay.bücher.example]:26 ", "relay.xn--bcher-kva.example", 26),
+    ];
+    for (value, host, port) in cases {
+        assert_eq!(
+            OutboundNextHop::parse(value).expect("next hop should parse"),
+            OutboundNextHop::Static {
+                host: host.to_string(),
+                port,
+            },
+            "parsing {}",
+            value
+        );
+    }
+
+    for invalid in [
+        "mail.internal",
+        "mail.internal:25",
+        "[mail.internal]:0",
+        "[mail.internal]:smtp",
+        "[mail.internal]2525",
+        "[]",
+        "[bad_host.example]",
+    ] {
+        let error = OutboundNextHop::parse(invalid).expect_err("invalid next hop should fail");
+        assert_eq!(error.kind(), ErrorKind::InvalidInput, "{} should be rejected", invalid);
+    }
+
+    assert_eq!(
+        OutboundNextHop::parse("[mail.internal]:2525")
+            .expect("next hop should parse")
+            .to_string(),
+        "[mail.internal]:2525"
+    );
+    assert_eq!(
+        OutboundNextHop::Smarthost("partner".to_string()).to_string(),
+        "smarthost:partner"
+    );
+
+    let route = OutboundTransportRoute::new(
+        ".Sandbox.Example.",
+        OutboundNextHop::Smarthost("partner".to_string()),
+    )
+    .expect("route should be valid");
+    assert_eq!(route.recipient_domain, ".sandbox.example");
+
+    let smarthost = OutboundSmarthost::new("partner", "[gw.partner.test]:587")
+        .expect("smarthost should be valid")
+        .with_credentials(password("relay-user", "hunter2-secret"));
+    assert_eq!(smarthost.host, "gw.partner.test");
+    assert_eq!(smarthost.port, 587);
+    let dump = format!("{:?}", smarthost);
+    assert!(dump.contains("relay-user"), "{}", dump);
+    assert!(!dump.contains("hunter2-secret"), "password leaked: {}", dump);
+}
+
+#[test]
+fn config_validation_checks_routes_and_smarthosts() {
+    let partner = OutboundSmarthost::new("partner", "[gw.partner.test]:587")
+        .expect("smarthost should be valid");
+    let cases = [
+        (
+            vec![route("sandbox.example", smarthost_hop("missing"))],
+            vec![partner.clone()],
+            "transport_routes entry sandbox.example names unknown smarthost: missing",
+        ),
+        (
+            Vec::new(),
+            vec![partner.clone(), partner.clone()],
+            "smarthosts contains duplicate name: partner",
+        ),
+        (
+            Vec::new(),
+            vec![OutboundSmarthost {
+                name: "partner gateway".to_string(),
+                ..partner.clone()
+            }],
+            "smarthosts contains an invalid name: \"partner gateway\"",
+        ),
+        (
+            Vec::new(),
+            vec![OutboundSmarthost {
+                port: 0,
+                ..partner.clone()
+            }],
+            "smarthost partner requires a non-zero port",
+        ),
+        (
+            Vec::new(),
+            vec![partner.clone().with_credentials(password("relay-user", ""))],
+            "smarthost partner credentials must not be empty",
+        ),
+        (
+            vec![route(
+                "internal.example",
+                OutboundNextHop::Static {
+                    host: "mail internal".to_string(),
+                    port: 25,
+                },
+            )],
+            Vec::new(),
+            "transport_routes contains an invalid next hop for internal.example: \
+             [mail internal]:25",
+        ),
+        (
+            vec![
+                route("internal.example", static_hop("[mail.internal]")),
+                route("Internal.Example", static_hop("[mail2.internal]")),
+            ],
+            Vec::new(),
+            "transport_routes contains duplicate route rule: internal.example",
+        ),
+        (
+            vec![
+                route(".internal.example", static_hop("[mail.internal]")),
+                route("*.internal.example", static_hop("[mail2.internal]")),
+            ],
+            Vec::new(),
+            "transport_routes contains overlapping rules: .internal.example and \
+             *.internal.example",
+        ),
+    ];
+
+    for (transport_routes, smarthosts, expected) in cases {
+        let config = OutboundListenerConfig {
+            transport_routes,
+            smarthosts,
+            ..OutboundListenerConfig::default()
+        };
+        let error = config.validate().expect_err("invalid transport map should be rejected");
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), expected);
+    }
+}
+
+#[test]
+fn static_route_bypasses_the_mx_lookup() {
+    let (mx_addr, mx) = spawn_plaintext_mx();
+    let resolver = RecordingResolver::default();
+    let lookups = Arc::clone(&resolver.lookups);
+    let next_hop = OutboundNextHop::Static {
+        host: "127.0.0.1".to_string(),
+        port: mx_addr.port(),
+    };
+    let config = OutboundListenerConfig {
+        transport_routes: vec![
+            route(".internal.example", next_hop.clone()),
+            route("*.eu.internal.example", smarthost_hop("partner")),
+        ],
+        smarthosts: vec![OutboundSmarthost::new("partner", "[192.0.2.1]:587")
+            .expect("smarthost should be valid")],
+        ..test_config()
+    };
+
+    let (summary, rcpt_reply) = deliver(config, resolver, "bob@hr.internal.example");
+
+    assert!(rcpt_reply.starts_with("250 "), "unexpected RCPT reply: {}", rcpt_reply);
+    assert_eq!(
+        summary.delivery_route,
+        Some(DeliveryRoute::Transport {
+            rule: ".internal.example".to_string(),
+            next_hop,
+        })
+    );
+    assert_eq!(
+        summary.delivery_route.as_ref().map(ToString::to_string).as_deref(),
+        Some(format!("transport:.internal.example=[127.0.0.1]:{}", mx_addr.port()).as_str())
+    );
+    assert_eq!(summary.selected_mx.as_deref(), Some("127.0.0.1"));
+    assert_eq!(summary.resolver_lookups, 0);
+    assert!(lookups.lock().expect("lookup log lock should not be poisoned").is_empty());
+    assert_eq!(join_mx(mx), 1);
+}
+
+#[test]
+fn smarthost_routes_keep_the_tls_policy() {
+    // The gateway offers no STARTTLS, so the domain rule requiring TLS defers delivery.
+    let (mx_addr, mx) = spawn_plaintext_mx();
+    let config = OutboundListenerConfig {
+        per_domain_tls_policies: vec![OutboundDomainTlsPolicy::new(
+            ".sandbox.example",
+            OutboundTlsPolicy::RequireTls,
+        )
+        .expect("domain policy should be valid")],
+        transport_routes: vec![route(".sandbox.example", smarthost_hop("partner"))],
+        smarthosts: vec![OutboundSmarthost::new(
+            "partner",
+            &format!("[127.0.0.1]:{}", mx_addr.port()),
+        )
+        .expect("smarthost should be valid")],
+        ..test_config()
+    };
+
+    let (summary, rcpt_reply) =
+        deliver(config, RecordingResolver::default(), "bob@qa.sandbox.example");
+
+    assert!(rcpt_reply.starts_with("451 4.7.5 "), "unexpected RCPT reply: {}", rcpt_reply);
+    assert_eq!(
+        summary.delivery_route,
+        Some(DeliveryRoute::Transport {
+            rule: ".sandbox.example".to_string(),
+            next_hop: smarthost_hop("partner"),
+        })
+    );
+    assert_eq!(summary.effective_tls_policy, Some(OutboundTlsPolicy::RequireTls));
+    assert_eq!(
+        summary.tls_policy_rule,
+        Some(TlsPolicyRule::RecipientDomain(".sandbox.example".to_string()))
+    );
+    assert_eq!(summary.policy_deferred_failures, 1);
+    assert_eq!(join_mx(mx), 0);
+}
+
+#[test]
+fn unrouted_domains_use_the_mx_lookup() {
+    let (mx_addr, mx) = spawn_plaintext_mx();
+    let resolver = RecordingResolver {
+        candidates: vec![MxCandidate::new(10, "mx.partner.test", mx_addr)
+            .expect("candidate should be valid")],
+        ..RecordingResolver::default()
+    };
+    let lookups = Arc::clone(&resolver.lookups);
+    let config = OutboundListenerConfig {
+        transport_routes: vec![route("internal.example", static_hop("[192.0.2.1]"))],
+        ..test_config()
+    };
+
+    let (summary, rcpt_reply) = deliver(config, resolver, "bob@sub.internal.example");
+
+    assert!(rcpt_reply.starts_with("250 "), "unexpected RCPT reply: {}", rcpt_reply);
+    assert_eq!(summary.delivery_route, Some(DeliveryRoute::Mx));
+    assert_eq!(summary.selected_mx.as_deref(), Some("mx.partner.test"));
+    assert_eq!(summary.resolver_lookups, 1);
+    assert_eq!(
+        *lookups.lock().expect("lookup log lock should not be poisoned"),
+        ["sub.internal.example"]
+    );
+    assert_eq!(join_mx(mx), 1);
+}
+
+fn test_config() -> OutboundListenerConfig {
+    OutboundListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        outbound_tls_policy: OutboundTlsPolicy::Opportunistic,
+        ..OutboundListenerConfig::default()
+    }
+}
+
+fn route(pattern: &str, next_hop: OutboundNextHop) -> OutboundTransportRoute {
+    OutboundTransportRoute::new(pattern, next_hop).expect("test route should be valid")
+}
+
+fn static_hop(value: &str) -> OutboundNextHop {
+    OutboundNextHop::parse(value).expect("test next hop should parse")
+}
+
+fn smarthost_hop(name: &str) -> OutboundNextHop {
+    OutboundNextHop::Smarthost(name.to_string())
+}
+
+fn password(username: &str, password: &str) -> SmarthostCredentials {
+    SmarthostCredentials::Password {
+        username: username.to_string(),
+        password: SecretSource::Value(password.to_string()),
+    }
+}
+
+// Returns the session summary and the first line of the RCPT reply.
+fn deliver(
+    config: OutboundListenerConfig,
+    resolver: RecordingResolver,
+    recipient: &str,
+) -> (OutboundSessionSummary, String) {
+    let listener = OutboundListener::bind(config, resolver)
+        .expect("outbound listener should bind for routing test");
+    let address = listener.local_addr().expect("listener address must resolve");
+    let handle = thread::spawn(move || listener.serve_one());
+
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    send(&mut stream, "EHLO postfix.local\r\n");
+    let _ehlo_reply = read_reply(&mut reader);
+    send(&mut stream, "MAIL FROM:<alice@example.org>\r\n");
+    assert!(read_reply(&mut reader)[0].starts_with("250 "));
+    send(&mut stream, &format!("RCPT TO:<{}>\r\n", recipient));
+    let rcpt_reply = read_reply(&mut reader).remove(0);
+    send(&mut stream, "QUIT\r\n");
+    let _quit_reply = read_reply(&mut reader);
+
+    let summary = handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary");
+    (summary, rcpt_reply)
+}
+
+// A next hop without STARTTLS. Returns how many recipients it accepted.
+fn spawn_plaintext_mx() -> (SocketAddr, thread::JoinHandle<usize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("mock MX should bind");
+    let address = listener.local_addr().expect("mock MX address must resolve");
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().expect("mock MX should accept");
+        let mut reader = BufReader::new(stream.try_clone().expect("mock MX socket should clone"));
+        let mut accepted = 0;
+        stream
+            .write_all(b"220 mx.test ESMTP\r\n")
+            .expect("mock MX banner should write");
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return accepted;
+            }
+            let verb = line.split_whitespace().next().unwrap_or("").to_ascii_uppercase();
+            let reply: &[u8] = match verb.as_str() {
+                "EHLO" => b"250-mx.test\r\n250 PIPELINING\r\n",
+                "RCPT" => {
+                    accepted += 1;
+                    b"250 2.1.5 OK\r\n"
+                }
+                "QUIT" => {
+                    let _ = stream.write_all(b"221 2.0.0 Bye\r\n");
+                    return accepted;
+                }
+                _ => b"250 2.0.0 OK\r\n",
+            };
+            if stream.write_all(reply).is_err() {
+                return accepted;
+            }
+        }
+    });
+    (address, handle)
+}
+
+fn join_mx(handle: thread::JoinHandle<usize>) -> usize {
+    handle.join().expect("mock MX thread should not panic")
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("test client read timeout should set");
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, line: &str) {
+    stream
+        .write_all(line.as_bytes())
+        .expect("test client write should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        let read = reader
+            .read_line(&mut line)
+            .expect("test client read should succeed");
+        assert!(read > 0, "listener closed the connection before replying");
+        let line = line.trim_end().to_string();
+        let last = line.as_bytes().get(3) != Some(&b'-');
+        lines.push(line);
+        if last {
+            return lines;
+        }
+    }
+}
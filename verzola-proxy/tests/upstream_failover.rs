@@ -0,0 +1,360 @@
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::inbound::{
+    InboundListener, InboundTlsPolicy, ListenerConfig, NoopTlsUpgrader, SessionSummary,
+};
+use verzola_proxy::metrics::MetricsRegistry;
+use verzola_proxy::upstream::{PostfixBackend, UpstreamPoolConfig, UpstreamTarget};
+
+// A Postfix stand-in that keeps accepting until the test process exits. MAIL replies
+// carry the backend tag so the test can tell which backend served a session.
+struct MockPostfix {
+    addr: SocketAddr,
+    sessions: Arc<AtomicUsize>,
+    healthy: Arc<AtomicBool>,
+}
+
+#[test]
+fn validate_rejects_conflicting_and_malformed_backends() {
+    let backend = PostfixBackend::new(addr("127.0.0.1:10026"));
+
+    let both = ListenerConfig {
+        postfix_upstream_addr: Some(addr("127.0.0.1:10025")),
+        postfix_upstreams: vec![backend.clone()],
+        ..relay_config(Vec::new())
+    };
+    let error = both
+        .validate()
+        .expect_err("a single upstream and a backend list must not be combined");
+    assert!(error.to_string().contains("not both"));
+
+    let zero_weight = relay_config(vec![backend.clone().with_weight(0)]);
+    assert_eq!(
+        zero_weight.validate().map_err(|error| error.kind()),
+        Err(ErrorKind::InvalidInput)
+    );
+
+    let duplicate = relay_config(vec![backend.clone(), backend.clone().with_priority(5)]);
+    let error = duplicate
+        .validate()
+        .expect_err("a backend listed twice must be refused");
+    assert!(error.to_string().contains("listed twice"));
+
+    let no_attempts = ListenerConfig {
+        upstream_pool: UpstreamPoolConfig {
+            max_connect_attempts: 0,
+            ..UpstreamPoolConfig::default()
+        },
+        ..relay_config(vec![backend])
+    };
+    assert!(no_attempts.validate().is_err());
+
+    let single = ListenerConfig {
+        postfix_upstream_addr: Some(addr("127.0.0.1:10025")),
+        ..relay_config(Vec::new())
+    };
+    assert_eq!(
+        single.postfix_backends(),
+        vec![PostfixBackend::new(addr("127.0.0.1:10025"))]
+    );
+}
+
+#[test]
+fn connect_failure_fails_over_and_ejects_the_backend() {
+    let dead = dead_addr();
+    let backup = spawn_mock_postfix("backup");
+    let config = ListenerConfig {
+        upstream_pool: pool_config(1),
+        ..relay_config(vec![
+            PostfixBackend::new(dead),
+            PostfixBackend::new(backup.addr).with_priority(10),
+        ])
+    };
+    let registry = Arc::new(MetricsRegistry::default());
+    let listener = InboundListener::bind(config, NoopTlsUpgrader)
+        .expect("relay listener should bind")
+        .with_metrics(Arc::clone(&registry), "mx");
+
+    for _ in 0..2 {
+        assert_eq!(mail_via(&listener), "250 2.1.0 Sender OK (backup)");
+    }
+    assert_eq!(backup.sessions.load(Ordering::SeqCst), 2);
+
+    let health = listener.upstream_health();
+    assert_eq!(health[0].backend.target, UpstreamTarget::Tcp(dead));
+    assert!(!health[0].healthy);
+    assert_eq!(health[0].ejections, 1);
+    // The second session skipped the ejected backend.
+    assert_eq!(health[0].total_failures, 1);
+    assert!(health[0].last_error.is_some());
+    assert!(health[1].healthy);
+    assert_eq!(health[1].total_failures, 0);
+
+    let rendered = registry.render();
+    assert!(rendered.contains("# TYPE verzola_upstream_backend_healthy gauge"));
+    assert!(rendered.contains(&format!(
+        "verzola_upstream_backend_healthy{{listener=\"mx\",backend=\"{}\"}} 0",
+        dead
+    )));
+    assert!(rendered.contains(&format!(
+        "verzola_upstream_backend_healthy{{listener=\"mx\",backend=\"{}\"}} 1",
+        backup.addr
+    )));
+    assert!(rendered.contains(&format!(
+        "verzola_upstream_backend_ejections_total{{listener=\"mx\",backend=\"{}\"}} 1",
+        dead
+    )));
+}
+
+#[test]
+fn greeting_rejection_is_retried_on_another_backend() {
+    let busy = spawn_mock_postfix("busy");
+    busy.healthy.store(false, Ordering::SeqCst);
+    let fallback = spawn_mock_postfix("fallback");
+    let listener = InboundListener::bind(
+        relay_config(vec![
+            PostfixBackend::new(busy.addr),
+            PostfixBackend::new(fallback.addr).with_priority(1),
+        ]),
+        NoopTlsUpgrader,
+    )
+    .expect("relay listener should bind");
+
+    assert_eq!(mail_via(&listener), "250 2.1.0 Sender OK (fallback)");
+    assert_eq!(busy.sessions.load(Ordering::SeqCst), 1);
+    let health = listener.upstream_health();
+    assert_eq!(health[0].consecutive_failures, 1);
+    assert!(health[0].healthy, "one failure is below the default threshold");
+    assert!(health[0]
+        .last_error
+        .as_deref()
+        .is_some_and(|error| error.contains("421")));
+}
+
+#[test]
+fn weight_splits_sessions_within_a_priority() {
+    let heavy = spawn_mock_postfix("heavy");
+    let light = spawn_mock_postfix("light");
+    let standby = spawn_mock_postfix("standby");
+    let listener = InboundListener::bind(
+        relay_config(vec![
+            PostfixBackend::new(heavy.addr).with_weight(3),
+            PostfixBackend::new(light.addr),
+            PostfixBackend::new(standby.addr).with_priority(1),
+        ]),
+        NoopTlsUpgrader,
+    )
+    .expect("relay listener should bind");
+
+    for _ in 0..8 {
+        mail_via(&listener);
+    }
+    assert_eq!(heavy.sessions.load(Ordering::SeqCst), 6);
+    assert_eq!(light.sessions.load(Ordering::SeqCst), 2);
+    assert_eq!(standby.sessions.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn active_health_checks_eject_and_restore_backends() {
+    let backend = spawn_mock_postfix("primary");
+    let listener = InboundListener::bind(
+        ListenerConfig {
+            upstream_pool: pool_config(2),
+            ..relay_config(vec![PostfixBackend::new(backend.addr)])
+        },
+        NoopTlsUpgrader,
+    )
+    .expect("relay listener should bind");
+
+    backend.healthy.store(false, Ordering::SeqCst);
+    listener.check_upstreams();
+    assert!(listener.upstream_health()[0].healthy);
+    listener.check_upstreams();
+    let health = listener.upstream_health();
+    assert!(!health[0].healthy);
+    assert_eq!(health[0].consecutive_failures, 2);
+
+    backend.healthy.store(true, Ordering::SeqCst);
+    listener.check_upstreams();
+    let health = listener.upstream_health();
+    assert!(health[0].healthy);
+    assert_eq!(health[0].consecutive_failures, 0);
+    assert_eq!(health[0].total_failures, 2);
+    assert_eq!(health[0].ejections, 1);
+}
+
+#[test]
+fn all_backends_down_is_a_temporary_failure() {
+    let listener = InboundListener::bind(
+        relay_config(vec![
+            PostfixBackend::new(dead_addr()),
+            PostfixBackend::new(dead_addr()),
+        ]),
+        NoopTlsUpgrader,
+    )
+    .expect("relay listener should bind");
+
+    assert!(mail_via(&listener).starts_with("451 4.4.0 Postfix relay unavailable"));
+    assert!(listener
+        .upstream_health()
+        .iter()
+        .all(|health| health.total_failures == 1));
+}
+
+fn relay_config(backends: Vec<PostfixBackend>) -> ListenerConfig {
+    ListenerConfig {
+        bind_addr: addr("127.0.0.1:0"),
+        banner_host: "mx.verzola.test".to_string(),
+        inbound_tls_policy: InboundTlsPolicy::Opportunistic,
+        postfix_upstreams: backends,
+        ..ListenerConfig::default()
+    }
+}
+
+fn pool_config(failure_threshold: u32) -> UpstreamPoolConfig {
+    UpstreamPoolConfig {
+        failure_threshold,
+        connect_timeout: Duration::from_secs(2),
+        health_check_timeout: Duration::from_secs(2),
+        ..UpstreamPoolConfig::default()
+    }
+}
+
+fn addr(value: &str) -> SocketAddr {
+    value.parse().expect("hard-coded socket address must parse")
+}
+
+// Binds and immediately releases a port, so connecting to it is refused.
+fn dead_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("placeholder socket should bind");
+    listener
+        .local_addr()
+        .expect("placeholder address must resolve")
+}
+
+// Runs one client session (EHLO, MAIL, QUIT) and returns the MAIL reply.
+fn mail_via(listener: &InboundListener<NoopTlsUpgrader>) -> String {
+    let address = listener.local_addr().expect("listener address must resolve");
+    thread::scope(|scope| {
+        let server = scope.spawn(|| listener.serve_one());
+
+        let (mut stream, mut reader) = connect(address);
+        let _banner = read_reply(&mut reader);
+        send(&mut stream, "EHLO sender.example\r\n");
+        let _ehlo_reply = read_reply(&mut reader);
+        send(&mut stream, "MAIL FROM:<alice@example.com>\r\n");
+        let mail_reply = read_reply(&mut reader).join("\n");
+        send(&mut stream, "QUIT\r\n");
+        let _quit_reply = read_reply(&mut reader);
+
+        join_session(server.join().expect("listener thread should not panic"));
+        mail_reply
+    })
+}
+
+fn join_session(result: std::io::Result<SessionSummary>) -> SessionSummary {
+    result.expect("listener should return a session summary")
+}
+
+fn spawn_mock_postfix(tag: &'static str) -> MockPostfix {
+    let listener =
+        TcpListener::bind("127.0.0.1:0").expect("mock postfix listener should bind to localhost");
+    let addr = listener
+        .local_addr()
+        .expect("mock postfix listener address should resolve");
+    let sessions = Arc::new(AtomicUsize::new(0));
+    let healthy = Arc::new(AtomicBool::new(true));
+
+    let (session_count, is_healthy) = (Arc::clone(&sessions), Arc::clone(&healthy));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            session_count.fetch_add(1, Ordering::SeqCst);
+            let _ = handle_postfix_session(stream, tag, is_healthy.load(Ordering::SeqCst));
+        }
+    });
+
+    MockPostfix {
+        addr,
+        sessions,
+        healthy,
+    }
+}
+
+fn handle_postfix_session(mut stream: TcpStream, tag: &str, healthy: bool) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(3)))?;
+    if !healthy {
+        return write_line(&mut stream, "421 4.3.2 postfix.verzola.test busy");
+    }
+    write_line(&mut stream, "220 postfix.verzola.test ESMTP Postfix")?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let verb = line
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_ascii_uppercase();
+        match verb.as_str() {
+            "EHLO" => write_line(&mut stream, "250 postfix.verzola.test")?,
+            "MAIL" => write_line(&mut stream, &format!("250 2.1.0 Sender OK ({})", tag))?,
+            "QUIT" => return write_line(&mut stream, "221 2.0.0 Bye"),
+            _ => write_line(&mut stream, "250 2.0.0 OK")?,
+        }
+    }
+}
+
+fn write_line(stream: &mut TcpStream, line: &str) -> std::io::Result<()> {
+    write!(stream, "{}\r\n", line)?;
+    stream.flush()
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("test client read timeout should set");
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, command: &str) {
+    stream
+        .write_all(command.as_bytes())
+        .expect("test client should write command bytes");
+    stream.flush().expect("test client flush should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut raw = String::new();
+        let bytes = reader
+            .read_line(&mut raw)
+            .expect("test client should read server reply");
+        assert!(bytes > 0, "server closed connection unexpectedly");
+        let line = raw.trim_end_matches(['\r', '\n']).to_string();
+        lines.push(line.clone());
+
+        let line_bytes = line.as_bytes();
+        if line_bytes.len() < 4 || line_bytes[3] == b' ' {
+            break;
+        }
+    }
+    lines
+}
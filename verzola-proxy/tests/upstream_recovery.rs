@@ -0,0 +1,408 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use verzola_proxy::inbound::{
+    InboundListener, InboundTlsPolicy, ListenerConfig, NoopTlsUpgrader, SessionSummary,
+};
+use verzola_proxy::transaction::TransactionOutcome;
+
+// What one fake Postfix connection does wrong. `nth` counts commands with that verb
+// on the connection, starting at 1.
+#[derive(Debug, Clone, Copy)]
+enum Fault {
+    None,
+    // Closes the connection instead of answering, like a crashed smtpd.
+    DropAt(&'static str, usize),
+    // Answers 421 and closes, like a Postfix shutting down.
+    ShutdownAt(&'static str, usize),
+    DropInDataBody,
+    RejectRecipients,
+}
+
+type CommandLog = Arc<Mutex<Vec<Vec<String>>>>;
+
+#[test]
+fn drop_at_mail_is_retried_on_a_new_connection() {
+    let (postfix_addr, log) = spawn_fake_postfix(vec![Fault::DropAt("MAIL", 1), Fault::None]);
+    let (address, handle) = spawn_listener(postfix_addr);
+    let (mut stream, mut reader) = open_session(address);
+
+    assert_eq!(
+        command(&mut stream, &mut reader, "MAIL FROM:<alice@example.com> SIZE=512"),
+        "250 2.1.0 Ok"
+    );
+    deliver(&mut stream, &mut reader, &["bob@example.net"]);
+    quit(&mut stream, &mut reader);
+
+    assert_eq!(join_listener(handle).protocol_errors, 0);
+    let connections = log.lock().expect("command log should lock").clone();
+    assert_eq!(connections.len(), 2);
+    assert_eq!(connections[1][1], "MAIL FROM:<alice@example.com> SIZE=512");
+}
+
+#[test]
+fn drop_at_rcpt_replays_the_envelope() {
+    let (postfix_addr, log) = spawn_fake_postfix(vec![Fault::DropAt("RCPT", 2), Fault::None]);
+    let (address, handle) = spawn_listener(postfix_addr);
+    let (mut stream, mut reader) = open_session(address);
+
+    command(&mut stream, &mut reader, "MAIL FROM:<alice@example.com> BODY=8BITMIME");
+    assert_eq!(
+        command(&mut stream, &mut reader, "RCPT TO:<bob@example.net>"),
+        "250 2.1.5 Ok"
+    );
+    assert_eq!(
+        command(&mut stream, &mut reader, "RCPT TO:<carol@example.net> NOTIFY=NEVER"),
+        "250 2.1.5 Ok"
+    );
+    assert!(command(&mut stream, &mut reader, "DATA").starts_with("354 "));
+    assert_eq!(
+        finish_data(&mut stream, &mut reader),
+        "250 2.0.0 Ok: queued as REPLAYED"
+    );
+    quit(&mut stream, &mut reader);
+
+    let summary = join_listener(handle);
+    assert_eq!(summary.protocol_errors, 0);
+    assert_eq!(summary.transactions.len(), 1);
+    assert_eq!(summary.transactions[0].recipient_count, 2);
+    let connections = log.lock().expect("command log should lock").clone();
+    assert_eq!(
+        connections[1],
+        vec![
+            "EHLO mx.verzola.test",
+            "MAIL FROM:<alice@example.com> BODY=8BITMIME",
+            "RCPT TO:<bob@example.net>",
+            "RCPT TO:<carol@example.net> NOTIFY=NEVER",
+            "DATA",
+            "QUIT",
+        ]
+    );
+}
+
+#[test]
+fn drop_at_data_command_replays_the_envelope() {
+    let (postfix_addr, log) = spawn_fake_postfix(vec![Fault::DropAt("DATA", 1), Fault::None]);
+    let (address, handle) = spawn_listener(postfix_addr);
+    let (mut stream, mut reader) = open_session(address);
+
+    command(&mut stream, &mut reader, "MAIL FROM:<alice@example.com>");
+    deliver(&mut stream, &mut reader, &["bob@example.net"]);
+    quit(&mut stream, &mut reader);
+
+    assert_eq!(join_listener(handle).protocol_errors, 0);
+    let connections = log.lock().expect("command log should lock").clone();
+    assert_eq!(
+        &connections[1][1..4],
+        &["MAIL FROM:<alice@example.com>", "RCPT TO:<bob@example.net>", "DATA"]
+    );
+}
+
+#[test]
+fn shutdown_reply_is_treated_like_a_drop() {
+    let (postfix_addr, _log) =
+        spawn_fake_postfix(vec![Fault::ShutdownAt("RCPT", 1), Fault::None]);
+    let (address, handle) = spawn_listener(postfix_addr);
+    let (mut stream, mut reader) = open_session(address);
+
+    command(&mut stream, &mut reader, "MAIL FROM:<alice@example.com>");
+    deliver(&mut stream, &mut reader, &["bob@example.net"]);
+    quit(&mut stream, &mut reader);
+    assert_eq!(join_listener(handle).protocol_errors, 0);
+}
+
+#[test]
+fn drop_in_message_body_fails_the_transaction_without_replay() {
+    let (postfix_addr, log) = spawn_fake_postfix(vec![Fault::DropInDataBody, Fault::None]);
+    let (address, handle) = spawn_listener(postfix_addr);
+    let (mut stream, mut reader) = open_session(address);
+
+    command(&mut stream, &mut reader, "MAIL FROM:<alice@example.com>");
+    command(&mut stream, &mut reader, "RCPT TO:<bob@example.net>");
+    assert!(command(&mut stream, &mut reader, "DATA").starts_with("354 "));
+    assert!(finish_data(&mut stream, &mut reader).starts_with("451 4.3.0 DATA relay failure"));
+
+    assert_eq!(command(&mut stream, &mut reader, "RSET"), "250 2.0.0 Reset state");
+    command(&mut stream, &mut reader, "MAIL FROM:<alice@example.com>");
+    deliver(&mut stream, &mut reader, &["bob@example.net"]);
+    quit(&mut stream, &mut reader);
+
+    join_listener(handle);
+    let connections = log.lock().expect("command log should lock").clone();
+    assert_eq!(connections.len(), 2);
+    assert_eq!(connections[1][1], "MAIL FROM:<alice@example.com>");
+}
+
+#[test]
+fn rejected_replay_fails_the_whole_transaction_until_rset() {
+    let (postfix_addr, _log) = spawn_fake_postfix(vec![
+        Fault::DropAt("RCPT", 2),
+        Fault::RejectRecipients,
+        Fault::None,
+    ]);
+    let (address, handle) = spawn_listener(postfix_addr);
+    let (mut stream, mut reader) = open_session(address);
+
+    command(&mut stream, &mut reader, "MAIL FROM:<alice@example.com>");
+    command(&mut stream, &mut reader, "RCPT TO:<bob@example.net>");
+    let rcpt_reply = command(&mut stream, &mut reader, "RCPT TO:<carol@example.net>");
+    assert!(rcpt_reply.starts_with("451 4.4.0 Postfix relay unavailable"));
+    assert!(rcpt_reply.contains("replayed RCPT"));
+    let lost = "451 4.4.0 Upstream transaction lost, send RSET and try again";
+    assert_eq!(command(&mut stream, &mut reader, "RCPT TO:<dave@example.net>"), lost);
+    assert_eq!(command(&mut stream, &mut reader, "DATA"), lost);
+
+    assert_eq!(command(&mut stream, &mut reader, "RSET"), "250 2.0.0 Reset state");
+    command(&mut stream, &mut reader, "MAIL FROM:<alice@example.com>");
+    deliver(&mut stream, &mut reader, &["bob@example.net"]);
+    quit(&mut stream, &mut reader);
+
+    let summary = join_listener(handle);
+    assert_eq!(summary.telemetry.relay_temporary_failures, 1);
+    let outcomes = summary
+        .transactions
+        .iter()
+        .map(|record| record.outcome)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        outcomes,
+        vec![TransactionOutcome::Deferred, TransactionOutcome::Accepted]
+    );
+}
+
+#[test]
+fn unreachable_upstream_after_drop_is_a_temporary_failure() {
+    let (postfix_addr, _log) = spawn_fake_postfix(vec![Fault::DropAt("DATA", 1)]);
+    let (address, handle) = spawn_listener(postfix_addr);
+    let (mut stream, mut reader) = open_session(address);
+
+    command(&mut stream, &mut reader, "MAIL FROM:<alice@example.com>");
+    command(&mut stream, &mut reader, "RCPT TO:<bob@example.net>");
+    assert!(command(&mut stream, &mut reader, "DATA")
+        .starts_with("451 4.4.0 Postfix relay unavailable"));
+    assert_eq!(
+        command(&mut stream, &mut reader, "DATA"),
+        "451 4.4.0 Upstream transaction lost, send RSET and try again"
+    );
+    quit(&mut stream, &mut reader);
+    join_listener(handle);
+}
+
+#[test]
+fn new_ehlo_resets_the_upstream_transaction() {
+    let (postfix_addr, log) = spawn_fake_postfix(vec![Fault::None]);
+    let (address, handle) = spawn_listener(postfix_addr);
+    let (mut stream, mut reader) = open_session(address);
+
+    command(&mut stream, &mut reader, "MAIL FROM:<alice@example.com>");
+    command(&mut stream, &mut reader, "RCPT TO:<bob@example.net>");
+    command(&mut stream, &mut reader, "EHLO sender.example");
+    command(&mut stream, &mut reader, "MAIL FROM:<carol@example.com>");
+    deliver(&mut stream, &mut reader, &["dave@example.net"]);
+    quit(&mut stream, &mut reader);
+
+    join_listener(handle);
+    let connections = log.lock().expect("command log should lock").clone();
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections[0][3], "RSET");
+    assert_eq!(connections[0][4], "MAIL FROM:<carol@example.com>");
+}
+
+fn spawn_listener(
+    postfix_addr: SocketAddr,
+) -> (SocketAddr, thread::JoinHandle<std::io::Result<SessionSummary>>) {
+    let config = ListenerConfig {
+        bind_addr: "127.0.0.1:0"
+            .parse()
+            .expect("hard-coded socket address must parse"),
+        banner_host: "mx.verzola.test".to_string(),
+        inbound_tls_policy: InboundTlsPolicy::Opportunistic,
+        postfix_upstream_addr: Some(postfix_addr),
+        ..ListenerConfig::default()
+    };
+    let listener = InboundListener::bind(config, NoopTlsUpgrader).expect("listener should bind");
+    let address = listener.local_addr().expect("listener address must resolve");
+    (address, thread::spawn(move || listener.serve_one()))
+}
+
+fn join_listener(handle: thread::JoinHandle<std::io::Result<SessionSummary>>) -> SessionSummary {
+    handle
+        .join()
+        .expect("listener thread should not panic")
+        .expect("listener should return a session summary")
+}
+
+// Serves one connection per fault, in order, then stops listening so further
+// connects are refused. Returns each connection's commands.
+fn spawn_fake_postfix(faults: Vec<Fault>) -> (SocketAddr, CommandLog) {
+    let listener =
+        TcpListener::bind("127.0.0.1:0").expect("fake postfix listener should bind to localhost");
+    let address = listener
+        .local_addr()
+        .expect("fake postfix listener address should resolve");
+    let log: CommandLog = Arc::new(Mutex::new(Vec::new()));
+
+    let connection_log = Arc::clone(&log);
+    thread::spawn(move || {
+        for fault in faults {
+            let Ok((stream, _)) = listener.accept() else {
+                return;
+            };
+            let index = {
+                let mut log = connection_log.lock().expect("command log should lock");
+                log.push(Vec::new());
+                log.len() - 1
+            };
+            let _ = serve_postfix_connection(stream, fault, &connection_log, index);
+        }
+    });
+
+    (address, log)
+}
+
+fn serve_postfix_connection(
+    mut stream: TcpStream,
+    fault: Fault,
+    log: &CommandLog,
+    index: usize,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(3)))?;
+    write_line(&mut stream, "220 postfix.verzola.test ESMTP Postfix")?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut seen = Vec::<String>::new();
+    let mut reading_data = false;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        if reading_data {
+            if matches!(fault, Fault::DropInDataBody) {
+                return Ok(());
+            }
+            if line == ".\r\n" {
+                reading_data = false;
+                write_line(&mut stream, "250 2.0.0 Ok: queued as REPLAYED")?;
+            }
+            continue;
+        }
+
+        let command = line.trim_end_matches(['\r', '\n']).to_string();
+        let verb = command
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_ascii_uppercase();
+        log.lock().expect("command log should lock")[index].push(command);
+        seen.push(verb.clone());
+        let nth = seen.iter().filter(|previous| **previous == verb).count();
+
+        match fault {
+            Fault::DropAt(fault_verb, fault_nth) if fault_verb == verb && fault_nth == nth => {
+                return Ok(());
+            }
+            Fault::ShutdownAt(fault_verb, fault_nth) if fault_verb == verb && fault_nth == nth => {
+                return write_line(&mut stream, "421 4.3.0 Service shutting down");
+            }
+            _ => {}
+        }
+
+        match verb.as_str() {
+            "EHLO" => write_line(&mut stream, "250 postfix.verzola.test")?,
+            "MAIL" => write_line(&mut stream, "250 2.1.0 Ok")?,
+            "RCPT" if matches!(fault, Fault::RejectRecipients) => {
+                write_line(&mut stream, "450 4.1.1 Recipient address rejected: try later")?
+            }
+            "RCPT" => write_line(&mut stream, "250 2.1.5 Ok")?,
+            "RSET" => write_line(&mut stream, "250 2.0.0 Ok")?,
+            "DATA" => {
+                reading_data = true;
+                write_line(&mut stream, "354 End data with <CR><LF>.<CR><LF>")?;
+            }
+            "QUIT" => return write_line(&mut stream, "221 2.0.0 Bye"),
+            _ => write_line(&mut stream, "502 5.5.2 Error: command not recognized")?,
+        }
+    }
+}
+
+// Connects, reads the banner and sends EHLO.
+fn open_session(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let (mut stream, mut reader) = connect(address);
+    let _banner = read_reply(&mut reader);
+    command(&mut stream, &mut reader, "EHLO sender.example");
+    (stream, reader)
+}
+
+// Sends RCPTs, DATA and a short body, asserting that each step is accepted.
+fn deliver(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, recipients: &[&str]) {
+    for recipient in recipients {
+        let reply = command(stream, reader, &format!("RCPT TO:<{}>", recipient));
+        assert!(reply.starts_with("250 "), "RCPT was not accepted: {}", reply);
+    }
+    let reply = command(stream, reader, "DATA");
+    assert!(reply.starts_with("354 "), "DATA was not accepted: {}", reply);
+    assert_eq!(finish_data(stream, reader), "250 2.0.0 Ok: queued as REPLAYED");
+}
+
+fn finish_data(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>) -> String {
+    send(stream, "Subject: recovery\r\n\r\nHello.\r\n.\r\n");
+    read_reply(reader).join("\n")
+}
+
+fn command(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, line: &str) -> String {
+    send(stream, &format!("{}\r\n", line));
+    read_reply(reader).join("\n")
+}
+
+fn quit(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>) {
+    assert!(command(stream, reader, "QUIT").starts_with("221 "));
+}
+
+fn write_line(stream: &mut TcpStream, line: &str) -> std::io::Result<()> {
+    write!(stream, "{}\r\n", line)?;
+    stream.flush()
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).expect("client should connect to test listener");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("test client read timeout should set");
+    let reader = BufReader::new(
+        stream
+            .try_clone()
+            .expect("test client socket clone should succeed"),
+    );
+    (stream, reader)
+}
+
+fn send(stream: &mut TcpStream, command: &str) {
+    stream
+        .write_all(command.as_bytes())
+        .expect("test client should write command bytes");
+    stream.flush().expect("test client flush should succeed");
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut raw = String::new();
+        let bytes = reader
+            .read_line(&mut raw)
+            .expect("test client should read server reply");
+        assert!(bytes > 0, "server closed connection unexpectedly");
+        let line = raw.trim_end_matches(['\r', '\n']).to_string();
+        lines.push(line.clone());
+
+        let line_bytes = line.as_bytes();
+        if line_bytes.len() < 4 || line_bytes[3] == b' ' {
+            break;
+        }
+    }
+    lines
+}